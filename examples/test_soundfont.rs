@@ -0,0 +1,192 @@
+//! Builds `src/embedded_assets/test.sf2`, the tiny soundfont embedded by the `test-sf` feature.
+//!
+//! Every melodic preset plays the same looped sine wave, and the percussion preset plays short
+//! sine blips. Run with `cargo run --example test_soundfont` after changing it.
+
+use std::{fs, path::Path};
+
+/// Length of the looped sine wave, a single cycle of 440 Hz at 44 kHz
+const CYCLE: usize = 100;
+const SAMPLE_RATE: u32 = 44_000;
+const ROOT_KEY: u8 = 69;
+/// Peak level of the sine wave, leaving headroom for chords
+const AMPLITUDE: f64 = 0.8;
+/// Silent sample points the SF2 spec requires after each sample
+const SAMPLE_PADDING: usize = 46;
+
+const ATTACK_VOL_ENV: u16 = 34;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const INITIAL_ATTENUATION: u16 = 48;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+
+/// Generator amount of a time in seconds, in timecents
+fn timecents(seconds: f64) -> i16 {
+    (1200.0 * seconds.log2()).round() as i16
+}
+
+/// Name field of a record, padded with zeros
+fn name(name: &str, len: usize) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.resize(len, 0);
+    bytes
+}
+
+fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut bytes = id.to_vec();
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+    // Chunks are padded to an even length
+    bytes.resize(bytes.len() + data.len() % 2, 0);
+    bytes
+}
+
+fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = kind.to_vec();
+    chunks
+        .iter()
+        .for_each(|chunk| data.extend_from_slice(chunk));
+    chunk(b"LIST", &data)
+}
+
+/// Records of one level of the hierarchy: headers pointing at their first zone, and zones
+/// pointing at their first generator
+#[derive(Default)]
+struct Level {
+    headers: Vec<u8>,
+    bags: Vec<u8>,
+    generators: Vec<u8>,
+}
+
+impl Level {
+    fn bag(&mut self, generators: &[(u16, i16)]) {
+        let generator_index = (self.generators.len() / 4) as u16;
+        self.bags.extend_from_slice(&generator_index.to_le_bytes());
+        self.bags.extend_from_slice(&0_u16.to_le_bytes());
+        for (generator, amount) in generators {
+            self.generators.extend_from_slice(&generator.to_le_bytes());
+            self.generators.extend_from_slice(&amount.to_le_bytes());
+        }
+    }
+
+    fn bag_index(&self) -> u16 {
+        (self.bags.len() / 4) as u16
+    }
+
+    /// Terminal zone and generator records
+    fn finish(&mut self) {
+        self.bag(&[(0, 0)]);
+    }
+}
+
+fn main() {
+    let mut samples: Vec<u8> = (0..CYCLE)
+        .map(|index| {
+            let phase = index as f64 / CYCLE as f64 * std::f64::consts::TAU;
+            (phase.sin() * AMPLITUDE * i16::MAX as f64).round() as i16
+        })
+        .flat_map(i16::to_le_bytes)
+        .collect();
+    samples.resize((CYCLE + SAMPLE_PADDING) * 2, 0);
+
+    let mut instruments = Level::default();
+    let sine = [
+        (ATTACK_VOL_ENV, timecents(0.005)),
+        (RELEASE_VOL_ENV, timecents(0.2)),
+    ];
+    // Short blips which decay to silence while held
+    let drum = [
+        (ATTACK_VOL_ENV, timecents(0.001)),
+        (DECAY_VOL_ENV, timecents(0.15)),
+        (SUSTAIN_VOL_ENV, 1000),
+        (RELEASE_VOL_ENV, timecents(0.15)),
+    ];
+    for (title, envelope) in [("Sine", &sine[..]), ("Sine Drum", &drum[..])] {
+        instruments.headers.extend(name(title, 20));
+        instruments
+            .headers
+            .extend_from_slice(&instruments.bag_index().to_le_bytes());
+        // A single looped zone, 6 dB down
+        let mut zone = vec![(SAMPLE_MODES, 1)];
+        zone.extend_from_slice(envelope);
+        zone.extend([(INITIAL_ATTENUATION, 60), (SAMPLE_ID, 0)]);
+        instruments.bag(&zone);
+    }
+    instruments.headers.extend(name("EOI", 20));
+    instruments
+        .headers
+        .extend_from_slice(&instruments.bag_index().to_le_bytes());
+    instruments.finish();
+
+    let mut presets = Level::default();
+    let melodic = (0..128).map(|preset| (format!("Sine {preset}"), preset, 0, 0));
+    for (title, preset, bank, instrument) in melodic.chain([("Sine Drums".into(), 0, 128, 1)]) {
+        presets.headers.extend(name(&title, 20));
+        presets
+            .headers
+            .extend_from_slice(&(preset as u16).to_le_bytes());
+        presets
+            .headers
+            .extend_from_slice(&(bank as u16).to_le_bytes());
+        presets
+            .headers
+            .extend_from_slice(&presets.bag_index().to_le_bytes());
+        // Library, genre and morphology
+        presets.headers.extend_from_slice(&[0; 12]);
+        presets.bag(&[(INSTRUMENT, instrument)]);
+    }
+    presets.headers.extend(name("EOP", 24));
+    presets
+        .headers
+        .extend_from_slice(&presets.bag_index().to_le_bytes());
+    presets.headers.extend_from_slice(&[0; 12]);
+    presets.finish();
+
+    let mut sample_headers = name("Sine", 20);
+    for value in [0, CYCLE as u32, 0, CYCLE as u32, SAMPLE_RATE] {
+        sample_headers.extend_from_slice(&value.to_le_bytes());
+    }
+    // Root key and pitch correction, then the sample link and a mono sample type
+    sample_headers.extend_from_slice(&[ROOT_KEY, 0, 0, 0, 1, 0]);
+    sample_headers.extend(name("EOS", 46));
+
+    let modulators = [0; 10];
+    let sf2 = chunk(
+        b"RIFF",
+        &[
+            b"sfbk".to_vec(),
+            list(
+                b"INFO",
+                &[
+                    chunk(b"ifil", &[2, 0, 1, 0]),
+                    chunk(b"isng", b"EMU8000\0"),
+                    chunk(b"INAM", &name("bevy_rustysynth test", 22)),
+                    chunk(b"ICMT", &name("Sine wave test soundfont", 26)),
+                ],
+            ),
+            list(b"sdta", &[chunk(b"smpl", &samples)]),
+            list(
+                b"pdta",
+                &[
+                    chunk(b"phdr", &presets.headers),
+                    chunk(b"pbag", &presets.bags),
+                    chunk(b"pmod", &modulators),
+                    chunk(b"pgen", &presets.generators),
+                    chunk(b"inst", &instruments.headers),
+                    chunk(b"ibag", &instruments.bags),
+                    chunk(b"imod", &modulators),
+                    chunk(b"igen", &instruments.generators),
+                    chunk(b"shdr", &sample_headers),
+                ],
+            ),
+        ]
+        .concat(),
+    );
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/embedded_assets/test.sf2");
+    fs::write(&path, sf2).expect("Failed to write the test soundfont.");
+    println!("Wrote {}", path.display());
+}
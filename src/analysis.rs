@@ -0,0 +1,137 @@
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+};
+
+use bevy::prelude::*;
+
+use crate::MidiControl;
+
+/// Number of frames the decoder collects before handing them to an analyzer
+pub(crate) const TAP_BLOCK: usize = 256;
+
+/// Recent output of a source, shared between the decoder and its [`MidiAnalyzer`]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AnalysisTap(Arc<Mutex<VecDeque<f32>>>);
+
+impl AnalysisTap {
+    /// Append mono samples, keeping at most `capacity` of the most recent ones
+    pub(crate) fn push(&self, samples: &[f32], capacity: usize) {
+        let mut buffer = self.0.lock().unwrap();
+        buffer.extend(samples);
+        let excess = buffer.len().saturating_sub(capacity);
+        buffer.drain(..excess);
+    }
+}
+
+/// Component capturing the recent output of a MIDI source for music visualizers.
+///
+/// Every frame, [`MidiAnalyzer::waveform`] is filled with the latest mono samples and
+/// [`MidiAnalyzer::spectrum`] with their magnitude spectrum.
+#[derive(Component, Clone, Debug)]
+pub struct MidiAnalyzer {
+    size: usize,
+    /// Most recent mono samples, oldest first
+    pub waveform: Vec<f32>,
+    /// Magnitude of each frequency bin of the waveform, from 0 Hz up to half the sample rate
+    pub spectrum: Vec<f32>,
+    tap: AnalysisTap,
+}
+
+impl Default for MidiAnalyzer {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl MidiAnalyzer {
+    /// Construct an analyzer over the given number of samples, rounded up to a power of two
+    pub fn new(size: usize) -> Self {
+        let size = size.max(2).next_power_of_two();
+        Self {
+            size,
+            waveform: vec![0.0; size],
+            spectrum: vec![0.0; size / 2],
+            tap: AnalysisTap::default(),
+        }
+    }
+
+    /// Number of samples analyzed
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Center frequency in Hz of the given spectrum bin
+    pub fn bin_frequency(&self, bin: usize) -> f32 {
+        bin as f32 * 44100.0 / self.size as f32
+    }
+}
+
+type AnalyzerChanged = Or<(Added<MidiAnalyzer>, Added<MidiControl>)>;
+
+pub(crate) fn attach_analyzers(query: Query<(&MidiControl, &MidiAnalyzer), AnalyzerChanged>) {
+    for (control, analyzer) in &query {
+        control.set_tap(analyzer.tap.clone(), analyzer.size);
+    }
+}
+
+pub(crate) fn update_analyzers(mut query: Query<&mut MidiAnalyzer>) {
+    for mut analyzer in &mut query {
+        let analyzer = &mut *analyzer;
+        {
+            let buffer = analyzer.tap.0.lock().unwrap();
+            let offset = analyzer.size - buffer.len().min(analyzer.size);
+            analyzer.waveform[..offset].fill(0.0);
+            for (sample, value) in analyzer.waveform[offset..].iter_mut().zip(buffer.iter()) {
+                *sample = *value;
+            }
+        }
+        spectrum(&analyzer.waveform, &mut analyzer.spectrum);
+    }
+}
+
+/// Compute the magnitude spectrum of `samples` under a Hann window
+pub(crate) fn spectrum(samples: &[f32], magnitudes: &mut [f32]) {
+    let size = samples.len();
+    let mut real: Vec<f32> = samples
+        .iter()
+        .enumerate()
+        .map(|(index, sample)| sample * (0.5 - 0.5 * (2.0 * PI * index as f32 / size as f32).cos()))
+        .collect();
+    let mut imaginary = vec![0.0; size];
+    fft(&mut real, &mut imaginary);
+    for (bin, magnitude) in magnitudes.iter_mut().enumerate() {
+        *magnitude = real[bin].hypot(imaginary[bin]) * 4.0 / size as f32;
+    }
+}
+
+/// In-place radix-2 fast Fourier transform over a power of two number of samples
+fn fft(real: &mut [f32], imaginary: &mut [f32]) {
+    let size = real.len();
+    let bits = size.trailing_zeros();
+    for index in 0..size {
+        let reversed = index.reverse_bits() >> (usize::BITS - bits);
+        if reversed > index {
+            real.swap(index, reversed);
+            imaginary.swap(index, reversed);
+        }
+    }
+    let mut len = 2;
+    while len <= size {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..size).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_real = real[b] * cos - imaginary[b] * sin;
+                let t_imaginary = real[b] * sin + imaginary[b] * cos;
+                real[b] = real[a] - t_real;
+                imaginary[b] = imaginary[a] - t_imaginary;
+                real[a] += t_real;
+                imaginary[a] += t_imaginary;
+            }
+        }
+        len *= 2;
+    }
+}
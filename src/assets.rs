@@ -1,27 +1,28 @@
-use std::{
-    io::{self, Cursor},
-    sync::Arc,
-    time::Duration,
-};
+use std::{io, time::Duration};
 
-use async_channel::{Receiver, TryRecvError};
 use bevy::{
     asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
-    audio::Source,
     prelude::*,
-    tasks::AsyncComputeTaskPool,
 };
-use itertools::Itertools;
-use rustysynth::{MidiFile, MidiFileSequencer, SoundFont, Synthesizer, SynthesizerSettings};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    decoder::SourceProgram, midi::Smf, settings::render_settings, MidiControl, MidiDynamics,
+    MidiError, MidiFileDecoder, MidiSyncGroup,
+};
 
 /// Represents a single MIDI note in a sequence
-#[derive(Clone, Debug)]
+#[derive(Reflect, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct MidiNote {
     /// Channel to play the note on
     pub channel: i32,
     /// Preset (instrument) to play the note with (see GM spec.)
     pub preset: i32,
-    /// Bank to play note with
+    /// Bank to play the note with, numbered as in the soundfont: 0 for General MIDI, variation
+    /// banks from 1 to 127, and percussion kits from 128 on channel 9. It's selected with bank
+    /// select messages (CC0 and CC32), with larger banks sent as 14-bit numbers for synthesizers
+    /// which use them.
     pub bank: i32,
     /// Key to play (60 is middle C)
     pub key: i32,
@@ -44,159 +45,347 @@ impl Default for MidiNote {
     }
 }
 
+/// A note starting at an absolute time, such as one taken from a MIDI file
+#[derive(Reflect, Clone, Debug)]
+pub struct TimedMidiNote {
+    /// Time at which the note starts
+    pub start: Duration,
+    /// The note, with the preset and bank of its channel at the time it started
+    pub note: MidiNote,
+}
+
+/// An event of a [`MidiAudio::Score`], where a beat is a quarter note
+#[derive(Reflect, Serialize, Deserialize, Clone, Debug)]
+pub enum ScoreEvent {
+    /// Play a note for the given number of beats at the current tempo, then continue after it.
+    /// The note's own duration is ignored.
+    Note {
+        /// The note to play
+        note: MidiNote,
+        /// Length of the note in beats
+        beats: f64,
+    },
+    /// Play notes together for the given number of beats at the current tempo, then continue
+    /// after them. The notes' own durations are ignored.
+    Chord {
+        /// The notes to play
+        notes: Vec<MidiNote>,
+        /// Length of the chord in beats
+        beats: f64,
+    },
+    /// Wait for the given number of beats
+    Rest(f64),
+    /// Change the tempo to the given quarter notes per minute from here on
+    Tempo(f64),
+    /// Change the time signature from here on, which otherwise is 4/4, so beat and bar
+    /// boundaries follow the score, such as for the [`MidiTransport`](crate::MidiTransport)'s
+    /// grid or a [`MidiPianoRoll`](crate::MidiPianoRoll)
+    TimeSignature {
+        /// Number of beats in a bar
+        numerator: u8,
+        /// Note value of a beat, such as 4 for quarter notes or 8 for eighth notes
+        denominator: u8,
+    },
+    /// Change the tempo gradually while the following events play, such as for a ritardando or
+    /// accelerando
+    TempoRamp {
+        /// Tempo reached at the end of the ramp, in quarter notes per minute
+        bpm: f64,
+        /// Length of the ramp in beats
+        beats: f64,
+    },
+}
+
 /// MIDI audio asset
-#[derive(Asset, TypePath, Clone, Debug)]
+#[derive(Asset, Reflect, Clone, Debug)]
 pub enum MidiAudio {
     /// Plays audio from a MIDI file
     File(Vec<u8>),
     /// Plays a simple sequence of notes
     Sequence(Vec<MidiNote>),
+    /// Plays notes at their own start times, so they can overlap, such as to play chords,
+    /// several parts at once or rhythms off the beat
+    Timeline(Vec<TimedMidiNote>),
+    /// Plays a score timed in beats, one event after another, whose tempo can change as it
+    /// plays
+    Score(Vec<ScoreEvent>),
+}
+
+/// A single controllable playback of MIDI audio
+///
+/// Created automatically for entities which carry a [`MidiControl`], [`MidiSyncGroup`],
+/// [`MidiSegmentPlayer`](crate::MidiSegmentPlayer) or [`MidiLayerPlayer`](crate::MidiLayerPlayer).
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct MidiSource {
+    pub(crate) program: SourceProgram,
+    /// Control shared with the playing decoder
+    pub control: MidiControl,
+    /// Sync group the decoder follows
+    pub sync: Option<MidiSyncGroup>,
+}
+
+/// Selects a track of a MIDI file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TrackSelector {
+    /// Track at the given index, starting from 0
+    Index(usize),
+    /// Track with the given name
+    Name(String),
+}
+
+impl TrackSelector {
+    fn matches(&self, index: usize, name: Option<&str>) -> bool {
+        match self {
+            TrackSelector::Index(selected) => *selected == index,
+            TrackSelector::Name(selected) => name == Some(selected.as_str()),
+        }
+    }
+}
+
+/// Chooses which tracks of a MIDI file are played
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TrackFilter {
+    /// Play every track
+    #[default]
+    All,
+    /// Only play the selected tracks
+    Include(Vec<TrackSelector>),
+    /// Play every track except the selected ones
+    Exclude(Vec<TrackSelector>),
+}
+
+impl TrackFilter {
+    /// Whether the track at `index` with the given name is played
+    pub fn includes(&self, index: usize, name: Option<&str>) -> bool {
+        match self {
+            TrackFilter::All => true,
+            TrackFilter::Include(selectors) => selectors
+                .iter()
+                .any(|selector| selector.matches(index, name)),
+            TrackFilter::Exclude(selectors) => !selectors
+                .iter()
+                .any(|selector| selector.matches(index, name)),
+        }
+    }
+}
+
+/// Settings for loading MIDI files, allowing one file to be loaded as several variants
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MidiLoaderSettings {
+    /// Tracks whose notes are played. Tempo and marker events are kept from every track.
+    ///
+    /// In a format 2 file this applies to the chosen [`song`](Self::song), whose single track is
+    /// at index 0 whatever its index in the file.
+    pub tracks: TrackFilter,
+    /// Pairs of `(from, to)` channels, counting from 0, whose events are moved to another
+    /// channel. Several channels can be merged by moving them to the same one.
+    pub channel_map: Vec<(u8, u8)>,
+    /// Number of semitones notes are shifted by, excluding the percussion channel
+    pub transpose_semitones: i8,
+    /// Multiplier of the tempo
+    pub tempo_scale: f64,
+    /// Compression or expansion of the velocities of notes
+    pub dynamics: MidiDynamics,
+    /// Song to load from a format 2 file, which holds several independent songs, each being one
+    /// of its tracks
+    pub song: usize,
+    /// Whether common defects, such as truncated data and stray bytes, are tolerated with a
+    /// warning instead of failing to load
+    pub lenient: bool,
+}
+
+impl Default for MidiLoaderSettings {
+    fn default() -> Self {
+        Self {
+            tracks: TrackFilter::All,
+            channel_map: Vec::new(),
+            transpose_semitones: 0,
+            tempo_scale: 1.0,
+            dynamics: MidiDynamics::default(),
+            song: 0,
+            lenient: false,
+        }
+    }
+}
+
+impl MidiLoaderSettings {
+    /// Whether the file has to be rewritten to apply the settings
+    fn rewrites(&self) -> bool {
+        self.tracks != TrackFilter::All
+            || !self.channel_map.is_empty()
+            || self.transpose_semitones != 0
+            || self.tempo_scale != 1.0
+            || !self.dynamics.is_identity()
+    }
 }
 
-/// AssetLoader for MIDI files (.mid/.midi)
+/// AssetLoader for MIDI files (.mid/.midi), RIFF-wrapped MIDI files (.rmi) and karaoke files
+/// (.kar)
+///
+/// Each track of a multi-track file is also loaded on its own, labelled with its name and with
+/// its index as `Track{index}`, e.g. `song.mid#melody` or `song.mid#Track2`. Each song of a
+/// format 2 file is instead labelled `Song{index}`.
 #[derive(Default, Debug)]
 pub struct MidiAssetLoader;
 
 impl AssetLoader for MidiAssetLoader {
     type Asset = MidiAudio;
 
-    type Settings = ();
+    type Settings = MidiLoaderSettings;
 
-    type Error = io::Error;
+    type Error = MidiError;
 
     async fn load<'a>(
         &'a self,
         reader: &'a mut Reader<'_>,
-        _settings: &'a Self::Settings,
-        _load_context: &'a mut LoadContext<'_>,
+        settings: &'a Self::Settings,
+        load_context: &'a mut LoadContext<'_>,
     ) -> Result<Self::Asset, Self::Error> {
         let mut bytes = vec![];
         reader.read_to_end(&mut bytes).await?;
-        Ok(MidiAudio::File(bytes))
+        let parsed = if settings.lenient {
+            Smf::parse_lenient(&bytes).map(|(smf, warnings)| {
+                for warning in &warnings {
+                    warn!("{}: {warning}", load_context.path().display());
+                }
+                (smf, !warnings.is_empty())
+            })
+        } else {
+            Smf::parse(&bytes).map(|smf| (smf, false))
+        };
+        let (mut smf, repaired) = match parsed {
+            Ok(parsed) => parsed,
+            // Invalid files are only reported once played, unless they need to be rewritten
+            Err(_) if !settings.rewrites() && !settings.lenient => {
+                return Ok(MidiAudio::File(bytes))
+            }
+            Err(error) => return Err(MidiError::parse(error)),
+        };
+        smf.remap_channels(&settings.channel_map);
+        smf.transpose(settings.transpose_semitones as i32);
+        smf.scale_tempo(settings.tempo_scale);
+        smf.apply_dynamics(&settings.dynamics);
+        if smf.format == 2 {
+            for index in 0..smf.tracks.len() {
+                let song = smf.song(index).unwrap();
+                let audio = MidiAudio::File(filter_tracks(&song, &settings.tracks).to_bytes());
+                load_context.add_labeled_asset(format!("Song{index}"), audio);
+            }
+            let song = smf.song(settings.song).ok_or_else(|| {
+                MidiError::parse(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "MIDI file has no such song.",
+                ))
+            })?;
+            return Ok(MidiAudio::File(
+                filter_tracks(&song, &settings.tracks).to_bytes(),
+            ));
+        }
+        if smf.tracks.len() > 1 {
+            for index in 0..smf.tracks.len() {
+                let only = TrackFilter::Include(vec![TrackSelector::Index(index)]);
+                let audio = MidiAudio::File(filter_tracks(&smf, &only).to_bytes());
+                if let Some(name) = smf.track_name(index) {
+                    if !load_context.has_labeled_asset(name.to_string()) {
+                        load_context.add_labeled_asset(name.to_string(), audio.clone());
+                    }
+                }
+                load_context.add_labeled_asset(format!("Track{index}"), audio);
+            }
+        }
+        if !settings.rewrites() && !repaired {
+            return Ok(MidiAudio::File(bytes));
+        }
+        Ok(MidiAudio::File(
+            filter_tracks(&smf, &settings.tracks).to_bytes(),
+        ))
     }
 
     fn extensions(&self) -> &[&str] {
-        &["mid", "midi"]
+        &["mid", "midi", "rmi", "kar"]
     }
 }
 
-/// Decoder for MIDI file playback
-pub struct MidiFileDecoder {
-    sample_rate: usize,
-    stream: Receiver<f32>,
-}
+/// Loader for scores written by hand as a RON list of [`ScoreEvent`]s, with the `.midiseq.ron`
+/// extension, loaded as a [`MidiAudio::Score`].
+///
+/// Notes only need the fields which differ from [`MidiNote::default`], such as
+/// `Note(note: (key: 64), beats: 0.5)`.
+#[derive(Default, Debug)]
+pub struct MidiScoreLoader;
 
-impl MidiFileDecoder {
-    /// Construct and begin a new MIDI sequencer with the given MIDI data and soundfont.
-    ///
-    /// The sequencer will push at most 1 second's worth of audio ahead, allowing the decoder to
-    /// be paused without endlessly backing up data forever.
-    pub fn new(midi: MidiAudio, soundfont: Arc<SoundFont>) -> Self {
-        let sample_rate = 44100_usize;
-        let (tx, rx) = async_channel::bounded::<f32>(sample_rate * 2);
-        AsyncComputeTaskPool::get()
-            .spawn(async move {
-                let settings = SynthesizerSettings::new(sample_rate as i32);
-                let mut synthesizer =
-                    Synthesizer::new(&soundfont, &settings).expect("Failed to create synthesizer.");
-
-                match midi {
-                    MidiAudio::File(midi_data) => {
-                        let mut sequencer = MidiFileSequencer::new(synthesizer);
-                        let mut midi_data = Cursor::new(midi_data);
-                        let midi = Arc::new(
-                            MidiFile::new(&mut midi_data).expect("Failed to read midi file."),
-                        );
-                        sequencer.play(&midi, false);
-                        let mut left: Vec<f32> = vec![0_f32; sample_rate];
-                        let mut right: Vec<f32> = vec![0_f32; sample_rate];
-                        while !sequencer.end_of_sequence() {
-                            sequencer.render(&mut left, &mut right);
-                            for value in left.iter().interleave(right.iter()) {
-                                if let Err(_) = tx.send(*value).await {
-                                    return;
-                                };
-                            }
-                        }
-                    }
-                    MidiAudio::Sequence(sequence) => {
-                        for MidiNote {
-                            channel,
-                            preset,
-                            bank,
-                            key,
-                            velocity,
-                            duration,
-                        } in sequence.iter()
-                        {
-                            synthesizer.process_midi_message(*channel, 0xB0, 0x00, *bank);
-                            synthesizer.process_midi_message(*channel, 0xC0, *preset, 0);
-                            synthesizer.note_on(*channel, *key, *velocity);
-                            let note_length =
-                                (sample_rate as f32 * duration.as_secs_f32()) as usize;
-                            let mut left: Vec<f32> = vec![0_f32; note_length];
-                            let mut right: Vec<f32> = vec![0_f32; note_length];
-                            for (left, right) in left.chunks_mut(sample_rate).zip(right.chunks_mut(sample_rate)) {
-                                synthesizer.render(left, right);
-                                for value in left.iter().interleave(right.iter()) {
-                                    if let Err(_) = tx.send(*value).await {
-                                        return;
-                                    };
-                                }
-                            }
-                            synthesizer.note_off(*channel, *key);
-                        }
-                    }
-                }
+impl AssetLoader for MidiScoreLoader {
+    type Asset = MidiAudio;
 
-                tx.close();
-            })
-            .detach();
-        Self {
-            sample_rate,
-            stream: rx,
-        }
-    }
-}
+    type Settings = ();
 
-impl Iterator for MidiFileDecoder {
-    type Item = f32;
+    type Error = MidiError;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.stream.try_recv() {
-            Ok(value) => Some(value),
-            Err(e) => match e {
-                TryRecvError::Empty => Some(0.0),
-                TryRecvError::Closed => None,
-            },
-        }
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a Self::Settings,
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        let events = ron::de::from_bytes(&bytes)
+            .map_err(|error| MidiError::parse(io::Error::new(io::ErrorKind::InvalidData, error)))?;
+        Ok(MidiAudio::Score(events))
     }
-}
 
-impl Source for MidiFileDecoder {
-    fn current_frame_len(&self) -> Option<usize> {
-        None
+    fn extensions(&self) -> &[&str] {
+        &["midiseq.ron"]
     }
+}
 
-    fn channels(&self) -> u16 {
-        2
+/// Copy of a MIDI file with the channel events of unselected tracks removed
+fn filter_tracks(smf: &Smf, filter: &TrackFilter) -> Smf {
+    let mut smf = smf.clone();
+    for index in 0..smf.tracks.len() {
+        if !filter.includes(index, smf.track_name(index)) {
+            smf.silence_track(index);
+        }
     }
+    smf
+}
 
-    fn sample_rate(&self) -> u32 {
-        self.sample_rate as u32
-    }
+impl Decodable for MidiAudio {
+    type Decoder = MidiFileDecoder;
 
-    fn total_duration(&self) -> Option<std::time::Duration> {
-        None
+    type DecoderItem = <MidiFileDecoder as Iterator>::Item;
+
+    fn decoder(&self) -> Self::Decoder {
+        MidiFileDecoder::with_program(
+            SourceProgram::Audio(self.clone()),
+            crate::current_soundfont(),
+            MidiControl::default(),
+            None,
+        )
     }
 }
 
-impl Decodable for MidiAudio {
+impl Decodable for MidiSource {
     type Decoder = MidiFileDecoder;
 
     type DecoderItem = <MidiFileDecoder as Iterator>::Item;
 
     fn decoder(&self) -> Self::Decoder {
-        MidiFileDecoder::new(self.clone(), crate::SOUNDFONT.get().unwrap().clone())
+        // Rendering ahead would delay notes requested while playing
+        if let SourceProgram::Sfx(_) = self.program {
+            return MidiFileDecoder::offline_program(
+                self.program.clone(),
+                crate::current_soundfont(),
+                self.control.clone(),
+                self.sync.clone(),
+                &render_settings(),
+            );
+        }
+        MidiFileDecoder::with_program(
+            self.program.clone(),
+            crate::current_soundfont(),
+            self.control.clone(),
+            self.sync.clone(),
+        )
     }
 }
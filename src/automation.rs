@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::MidiControl;
+
+/// How the values between the keyframes of a [`MidiControllerCurve`] are found
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiCurveInterpolation {
+    /// Move in a straight line from one keyframe to the next
+    #[default]
+    Linear,
+    /// Hold each keyframe's value until the next one
+    Step,
+}
+
+/// Keyframed values of one controller (CC) of one channel, such as 1 for modulation, 7 for
+/// volume, 10 for pan or 74 for brightness.
+///
+/// Controllers the synthesizer doesn't support, such as brightness with the default backend,
+/// have no effect.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiControllerCurve {
+    /// Channel to control, counting from 0
+    pub channel: u8,
+    /// Controller number
+    pub controller: u8,
+    /// Times and values from 0.0 to 1.0, in order of time
+    pub keyframes: Vec<(Duration, f32)>,
+    /// How values between keyframes are found
+    pub interpolation: MidiCurveInterpolation,
+}
+
+impl MidiControllerCurve {
+    /// Construct a curve without keyframes for a controller of a channel
+    pub fn new(channel: u8, controller: u8) -> Self {
+        Self {
+            channel,
+            controller,
+            keyframes: Vec::new(),
+            interpolation: MidiCurveInterpolation::Linear,
+        }
+    }
+
+    /// Add a keyframe reaching `value`, from 0.0 to 1.0, at `time`
+    pub fn with_keyframe(mut self, time: Duration, value: f32) -> Self {
+        let index = self.keyframes.partition_point(|(start, _)| *start <= time);
+        self.keyframes.insert(index, (time, value));
+        self
+    }
+
+    /// Hold each keyframe's value until the next one
+    pub fn stepped(mut self) -> Self {
+        self.interpolation = MidiCurveInterpolation::Step;
+        self
+    }
+
+    /// Time of the last keyframe
+    pub fn duration(&self) -> Duration {
+        self.keyframes
+            .last()
+            .map_or(Duration::ZERO, |(time, _)| *time)
+    }
+
+    /// Value of the curve at `time`, holding the first and last keyframes before and after them
+    pub fn value_at(&self, time: Duration) -> Option<f32> {
+        let next = self.keyframes.partition_point(|(start, _)| *start <= time);
+        let Some(previous) = next.checked_sub(1) else {
+            return self.keyframes.first().map(|(_, value)| *value);
+        };
+        let (start, from) = self.keyframes[previous];
+        let Some(&(end, to)) = self.keyframes.get(next) else {
+            return Some(from);
+        };
+        if self.interpolation == MidiCurveInterpolation::Step {
+            return Some(from);
+        }
+        let progress = (time - start).as_secs_f32() / (end - start).as_secs_f32();
+        Some(from + (to - from) * progress)
+    }
+}
+
+/// Component animating controllers of a MIDI source along [`MidiControllerCurve`]s, for
+/// authored filter sweeps and volume rides.
+///
+/// The curves follow [`MidiControllerAnimation::time`], which advances with the app's time. To
+/// keep them in step with another clock, such as a gameplay animation, set the speed to zero and
+/// set the time directly each frame.
+#[derive(Component, Clone, Debug)]
+pub struct MidiControllerAnimation {
+    /// Curves to play
+    pub curves: Vec<MidiControllerCurve>,
+    /// Current time along the curves
+    pub time: Duration,
+    /// Multiplier of the app's time the curves advance by
+    pub speed: f32,
+    /// Whether the curves start over after the last keyframe
+    pub repeat: bool,
+    /// Value last sent for each curve
+    sent: Vec<Option<u8>>,
+}
+
+impl MidiControllerAnimation {
+    /// Play the given curves once, from the start
+    pub fn new(curves: impl IntoIterator<Item = MidiControllerCurve>) -> Self {
+        Self {
+            curves: curves.into_iter().collect(),
+            time: Duration::ZERO,
+            speed: 1.0,
+            repeat: false,
+            sent: Vec::new(),
+        }
+    }
+
+    /// Start the curves over after the last keyframe
+    pub fn repeating(mut self) -> Self {
+        self.repeat = true;
+        self
+    }
+
+    /// Time of the last keyframe of any curve
+    pub fn duration(&self) -> Duration {
+        self.curves
+            .iter()
+            .map(MidiControllerCurve::duration)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+pub(crate) fn animate_controllers(
+    time: Res<Time>,
+    mut query: Query<(&MidiControl, &mut MidiControllerAnimation)>,
+) {
+    for (control, mut animation) in &mut query {
+        let animation = &mut *animation;
+        animation.time += time.delta().mul_f32(animation.speed.max(0.0));
+        let duration = animation.duration();
+        if animation.repeat && !duration.is_zero() && animation.time > duration {
+            animation.time =
+                Duration::from_secs_f64(animation.time.as_secs_f64() % duration.as_secs_f64());
+        }
+        animation.sent.resize(animation.curves.len(), None);
+        for (curve, sent) in animation.curves.iter().zip(&mut animation.sent) {
+            let Some(value) = curve.value_at(animation.time) else {
+                continue;
+            };
+            let value = (value.clamp(0.0, 1.0) * 127.0).round() as u8;
+            if *sent != Some(value) {
+                *sent = Some(value);
+                control.set_controller(curve.channel, curve.controller, value);
+            }
+        }
+    }
+}
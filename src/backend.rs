@@ -0,0 +1,227 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use bevy::prelude::*;
+use rustysynth::{SoundFont, Synthesizer, SynthesizerSettings};
+
+use crate::{MidiError, OscillatorSynth, Waveform};
+
+/// Backend used by newly started render tasks, mirroring the [`MidiSynthBackend`] resource
+static SYNTH_BACKEND: Mutex<Option<Arc<dyn SynthBackendFactory>>> = Mutex::new(None);
+
+/// A synthesis engine which turns MIDI messages into audio.
+///
+/// Implemented for rustysynth's [`Synthesizer`], which is used by default. Other engines can be
+/// played by implementing this trait and inserting a [`MidiSynthBackend`] which creates them.
+pub trait SynthBackend: Send + 'static {
+    /// Handle a channel message, such as a note, controller or program change
+    fn process_midi_message(&mut self, channel: i32, command: i32, data1: i32, data2: i32);
+
+    /// Start playing a note
+    fn note_on(&mut self, channel: i32, key: i32, velocity: i32) {
+        self.process_midi_message(channel, 0x90, key, velocity);
+    }
+
+    /// Release a note
+    fn note_off(&mut self, channel: i32, key: i32) {
+        self.process_midi_message(channel, 0x80, key, 0);
+    }
+
+    /// Release every note, cutting them off if `immediate` is set
+    fn note_off_all(&mut self, immediate: bool);
+
+    /// Silence every note and reset every channel to its initial state
+    fn reset(&mut self);
+
+    /// Render the next frames of audio into the given buffers
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]);
+
+    /// Number of frames the engine renders at a time, which controls how often messages are
+    /// handled
+    fn block_size(&self) -> usize;
+
+    /// Sample rate the engine renders at
+    fn sample_rate(&self) -> i32;
+}
+
+impl SynthBackend for Synthesizer {
+    fn process_midi_message(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        Synthesizer::process_midi_message(self, channel, command, data1, data2);
+    }
+
+    fn note_on(&mut self, channel: i32, key: i32, velocity: i32) {
+        Synthesizer::note_on(self, channel, key, velocity);
+    }
+
+    fn note_off(&mut self, channel: i32, key: i32) {
+        Synthesizer::note_off(self, channel, key);
+    }
+
+    fn note_off_all(&mut self, immediate: bool) {
+        Synthesizer::note_off_all(self, immediate);
+    }
+
+    fn reset(&mut self) {
+        Synthesizer::reset(self);
+    }
+
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        Synthesizer::render(self, left, right);
+    }
+
+    fn block_size(&self) -> usize {
+        self.get_block_size()
+    }
+
+    fn sample_rate(&self) -> i32 {
+        self.get_sample_rate()
+    }
+}
+
+/// Settings a [`SynthBackendFactory`] creates engines with
+#[derive(Clone, Debug)]
+pub struct SynthBackendSettings {
+    /// Soundfont loaded by the plugin, if it has one
+    pub soundfont: Option<Arc<SoundFont>>,
+    /// Sample rate the engine must render at
+    pub sample_rate: i32,
+    /// Maximum number of voices to play at once
+    pub polyphony: usize,
+    /// Whether reverb and chorus effects should be applied
+    pub reverb_and_chorus: bool,
+}
+
+/// Creates the synthesis engines played by MIDI sources
+pub trait SynthBackendFactory: Send + Sync + 'static {
+    /// Create a new engine, one of which is created for each voice of music
+    fn create(&self, settings: &SynthBackendSettings) -> Box<dyn SynthBackend>;
+
+    /// Create a new engine, or report why it can't be created.
+    ///
+    /// Sources whose engine fails to be created stay silent and send a
+    /// [`MidiPlaybackError`](crate::MidiPlaybackError) instead.
+    fn try_create(
+        &self,
+        settings: &SynthBackendSettings,
+    ) -> Result<Box<dyn SynthBackend>, MidiError> {
+        Ok(self.create(settings))
+    }
+}
+
+impl<F, B> SynthBackendFactory for F
+where
+    F: Fn(&SynthBackendSettings) -> B + Send + Sync + 'static,
+    B: SynthBackend,
+{
+    fn create(&self, settings: &SynthBackendSettings) -> Box<dyn SynthBackend> {
+        Box::new(self(settings))
+    }
+}
+
+/// The default backend, playing the plugin's soundfont with rustysynth.
+///
+/// Without a soundfont, music is played by an [`OscillatorSynth`] instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RustySynthBackend;
+
+impl SynthBackendFactory for RustySynthBackend {
+    /// Create a synthesizer, which is silent if the settings are out of rustysynth's range
+    fn create(&self, settings: &SynthBackendSettings) -> Box<dyn SynthBackend> {
+        self.try_create(settings).unwrap_or_else(|error| {
+            error!("{error}");
+            Box::new(SilentSynth::new(settings.sample_rate))
+        })
+    }
+
+    fn try_create(
+        &self,
+        settings: &SynthBackendSettings,
+    ) -> Result<Box<dyn SynthBackend>, MidiError> {
+        let Some(soundfont) = &settings.soundfont else {
+            return Ok(Box::new(OscillatorSynth::new(
+                settings.sample_rate,
+                settings.polyphony,
+                Waveform::default(),
+            )));
+        };
+        let mut synthesizer_settings = SynthesizerSettings::new(settings.sample_rate);
+        synthesizer_settings.maximum_polyphony = settings.polyphony;
+        synthesizer_settings.enable_reverb_and_chorus = settings.reverb_and_chorus;
+        match Synthesizer::new(soundfont, &synthesizer_settings) {
+            Ok(synthesizer) => Ok(Box::new(synthesizer)),
+            Err(error) => Err(MidiError::SynthInit(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                error,
+            ))),
+        }
+    }
+}
+
+/// Engine rendering silence, standing in for one which couldn't be created
+#[derive(Debug)]
+pub(crate) struct SilentSynth {
+    sample_rate: i32,
+}
+
+impl SilentSynth {
+    pub(crate) fn new(sample_rate: i32) -> Self {
+        Self { sample_rate }
+    }
+}
+
+impl SynthBackend for SilentSynth {
+    fn process_midi_message(&mut self, _channel: i32, _command: i32, _data1: i32, _data2: i32) {}
+
+    fn note_off_all(&mut self, _immediate: bool) {}
+
+    fn reset(&mut self) {}
+
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        left.fill(0.0);
+        right.fill(0.0);
+    }
+
+    fn block_size(&self) -> usize {
+        64
+    }
+
+    fn sample_rate(&self) -> i32 {
+        self.sample_rate
+    }
+}
+
+/// Resource choosing the synthesis engine MIDI sources are rendered with.
+///
+/// Changes apply to sources started afterwards.
+#[derive(Resource, Clone)]
+pub struct MidiSynthBackend(pub Arc<dyn SynthBackendFactory>);
+
+impl MidiSynthBackend {
+    /// Render with engines created by the given factory
+    pub fn new(factory: impl SynthBackendFactory) -> Self {
+        Self(Arc::new(factory))
+    }
+}
+
+impl Default for MidiSynthBackend {
+    fn default() -> Self {
+        Self::new(RustySynthBackend)
+    }
+}
+
+/// Backend to start a new render task with
+pub(crate) fn synth_backend() -> Arc<dyn SynthBackendFactory> {
+    SYNTH_BACKEND
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| Arc::new(RustySynthBackend))
+}
+
+pub(crate) fn apply_synth_backend(backend: Res<MidiSynthBackend>) {
+    if backend.is_changed() {
+        *SYNTH_BACKEND.lock().unwrap() = Some(backend.0.clone());
+    }
+}
@@ -0,0 +1,107 @@
+use bevy::prelude::*;
+
+use crate::{diagnostics::playing_sources, MidiControl, MidiRenderSettings};
+
+/// Component setting the priority of a MIDI source, so ambient parts are stopped before the main
+/// score when too many sources play at once.
+///
+/// Sources without this component have priority 0, and higher priorities are stopped last. See
+/// [`MidiRenderSettings::max_sources`], [`MidiRenderSettings::max_voices`] and
+/// [`MidiRenderSettings::max_render_load`].
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MidiPriority(pub i32);
+
+/// Sources whose priority changed, or which can only now be controlled
+type PriorityChanged = Or<(Changed<MidiPriority>, Added<MidiControl>)>;
+
+pub(crate) fn apply_priorities(query: Query<(&MidiControl, &MidiPriority), PriorityChanged>) {
+    for (control, priority) in &query {
+        control.set_priority(priority.0);
+    }
+}
+
+/// Fade out the sources with the lowest priority, oldest first, while the sources playing
+/// exceed the voice or render load budget, always keeping the most important one
+pub(crate) fn enforce_render_budget(settings: Res<MidiRenderSettings>) {
+    // Render load depends on the machine, and the notes held when this runs on how far the
+    // render tasks got, so neither can decide what plays in deterministic mode
+    let max_load = settings.max_render_load.filter(|_| !settings.deterministic);
+    let max_voices = settings.max_voices.filter(|_| !settings.deterministic);
+    if max_voices.is_none() && max_load.is_none() {
+        return;
+    }
+    let mut playing = playing_sources();
+    playing.sort_by_key(|stats| stats.priority());
+    let usage: Vec<_> = playing
+        .iter()
+        .map(|stats| (stats.sounding_notes(), stats.load()))
+        .collect();
+    for stats in &playing[..sources_to_stop(&usage, max_voices, max_load)] {
+        stats.evict();
+        warn!(
+            "Stopped a MIDI source with priority {} to stay within the render budget.",
+            stats.priority()
+        );
+    }
+}
+
+/// Number of sources to stop, from the start of `usage`, so the rest stay within the budget,
+/// given the voices and render load of each source in the order they'd be stopped. The last
+/// source is always kept.
+fn sources_to_stop(
+    usage: &[(usize, f32)],
+    max_voices: Option<usize>,
+    max_load: Option<f32>,
+) -> usize {
+    let mut voices: usize = usage.iter().map(|&(voices, _)| voices).sum();
+    let mut load: f32 = usage.iter().map(|&(_, load)| load).sum();
+    let within_budget = |voices: usize, load: f32| {
+        max_voices.map_or(true, |max| voices <= max) && max_load.map_or(true, |max| load <= max)
+    };
+    let mut stopped = 0;
+    while stopped + 1 < usage.len() && !within_budget(voices, load) {
+        voices -= usage[stopped].0;
+        load -= usage[stopped].1;
+        stopped += 1;
+    }
+    stopped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_within_budget_keep_playing() {
+        let usage = [(10, 0.1), (20, 0.2)];
+        assert_eq!(sources_to_stop(&usage, Some(30), Some(0.5)), 0);
+        assert_eq!(sources_to_stop(&usage, None, None), 0);
+    }
+
+    #[test]
+    fn first_sources_stop_until_within_budget() {
+        let usage = [(10, 0.1), (20, 0.1), (30, 0.1)];
+        assert_eq!(sources_to_stop(&usage, Some(50), None), 1);
+        assert_eq!(sources_to_stop(&usage, Some(30), None), 2);
+        assert_eq!(
+            sources_to_stop(&[(0, 0.4), (0, 0.4), (0, 0.4)], None, Some(0.5)),
+            2
+        );
+    }
+
+    #[test]
+    fn both_limits_must_hold() {
+        let usage = [(10, 0.5), (10, 0.1), (10, 0.1)];
+        assert_eq!(sources_to_stop(&usage, Some(30), Some(0.3)), 1);
+    }
+
+    #[test]
+    fn last_source_is_always_kept() {
+        assert_eq!(
+            sources_to_stop(&[(100, 1.0), (100, 1.0)], Some(10), Some(0.1)),
+            1
+        );
+        assert_eq!(sources_to_stop(&[(100, 1.0)], Some(10), None), 0);
+        assert_eq!(sources_to_stop(&[], Some(10), None), 0);
+    }
+}
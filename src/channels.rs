@@ -0,0 +1,88 @@
+use bevy::prelude::*;
+
+use crate::MidiControl;
+
+/// Settings of one channel of a [`MidiChannelControls`], each left to the music when `None`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MidiChannelControl {
+    /// Channel volume (CC7), from 0.0 to 1.0
+    pub volume: Option<f32>,
+    /// Pan (CC10), from -1.0 for left to 1.0 for right
+    pub pan: Option<f32>,
+    /// Expression (CC11), from 0.0 to 1.0
+    pub expression: Option<f32>,
+    /// Modulation wheel (CC1), from 0.0 to 1.0
+    pub modulation: Option<f32>,
+    /// Whether the sustain pedal (CC64) is held
+    pub sustain: Option<bool>,
+}
+
+impl MidiChannelControl {
+    /// Controller numbers and values of each setting, from 0 to 127
+    fn controllers(&self) -> [(u8, Option<u8>); 5] {
+        let scale = |value: f32| (value.clamp(0.0, 1.0) * 127.0).round() as u8;
+        [
+            (7, self.volume.map(scale)),
+            (10, self.pan.map(|pan| scale((pan + 1.0) / 2.0))),
+            (11, self.expression.map(scale)),
+            (1, self.modulation.map(scale)),
+            (64, self.sustain.map(|held| if held { 127 } else { 0 })),
+        ]
+    }
+}
+
+/// Component setting the volume, pan, expression, modulation and sustain of each channel of a
+/// MIDI source, sent to its synthesizer as controller changes whenever they change.
+///
+/// Settings returned to `None` keep their last value until the music changes them.
+#[derive(Component, Clone, Debug, Default)]
+pub struct MidiChannelControls {
+    /// Settings of each channel
+    pub channels: [MidiChannelControl; 16],
+    /// Settings last sent to the synthesizer
+    sent: [MidiChannelControl; 16],
+}
+
+impl MidiChannelControls {
+    /// Settings of a channel, counting from 0
+    pub fn channel(&self, channel: u8) -> &MidiChannelControl {
+        &self.channels[channel as usize & 0xF]
+    }
+
+    /// Mutable settings of a channel, counting from 0
+    pub fn channel_mut(&mut self, channel: u8) -> &mut MidiChannelControl {
+        &mut self.channels[channel as usize & 0xF]
+    }
+}
+
+/// Sources whose settings changed, or which can only now be controlled
+type ChangedControlsFilter = Or<(Changed<MidiChannelControls>, Added<MidiControl>)>;
+
+pub(crate) fn apply_channel_controls(
+    mut query: Query<(&MidiControl, &mut MidiChannelControls), ChangedControlsFilter>,
+) {
+    for (control, mut controls) in &mut query {
+        let controls = &mut *controls;
+        for (channel, (settings, sent)) in controls
+            .channels
+            .iter()
+            .zip(controls.sent.iter_mut())
+            .enumerate()
+        {
+            if settings == sent {
+                continue;
+            }
+            let old = sent.controllers();
+            for ((controller, value), (_, old)) in settings.controllers().into_iter().zip(old) {
+                match value {
+                    Some(value) if Some(value) != old => {
+                        control.set_controller(channel as u8, controller, value)
+                    }
+                    None if old.is_some() => control.clear_controller(channel as u8, controller),
+                    _ => {}
+                }
+            }
+            *sent = *settings;
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use std::str::FromStr;
+
+use crate::{MidiAudio, MidiError, MidiNote, ScoreEvent};
+
+/// Semitones above the root of each chord tone, by chord symbol suffix
+const QUALITIES: &[(&[&str], &[u8])] = &[
+    (&["", "maj", "M"], &[0, 4, 7]),
+    (&["m", "min", "-"], &[0, 3, 7]),
+    (&["dim", "°", "o"], &[0, 3, 6]),
+    (&["aug", "+"], &[0, 4, 8]),
+    (&["sus2"], &[0, 2, 7]),
+    (&["sus4", "sus"], &[0, 5, 7]),
+    (&["5"], &[0, 7]),
+    (&["6"], &[0, 4, 7, 9]),
+    (&["m6", "min6"], &[0, 3, 7, 9]),
+    (&["7"], &[0, 4, 7, 10]),
+    (&["maj7", "M7", "Δ", "Δ7"], &[0, 4, 7, 11]),
+    (&["m7", "min7", "-7"], &[0, 3, 7, 10]),
+    (&["mmaj7", "mM7", "minmaj7"], &[0, 3, 7, 11]),
+    (&["m7b5", "ø", "ø7"], &[0, 3, 6, 10]),
+    (&["dim7", "°7", "o7"], &[0, 3, 6, 9]),
+    (&["7sus4", "7sus"], &[0, 5, 7, 10]),
+    (&["add9"], &[0, 4, 7, 14]),
+    (&["9"], &[0, 4, 7, 10, 14]),
+    (&["maj9", "M9"], &[0, 4, 7, 11, 14]),
+    (&["m9", "min9"], &[0, 3, 7, 10, 14]),
+];
+
+/// Steps of the major and natural minor scales, which Roman numerals count along
+const MAJOR_SCALE: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+const MINOR_SCALE: [u8; 7] = [0, 2, 3, 5, 7, 8, 10];
+
+fn invalid(what: &str) -> MidiError {
+    MidiError::Chord(what.to_string())
+}
+
+/// Split a note name such as `F#` off the start of `text`, returning its pitch class and the
+/// rest of the text
+fn parse_pitch(text: &str) -> Option<(u8, &str)> {
+    let mut chars = text.chars();
+    let natural = match chars.next()? {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let mut pitch = natural + 12;
+    let mut rest = chars.as_str();
+    loop {
+        let mut chars = rest.chars();
+        match chars.next() {
+            Some('#' | '♯') => pitch += 1,
+            Some('b' | '♭') => pitch -= 1,
+            _ => break,
+        }
+        rest = chars.as_str();
+    }
+    Some((pitch % 12, rest))
+}
+
+/// A chord, parsed from a symbol such as `Cmaj7`, `F#m7b5` or `G7/B`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MidiChord {
+    /// Pitch class of the root, from 0 for C to 11 for B
+    pub root: u8,
+    /// Semitones above the root of each chord tone
+    pub intervals: Vec<u8>,
+    /// Pitch class played below the chord, for slash chords
+    pub bass: Option<u8>,
+}
+
+impl FromStr for MidiChord {
+    type Err = MidiError;
+
+    fn from_str(symbol: &str) -> Result<Self, Self::Err> {
+        let (chord, bass) = match symbol.split_once('/') {
+            Some((chord, bass)) => match parse_pitch(bass) {
+                Some((bass, "")) => (chord, Some(bass)),
+                _ => return Err(invalid(symbol)),
+            },
+            None => (symbol, None),
+        };
+        let (root, suffix) = parse_pitch(chord).ok_or_else(|| invalid(symbol))?;
+        let (_, intervals) = QUALITIES
+            .iter()
+            .find(|(suffixes, _)| suffixes.contains(&suffix))
+            .ok_or_else(|| invalid(symbol))?;
+        Ok(Self {
+            root,
+            intervals: intervals.to_vec(),
+            bass,
+        })
+    }
+}
+
+impl MidiChord {
+    /// Keys of the chord tones, with the root at the first key at or above `lowest`
+    pub fn keys(&self, lowest: i32) -> Vec<i32> {
+        let root = lowest + (self.root as i32 - lowest).rem_euclid(12);
+        self.intervals
+            .iter()
+            .map(|&interval| root + interval as i32)
+            .collect()
+    }
+
+    /// Key of the bass note, which is the root unless it's a slash chord, at or above `lowest`
+    pub fn bass_key(&self, lowest: i32) -> i32 {
+        let bass = self.bass.unwrap_or(self.root) as i32;
+        lowest + (bass - lowest).rem_euclid(12)
+    }
+}
+
+/// A chord progression, expanded into a playable [`MidiAudio::Score`] for backing tracks.
+///
+/// Each chord is struck following the [`rhythm`](Self::rhythm), so the progression moves on to
+/// the next chord once the rhythm has finished.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiChordProgression {
+    /// Chords to play, in order
+    pub chords: Vec<MidiChord>,
+    /// Lengths in beats of the strikes of each chord, where a beat is a quarter note, such as
+    /// `[4.0]` to hold each chord for a bar of 4/4 or `[1.0; 4]` to strike it on every beat.
+    /// Negative lengths are rests.
+    pub rhythm: Vec<f64>,
+    /// Tempo in quarter notes per minute
+    pub bpm: f64,
+    /// Lowest key the roots of the chords are placed from
+    pub lowest_key: i32,
+    /// Channel the chords are played on
+    pub channel: i32,
+    /// Preset the chords are played with
+    pub preset: i32,
+    /// Velocity of the chords
+    pub velocity: i32,
+    /// Preset of a bass line playing the root of each chord an octave below the chords, on the
+    /// next channel, if any
+    pub bass: Option<i32>,
+}
+
+impl MidiChordProgression {
+    /// Parse chord symbols separated by whitespace, such as `"Cmaj7 Am7 Dm7 G7"`
+    pub fn parse(symbols: &str) -> Result<Self, MidiError> {
+        Ok(Self::new(
+            symbols
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+
+    /// Parse Roman numerals separated by whitespace in the given key, such as `"I vi ii V7"` in
+    /// `"C"`, or `"i iv v"` in the minor key `"Am"`.
+    ///
+    /// Uppercase numerals are major chords and lowercase ones minor, followed by any chord
+    /// symbol suffix such as `7` or `°`.
+    pub fn roman(numerals: &str, key: &str) -> Result<Self, MidiError> {
+        let (tonic, mode) = parse_pitch(key).ok_or_else(|| invalid(key))?;
+        let scale = match mode {
+            "" | "maj" | "major" => MAJOR_SCALE,
+            "m" | "min" | "minor" => MINOR_SCALE,
+            _ => return Err(invalid(key)),
+        };
+        let chords = numerals
+            .split_whitespace()
+            .map(|numeral| {
+                let mut shift = 0_i32;
+                let mut rest = numeral;
+                loop {
+                    if let Some(after) = rest.strip_prefix(['b', '♭']) {
+                        shift -= 1;
+                        rest = after;
+                    } else if let Some(after) = rest.strip_prefix(['#', '♯']) {
+                        shift += 1;
+                        rest = after;
+                    } else {
+                        break;
+                    }
+                }
+                let length = rest.find(|c| !"IViv".contains(c)).unwrap_or(rest.len());
+                let (roman, suffix) = rest.split_at(length);
+                let degree = match roman.to_uppercase().as_str() {
+                    "I" => 0,
+                    "II" => 1,
+                    "III" => 2,
+                    "IV" => 3,
+                    "V" => 4,
+                    "VI" => 5,
+                    "VII" => 6,
+                    _ => return Err(invalid(numeral)),
+                };
+                let minor = roman.chars().all(char::is_lowercase)
+                    && !["°", "o", "dim", "ø"]
+                        .iter()
+                        .any(|diminished| suffix.starts_with(diminished));
+                let quality = match (minor, suffix) {
+                    (true, "") => "m".to_string(),
+                    (true, suffix) => format!("m{suffix}"),
+                    (false, suffix) => suffix.to_string(),
+                };
+                let (_, intervals) = QUALITIES
+                    .iter()
+                    .find(|(suffixes, _)| suffixes.contains(&quality.as_str()))
+                    .ok_or_else(|| invalid(numeral))?;
+                Ok(MidiChord {
+                    root: (tonic as i32 + scale[degree] as i32 + shift).rem_euclid(12) as u8,
+                    intervals: intervals.to_vec(),
+                    bass: None,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self::new(chords))
+    }
+
+    /// Play the given chords, each held for a bar of 4/4 at 120 BPM with an electric piano
+    pub fn new(chords: Vec<MidiChord>) -> Self {
+        Self {
+            chords,
+            rhythm: vec![4.0],
+            bpm: 120.0,
+            lowest_key: 48,
+            channel: 0,
+            preset: 4,
+            velocity: 80,
+            bass: None,
+        }
+    }
+
+    /// Strike each chord with the given lengths in beats, negative for rests
+    pub fn with_rhythm(mut self, rhythm: impl Into<Vec<f64>>) -> Self {
+        self.rhythm = rhythm.into();
+        self
+    }
+
+    /// Play at the given quarter notes per minute
+    pub fn with_bpm(mut self, bpm: f64) -> Self {
+        self.bpm = bpm;
+        self
+    }
+
+    /// Play the chords with the given preset
+    pub fn with_preset(mut self, preset: i32) -> Self {
+        self.preset = preset;
+        self
+    }
+
+    /// Add a bass line playing the root of each chord with the given preset
+    pub fn with_bass(mut self, preset: i32) -> Self {
+        self.bass = Some(preset);
+        self
+    }
+
+    /// Events of a score playing the progression
+    pub fn to_score(&self) -> Vec<ScoreEvent> {
+        let mut events = vec![ScoreEvent::Tempo(self.bpm)];
+        for chord in &self.chords {
+            let mut notes: Vec<MidiNote> = chord
+                .keys(self.lowest_key)
+                .into_iter()
+                .map(|key| MidiNote {
+                    channel: self.channel,
+                    preset: self.preset,
+                    key,
+                    velocity: self.velocity,
+                    ..Default::default()
+                })
+                .collect();
+            if let Some(preset) = self.bass {
+                notes.push(MidiNote {
+                    channel: (self.channel + 1).rem_euclid(16),
+                    preset,
+                    key: chord.bass_key(self.lowest_key - 12),
+                    velocity: self.velocity,
+                    ..Default::default()
+                });
+            }
+            events.extend(self.rhythm.iter().map(|&beats| {
+                if beats < 0.0 {
+                    ScoreEvent::Rest(-beats)
+                } else {
+                    ScoreEvent::Chord {
+                        notes: notes.clone(),
+                        beats,
+                    }
+                }
+            }));
+        }
+        events
+    }
+
+    /// Audio playing the progression
+    pub fn to_audio(&self) -> MidiAudio {
+        MidiAudio::Score(self.to_score())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(symbol: &str) -> MidiChord {
+        symbol.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_chord_symbols() {
+        assert_eq!(chord("C").intervals, [0, 4, 7]);
+        assert_eq!(
+            chord("Cmaj7"),
+            MidiChord {
+                root: 0,
+                intervals: vec![0, 4, 7, 11],
+                bass: None,
+            }
+        );
+        assert_eq!(
+            chord("F#m7b5"),
+            MidiChord {
+                root: 6,
+                intervals: vec![0, 3, 6, 10],
+                bass: None,
+            }
+        );
+        assert_eq!(chord("Bb°7").root, 10);
+        assert_eq!(chord("G7/B").bass, Some(11));
+        assert_eq!(chord("Cb").root, 11);
+    }
+
+    #[test]
+    fn rejects_invalid_symbols() {
+        for symbol in ["", "H", "Cxyz", "C/", "C/Q", "m7"] {
+            assert!(symbol.parse::<MidiChord>().is_err(), "{symbol}");
+        }
+    }
+
+    #[test]
+    fn places_keys_above_the_lowest() {
+        assert_eq!(chord("A").keys(60), [69, 73, 76]);
+        assert_eq!(chord("C").keys(60), [60, 64, 67]);
+        assert_eq!(chord("G7/B").bass_key(36), 47);
+    }
+
+    #[test]
+    fn roman_numerals_in_major_keys() {
+        let progression = MidiChordProgression::roman("I vi ii V7", "C").unwrap();
+        let expected = ["C", "Am", "Dm", "G7"].map(chord);
+        assert_eq!(progression.chords, expected);
+        let progression = MidiChordProgression::roman("vii° bVII #iv", "D").unwrap();
+        assert_eq!(progression.chords, ["C#dim", "C", "G#m"].map(chord));
+    }
+
+    #[test]
+    fn roman_numerals_in_minor_keys() {
+        let progression = MidiChordProgression::roman("i iv v III", "Am").unwrap();
+        assert_eq!(progression.chords, ["Am", "Dm", "Em", "C"].map(chord));
+    }
+
+    #[test]
+    fn rejects_invalid_numerals() {
+        assert!(MidiChordProgression::roman("I VIII", "C").is_err());
+        assert!(MidiChordProgression::roman("I", "C lydian").is_err());
+        assert!(MidiChordProgression::roman("Ixyz", "C").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::MidiTransport;
+
+/// Number of clock pulses in a quarter note
+const PULSES_PER_QUARTER: u64 = 24;
+
+/// Number of clock pulses in a sixteenth note, the unit of song positions
+const PULSES_PER_SIXTEENTH: u64 = 6;
+
+/// System real-time message keeping external MIDI hardware or software in time with the
+/// [`MidiTransport`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiClockMessage {
+    /// Timing clock, sent 24 times per quarter note
+    Clock,
+    /// Start playing from the beginning
+    Start,
+    /// Resume playing from the current song position
+    Continue,
+    /// Stop playing
+    Stop,
+    /// Move to the given number of sixteenth notes from the beginning
+    SongPosition(u16),
+}
+
+impl MidiClockMessage {
+    /// Raw bytes of the message, ready to send to a MIDI output port
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MidiClockMessage::Clock => vec![0xF8],
+            MidiClockMessage::Start => vec![0xFA],
+            MidiClockMessage::Continue => vec![0xFB],
+            MidiClockMessage::Stop => vec![0xFC],
+            MidiClockMessage::SongPosition(position) => {
+                let position = position.min(&0x3FFF);
+                vec![0xF2, (position & 0x7F) as u8, (position >> 7) as u8]
+            }
+        }
+    }
+}
+
+/// Event carrying a MIDI clock message derived from the [`MidiTransport`] while a
+/// [`MidiClockOutput`] is present
+#[derive(Event, Clone, Copy, Debug)]
+pub struct MidiClockEvent {
+    /// Message to send
+    pub message: MidiClockMessage,
+    /// Time of the transport the message belongs to
+    pub time: Duration,
+}
+
+/// Resource turning the play, pause and locate commands and the tempo of the [`MidiTransport`]
+/// into [`MidiClockEvent`]s, so external hardware and DAWs can follow the game's music.
+///
+/// Insert it to start sending clocks, and forward the bytes of each message to a MIDI output
+/// port, or with the `midi-out` feature insert a [`MidiClockPort`] to have them sent. Messages are sent once per frame, so the clocks of a frame arrive together and receivers
+/// smooth out the jitter, with [`MidiClockEvent::time`] giving each one's exact time.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct MidiClockOutput {
+    playing: bool,
+    /// Index of the next clock pulse to send
+    next_pulse: u64,
+    locations: u64,
+    tempo_changes: u64,
+}
+
+impl MidiClockOutput {
+    /// Whether external devices have been told to play
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+}
+
+/// Number of clock pulses from the start of the transport to its current position, or `None`
+/// for SMPTE timing, which has no quarter notes to count
+fn pulses(transport: &MidiTransport) -> Option<f64> {
+    let tempo = transport.tempo();
+    if tempo.division & 0x8000 != 0 {
+        return None;
+    }
+    let ticks = tempo.ticks(transport.position().as_secs_f64());
+    Some(ticks * PULSES_PER_QUARTER as f64 / tempo.division.max(1) as f64)
+}
+
+/// Time of the transport at the given clock pulse
+fn pulse_time(transport: &MidiTransport, pulse: u64) -> Duration {
+    let tempo = transport.tempo();
+    // Pulses rarely fall on a whole tick, so rounding here would drift from the tempo
+    let tick = pulse as f64 * tempo.division as f64 / PULSES_PER_QUARTER as f64;
+    Duration::from_secs_f64(tempo.fractional_seconds(tick))
+}
+
+pub(crate) fn send_midi_clock(
+    transport: Res<MidiTransport>,
+    clock: Option<ResMut<MidiClockOutput>>,
+    mut events: EventWriter<MidiClockEvent>,
+) {
+    let Some(mut clock) = clock else {
+        return;
+    };
+    let Some(pulses) = pulses(&transport) else {
+        return;
+    };
+    let mut send = |message| {
+        events.send(MidiClockEvent {
+            message,
+            time: transport.position(),
+        });
+    };
+    // Receivers resume on the next sixteenth note after a song position
+    let sixteenth = (pulses / PULSES_PER_SIXTEENTH as f64).ceil() as u64;
+    let located = clock.locations != transport.locations;
+    clock.locations = transport.locations;
+
+    if !transport.is_playing() {
+        if clock.playing {
+            send(MidiClockMessage::Stop);
+            clock.playing = false;
+        }
+        if located {
+            send(MidiClockMessage::SongPosition(sixteenth.min(0x3FFF) as u16));
+        }
+        clock.tempo_changes = transport.tempo_changes;
+        return;
+    }
+
+    if !clock.playing || located {
+        if clock.playing {
+            send(MidiClockMessage::Stop);
+        }
+        if transport.position().is_zero() {
+            send(MidiClockMessage::Start);
+            clock.next_pulse = 0;
+        } else {
+            send(MidiClockMessage::SongPosition(sixteenth.min(0x3FFF) as u16));
+            send(MidiClockMessage::Continue);
+            clock.next_pulse = sixteenth * PULSES_PER_SIXTEENTH;
+        }
+        clock.playing = true;
+    } else if clock.tempo_changes != transport.tempo_changes {
+        // The same time falls on another pulse of the new tempo
+        clock.next_pulse = pulses.ceil() as u64;
+    }
+    clock.tempo_changes = transport.tempo_changes;
+
+    while clock.next_pulse as f64 <= pulses {
+        events.send(MidiClockEvent {
+            message: MidiClockMessage::Clock,
+            time: pulse_time(&transport, clock.next_pulse),
+        });
+        clock.next_pulse += 1;
+    }
+}
+
+/// Resource sending the messages of the [`MidiClockOutput`] to a MIDI output port
+#[cfg(feature = "midi-out")]
+#[derive(Resource)]
+pub struct MidiClockPort {
+    connection: std::sync::Mutex<midir::MidiOutputConnection>,
+    /// Whether the last message failed to send, so a lost port is only reported once
+    failed: bool,
+}
+
+#[cfg(feature = "midi-out")]
+impl MidiClockPort {
+    /// Send clock messages through an open connection
+    pub fn new(connection: midir::MidiOutputConnection) -> Self {
+        Self {
+            connection: std::sync::Mutex::new(connection),
+            failed: false,
+        }
+    }
+
+    /// Connect to the first MIDI output port whose name contains `name`
+    pub fn connect(name: &str) -> Result<Self, crate::MidiError> {
+        let device = |error: String| crate::MidiError::Device(std::io::Error::other(error));
+        let output =
+            midir::MidiOutput::new("bevy_rustysynth").map_err(|error| device(error.to_string()))?;
+        let port = output
+            .ports()
+            .into_iter()
+            .find(|port| {
+                output
+                    .port_name(port)
+                    .is_ok_and(|port_name| port_name.contains(name))
+            })
+            .ok_or_else(|| device(format!("no MIDI output port named {name}")))?;
+        let connection = output
+            .connect(&port, "clock")
+            .map_err(|error| device(error.to_string()))?;
+        Ok(Self::new(connection))
+    }
+}
+
+#[cfg(feature = "midi-out")]
+impl std::fmt::Debug for MidiClockPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MidiClockPort")
+            .field("failed", &self.failed)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "midi-out")]
+pub(crate) fn forward_midi_clock(
+    port: Option<ResMut<MidiClockPort>>,
+    mut events: EventReader<MidiClockEvent>,
+) {
+    let Some(mut port) = port else {
+        events.clear();
+        return;
+    };
+    let port = &mut *port;
+    let connection = port.connection.get_mut().unwrap();
+    for event in events.read() {
+        match connection.send(&event.message.to_bytes()) {
+            Ok(()) => port.failed = false,
+            Err(error) => {
+                if !port.failed {
+                    warn!("Failed to send MIDI clock: {error}");
+                }
+                port.failed = true;
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,1036 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_channel::WeakReceiver;
+
+use bevy::{
+    audio::{AudioSink, SpatialAudioSink},
+    prelude::*,
+};
+
+use crate::{
+    analysis::AnalysisTap, decoder::SourceProgram, render::RenderedBlock, FollowTransport,
+    HeadlessMidiOutput, MidiAnalyzer, MidiAudio, MidiChannelControls, MidiControllerAnimation,
+    MidiCountIn, MidiDrumChannel, MidiEnvelopeFollower, MidiError, MidiGroove, MidiInputMessage,
+    MidiLevels, MidiMpe, MidiPressureTarget, MidiPriority, MidiProgramWatcher, MidiRecorder,
+    MidiSource, MidiSourceOrigin, MidiSyncGroup, MidiVelocityCurve, QuantizedStart,
+};
+
+/// A gain change requested from outside the audio thread
+#[derive(Clone, Copy, Debug)]
+struct Fade {
+    target: f32,
+    duration: Duration,
+    stop: bool,
+}
+
+/// Bank and preset pinned on each channel
+pub(crate) type PinnedPrograms = [Option<(u8, u8)>; 16];
+
+/// Value of each controller of each channel set through a [`MidiControl`]
+pub(crate) type ControllerValues = [[Option<u8>; 128]; 16];
+
+/// Channel settings made through a [`MidiControl`], which its synthesizers apply once they change
+#[derive(Clone, Debug)]
+pub(crate) struct ChannelState {
+    pub(crate) programs: PinnedPrograms,
+    pub(crate) controllers: Box<ControllerValues>,
+    pub(crate) velocity_curve: MidiVelocityCurve,
+    pub(crate) drum_channel: MidiDrumChannel,
+    pub(crate) pressure_target: MidiPressureTarget,
+    pub(crate) mpe: Option<MidiMpe>,
+    pub(crate) groove: Option<MidiGroove>,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            programs: [None; 16],
+            controllers: Box::new([[None; 128]; 16]),
+            velocity_curve: MidiVelocityCurve::Linear,
+            drum_channel: MidiDrumChannel::Percussion,
+            pressure_target: MidiPressureTarget::Ignore,
+            mpe: None,
+            groove: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ControlState {
+    gain: AtomicU32,
+    pending: Mutex<Option<Fade>>,
+    dirty: AtomicBool,
+    stopped: AtomicBool,
+    looping: AtomicBool,
+    paused: AtomicBool,
+    seek: Mutex<Option<Duration>>,
+    seeking: AtomicBool,
+    position: AtomicU64,
+    stream: Mutex<Option<WeakReceiver<RenderedBlock>>>,
+    start_delay: Mutex<Duration>,
+    time_scale: AtomicU64,
+    speed: AtomicU64,
+    skip_silence: AtomicBool,
+    release_tail: Mutex<MidiReleaseTail>,
+    block_length: Mutex<Option<Duration>>,
+    dry_stream: Mutex<MidiDryStream>,
+    channel_layout: Mutex<MidiChannelLayout>,
+    scrubbing: AtomicBool,
+    scrub_position: Mutex<Option<Duration>>,
+    underruns: AtomicU64,
+    reported_underruns: AtomicU64,
+    levels: Mutex<MidiLevels>,
+    tap: Mutex<Option<(AnalysisTap, usize)>>,
+    /// Channel settings, and their version, which changes whenever they do
+    channels: Mutex<(u64, ChannelState)>,
+    /// Bank and preset heard on each channel
+    playing_programs: Mutex<[(u16, u8); 16]>,
+    /// Keys held on each channel, one bit per key
+    active_notes: Mutex<[u128; 16]>,
+    /// Messages sent live, waiting for the next rendered block
+    input: Mutex<Vec<MidiInputMessage>>,
+    recorder: Mutex<Option<MidiRecorder>>,
+    priority: AtomicI32,
+    /// Entity the control was last added to
+    entity: Mutex<Option<Entity>>,
+    /// Failures of the render task not yet reported
+    errors: Mutex<Vec<MidiError>>,
+    /// Number of changes to the channel settings, live input and recorder, so synthesizers only
+    /// check them once they change
+    channel_changes: AtomicU64,
+}
+
+/// Programs of the channels before the music chooses any: the first preset, with the standard
+/// percussion kit on channel 9
+const DEFAULT_PROGRAMS: [(u16, u8); 16] = {
+    let mut programs = [(0, 0); 16];
+    programs[9] = (128, 0);
+    programs
+};
+
+/// Handle for controlling a MIDI source while it plays.
+///
+/// Add this component next to a [`Handle<MidiAudio>`] and [`PlaybackSettings`] to make the
+/// source controllable. Clones share the same underlying state, so a copy can be kept around
+/// after spawning.
+#[derive(Component, Clone, Debug)]
+pub struct MidiControl(Arc<ControlState>);
+
+impl Default for MidiControl {
+    fn default() -> Self {
+        Self::with_gain(1.0)
+    }
+}
+
+impl MidiControl {
+    /// Construct a new control starting at the given gain
+    pub fn with_gain(gain: f32) -> Self {
+        Self(Arc::new(ControlState {
+            gain: AtomicU32::new(gain.to_bits()),
+            pending: Mutex::new(None),
+            dirty: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            looping: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            seek: Mutex::new(None),
+            seeking: AtomicBool::new(false),
+            position: AtomicU64::new(0.0_f64.to_bits()),
+            stream: Mutex::new(None),
+            start_delay: Mutex::new(Duration::ZERO),
+            time_scale: AtomicU64::new(1.0_f64.to_bits()),
+            speed: AtomicU64::new(1.0_f64.to_bits()),
+            skip_silence: AtomicBool::new(false),
+            release_tail: Mutex::new(MidiReleaseTail::default()),
+            block_length: Mutex::new(None),
+            dry_stream: Mutex::new(MidiDryStream::default()),
+            channel_layout: Mutex::new(MidiChannelLayout::default()),
+            scrubbing: AtomicBool::new(false),
+            scrub_position: Mutex::new(None),
+            underruns: AtomicU64::new(0),
+            reported_underruns: AtomicU64::new(0),
+            levels: Mutex::new(MidiLevels::default()),
+            tap: Mutex::new(None),
+            channels: Mutex::new((0, ChannelState::default())),
+            playing_programs: Mutex::new(DEFAULT_PROGRAMS),
+            active_notes: Mutex::new([0; 16]),
+            input: Mutex::new(Vec::new()),
+            recorder: Mutex::new(None),
+            priority: AtomicI32::new(0),
+            entity: Mutex::new(None),
+            errors: Mutex::new(Vec::new()),
+            channel_changes: AtomicU64::new(0),
+        }))
+    }
+
+    /// Current gain applied to the source output
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.0.gain.load(Ordering::Relaxed))
+    }
+
+    /// Set the gain immediately
+    pub fn set_gain(&self, gain: f32) {
+        self.fade_to(gain, Duration::ZERO);
+    }
+
+    /// Ramp the gain linearly to `target` over `duration`
+    pub fn fade_to(&self, target: f32, duration: Duration) {
+        self.request(Fade {
+            target,
+            duration,
+            stop: false,
+        });
+    }
+
+    /// Ramp the gain to zero over `duration`, then end the source
+    pub fn fade_out(&self, duration: Duration) {
+        self.request(Fade {
+            target: 0.0,
+            duration,
+            stop: true,
+        });
+    }
+
+    /// Stop the source, ramping its gain to zero over `duration` before the stream is closed.
+    ///
+    /// Same as [`MidiControl::fade_out`].
+    pub fn stop_with_fade(&self, duration: Duration) {
+        self.fade_out(duration);
+    }
+
+    /// Whether the source has been stopped through this control
+    pub fn is_stopped(&self) -> bool {
+        self.0.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Make the source start again from the beginning each time it reaches the end, until it's
+    /// stopped.
+    ///
+    /// The decoder loops the music itself, so fades, seeks and the position keep working on every
+    /// pass, unlike with [`PlaybackMode::Loop`](bevy::audio::PlaybackMode::Loop).
+    pub fn set_looping(&self, looping: bool) {
+        self.0.looping.store(looping, Ordering::Relaxed);
+    }
+
+    /// Whether the source starts again from the beginning when it reaches the end
+    pub fn is_looping(&self) -> bool {
+        self.0.looping.load(Ordering::Relaxed)
+    }
+
+    /// Pause the source, outputting silence until resumed
+    pub fn pause(&self) {
+        self.0.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resume the source after a pause
+    pub fn resume(&self) {
+        self.0.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether the source is paused through this control
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::Relaxed)
+    }
+
+    /// Jump to a position in the music.
+    ///
+    /// The source is silent until audio from the new position is available. Seeking has no
+    /// effect once the source has finished rendering.
+    pub fn seek(&self, position: Duration) {
+        *self.0.seek.lock().unwrap() = Some(position);
+        self.0.seeking.store(true, Ordering::Release);
+        // Unblock a render task waiting for room in the stream
+        if let Some(stream) = self.stream() {
+            while stream.try_recv().is_ok() {}
+        }
+    }
+
+    /// Playback speed multiplier set through this control
+    pub fn speed(&self) -> f64 {
+        f64::from_bits(self.0.speed.load(Ordering::Relaxed))
+    }
+
+    /// Set the playback speed multiplier, e.g. `2.0` to fast-forward at double speed.
+    ///
+    /// Pitch is unaffected; only the music's clock runs faster or slower.
+    pub fn set_speed(&self, speed: f64) {
+        self.0
+            .speed
+            .store(speed.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Audition the music around `position`, as when dragging the playhead of a timeline.
+    ///
+    /// A short window of audio is played each time the position changes, with silence in
+    /// between. Call [`MidiControl::end_scrub`] to resume normal playback.
+    pub fn scrub(&self, position: Duration) {
+        self.0.scrubbing.store(true, Ordering::Release);
+        let mut scrub_position = self.0.scrub_position.lock().unwrap();
+        if *scrub_position != Some(position) {
+            *scrub_position = Some(position);
+            self.seek(position);
+        }
+    }
+
+    /// Resume normal playback from the last scrub window
+    pub fn end_scrub(&self) {
+        self.0.scrubbing.store(false, Ordering::Release);
+        *self.0.scrub_position.lock().unwrap() = None;
+    }
+
+    /// Whether the source is being scrubbed
+    pub fn is_scrubbing(&self) -> bool {
+        self.0.scrubbing.load(Ordering::Acquire)
+    }
+
+    /// Playback position of the audio output so far, in seconds of music
+    pub fn position(&self) -> Duration {
+        Duration::from_secs_f64(f64::from_bits(self.0.position.load(Ordering::Relaxed)))
+    }
+
+    /// Make a channel, counting from 0, play the given bank and preset, ignoring the music's own
+    /// bank and program changes on it until it's unpinned.
+    ///
+    /// `bank` is the bank select value, so the percussion channel 9 uses the soundfont's bank
+    /// `128 + bank`.
+    pub fn pin_program(&self, channel: u8, bank: u8, preset: u8) {
+        self.set_pinned_program(channel, Some((bank, preset)));
+    }
+
+    /// Let a channel follow the music's bank and program changes again, returning it to the
+    /// music's latest program
+    pub fn unpin_program(&self, channel: u8) {
+        self.set_pinned_program(channel, None);
+    }
+
+    /// Bank and preset pinned on a channel, if any
+    pub fn pinned_program(&self, channel: u8) -> Option<(u8, u8)> {
+        self.0.channels.lock().unwrap().1.programs[channel as usize & 0xF]
+    }
+
+    fn set_pinned_program(&self, channel: u8, program: Option<(u8, u8)>) {
+        self.change_channels(|channels| {
+            std::mem::replace(&mut channels.programs[channel as usize & 0xF], program) != program
+        });
+    }
+
+    /// Bank and preset the synthesizer plays on a channel, counting from 0, whether chosen by
+    /// the music or pinned, with percussion kits on channel 9 numbered from 128.
+    ///
+    /// It follows the rendered audio, which may be ahead of what's heard.
+    pub fn channel_program(&self, channel: u8) -> (u16, u8) {
+        self.0.playing_programs.lock().unwrap()[channel as usize & 0xF]
+    }
+
+    pub(crate) fn set_channel_program(&self, channel: u8, bank: u16, preset: u8) {
+        self.0.playing_programs.lock().unwrap()[channel as usize & 0xF] = (bank, preset);
+    }
+
+    pub(crate) fn reset_channel_programs(&self) {
+        *self.0.playing_programs.lock().unwrap() = DEFAULT_PROGRAMS;
+    }
+
+    /// Notes held by the synthesizer as `(channel, key)`, ordered by channel and key, such as for
+    /// highlighting the keys of an on-screen keyboard.
+    ///
+    /// Notes count from their note on to their note off, even while a sustain pedal keeps them
+    /// sounding. Like [`MidiControl::channel_program`], it follows the rendered audio.
+    pub fn active_notes(&self) -> Vec<(u8, u8)> {
+        let notes = self.0.active_notes.lock().unwrap();
+        (0..16)
+            .flat_map(|channel| {
+                let keys = notes[channel as usize];
+                (0..128)
+                    .filter(move |key| keys & 1 << key != 0)
+                    .map(move |key| (channel, key))
+            })
+            .collect()
+    }
+
+    /// Whether a key is held on a channel, counting from 0
+    pub fn is_note_active(&self, channel: u8, key: u8) -> bool {
+        self.0.active_notes.lock().unwrap()[channel as usize & 0xF] & 1 << (key & 0x7F) != 0
+    }
+
+    /// Mark a key as held or released
+    pub(crate) fn set_note_active(&self, channel: u8, key: u8, active: bool) {
+        let notes = &mut self.0.active_notes.lock().unwrap()[channel as usize & 0xF];
+        if active {
+            *notes |= 1 << (key & 0x7F);
+        } else {
+            *notes &= !(1 << (key & 0x7F));
+        }
+    }
+
+    /// Release every key of a channel, or of every channel if `None`
+    pub(crate) fn release_notes(&self, channel: Option<u8>) {
+        let mut notes = self.0.active_notes.lock().unwrap();
+        match channel {
+            Some(channel) => notes[channel as usize & 0xF] = 0,
+            None => *notes = [0; 16],
+        }
+    }
+
+    /// Entity the control was last added to, once the plugin has seen it
+    pub fn entity(&self) -> Option<Entity> {
+        *self.0.entity.lock().unwrap()
+    }
+
+    /// Record a failure which left the source silent, to be sent as a
+    /// [`MidiPlaybackError`](crate::MidiPlaybackError)
+    pub(crate) fn report_error(&self, error: MidiError) {
+        match self.entity() {
+            Some(entity) => error!("MIDI source {entity} failed to play: {error}"),
+            None => error!("MIDI source failed to play: {error}"),
+        }
+        self.0.errors.lock().unwrap().push(error);
+    }
+
+    pub(crate) fn take_errors(&self) -> Vec<MidiError> {
+        std::mem::take(&mut *self.0.errors.lock().unwrap())
+    }
+
+    /// Number of changes made to the channel settings, live input and recorder
+    pub(crate) fn channel_changes(&self) -> u64 {
+        self.0.channel_changes.load(Ordering::Acquire)
+    }
+
+    /// Version of the channel settings, which changes whenever they do, and the settings, if
+    /// they changed since `version`
+    pub(crate) fn channels_since(&self, version: u64) -> Option<(u64, ChannelState)> {
+        let channels = self.0.channels.lock().unwrap();
+        (channels.0 != version).then(|| channels.clone())
+    }
+
+    /// Change the channel settings, counting a change if `change` returns `true`
+    fn change_channels(&self, change: impl FnOnce(&mut ChannelState) -> bool) {
+        let mut channels = self.0.channels.lock().unwrap();
+        if change(&mut channels.1) {
+            channels.0 += 1;
+            self.0.channel_changes.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// Send a controller change (CC) to a channel, counting from 0, as if the music had sent it.
+    ///
+    /// The value is sent again after a seek, after the music's own controller changes before the
+    /// new position. Like other changes, it's heard once the audio already rendered ahead has
+    /// played.
+    pub fn set_controller(&self, channel: u8, controller: u8, value: u8) {
+        self.replace_controller(channel, controller, Some(value.min(127)));
+    }
+
+    /// Stop sending a controller set with [`MidiControl::set_controller`] again after seeks.
+    ///
+    /// The channel keeps the last value until the music changes the controller itself.
+    pub fn clear_controller(&self, channel: u8, controller: u8) {
+        self.replace_controller(channel, controller, None);
+    }
+
+    fn replace_controller(&self, channel: u8, controller: u8, value: Option<u8>) {
+        self.change_channels(|channels| {
+            let slot =
+                &mut channels.controllers[channel as usize & 0xF][controller as usize & 0x7F];
+            std::mem::replace(slot, value) != value
+        });
+    }
+
+    /// Latest value of a controller of a channel set through this control
+    pub fn controller(&self, channel: u8, controller: u8) -> Option<u8> {
+        self.0.channels.lock().unwrap().1.controllers[channel as usize & 0xF]
+            [controller as usize & 0x7F]
+    }
+
+    /// Change how loudly notes of each velocity are played
+    pub fn set_velocity_curve(&self, curve: MidiVelocityCurve) {
+        self.change_channels(|channels| {
+            std::mem::replace(&mut channels.velocity_curve, curve.clone()) != curve
+        });
+    }
+
+    /// Curve notes are currently played with
+    pub fn velocity_curve(&self) -> MidiVelocityCurve {
+        self.0.channels.lock().unwrap().1.velocity_curve.clone()
+    }
+
+    /// Change how channel 10 is played
+    pub fn set_drum_channel(&self, drums: MidiDrumChannel) {
+        self.change_channels(|channels| {
+            std::mem::replace(&mut channels.drum_channel, drums) != drums
+        });
+    }
+
+    /// How channel 10 is currently played
+    pub fn drum_channel(&self) -> MidiDrumChannel {
+        self.0.channels.lock().unwrap().1.drum_channel
+    }
+
+    /// Change what aftertouch does
+    pub fn set_pressure_target(&self, target: MidiPressureTarget) {
+        self.change_channels(|channels| {
+            std::mem::replace(&mut channels.pressure_target, target) != target
+        });
+    }
+
+    /// What aftertouch currently does
+    pub fn pressure_target(&self) -> MidiPressureTarget {
+        self.0.channels.lock().unwrap().1.pressure_target
+    }
+
+    /// Play live input as coming from an MPE controller with the given zones, or as ordinary
+    /// channel messages
+    pub fn set_mpe(&self, zones: Option<MidiMpe>) {
+        self.change_channels(|channels| std::mem::replace(&mut channels.mpe, zones) != zones);
+    }
+
+    /// MPE zones live input is currently played with
+    pub fn mpe(&self) -> Option<MidiMpe> {
+        self.0.channels.lock().unwrap().1.mpe
+    }
+
+    /// Play a message live on the source's synthesizer, such as a note or aftertouch from a MIDI
+    /// controller, as if the music had sent it.
+    ///
+    /// It's played at the start of the next rendered block, so the source's block length sets
+    /// the latency, and heard once the audio already rendered ahead has played.
+    pub fn send_input(&self, message: MidiInputMessage) {
+        self.0.input.lock().unwrap().push(message);
+        self.0.channel_changes.fetch_add(1, Ordering::Release);
+    }
+
+    /// Take the messages sent live since they were last taken
+    pub(crate) fn take_input(&self) -> Vec<MidiInputMessage> {
+        std::mem::take(&mut *self.0.input.lock().unwrap())
+    }
+
+    /// Move notes off the beat grid with a groove template, or play them as written
+    pub fn set_groove(&self, groove: Option<MidiGroove>) {
+        self.change_channels(|channels| {
+            std::mem::replace(&mut channels.groove, groove.clone()) != groove
+        });
+    }
+
+    /// Groove template notes are currently played with
+    pub fn groove(&self) -> Option<MidiGroove> {
+        self.0.channels.lock().unwrap().1.groove.clone()
+    }
+
+    /// Record every message the source's synthesizers receive from now on, or stop recording
+    pub fn set_recorder(&self, recorder: Option<MidiRecorder>) {
+        *self.0.recorder.lock().unwrap() = recorder;
+        self.0.channel_changes.fetch_add(1, Ordering::Release);
+    }
+
+    /// Recorder messages are currently recorded to
+    pub fn recorder(&self) -> Option<MidiRecorder> {
+        self.0.recorder.lock().unwrap().clone()
+    }
+
+    /// Set the priority of the source, with higher priorities stopped last when too many sources
+    /// play at once
+    pub fn set_priority(&self, priority: i32) {
+        self.0.priority.store(priority, Ordering::Relaxed);
+    }
+
+    /// Priority of the source
+    pub fn priority(&self) -> i32 {
+        self.0.priority.load(Ordering::Relaxed)
+    }
+
+    /// Latest output levels of the source
+    pub fn levels(&self) -> MidiLevels {
+        *self.0.levels.lock().unwrap()
+    }
+
+    pub(crate) fn set_levels(&self, levels: MidiLevels) {
+        *self.0.levels.lock().unwrap() = levels;
+    }
+
+    pub(crate) fn set_tap(&self, tap: AnalysisTap, capacity: usize) {
+        *self.0.tap.lock().unwrap() = Some((tap, capacity));
+    }
+
+    /// Analysis tap receiving the output and the number of samples it keeps
+    pub(crate) fn tap(&self) -> Option<(AnalysisTap, usize)> {
+        self.0.tap.lock().unwrap().clone()
+    }
+
+    /// Number of times the output ran out of rendered audio mid-playback, causing a gap
+    pub fn underruns(&self) -> u64 {
+        self.0.underruns.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_underrun(&self) {
+        self.0.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of underruns since the last call
+    pub(crate) fn take_new_underruns(&self) -> u64 {
+        let underruns = self.underruns();
+        underruns - self.0.reported_underruns.swap(underruns, Ordering::Relaxed)
+    }
+
+    fn request(&self, fade: Fade) {
+        *self.0.pending.lock().unwrap() = Some(fade);
+        self.0.dirty.store(true, Ordering::Release);
+    }
+
+    fn stream(&self) -> Option<async_channel::Receiver<RenderedBlock>> {
+        self.0.stream.lock().unwrap().as_ref()?.upgrade()
+    }
+
+    /// Give the control access to the stream of the decoder it drives
+    pub(crate) fn attach_stream(&self, stream: &async_channel::Receiver<RenderedBlock>) {
+        *self.0.stream.lock().unwrap() = Some(stream.downgrade());
+    }
+
+    pub(crate) fn is_seeking(&self) -> bool {
+        self.0.seeking.load(Ordering::Acquire)
+    }
+
+    /// Take a pending seek on the render task, discarding audio already in the stream
+    pub(crate) fn take_seek(&self) -> Option<Duration> {
+        let position = self.0.seek.lock().unwrap().take()?;
+        if let Some(stream) = self.stream() {
+            while stream.try_recv().is_ok() {}
+        }
+        Some(position)
+    }
+
+    /// Mark a seek as done once the render task has moved to `position`
+    pub(crate) fn finish_seek(&self, position: Duration) {
+        self.set_position(position.as_secs_f64());
+        if self.0.seek.lock().unwrap().is_none() {
+            self.0.seeking.store(false, Ordering::Release);
+        }
+    }
+
+    /// Advance the output position by the given number of seconds
+    pub(crate) fn advance_position(&self, seconds: f64) {
+        let position = f64::from_bits(self.0.position.load(Ordering::Relaxed));
+        self.set_position(position + seconds);
+    }
+
+    /// Move the output position back to the start, as when the music loops
+    pub(crate) fn restart_position(&self) {
+        self.set_position(0.0);
+    }
+
+    fn set_position(&self, seconds: f64) {
+        self.0.position.store(seconds.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Set the silence a decoder created from now on outputs before starting
+    pub(crate) fn set_start_delay(&self, delay: Duration) {
+        *self.0.start_delay.lock().unwrap() = delay;
+    }
+
+    pub(crate) fn take_start_delay(&self) -> Duration {
+        std::mem::take(&mut *self.0.start_delay.lock().unwrap())
+    }
+
+    /// Make a decoder created from now on begin at the first note
+    pub(crate) fn set_skip_silence(&self) {
+        self.0.skip_silence.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn take_skip_silence(&self) -> bool {
+        self.0.skip_silence.swap(false, Ordering::Relaxed)
+    }
+
+    pub(crate) fn release_tail(&self) -> MidiReleaseTail {
+        *self.0.release_tail.lock().unwrap()
+    }
+
+    /// Length of the blocks this source renders at a time, if it overrides the render settings
+    pub(crate) fn block_length(&self) -> Option<Duration> {
+        *self.0.block_length.lock().unwrap()
+    }
+
+    /// What the source plays when it runs out of rendered audio
+    pub(crate) fn dry_stream(&self) -> MidiDryStream {
+        *self.0.dry_stream.lock().unwrap()
+    }
+
+    /// Channels decoders of this source play
+    pub(crate) fn channel_layout(&self) -> MidiChannelLayout {
+        *self.0.channel_layout.lock().unwrap()
+    }
+
+    /// Set the speed of game time the music follows
+    pub(crate) fn set_time_scale(&self, scale: f64) {
+        self.0.time_scale.store(scale.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Speed multiplier of the music's clock, combining the set speed and game time
+    pub(crate) fn playback_speed(&self) -> f64 {
+        self.speed() * f64::from_bits(self.0.time_scale.load(Ordering::Relaxed))
+    }
+}
+
+/// Linear ramp from the current value to a target over a number of frames
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Ramp {
+    current: f32,
+    step: f32,
+    remaining: u64,
+}
+
+impl Ramp {
+    pub(crate) fn new(value: f32) -> Self {
+        Self {
+            current: value,
+            step: 0.0,
+            remaining: 0,
+        }
+    }
+
+    /// Begin ramping towards `target`, reaching it after `frames` frames
+    pub(crate) fn start(&mut self, target: f32, frames: u64) {
+        if frames == 0 {
+            self.current = target;
+            self.step = 0.0;
+        } else {
+            self.step = (target - self.current) / frames as f32;
+        }
+        self.remaining = frames;
+    }
+
+    /// Advance by one frame and return the new value
+    pub(crate) fn next(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.current += self.step;
+        }
+        self.current
+    }
+
+    pub(crate) fn value(&self) -> f32 {
+        self.current
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// Per-frame gain ramp driven by a [`MidiControl`] on the audio thread
+#[derive(Debug)]
+pub(crate) struct GainRamp {
+    control: MidiControl,
+    sample_rate: f32,
+    ramp: Ramp,
+    stop: bool,
+}
+
+impl GainRamp {
+    pub(crate) fn new(control: MidiControl, sample_rate: u32) -> Self {
+        Self {
+            ramp: Ramp::new(control.gain()),
+            control,
+            sample_rate: sample_rate as f32,
+            stop: false,
+        }
+    }
+
+    /// Advance the ramp by one frame, returning the gain for that frame or `None` once stopped.
+    pub(crate) fn next_frame(&mut self) -> Option<f32> {
+        let state = &self.control.0;
+        if state.stopped.load(Ordering::Relaxed) {
+            return None;
+        }
+        if state.dirty.swap(false, Ordering::Acquire) {
+            if let Some(fade) = state.pending.lock().unwrap().take() {
+                let frames = (fade.duration.as_secs_f32() * self.sample_rate) as u64;
+                self.stop = fade.stop;
+                self.ramp.start(fade.target, frames);
+            }
+        }
+        let gain = self.ramp.next();
+        state.gain.store(gain.to_bits(), Ordering::Relaxed);
+        if self.stop && self.ramp.is_done() {
+            state.stopped.store(true, Ordering::Relaxed);
+            return None;
+        }
+        Some(gain)
+    }
+}
+
+/// Remember the entity of each newly added control, so its decoder can tell which source it
+/// belongs to
+pub(crate) fn track_control_entities(query: Query<(Entity, &MidiControl), Added<MidiControl>>) {
+    for (entity, control) in &query {
+        *control.0.entity.lock().unwrap() = Some(entity);
+    }
+}
+
+/// Component making a MIDI source's tempo follow the relative speed of [`Time<Virtual>`], so
+/// slowing down or pausing game time slows down or holds the music.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct FollowVirtualTime;
+
+pub(crate) fn apply_virtual_time(
+    time: Res<Time<Virtual>>,
+    query: Query<&MidiControl, With<FollowVirtualTime>>,
+) {
+    for control in &query {
+        control.set_time_scale(time.effective_speed_f64());
+    }
+}
+
+/// Component starting a MIDI source from the given position in the music.
+///
+/// Controller and program changes before the offset are applied instantly, without rendering the
+/// skipped audio.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct MidiStartOffset(pub Duration);
+
+pub(crate) fn apply_start_offsets(query: Query<(&MidiControl, &MidiStartOffset), QueuedFilter>) {
+    for (control, offset) in &query {
+        if !offset.0.is_zero() {
+            control.seek(offset.0);
+        }
+    }
+}
+
+/// Component making a MIDI source begin at its first note, skipping any leading silence
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct SkipLeadingSilence;
+
+pub(crate) fn apply_skip_silence(
+    query: Query<&MidiControl, (With<SkipLeadingSilence>, QueuedFilter)>,
+) {
+    for control in &query {
+        control.set_skip_silence();
+    }
+}
+
+/// Component configuring how long a MIDI source keeps rendering reverb and note releases after
+/// its last event.
+///
+/// Sources without this component use the default settings.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct MidiReleaseTail {
+    /// Longest tail to render
+    pub max_duration: Duration,
+    /// Sample amplitude below which the tail is considered silent and playback ends
+    pub threshold: f32,
+}
+
+impl Default for MidiReleaseTail {
+    fn default() -> Self {
+        Self {
+            max_duration: Duration::from_secs(5),
+            threshold: 1e-4,
+        }
+    }
+}
+
+type ReleaseTailChanged = Or<(Changed<MidiReleaseTail>, Added<MidiControl>)>;
+
+pub(crate) fn apply_release_tails(
+    query: Query<(&MidiControl, &MidiReleaseTail), ReleaseTailChanged>,
+) {
+    for (control, tail) in &query {
+        *control.0.release_tail.lock().unwrap() = *tail;
+    }
+}
+
+/// Component overriding [`MidiRenderSettings::block_length`](crate::MidiRenderSettings) for a
+/// single MIDI source.
+///
+/// Changes to the source's [`MidiControl`] are only heard from the next block, so short blocks
+/// suit music which reacts to gameplay, while long blocks render background music with less
+/// overhead. It applies to decoders created after it's inserted.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MidiBlockLength(pub Duration);
+
+type BlockLengthChanged = Or<(Changed<MidiBlockLength>, Added<MidiControl>)>;
+
+pub(crate) fn apply_block_lengths(
+    query: Query<(&MidiControl, &MidiBlockLength), BlockLengthChanged>,
+) {
+    for (control, length) in &query {
+        *control.0.block_length.lock().unwrap() = Some(length.0);
+    }
+}
+
+/// Component choosing what a MIDI source plays when it runs out of rendered audio, such as when
+/// rendering falls behind or its synthesizer stops sending audio.
+///
+/// Sources without this component use [`MidiDryStream::MarkUnderrun`].
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiDryStream {
+    /// Play silence until audio arrives, counting an underrun in [`MidiControl::underruns`] and
+    /// sending a [`MidiUnderrunEvent`](crate::MidiUnderrunEvent)
+    #[default]
+    MarkUnderrun,
+    /// Play silence until audio arrives without reporting it, for sources where gaps are
+    /// expected
+    Silence,
+    /// Play silence for at most the given time, counting an underrun, then end the source so the
+    /// audio output sees it finish promptly
+    EndAfter(Duration),
+}
+
+type DryStreamChanged = Or<(Changed<MidiDryStream>, Added<MidiControl>)>;
+
+pub(crate) fn apply_dry_stream_policies(
+    query: Query<(&MidiControl, &MidiDryStream), DryStreamChanged>,
+) {
+    for (control, policy) in &query {
+        *control.0.dry_stream.lock().unwrap() = *policy;
+    }
+}
+
+/// Component choosing the channels a MIDI source is played on, for audio outputs other than
+/// stereo.
+///
+/// Music is rendered in stereo, then mixed down to mono or played on the front left and right
+/// channels of larger layouts, leaving the others silent. It applies to decoders created after
+/// it's inserted.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiChannelLayout {
+    /// A single channel mixing left and right
+    Mono,
+    /// Left and right
+    #[default]
+    Stereo,
+    /// Front left, front right, rear left and rear right
+    Quad,
+    /// Front left, front right, center, low frequency effects, rear left and rear right
+    Surround51,
+}
+
+impl MidiChannelLayout {
+    /// Number of channels in the layout
+    pub fn channels(&self) -> u16 {
+        match self {
+            Self::Mono => 1,
+            Self::Stereo => 2,
+            Self::Quad => 4,
+            Self::Surround51 => 6,
+        }
+    }
+
+    /// Sample of the given channel playing a stereo frame
+    pub(crate) fn sample(&self, [left, right]: [f32; 2], channel: u16) -> f32 {
+        match (self, channel) {
+            (Self::Mono, _) => (left + right) / 2.0,
+            (_, 0) => left,
+            (_, 1) => right,
+            _ => 0.0,
+        }
+    }
+}
+
+type ChannelLayoutChanged = Or<(Changed<MidiChannelLayout>, Added<MidiControl>)>;
+
+pub(crate) fn apply_channel_layouts(
+    query: Query<(&MidiControl, &MidiChannelLayout), ChannelLayoutChanged>,
+) {
+    for (control, layout) in &query {
+        *control.0.channel_layout.lock().unwrap() = *layout;
+    }
+}
+
+pub(crate) type QueuedFilter = (
+    With<PlaybackSettings>,
+    Without<AudioSink>,
+    Without<SpatialAudioSink>,
+    Without<HeadlessMidiOutput>,
+);
+
+/// Optional components configuring the [`MidiSource`] of a queued entity
+pub(crate) type SourceOptions = (Option<&'static MidiControl>, Option<&'static MidiSyncGroup>);
+
+type QueuedAudio = (
+    Entity,
+    &'static Handle<MidiAudio>,
+    SourceOptions,
+    Option<&'static MidiCountIn>,
+);
+
+type QueuedControlledFilter = (
+    QueuedFilter,
+    Or<(
+        With<MidiControl>,
+        With<MidiSyncGroup>,
+        With<FollowTransport>,
+        With<MidiCountIn>,
+        With<QuantizedStart>,
+        With<FollowVirtualTime>,
+        With<MidiStartOffset>,
+        With<SkipLeadingSilence>,
+        With<MidiReleaseTail>,
+        With<MidiLevels>,
+        With<MidiAnalyzer>,
+        With<MidiEnvelopeFollower>,
+        With<MidiProgramWatcher>,
+        RenderTunedFilter,
+        ChannelControlledFilter,
+    )>,
+);
+
+/// Components changing how a source is rendered
+type RenderTunedFilter = Or<(
+    With<MidiPriority>,
+    With<MidiBlockLength>,
+    With<MidiDryStream>,
+    With<MidiChannelLayout>,
+)>;
+
+/// Components changing what the channels of a source play
+type ChannelControlledFilter = Or<(
+    With<MidiControllerAnimation>,
+    With<MidiChannelControls>,
+    With<MidiVelocityCurve>,
+    With<MidiRecorder>,
+    With<MidiDrumChannel>,
+    With<MidiPressureTarget>,
+    With<MidiMpe>,
+    With<MidiGroove>,
+)>;
+
+/// Moves queued MIDI entities carrying any of the components matched by `QueuedControlledFilter`
+/// onto their own [`MidiSource`] so the decoder can be reached from the ECS.
+pub(crate) fn prepare_controlled_sources(
+    mut commands: Commands,
+    midi_assets: Res<Assets<MidiAudio>>,
+    mut sources: ResMut<Assets<MidiSource>>,
+    query: Query<QueuedAudio, QueuedControlledFilter>,
+) {
+    for (entity, handle, (control, sync), count_in) in &query {
+        let Some(audio) = midi_assets.get(handle) else {
+            continue;
+        };
+        let program = match count_in {
+            Some(count_in) => match audio.to_song() {
+                Ok(song) => SourceProgram::Song(Arc::new(
+                    song.with_count_in(count_in.bars, &count_in.clicks),
+                )),
+                Err(error) => {
+                    error!("Failed to read MIDI audio: {error}");
+                    commands.entity(entity).remove::<MidiCountIn>();
+                    continue;
+                }
+            },
+            None => SourceProgram::Audio(audio.clone()),
+        };
+        let control = control.cloned().unwrap_or_default();
+        let source = sources.add(MidiSource {
+            program,
+            control: control.clone(),
+            sync: sync.cloned(),
+        });
+        commands
+            .entity(entity)
+            .remove::<Handle<MidiAudio>>()
+            .insert((source, control, MidiSourceOrigin(handle.clone())));
+    }
+}
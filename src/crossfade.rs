@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use bevy::{
+    audio::{AudioSink, AudioSinkPlayback, SpatialAudioSink},
+    ecs::world::Command,
+    prelude::*,
+};
+
+use crate::{HeadlessMidiOutput, MidiAudio, MidiControl};
+
+/// Command which fades a playing MIDI source out and despawns it once silent.
+///
+/// Sources without a [`MidiControl`] cannot be faded and are stopped immediately.
+#[derive(Debug)]
+pub struct FadeOutMidi {
+    /// Entity playing the source
+    pub entity: Entity,
+    /// Length of the fade
+    pub duration: Duration,
+}
+
+impl Command for FadeOutMidi {
+    fn apply(self, world: &mut World) {
+        let Some(mut entity) = world.get_entity_mut(self.entity) else {
+            return;
+        };
+        if let Some(control) = entity.get::<MidiControl>() {
+            control.fade_out(self.duration);
+            entity.insert(DespawnWhenFinished);
+        } else if let Some(sink) = entity.get::<AudioSink>() {
+            sink.stop();
+            entity.insert(DespawnWhenFinished);
+        } else {
+            entity.despawn_recursive();
+        }
+    }
+}
+
+/// Marks a source entity to be despawned once it has finished playing
+#[derive(Component, Debug)]
+pub(crate) struct DespawnWhenFinished;
+
+/// Outputs a source may be playing through
+pub(crate) type SourceOutput = (
+    Option<&'static AudioSink>,
+    Option<&'static SpatialAudioSink>,
+    Option<&'static HeadlessMidiOutput>,
+);
+
+/// Whether a source has played to its end through any of its outputs
+pub(crate) fn output_finished(
+    (sink, spatial_sink, headless): (
+        Option<&AudioSink>,
+        Option<&SpatialAudioSink>,
+        Option<&HeadlessMidiOutput>,
+    ),
+) -> bool {
+    sink.is_some_and(|sink| sink.empty())
+        || spatial_sink.is_some_and(|sink| sink.empty())
+        || headless.is_some_and(HeadlessMidiOutput::is_finished)
+}
+
+pub(crate) fn despawn_finished_sources(
+    mut commands: Commands,
+    query: Query<(Entity, SourceOutput), With<DespawnWhenFinished>>,
+) {
+    for (entity, output) in &query {
+        if output_finished(output) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Extension trait adding MIDI playback helpers to [`Commands`]
+pub trait MidiCommandsExt {
+    /// Start playing `to`, fading it in while `from` fades out over `duration`.
+    ///
+    /// `from` is despawned once its fade has finished. Returns the entity of the new source.
+    fn crossfade_midi(
+        &mut self,
+        from: Entity,
+        to: Handle<MidiAudio>,
+        settings: PlaybackSettings,
+        duration: Duration,
+    ) -> Entity;
+
+    /// Stop the source played by `entity`, fading it out over `duration` and despawning it once
+    /// silent
+    fn stop_midi_with_fade(&mut self, entity: Entity, duration: Duration);
+}
+
+impl MidiCommandsExt for Commands<'_, '_> {
+    fn crossfade_midi(
+        &mut self,
+        from: Entity,
+        to: Handle<MidiAudio>,
+        settings: PlaybackSettings,
+        duration: Duration,
+    ) -> Entity {
+        let control = MidiControl::with_gain(0.0);
+        control.fade_to(1.0, duration);
+        let entity = self
+            .spawn((
+                AudioSourceBundle {
+                    source: to,
+                    settings,
+                },
+                control,
+            ))
+            .id();
+        self.stop_midi_with_fade(from, duration);
+        entity
+    }
+
+    fn stop_midi_with_fade(&mut self, entity: Entity, duration: Duration) {
+        self.add(FadeOutMidi { entity, duration });
+    }
+}
@@ -0,0 +1,651 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_channel::{Receiver, TryRecvError};
+use bevy::audio::Source;
+use rustysynth::SoundFont;
+
+#[cfg(feature = "generative")]
+use crate::generative::{GenerativeProgram, GenerativeRenderer};
+use crate::{
+    analysis::TAP_BLOCK,
+    control::GainRamp,
+    diagnostics::SourceStats,
+    input::LiveRenderer,
+    layers::{LayerProgram, LayerRenderer},
+    metering::LevelMeter,
+    metronome::{MetronomeProgram, MetronomeRenderer},
+    midi::Song,
+    render::{RenderLoop, RenderedBlock},
+    resample::Resampler,
+    segments::{SegmentProgram, SegmentRenderer},
+    sequencer::{MidiRender, Sequencer, SongRenderer, SynthFactory},
+    settings::{render_pool, render_settings},
+    sfx::{SfxProgram, SfxRenderer},
+    sync::SyncCursor,
+    DitheredI16, MidiAudio, MidiChannelLayout, MidiControl, MidiDryStream, MidiError,
+    MidiRenderSettings, MidiSyncGroup,
+};
+
+/// Sample rate of every decoder's output
+pub(crate) const SAMPLE_RATE: usize = 44100;
+
+/// Length of the blocks rendered at a time by programs played as soon as they're requested
+const LIVE_BLOCK_LENGTH: Duration = Duration::from_millis(5);
+
+/// What a [`MidiSource`](crate::MidiSource) plays
+#[derive(Clone, Debug)]
+pub(crate) enum SourceProgram {
+    Audio(MidiAudio),
+    Song(Arc<Song>),
+    Segments(Arc<SegmentProgram>),
+    Layers(Arc<LayerProgram>),
+    Metronome(Arc<MetronomeProgram>),
+    Sfx(Arc<SfxProgram>),
+    Live,
+    #[cfg(feature = "generative")]
+    Generative(Arc<GenerativeProgram>),
+}
+
+/// Decoder for MIDI file playback
+pub struct MidiFileDecoder {
+    sample_rate: usize,
+    stream: Stream,
+    control: MidiControl,
+    gain: GainRamp,
+    channel: u16,
+    /// Right sample of the frame being played, read along with the left one
+    right: f32,
+    sync: Option<SyncCursor>,
+    /// Number of frames missed while the stream was empty, skipped to stay in sync
+    behind: usize,
+    delay: u64,
+    /// Whether the stream has delivered audio since the last seek
+    primed: bool,
+    /// Whether the stream is currently running dry
+    starved: bool,
+    /// Number of frames of silence played since the stream ran dry
+    dry_frames: usize,
+    meter: LevelMeter,
+    /// Conversion to the output sample rate, if it differs from the rendered one
+    resampler: Option<Resampler>,
+    layout: MidiChannelLayout,
+    /// Channel of the layout played next
+    layout_channel: u16,
+    /// Stereo frame being played in the layout
+    frame: [f32; 2],
+    /// Mono output not yet handed to the analysis tap
+    tap_buffer: Vec<f32>,
+    _cancel: CancelOnDrop,
+}
+
+/// Where a decoder's rendered audio comes from
+enum Stream {
+    /// Rendered ahead by a task on the render pool
+    Task(TaskStream),
+    /// Rendered on demand by the thread pulling samples
+    Inline(Box<InlineStream>),
+}
+
+impl Stream {
+    /// Next stereo frame, so a block arriving between its samples can't swap the channels
+    fn try_recv(&mut self) -> Result<[f32; 2], TryRecvError> {
+        match self {
+            Stream::Task(stream) => stream.try_recv(),
+            Stream::Inline(stream) => stream.try_recv(),
+        }
+    }
+
+    /// Number of samples rendered and waiting to be played, and whether no more will follow
+    fn buffered(&self) -> (usize, bool) {
+        match self {
+            Stream::Task(stream) => stream.buffered(),
+            Stream::Inline(stream) => ((stream.frames * 2).saturating_sub(stream.index), false),
+        }
+    }
+
+    /// Whether looping music started again from the beginning at the last sample received
+    fn take_restart(&mut self) -> bool {
+        match self {
+            Stream::Task(stream) => std::mem::take(&mut stream.restarted),
+            Stream::Inline(stream) => std::mem::take(&mut stream.restarted),
+        }
+    }
+}
+
+/// Blocks of interleaved stereo samples sent by a render task
+struct TaskStream {
+    blocks: Receiver<RenderedBlock>,
+    /// Block being played
+    block: RenderedBlock,
+    /// Index of the next sample of the block being played
+    index: usize,
+    /// Number of samples in a full block
+    block_samples: usize,
+    restarted: bool,
+}
+
+impl TaskStream {
+    fn new(blocks: Receiver<RenderedBlock>, block_frames: usize) -> Self {
+        Self {
+            blocks,
+            block: RenderedBlock::default(),
+            index: 0,
+            block_samples: block_frames * 2,
+            restarted: false,
+        }
+    }
+
+    fn try_recv(&mut self) -> Result<[f32; 2], TryRecvError> {
+        while self.index >= self.block.samples.len() {
+            self.block = self.blocks.try_recv()?;
+            self.index = 0;
+        }
+        self.restarted |= self.block.restart.map(|frame| frame * 2) == Some(self.index);
+        let frame = [
+            self.block.samples[self.index],
+            self.block.samples[self.index + 1],
+        ];
+        self.index += 2;
+        Ok(frame)
+    }
+
+    fn buffered(&self) -> (usize, bool) {
+        let current = self.block.samples.len() - self.index;
+        let ended = current == 0 && self.blocks.is_closed() && self.blocks.is_empty();
+        (current + self.blocks.len() * self.block_samples, ended)
+    }
+
+    /// Drop what is left of the block being played, such as audio from before a seek
+    fn discard_block(&mut self) {
+        self.block = RenderedBlock::default();
+        self.index = 0;
+        self.restarted = false;
+    }
+}
+
+struct InlineStream {
+    render: RenderLoop,
+    /// Frames in the current block
+    frames: usize,
+    /// Index of the next interleaved sample of the current block
+    index: usize,
+    restarted: bool,
+}
+
+impl InlineStream {
+    fn try_recv(&mut self) -> Result<[f32; 2], TryRecvError> {
+        while self.index >= self.frames * 2 {
+            self.frames = self.render.render_block().ok_or(TryRecvError::Closed)?;
+            self.index = 0;
+        }
+        self.restarted |= self.render.restart().map(|frame| frame * 2) == Some(self.index);
+        let block = self.render.block();
+        let frame = [block[self.index], block[self.index + 1]];
+        self.index += 2;
+        Ok(frame)
+    }
+
+    /// Drop the rest of the current block and render the next, taking any pending seek.
+    ///
+    /// Returns `false` if the music has already ended.
+    fn restart(&mut self) -> bool {
+        self.index = 0;
+        self.restarted = false;
+        match self.render.render_block() {
+            Some(frames) => {
+                self.frames = frames;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Signals the render task to stop once the decoder is dropped
+#[derive(Debug, Default)]
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+impl MidiFileDecoder {
+    /// Construct and begin a new MIDI sequencer with the given MIDI data and soundfont.
+    ///
+    /// The sequencer will push at most 1 second's worth of audio ahead, allowing the decoder to
+    /// be paused without endlessly backing up data forever.
+    pub fn new(midi: MidiAudio, soundfont: Arc<SoundFont>) -> Self {
+        Self::with_control(midi, soundfont, MidiControl::default())
+    }
+
+    /// Construct and begin a new MIDI sequencer whose output is driven by the given control.
+    pub fn with_control(midi: MidiAudio, soundfont: Arc<SoundFont>, control: MidiControl) -> Self {
+        Self::with_program(SourceProgram::Audio(midi), Some(soundfont), control, None)
+    }
+
+    /// Construct a decoder which renders on the thread pulling samples rather than on a
+    /// background task, for use without an audio device.
+    ///
+    /// The decoder ends once the music and its release tail have been rendered, so the whole
+    /// piece can be rendered with [`Iterator::collect`].
+    pub fn offline(midi: MidiAudio, soundfont: Arc<SoundFont>) -> Self {
+        Self::offline_with_control(midi, soundfont, MidiControl::default())
+    }
+
+    /// Construct an offline decoder whose output is driven by the given control.
+    ///
+    /// See [`MidiFileDecoder::offline`].
+    pub fn offline_with_control(
+        midi: MidiAudio,
+        soundfont: Arc<SoundFont>,
+        control: MidiControl,
+    ) -> Self {
+        let settings = render_settings();
+        Self::offline_program(
+            SourceProgram::Audio(midi),
+            Some(soundfont),
+            control,
+            None,
+            &settings,
+        )
+    }
+
+    /// Produce 16-bit samples with TPDF dithering instead of `f32` samples, with fresh noise
+    /// every time; [`Self::into_i16_with_seed`] gives reproducible output
+    pub fn into_i16(self) -> DitheredI16<Self> {
+        DitheredI16::new(self)
+    }
+
+    /// Produce 16-bit samples dithered with noise from the given seed, so the same music always
+    /// gives the same samples
+    pub fn into_i16_with_seed(self, seed: u64) -> DitheredI16<Self> {
+        DitheredI16::with_seed(self, seed)
+    }
+
+    /// Construct a decoder for the given program, waiting for the plugin's soundfont to finish
+    /// loading if `soundfont` is `None`
+    pub(crate) fn with_program(
+        program: SourceProgram,
+        soundfont: Option<Arc<SoundFont>>,
+        control: MidiControl,
+        sync: Option<MidiSyncGroup>,
+    ) -> Self {
+        let settings = render_settings();
+        let sample_rate = SAMPLE_RATE;
+        let block = program.block_frames(&settings, &control, sample_rate);
+        let buffer = (settings.buffer_length.as_secs_f64() * sample_rate as f64) as usize;
+        let (tx, rx) = async_channel::bounded::<RenderedBlock>(buffer.div_ceil(block).max(1));
+        control.attach_stream(&rx);
+        let task_control = control.clone();
+        let cancel = CancelOnDrop::default();
+        let cancelled = cancel.0.clone();
+        let stats = SourceStats::register_live(&rx, &control, &settings);
+        render_pool(&settings)
+            .spawn(async move {
+                let control = task_control;
+                let soundfont = match soundfont {
+                    Some(soundfont) => Some(soundfont),
+                    None => crate::soundfont().await,
+                };
+                let renderer = match program.renderer(soundfont, &settings, &control) {
+                    Ok(renderer) => renderer,
+                    Err(error) => {
+                        // The source ends without playing anything
+                        control.report_error(error);
+                        tx.close();
+                        return;
+                    }
+                };
+                let mut render =
+                    RenderLoop::new(renderer, control.clone(), sample_rate, block, stats);
+                loop {
+                    if cancelled.load(Ordering::Acquire) {
+                        return;
+                    }
+                    if render.render_block().is_none() {
+                        break;
+                    }
+                    // Audio rendered before a seek is dropped, the next block takes the seek
+                    if control.is_seeking() {
+                        continue;
+                    }
+                    if tx.send(render.take_block()).await.is_err() {
+                        return;
+                    }
+                }
+
+                tx.close();
+            })
+            .detach();
+        let stream = Stream::Task(TaskStream::new(rx, block));
+        Self::from_stream(stream, control, sync, cancel, &settings)
+    }
+
+    /// Construct a decoder for the given program which renders inline
+    pub(crate) fn offline_program(
+        program: SourceProgram,
+        soundfont: Option<Arc<SoundFont>>,
+        control: MidiControl,
+        sync: Option<MidiSyncGroup>,
+        settings: &MidiRenderSettings,
+    ) -> Self {
+        let block = program.block_frames(settings, &control, SAMPLE_RATE);
+        // Offline decoders have no buffer to report, so track them against an empty channel
+        let (_, rx) = async_channel::bounded::<RenderedBlock>(1);
+        let stats = SourceStats::register(&rx);
+        let renderer = match program.renderer(soundfont, settings, &control) {
+            Ok(renderer) => renderer,
+            Err(error) => {
+                control.report_error(error);
+                // The channel's sender is gone, so the decoder ends without playing anything
+                return Self::from_stream(
+                    Stream::Task(TaskStream::new(rx, block)),
+                    control,
+                    sync,
+                    CancelOnDrop::default(),
+                    settings,
+                );
+            }
+        };
+        let stream = Stream::Inline(Box::new(InlineStream {
+            render: RenderLoop::new(renderer, control.clone(), SAMPLE_RATE, block, stats),
+            frames: 0,
+            index: 0,
+            restarted: false,
+        }));
+        Self::from_stream(stream, control, sync, CancelOnDrop::default(), settings)
+    }
+
+    fn from_stream(
+        stream: Stream,
+        control: MidiControl,
+        sync: Option<MidiSyncGroup>,
+        cancel: CancelOnDrop,
+        settings: &MidiRenderSettings,
+    ) -> Self {
+        let resampler = settings
+            .output_sample_rate
+            .filter(|&rate| rate > 0 && rate as usize != SAMPLE_RATE)
+            .map(|rate| Resampler::new(settings.resampler, SAMPLE_RATE as u32, rate));
+        let layout = control.channel_layout();
+        let delay = control.take_start_delay().as_secs_f64() * SAMPLE_RATE as f64;
+        Self {
+            sample_rate: SAMPLE_RATE,
+            stream,
+            gain: GainRamp::new(control.clone(), SAMPLE_RATE as u32),
+            control,
+            channel: 0,
+            right: 0.0,
+            sync: sync.map(SyncCursor::new),
+            behind: 0,
+            delay: delay as u64,
+            primed: false,
+            starved: false,
+            dry_frames: 0,
+            meter: LevelMeter::default(),
+            resampler,
+            layout,
+            layout_channel: 0,
+            frame: [0.0; 2],
+            tap_buffer: Vec::with_capacity(TAP_BLOCK),
+            _cancel: cancel,
+        }
+    }
+}
+
+impl SourceProgram {
+    /// Number of frames in the blocks to render this program in
+    fn block_frames(
+        &self,
+        settings: &MidiRenderSettings,
+        control: &MidiControl,
+        sample_rate: usize,
+    ) -> usize {
+        let length = control.block_length().unwrap_or(match self {
+            // Notes are played as soon as they're requested, so longer blocks add latency
+            SourceProgram::Sfx(_) | SourceProgram::Live => LIVE_BLOCK_LENGTH,
+            _ => settings.block_length,
+        });
+        (length.as_secs_f64() * sample_rate as f64).max(1.0) as usize
+    }
+
+    /// Create the renderer playing this program, or report why it can't be played
+    fn renderer(
+        self,
+        soundfont: Option<Arc<SoundFont>>,
+        settings: &MidiRenderSettings,
+        control: &MidiControl,
+    ) -> Result<Box<dyn MidiRender>, MidiError> {
+        let synthesizers = SynthFactory::new(soundfont, settings).with_control(control.clone());
+        let renderer: Box<dyn MidiRender> = match self {
+            SourceProgram::Audio(midi) => {
+                let song = midi.to_song()?;
+                let synthesizer = match midi {
+                    MidiAudio::Sequence(_) | MidiAudio::Timeline(_) | MidiAudio::Score(_) => {
+                        synthesizers.acquire()
+                    }
+                    MidiAudio::File(_) => synthesizers.create(),
+                };
+                Box::new(SongRenderer {
+                    synthesizer,
+                    sequencer: Sequencer::new(Arc::new(song)),
+                })
+            }
+            SourceProgram::Song(song) => Box::new(SongRenderer {
+                synthesizer: synthesizers.create(),
+                sequencer: Sequencer::new(song),
+            }),
+            SourceProgram::Segments(program) => {
+                Box::new(SegmentRenderer::new(&synthesizers, program))
+            }
+            SourceProgram::Layers(program) => Box::new(LayerRenderer::new(&synthesizers, program)),
+            SourceProgram::Metronome(program) => {
+                Box::new(MetronomeRenderer::new(synthesizers.create(), program))
+            }
+            SourceProgram::Sfx(program) => Box::new(SfxRenderer::new(&synthesizers, program)),
+            SourceProgram::Live => Box::new(LiveRenderer::new(synthesizers.create())),
+            #[cfg(feature = "generative")]
+            SourceProgram::Generative(program) => {
+                Box::new(GenerativeRenderer::new(synthesizers.create(), program))
+            }
+        };
+        match synthesizers.take_error() {
+            Some(error) => Err(error),
+            None => Ok(renderer),
+        }
+    }
+}
+
+impl Iterator for MidiFileDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.layout_channel == 0 {
+            self.frame = [self.next_stereo()?, self.next_stereo()?];
+        }
+        let value = self.layout.sample(self.frame, self.layout_channel);
+        self.layout_channel = (self.layout_channel + 1) % self.layout.channels();
+        Some(value)
+    }
+}
+
+impl MidiFileDecoder {
+    /// Render the output in stereo, whatever the source's channel layout
+    #[cfg(feature = "kira")]
+    pub(crate) fn stereo(mut self) -> Self {
+        self.layout = MidiChannelLayout::Stereo;
+        self
+    }
+
+    /// Next stereo sample at the output sample rate
+    fn next_stereo(&mut self) -> Option<f32> {
+        let Some(mut resampler) = self.resampler.take() else {
+            return self.next_rendered();
+        };
+        let value = resampler.next(|| Some([self.next_rendered()?, self.next_rendered()?]));
+        self.resampler = Some(resampler);
+        value
+    }
+
+    /// Next sample at the rate the synthesizers render at
+    fn next_rendered(&mut self) -> Option<f32> {
+        let channel = self.channel as usize;
+        let value = self.next_sample()?;
+        if let Some(levels) = self.meter.push(channel, value) {
+            self.control.set_levels(levels);
+        }
+        if channel == 0 {
+            self.tap_buffer.push(value / 2.0);
+        } else if let Some(left) = self.tap_buffer.last_mut() {
+            *left += value / 2.0;
+            if self.tap_buffer.len() >= TAP_BLOCK {
+                if let Some((tap, capacity)) = self.control.tap() {
+                    tap.push(&self.tap_buffer, capacity);
+                }
+                self.tap_buffer.clear();
+            }
+        }
+        Some(value)
+    }
+
+    fn next_sample(&mut self) -> Option<f32> {
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % 2;
+        if channel == 1 {
+            return Some(std::mem::take(&mut self.right));
+        }
+        let gain = self.gain.next_frame()?;
+        if let Stream::Inline(stream) = &mut self.stream {
+            if self.control.is_seeking() && !stream.restart() {
+                return None;
+            }
+        }
+        let synced = self.sync.as_mut().map_or(true, SyncCursor::advance);
+        let waiting = self.delay > 0;
+        self.delay = self.delay.saturating_sub(1);
+        let seeking = self.control.is_seeking();
+        if seeking {
+            self.primed = false;
+            self.dry_frames = 0;
+            if let Stream::Task(stream) = &mut self.stream {
+                stream.discard_block();
+            }
+        }
+        if !synced || waiting || self.control.is_paused() || seeking {
+            return Some(0.0);
+        }
+        self.control
+            .advance_position(self.control.playback_speed() / self.sample_rate as f64);
+        // Skip frames missed while the stream was empty to stay locked to the sync group
+        while self.behind > 0 {
+            match self.stream.try_recv() {
+                Ok(_) => self.behind -= 1,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => return None,
+            }
+        }
+        match self.stream.try_recv() {
+            Ok([left, right]) => {
+                if self.stream.take_restart() {
+                    self.control.restart_position();
+                }
+                self.primed = true;
+                self.starved = false;
+                self.dry_frames = 0;
+                self.right = right * gain;
+                Some(left * gain)
+            }
+            Err(e) => match e {
+                TryRecvError::Empty => {
+                    // Waiting for the first block after starting or seeking is expected
+                    if self.primed {
+                        let policy = self.control.dry_stream();
+                        if !self.starved && policy != MidiDryStream::Silence {
+                            self.control.record_underrun();
+                        }
+                        self.starved = true;
+                        if let MidiDryStream::EndAfter(limit) = policy {
+                            let limit = limit.as_secs_f64() * self.sample_rate as f64;
+                            if self.dry_frames as f64 >= limit {
+                                return None;
+                            }
+                            self.dry_frames += 1;
+                        }
+                    }
+                    if self.sync.is_some() {
+                        self.behind += 1;
+                    }
+                    Some(0.0)
+                }
+                TryRecvError::Closed => None,
+            },
+        }
+    }
+}
+
+impl Source for MidiFileDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        let channels = self.layout.channels() as usize;
+        let rest_of_frame = (channels - self.layout_channel as usize) % channels;
+        let (buffered, ended) = self.stream.buffered();
+        if ended {
+            return Some(rest_of_frame);
+        }
+        let frames = match &self.resampler {
+            Some(resampler) => (buffered as f64 / 2.0 * resampler.ratio()) as usize,
+            None => buffered / 2,
+        };
+        // The next frame is always played, with silence if nothing was rendered for it
+        Some(rest_of_frame + frames.max(1) * channels)
+    }
+
+    fn channels(&self) -> u16 {
+        self.layout.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        match &self.resampler {
+            Some(resampler) => resampler.sample_rate(),
+            None => self.sample_rate as u32,
+        }
+    }
+
+    fn total_duration(&self) -> Option<std::time::Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_arriving_mid_frame_keep_the_channels() {
+        let (tx, rx) = async_channel::bounded(2);
+        let mut decoder = MidiFileDecoder::from_stream(
+            Stream::Task(TaskStream::new(rx, 1)),
+            MidiControl::default(),
+            None,
+            CancelOnDrop::default(),
+            &MidiRenderSettings::default(),
+        );
+        assert_eq!(decoder.next_sample(), Some(0.0));
+        tx.try_send(RenderedBlock {
+            samples: vec![1.0, -1.0],
+            restart: None,
+        })
+        .unwrap();
+        // The block arrived after the left sample of a silent frame was played
+        assert_eq!(decoder.next_sample(), Some(0.0));
+        assert_eq!(decoder.next_sample(), Some(1.0));
+        assert_eq!(decoder.next_sample(), Some(-1.0));
+    }
+}
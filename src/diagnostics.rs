@@ -0,0 +1,301 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use async_channel::{Receiver, WeakReceiver};
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic},
+    prelude::*,
+};
+
+use crate::{
+    render::RenderedBlock, MidiControl, MidiError, MidiRenderSettings, MidiSourceEviction,
+};
+
+/// Statistics of every live render task
+static SOURCES: Mutex<Vec<Weak<SourceStats>>> = Mutex::new(Vec::new());
+
+/// Time spent rendering since the last measurement, in nanoseconds
+static RENDER_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Frames rendered since the last measurement
+static RENDERED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// Length of the fade out of sources stopped to make room for new ones
+const EVICTION_FADE: Duration = Duration::from_millis(50);
+
+/// Statistics of a single render task, shared with the diagnostics
+#[derive(Debug)]
+pub(crate) struct SourceStats {
+    stream: WeakReceiver<RenderedBlock>,
+    sounding_notes: AtomicUsize,
+    /// Fraction of real time the last block took to render
+    load: AtomicU32,
+    /// Control of a source played in real time, which counts towards
+    /// [`MidiRenderSettings::max_sources`]
+    control: Option<MidiControl>,
+    /// Whether the source was stopped to make room for another
+    evicted: AtomicBool,
+}
+
+impl SourceStats {
+    /// Track the render task feeding `stream`
+    pub(crate) fn register(stream: &Receiver<RenderedBlock>) -> Arc<Self> {
+        Self::track(stream, None)
+    }
+
+    /// Track the render task of a source played in real time, stopping a source if more than
+    /// [`MidiRenderSettings::max_sources`] would play
+    pub(crate) fn register_live(
+        stream: &Receiver<RenderedBlock>,
+        control: &MidiControl,
+        settings: &MidiRenderSettings,
+    ) -> Arc<Self> {
+        let Some(max_sources) = settings.max_sources else {
+            return Self::track(stream, Some(control.clone()));
+        };
+        let mut playing = playing_sources();
+        let stats = Self::track(stream, Some(control.clone()));
+        let excess = (playing.len() + 1).saturating_sub(max_sources);
+        // Sources the new one may replace, in the order they're stopped
+        if settings.eviction == MidiSourceEviction::LowestPriority {
+            playing.retain(|stats| stats.priority() <= control.priority());
+            playing.sort_by_key(|stats| stats.priority());
+        }
+        match settings.eviction {
+            _ if excess == 0 => {}
+            MidiSourceEviction::StopOldest | MidiSourceEviction::LowestPriority
+                if max_sources > 0 && playing.len() >= excess =>
+            {
+                for stats in &playing[..excess] {
+                    stats.evict();
+                }
+                warn!("Stopped a MIDI source to play no more than {max_sources}.");
+            }
+            _ => {
+                stats.evicted.store(true, Ordering::Relaxed);
+                control.fade_out(Duration::ZERO);
+                warn!("Refused to play more than {max_sources} MIDI sources at once.");
+            }
+        }
+        stats
+    }
+
+    fn track(stream: &Receiver<RenderedBlock>, control: Option<MidiControl>) -> Arc<Self> {
+        let stats = Arc::new(Self {
+            stream: stream.downgrade(),
+            sounding_notes: AtomicUsize::new(0),
+            load: AtomicU32::new(0),
+            control,
+            evicted: AtomicBool::new(false),
+        });
+        let mut sources = SOURCES.lock().unwrap();
+        // Forget the sources which have ended, which would otherwise pile up for good
+        sources.retain(|stats| stats.strong_count() > 0);
+        sources.push(Arc::downgrade(&stats));
+        stats
+    }
+
+    /// Whether this is a source played in real time which hasn't been stopped
+    fn is_playing(&self) -> bool {
+        let Some(control) = &self.control else {
+            return false;
+        };
+        !self.evicted.load(Ordering::Relaxed)
+            && !control.is_stopped()
+            && self.stream.upgrade().is_some()
+    }
+
+    /// Priority of the source, with higher priorities stopped last
+    pub(crate) fn priority(&self) -> i32 {
+        self.control.as_ref().map_or(0, MidiControl::priority)
+    }
+
+    /// Number of notes held by the source when its last block was rendered
+    pub(crate) fn sounding_notes(&self) -> usize {
+        self.sounding_notes.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of real time the source's last block took to render
+    pub(crate) fn load(&self) -> f32 {
+        f32::from_bits(self.load.load(Ordering::Relaxed))
+    }
+
+    /// Fade out the source to make room for another
+    pub(crate) fn evict(&self) {
+        if let Some(control) = &self.control {
+            self.evicted.store(true, Ordering::Relaxed);
+            control.fade_out(EVICTION_FADE);
+        }
+    }
+
+    /// Record a block of `frames` which took `time` to render
+    pub(crate) fn rendered(&self, frames: usize, time: Duration, sounding_notes: usize) {
+        RENDER_TIME.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+        RENDERED_FRAMES.fetch_add(frames as u64, Ordering::Relaxed);
+        self.sounding_notes.store(sounding_notes, Ordering::Relaxed);
+        if frames > 0 {
+            let load = time.as_secs_f32() * 44100.0 / frames as f32;
+            self.load.store(load.to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sources played in real time which haven't been stopped, from oldest to newest
+pub(crate) fn playing_sources() -> Vec<Arc<SourceStats>> {
+    SOURCES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .filter(|stats| stats.is_playing())
+        .collect()
+}
+
+/// Plugin registering diagnostics of MIDI playback, which show up in
+/// [`LogDiagnosticsPlugin`](bevy::diagnostic::LogDiagnosticsPlugin) and performance overlays.
+#[derive(Debug, Default)]
+pub struct MidiDiagnosticsPlugin;
+
+impl MidiDiagnosticsPlugin {
+    /// Number of MIDI sources being rendered
+    pub const ACTIVE_SOURCES: DiagnosticPath = DiagnosticPath::const_new("midi/active_sources");
+    /// Number of notes held across every source.
+    ///
+    /// rustysynth does not expose its voice count, so this stands in for the number of active
+    /// synthesizer voices.
+    pub const SOUNDING_NOTES: DiagnosticPath = DiagnosticPath::const_new("midi/sounding_notes");
+    /// Time spent rendering per second of audio produced, in milliseconds
+    pub const RENDER_TIME: DiagnosticPath = DiagnosticPath::const_new("midi/render_time");
+    /// Average fill level of the sources' output buffers, in percent
+    pub const BUFFER_FILL: DiagnosticPath = DiagnosticPath::const_new("midi/buffer_fill");
+}
+
+impl Plugin for MidiDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_diagnostic(Diagnostic::new(Self::ACTIVE_SOURCES))
+            .register_diagnostic(Diagnostic::new(Self::SOUNDING_NOTES))
+            .register_diagnostic(Diagnostic::new(Self::RENDER_TIME).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(Self::BUFFER_FILL).with_suffix("%"))
+            .add_systems(Update, measure_midi_diagnostics);
+    }
+}
+
+fn measure_midi_diagnostics(mut diagnostics: Diagnostics) {
+    let sources: Vec<Arc<SourceStats>> = {
+        let mut sources = SOURCES.lock().unwrap();
+        sources.retain(|stats| stats.strong_count() > 0);
+        sources.iter().filter_map(Weak::upgrade).collect()
+    };
+
+    diagnostics.add_measurement(&MidiDiagnosticsPlugin::ACTIVE_SOURCES, || {
+        sources.len() as f64
+    });
+    diagnostics.add_measurement(&MidiDiagnosticsPlugin::SOUNDING_NOTES, || {
+        sources
+            .iter()
+            .map(|stats| stats.sounding_notes.load(Ordering::Relaxed))
+            .sum::<usize>() as f64
+    });
+
+    let frames = RENDERED_FRAMES.swap(0, Ordering::Relaxed);
+    let time = RENDER_TIME.swap(0, Ordering::Relaxed);
+    if frames > 0 {
+        diagnostics.add_measurement(&MidiDiagnosticsPlugin::RENDER_TIME, || {
+            time as f64 / 1e6 / (frames as f64 / 44100.0)
+        });
+    }
+
+    let fill: Vec<f64> = sources
+        .iter()
+        .filter_map(|stats| stats.stream.upgrade())
+        .filter_map(|stream| Some(stream.len() as f64 / stream.capacity()? as f64))
+        .collect();
+    if !fill.is_empty() {
+        diagnostics.add_measurement(&MidiDiagnosticsPlugin::BUFFER_FILL, || {
+            100.0 * fill.iter().sum::<f64>() / fill.len() as f64
+        });
+    }
+}
+
+/// Event sent when a source carrying a [`MidiControl`] ran out of rendered audio mid-playback,
+/// leaving an audible gap
+#[derive(Event, Clone, Debug)]
+pub struct MidiUnderrunEvent {
+    /// Entity playing the source
+    pub entity: Entity,
+    /// Number of underruns since the last event for this source
+    pub count: u64,
+    /// Total number of underruns of this source
+    pub total: u64,
+}
+
+pub(crate) fn report_underruns(
+    query: Query<(Entity, &MidiControl)>,
+    mut events: EventWriter<MidiUnderrunEvent>,
+) {
+    for (entity, control) in &query {
+        let count = control.take_new_underruns();
+        if count == 0 {
+            continue;
+        }
+        let total = control.underruns();
+        warn!("MIDI source {entity} underran {count} time(s) ({total} in total).");
+        events.send(MidiUnderrunEvent {
+            entity,
+            count,
+            total,
+        });
+    }
+}
+
+/// Event sent when a source carrying a [`MidiControl`] can't be played, such as when its MIDI
+/// file can't be read or its synthesizer can't be created.
+///
+/// The source stays silent and finishes instead of taking down its render task.
+#[derive(Event, Clone, Debug)]
+pub struct MidiPlaybackError {
+    /// Entity playing the source
+    pub entity: Entity,
+    /// What went wrong
+    pub error: Arc<MidiError>,
+}
+
+pub(crate) fn report_playback_errors(
+    query: Query<(Entity, &MidiControl)>,
+    mut events: EventWriter<MidiPlaybackError>,
+) {
+    for (entity, control) in &query {
+        for error in control.take_errors() {
+            events.send(MidiPlaybackError {
+                entity,
+                error: Arc::new(error),
+            });
+        }
+    }
+}
+
+#[cfg(all(test, feature = "hl4mgm"))]
+mod tests {
+    use rustysynth::SoundFont;
+
+    use super::*;
+    use crate::{MidiAudio, MidiFileDecoder, HL4MGM};
+
+    #[test]
+    fn ended_sources_are_forgotten() {
+        let soundfont = Arc::new(SoundFont::new(&mut &HL4MGM[..]).unwrap());
+        for _ in 0..200 {
+            drop(MidiFileDecoder::offline(
+                MidiAudio::Sequence(Vec::new()),
+                soundfont.clone(),
+            ));
+        }
+        // Other tests may be playing a few sources at the same time
+        assert!(SOURCES.lock().unwrap().len() < 100);
+    }
+}
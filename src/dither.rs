@@ -0,0 +1,127 @@
+use bevy::audio::Source;
+
+/// Seed of the noise added by [`dither_to_i16`]
+const DITHER_SEED: u64 = 0x5EED;
+
+/// Adapter turning a source of `f32` samples into 16-bit samples with triangular (TPDF)
+/// dithering, for pipelines which want integer audio.
+///
+/// Dithering adds a tiny amount of noise, which keeps quiet passages and fades from turning into
+/// harmonic distortion when rounded to 16 bits.
+#[derive(Debug)]
+pub struct DitheredI16<S> {
+    source: S,
+    rng: fastrand::Rng,
+}
+
+impl<S> DitheredI16<S> {
+    /// Dither the samples of `source`
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            rng: fastrand::Rng::new(),
+        }
+    }
+
+    /// Dither the samples of `source` with noise from the given seed, so the output is the same
+    /// every time
+    pub fn with_seed(source: S, seed: u64) -> Self {
+        Self {
+            source,
+            rng: fastrand::Rng::with_seed(seed),
+        }
+    }
+
+    /// Source being dithered
+    pub fn inner(&self) -> &S {
+        &self.source
+    }
+
+    /// Take back the source being dithered
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    /// Convert a single sample, adding noise of up to one step of 16-bit audio either way
+    fn dither(&mut self, sample: f32) -> i16 {
+        let noise = self.rng.f32() - self.rng.f32();
+        let scaled = sample * i16::MAX as f32 + noise;
+        scaled.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+}
+
+impl<S: Iterator<Item = f32>> Iterator for DitheredI16<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.source.next()?;
+        Some(self.dither(sample))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.source.size_hint()
+    }
+}
+
+impl<S: Source<Item = f32>> Source for DitheredI16<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<std::time::Duration> {
+        self.source.total_duration()
+    }
+}
+
+/// Convert samples to 16-bit audio with TPDF dithering, such as the output of a
+/// [`HeadlessMidiOutput`](crate::HeadlessMidiOutput).
+///
+/// The noise always starts from the same seed, so the same samples give the same output.
+pub fn dither_to_i16(samples: &[f32]) -> Vec<i16> {
+    DitheredI16::with_seed(samples.iter().copied(), DITHER_SEED).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Quiet signal, which dithering turns into more than the few values rounding would
+    fn ramp() -> impl Iterator<Item = f32> {
+        (0..1000).map(|index| index as f32 * 1e-7)
+    }
+
+    #[test]
+    fn seeded_dither_is_reproducible() {
+        let first: Vec<_> = DitheredI16::with_seed(ramp(), 7).collect();
+        let second: Vec<_> = DitheredI16::with_seed(ramp(), 7).collect();
+        assert_eq!(first, second);
+        let other: Vec<_> = DitheredI16::with_seed(ramp(), 8).collect();
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn dither_to_i16_uses_a_fixed_seed() {
+        let samples: Vec<_> = ramp().collect();
+        assert_eq!(dither_to_i16(&samples), dither_to_i16(&samples));
+    }
+
+    #[test]
+    fn noise_stays_within_a_step() {
+        let samples = [0.0, 0.25, -0.5, 1.0, -1.0];
+        for (sample, dithered) in samples.iter().zip(dither_to_i16(&samples)) {
+            let exact = sample * i16::MAX as f32;
+            assert!(
+                (dithered as f32 - exact).abs() <= 1.5,
+                "{dithered} != {exact}"
+            );
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::MidiControl;
+
+/// Component choosing how a MIDI source plays channel 10, which General MIDI reserves for
+/// percussion.
+///
+/// Channels are counted from 0 in the API, so channel 10 is channel 9. Changing it while notes
+/// of the channel are held releases them.
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiDrumChannel {
+    /// Play channel 10 with the soundfont's percussion kits, following General MIDI
+    #[default]
+    Percussion,
+    /// Play channel 10 with the soundfont's melodic presets like any other channel, for
+    /// soundfonts without percussion kits or music which doesn't follow General MIDI
+    Melodic,
+    /// Play the messages of channel 10 on the given channel instead, counting from 0
+    Remap(u8),
+    /// Don't play the notes of channel 10
+    Mute,
+}
+
+/// Sources whose drum channel handling changed, or which can only now be controlled
+type ChangedDrumChannelFilter = Or<(Changed<MidiDrumChannel>, Added<MidiControl>)>;
+
+pub(crate) fn apply_drum_channels(
+    query: Query<(&MidiControl, &MidiDrumChannel), ChangedDrumChannelFilter>,
+) {
+    for (control, drums) in &query {
+        control.set_drum_channel(*drums);
+    }
+}
@@ -0,0 +1,39 @@
+use std::io;
+
+use thiserror::Error;
+
+/// Errors reported by the plugin, grouped by what failed so apps can explain them to players
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum MidiError {
+    /// A soundfont couldn't be read or parsed
+    #[error("failed to load soundfont: {0}")]
+    SoundFont(#[source] io::Error),
+    /// MIDI data couldn't be parsed
+    #[error("failed to parse MIDI data: {0}")]
+    MidiParse(#[source] io::Error),
+    /// A synthesizer couldn't be created with the render settings
+    #[error("failed to create synthesizer: {0}")]
+    SynthInit(#[source] io::Error),
+    /// An audio device couldn't be opened or stopped working, for backends which drive one
+    #[error("audio device error: {0}")]
+    Device(#[source] io::Error),
+    /// A chord symbol, Roman numeral or key couldn't be understood
+    #[error("invalid chord: {0}")]
+    Chord(String),
+    /// Reading or writing a file or other data failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl MidiError {
+    /// Wrap an error from reading MIDI data
+    pub(crate) fn parse(error: io::Error) -> Self {
+        Self::MidiParse(error)
+    }
+
+    /// Wrap an error from reading a soundfont
+    pub(crate) fn soundfont(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self::SoundFont(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
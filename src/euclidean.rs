@@ -0,0 +1,221 @@
+use crate::{MidiAudio, MidiNote, ScoreEvent};
+
+/// Which of `steps` steps are hit when `hits` hits are spread as evenly as possible over them,
+/// starting with a hit and then moved `rotation` steps later
+pub fn euclidean_pattern(hits: usize, steps: usize, rotation: usize) -> Vec<bool> {
+    let hits = hits.min(steps);
+    (0..steps)
+        .map(|step| {
+            let step = (step + steps - rotation % steps.max(1)) % steps;
+            step * hits % steps < hits
+        })
+        .collect()
+}
+
+/// A percussion sound repeating a Euclidean rhythm, spreading its hits as evenly as possible over
+/// its steps, such as 3 hits over 8 steps for a tresillo
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MidiEuclideanRhythm {
+    /// Number of hits in each cycle
+    pub hits: usize,
+    /// Number of steps in each cycle
+    pub steps: usize,
+    /// Number of steps the hits are moved later by
+    pub rotation: usize,
+    /// Percussion key played on each hit, such as 36 for a kick drum
+    pub key: i32,
+    /// Velocity of the hits
+    pub velocity: i32,
+}
+
+impl MidiEuclideanRhythm {
+    /// Spread `hits` kick drum hits over `steps` steps
+    pub fn new(hits: usize, steps: usize) -> Self {
+        Self {
+            hits,
+            steps,
+            rotation: 0,
+            key: 36,
+            velocity: 100,
+        }
+    }
+
+    /// Move the hits the given number of steps later
+    pub fn with_rotation(mut self, steps: usize) -> Self {
+        self.rotation = steps;
+        self
+    }
+
+    /// Play the given percussion key on each hit
+    pub fn with_key(mut self, key: i32) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Hit with the given velocity
+    pub fn with_velocity(mut self, velocity: i32) -> Self {
+        self.velocity = velocity;
+        self
+    }
+
+    /// Which steps of a cycle are hit
+    pub fn pattern(&self) -> Vec<bool> {
+        euclidean_pattern(self.hits, self.steps, self.rotation)
+    }
+}
+
+/// Percussion made of layered [`MidiEuclideanRhythm`]s, expanded into a playable
+/// [`MidiAudio::Score`] on the General MIDI percussion channel.
+///
+/// Rhythms with different numbers of steps cycle independently, so the pattern only repeats once
+/// every rhythm has finished a whole number of cycles.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiEuclideanDrums {
+    /// Rhythms played together
+    pub rhythms: Vec<MidiEuclideanRhythm>,
+    /// Length of a step in beats, where a beat is a quarter note, such as `0.25` for sixteenth
+    /// notes
+    pub step: f64,
+    /// Tempo in quarter notes per minute
+    pub bpm: f64,
+    /// Number of times the whole pattern is played
+    pub repeats: usize,
+    /// Channel the rhythms are played on
+    pub channel: i32,
+}
+
+impl Default for MidiEuclideanDrums {
+    fn default() -> Self {
+        Self {
+            rhythms: Vec::new(),
+            step: 0.25,
+            bpm: 120.0,
+            repeats: 1,
+            channel: 9,
+        }
+    }
+}
+
+impl MidiEuclideanDrums {
+    /// Play the given rhythms together in sixteenth notes at 120 BPM
+    pub fn new(rhythms: impl Into<Vec<MidiEuclideanRhythm>>) -> Self {
+        Self {
+            rhythms: rhythms.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Add a rhythm played together with the others
+    pub fn with_rhythm(mut self, rhythm: MidiEuclideanRhythm) -> Self {
+        self.rhythms.push(rhythm);
+        self
+    }
+
+    /// Use steps of the given number of beats
+    pub fn with_step(mut self, beats: f64) -> Self {
+        self.step = beats;
+        self
+    }
+
+    /// Play at the given quarter notes per minute
+    pub fn with_bpm(mut self, bpm: f64) -> Self {
+        self.bpm = bpm;
+        self
+    }
+
+    /// Play the whole pattern the given number of times
+    pub fn with_repeats(mut self, repeats: usize) -> Self {
+        self.repeats = repeats;
+        self
+    }
+
+    /// Number of steps before the pattern repeats
+    pub fn cycle_steps(&self) -> usize {
+        fn gcd(a: usize, b: usize) -> usize {
+            if b == 0 {
+                a
+            } else {
+                gcd(b, a % b)
+            }
+        }
+        self.rhythms
+            .iter()
+            .filter(|rhythm| rhythm.steps > 0)
+            .fold(0, |cycle, rhythm| match cycle {
+                0 => rhythm.steps,
+                cycle => cycle / gcd(cycle, rhythm.steps) * rhythm.steps,
+            })
+    }
+
+    /// Events of a score playing the pattern
+    pub fn to_score(&self) -> Vec<ScoreEvent> {
+        let patterns: Vec<(&MidiEuclideanRhythm, Vec<bool>)> = self
+            .rhythms
+            .iter()
+            .filter(|rhythm| rhythm.steps > 0)
+            .map(|rhythm| (rhythm, rhythm.pattern()))
+            .collect();
+        let mut events = vec![ScoreEvent::Tempo(self.bpm)];
+        for step in 0..self.cycle_steps() * self.repeats {
+            let mut notes: Vec<MidiNote> = patterns
+                .iter()
+                .filter(|(_, pattern)| pattern[step % pattern.len()])
+                .map(|(rhythm, _)| MidiNote {
+                    channel: self.channel,
+                    key: rhythm.key,
+                    velocity: rhythm.velocity,
+                    ..Default::default()
+                })
+                .collect();
+            events.push(match notes.len() {
+                0 => ScoreEvent::Rest(self.step),
+                1 => ScoreEvent::Note {
+                    note: notes.remove(0),
+                    beats: self.step,
+                },
+                _ => ScoreEvent::Chord {
+                    notes,
+                    beats: self.step,
+                },
+            });
+        }
+        events
+    }
+
+    /// Audio playing the pattern
+    pub fn to_audio(&self) -> MidiAudio {
+        MidiAudio::Score(self.to_score())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(hits: usize, steps: usize, rotation: usize) -> String {
+        euclidean_pattern(hits, steps, rotation)
+            .into_iter()
+            .map(|hit| if hit { 'x' } else { '.' })
+            .collect()
+    }
+
+    #[test]
+    fn spreads_hits_evenly() {
+        assert_eq!(pattern(3, 8, 0), "x..x..x.");
+        assert_eq!(pattern(5, 8, 0), "x.x.xx.x");
+        assert_eq!(pattern(4, 16, 0), "x...x...x...x...");
+    }
+
+    #[test]
+    fn rotation_moves_hits_later() {
+        assert_eq!(pattern(3, 8, 1), ".x..x..x");
+        assert_eq!(pattern(3, 8, 9), pattern(3, 8, 1));
+    }
+
+    #[test]
+    fn degenerate_patterns() {
+        assert_eq!(pattern(0, 4, 0), "....");
+        assert_eq!(pattern(6, 4, 0), "xxxx");
+        assert_eq!(pattern(3, 0, 2), "");
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Cursor, Seek, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use bevy::{
+    asset::{
+        io::Writer,
+        saver::{AssetSaver, SavedAsset},
+        AsyncWriteExt,
+    },
+    audio::AudioLoader,
+};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rustysynth::SoundFont;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    decoder::SourceProgram, sampler::wav_error, settings::render_settings, MidiAudio, MidiControl,
+    MidiError, MidiFileDecoder,
+};
+
+/// File format rendered audio is exported in
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiExportFormat {
+    /// 32-bit float WAV
+    #[default]
+    Wav,
+    /// Lossless 16-bit FLAC, dithered from the rendered audio
+    #[cfg(feature = "flac")]
+    Flac,
+    /// Lossy Ogg Vorbis, for music shipped with a game
+    #[cfg(feature = "ogg")]
+    Ogg,
+}
+
+impl MidiExportFormat {
+    /// Extension of files in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MidiExportFormat::Wav => "wav",
+            #[cfg(feature = "flac")]
+            MidiExportFormat::Flac => "flac",
+            #[cfg(feature = "ogg")]
+            MidiExportFormat::Ogg => "ogg",
+        }
+    }
+}
+
+impl MidiAudio {
+    /// Render the whole piece with the plugin's
+    /// [`MidiRenderSettings`](crate::MidiRenderSettings) and save it to `path`, such as to
+    /// ship pre-baked music from a bake step
+    pub fn export(
+        &self,
+        soundfont: Arc<SoundFont>,
+        format: MidiExportFormat,
+        path: impl AsRef<Path>,
+    ) -> Result<(), MidiError> {
+        let (samples, sample_rate) = self.render(Some(soundfont));
+        write_audio(path, &samples, 2, sample_rate, format)
+    }
+
+    /// Render the whole piece in stereo, returning its samples and sample rate
+    pub(crate) fn render(&self, soundfont: Option<Arc<SoundFont>>) -> (Vec<f32>, u32) {
+        let decoder = MidiFileDecoder::offline_program(
+            SourceProgram::Audio(self.clone()),
+            soundfont,
+            MidiControl::default(),
+            None,
+            &render_settings(),
+        );
+        let sample_rate = bevy::audio::Source::sample_rate(&decoder);
+        (decoder.collect(), sample_rate)
+    }
+}
+
+/// Save interleaved samples to `path` in the given format
+pub fn write_audio(
+    path: impl AsRef<Path>,
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    format: MidiExportFormat,
+) -> Result<(), MidiError> {
+    let file = BufWriter::new(File::create(path)?);
+    Ok(encode_audio(file, samples, channels, sample_rate, format)?)
+}
+
+/// Encode interleaved samples in the given format
+fn encode_audio(
+    writer: impl Write + Seek,
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    format: MidiExportFormat,
+) -> io::Result<()> {
+    match format {
+        MidiExportFormat::Wav => {
+            let spec = WavSpec {
+                channels,
+                sample_rate,
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+            };
+            let mut writer = WavWriter::new(writer, spec).map_err(wav_error)?;
+            for &sample in samples {
+                writer.write_sample(sample).map_err(wav_error)?;
+            }
+            writer.finalize().map_err(wav_error)
+        }
+        #[cfg(feature = "flac")]
+        MidiExportFormat::Flac => crate::flac::write_flac(
+            writer,
+            &crate::dither_to_i16(samples),
+            channels,
+            sample_rate,
+        ),
+        #[cfg(feature = "ogg")]
+        MidiExportFormat::Ogg => write_ogg(writer, samples, channels, sample_rate),
+    }
+}
+
+/// Frames passed to the Vorbis encoder at a time
+#[cfg(feature = "ogg")]
+const OGG_BLOCK_SIZE: usize = 1024;
+
+/// Serial number of exported Ogg streams, fixed so exports are reproducible
+#[cfg(feature = "ogg")]
+const OGG_STREAM_SERIAL: i32 = 0x4D494449;
+
+/// Encode interleaved samples as Ogg Vorbis at the encoder's default quality
+#[cfg(feature = "ogg")]
+fn write_ogg(
+    writer: impl Write,
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+) -> io::Result<()> {
+    use std::num::{NonZeroU32, NonZeroU8};
+    use vorbis_rs::VorbisEncoderBuilder;
+
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let channel_count = u8::try_from(channels)
+        .ok()
+        .and_then(NonZeroU8::new)
+        .ok_or_else(|| invalid("Ogg Vorbis takes 1 to 255 channels."))?;
+    let sample_rate =
+        NonZeroU32::new(sample_rate).ok_or_else(|| invalid("Sample rate must not be zero."))?;
+    let mut encoder = VorbisEncoderBuilder::new_with_serial(
+        sample_rate,
+        channel_count,
+        writer,
+        OGG_STREAM_SERIAL,
+    )
+    .build()
+    .map_err(vorbis_error)?;
+    let channels = channels as usize;
+    let mut block = vec![Vec::with_capacity(OGG_BLOCK_SIZE); channels];
+    for frames in samples.chunks(OGG_BLOCK_SIZE * channels) {
+        for (index, channel) in block.iter_mut().enumerate() {
+            channel.clear();
+            channel.extend(frames.iter().skip(index).step_by(channels));
+        }
+        encoder.encode_audio_block(&block).map_err(vorbis_error)?;
+    }
+    encoder.finish().map_err(vorbis_error)?;
+    Ok(())
+}
+
+#[cfg(feature = "ogg")]
+fn vorbis_error(error: vorbis_rs::VorbisError) -> io::Error {
+    match error {
+        vorbis_rs::VorbisError::Io(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidData, error),
+    }
+}
+
+/// Settings for baking MIDI audio with the [`MidiAudioSaver`]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MidiAudioSaverSettings {
+    /// Format of the baked audio
+    pub format: MidiExportFormat,
+}
+
+/// Asset saver baking [`MidiAudio`] into rendered audio, loaded back as bevy's
+/// [`AudioSource`](bevy::audio::AudioSource).
+///
+/// It renders with the plugin's soundfont and [`MidiRenderSettings`](crate::MidiRenderSettings),
+/// so processed builds ship rendered music instead of synthesizing it while playing. Playing
+/// the baked audio needs bevy's audio feature for its format, such as `wav`, `flac` or `vorbis`.
+#[derive(Default, Debug)]
+pub struct MidiAudioSaver;
+
+impl AssetSaver for MidiAudioSaver {
+    type Asset = MidiAudio;
+
+    type Settings = MidiAudioSaverSettings;
+
+    type OutputLoader = AudioLoader;
+
+    type Error = MidiError;
+
+    async fn save<'a>(
+        &'a self,
+        writer: &'a mut Writer,
+        asset: SavedAsset<'a, Self::Asset>,
+        settings: &'a Self::Settings,
+    ) -> Result<(), Self::Error> {
+        let (samples, sample_rate) = asset.render(crate::soundfont().await);
+        let mut bytes = Cursor::new(Vec::new());
+        encode_audio(&mut bytes, &samples, 2, sample_rate, settings.format)?;
+        Ok(writer.write_all(bytes.get_ref()).await?)
+    }
+}
+
+/// Asset processor baking MIDI files into rendered audio.
+///
+/// Set it as the processor of some files in their `.meta` files, or of every MIDI file with
+/// [`AssetApp::set_default_asset_processor`](bevy::asset::AssetApp::set_default_asset_processor).
+pub type MidiBakeProcessor =
+    bevy::asset::processor::LoadAndSave<crate::MidiAssetLoader, MidiAudioSaver>;
+
+#[cfg(all(test, feature = "ogg"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ogg_round_trips_a_tone() {
+        let samples: Vec<f32> = (0..44100)
+            .flat_map(|frame| {
+                let phase = frame as f32 * 440.0 / 44100.0 * std::f32::consts::TAU;
+                [phase.sin() * 0.5, phase.cos() * 0.25]
+            })
+            .collect();
+        let mut bytes = Cursor::new(Vec::new());
+        encode_audio(&mut bytes, &samples, 2, 44100, MidiExportFormat::Ogg).unwrap();
+        bytes.set_position(0);
+        let mut decoder = vorbis_rs::VorbisDecoder::<Cursor<Vec<u8>>>::new(bytes).unwrap();
+        assert_eq!(decoder.channels().get(), 2);
+        assert_eq!(decoder.sampling_frequency().get(), 44100);
+        let mut decoded = vec![Vec::new(); 2];
+        while let Some(block) = decoder.decode_audio_block().unwrap() {
+            for (channel, samples) in decoded.iter_mut().zip(block.samples()) {
+                channel.extend_from_slice(samples);
+            }
+        }
+        assert_eq!(decoded[0].len(), 44100);
+        // Lossy, but close to the input
+        let error = decoded[0]
+            .iter()
+            .zip(samples.iter().step_by(2))
+            .map(|(decoded, sample)| (decoded - sample).abs())
+            .fold(0.0, f32::max);
+        assert!(error < 0.05, "{error}");
+    }
+}
@@ -0,0 +1,329 @@
+use std::io::{self, Write};
+
+/// Number of frames in each FLAC frame
+const BLOCK_SIZE: usize = 4096;
+
+/// Highest fixed predictor order tried for each subframe
+const MAX_ORDER: usize = 4;
+
+/// Highest partition order tried for the residual of each subframe
+const MAX_PARTITION_ORDER: u32 = 6;
+
+/// Highest Rice parameter which can be stored with 4 bits
+const MAX_RICE_PARAMETER: u32 = 14;
+
+/// Write interleaved 16-bit samples as a FLAC file.
+///
+/// Each subframe uses whichever fixed predictor gives the smallest output, or a constant for
+/// silence, which keeps stems with long silent stretches small.
+pub(crate) fn write_flac(
+    mut writer: impl Write,
+    samples: &[i16],
+    channels: u16,
+    sample_rate: u32,
+) -> io::Result<()> {
+    let channels = channels as usize;
+    let total_frames = samples.len() / channels;
+    let mut header = BitWriter::default();
+    header.bytes.extend_from_slice(b"fLaC");
+    // Last metadata block, of type STREAMINFO
+    header.write(1, 1);
+    header.write(0, 7);
+    header.write(34, 24);
+    header.write(BLOCK_SIZE as u64, 16);
+    header.write(BLOCK_SIZE as u64, 16);
+    // Unknown minimum and maximum frame sizes
+    header.write(0, 24);
+    header.write(0, 24);
+    header.write(sample_rate as u64, 20);
+    header.write(channels as u64 - 1, 3);
+    header.write(15, 5);
+    header.write(total_frames as u64, 36);
+    // Unknown MD5 signature
+    header.write(0, 64);
+    header.write(0, 64);
+    writer.write_all(&header.bytes)?;
+
+    let mut channel = Vec::with_capacity(BLOCK_SIZE);
+    for (number, block) in samples.chunks(BLOCK_SIZE * channels).enumerate() {
+        let frames = block.len() / channels;
+        if frames == 0 {
+            break;
+        }
+        let mut frame = BitWriter::default();
+        frame.write(0b11111111111110, 14);
+        // Reserved bit and fixed block size
+        frame.write(0, 2);
+        // Block size stored at the end of the header, sample rate from STREAMINFO
+        frame.write(0b0111, 4);
+        frame.write(0, 4);
+        frame.write(channels as u64 - 1, 4);
+        // 16 bits per sample, then a reserved bit
+        frame.write(0b100, 3);
+        frame.write(0, 1);
+        frame.write_utf8(number as u64);
+        frame.write(frames as u64 - 1, 16);
+        let crc = crc8(&frame.bytes);
+        frame.write(crc as u64, 8);
+        for index in 0..channels {
+            channel.clear();
+            channel.extend(
+                block
+                    .iter()
+                    .skip(index)
+                    .step_by(channels)
+                    .map(|&sample| sample as i32),
+            );
+            write_subframe(&mut frame, &channel);
+        }
+        frame.align();
+        let crc = crc16(&frame.bytes);
+        frame.write(crc as u64, 16);
+        writer.write_all(&frame.bytes)?;
+    }
+    Ok(())
+}
+
+/// Write the smallest encoding of one channel of a frame
+fn write_subframe(output: &mut BitWriter, samples: &[i32]) {
+    if samples.iter().all(|&sample| sample == samples[0]) {
+        output.write(0, 8);
+        output.write_signed(samples[0], 16);
+        return;
+    }
+    let mut best: Option<(u64, usize, Vec<i32>)> = None;
+    for order in 0..=MAX_ORDER.min(samples.len() - 1) {
+        let residual = fixed_residual(samples, order);
+        let (bits, _) = residual_encoding(&residual, samples.len(), order);
+        let bits = bits + order as u64 * 16;
+        if best.as_ref().map_or(true, |(best, _, _)| bits < *best) {
+            best = Some((bits, order, residual));
+        }
+    }
+    let (bits, order, residual) = best.unwrap();
+    if bits >= samples.len() as u64 * 16 {
+        output.write(0b00000010, 8);
+        for &sample in samples {
+            output.write_signed(sample, 16);
+        }
+        return;
+    }
+    output.write(0b00010000 | (order as u64) << 1, 8);
+    for &sample in &samples[..order] {
+        output.write_signed(sample, 16);
+    }
+    let (_, (partition_order, parameters)) = residual_encoding(&residual, samples.len(), order);
+    output.write(0, 2);
+    output.write(partition_order as u64, 4);
+    let partition_size = samples.len() >> partition_order;
+    let mut residual = residual.iter();
+    for (index, &parameter) in parameters.iter().enumerate() {
+        output.write(parameter as u64, 4);
+        let count = partition_size - if index == 0 { order } else { 0 };
+        for &value in residual.by_ref().take(count) {
+            output.write_rice(value, parameter);
+        }
+    }
+}
+
+/// Residual of a fixed polynomial predictor of the given order, after its warm-up samples
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i32> {
+    (order..samples.len())
+        .map(|index| {
+            let s = |back: usize| samples[index - back];
+            s(0) - match order {
+                0 => 0,
+                1 => s(1),
+                2 => 2 * s(1) - s(2),
+                3 => 3 * s(1) - 3 * s(2) + s(3),
+                _ => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4),
+            }
+        })
+        .collect()
+}
+
+/// Size in bits of the smallest Rice coding of a residual, with its partition order and the
+/// parameter of each partition
+fn residual_encoding(residual: &[i32], block: usize, order: usize) -> (u64, (u32, Vec<u32>)) {
+    let mut best: Option<(u64, (u32, Vec<u32>))> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1 << partition_order;
+        if block % partitions != 0 || block / partitions <= order {
+            break;
+        }
+        let partition_size = block / partitions;
+        let mut bits = 6;
+        let mut parameters = Vec::with_capacity(partitions);
+        let mut start = 0;
+        for index in 0..partitions {
+            let count = partition_size - if index == 0 { order } else { 0 };
+            let partition = &residual[start..start + count];
+            start += count;
+            let (size, parameter) = (0..=MAX_RICE_PARAMETER)
+                .map(|parameter| (rice_size(partition, parameter), parameter))
+                .min()
+                .unwrap();
+            bits += 4 + size;
+            parameters.push(parameter);
+        }
+        if best.as_ref().map_or(true, |(best, _)| bits < *best) {
+            best = Some((bits, (partition_order, parameters)));
+        }
+    }
+    best.unwrap()
+}
+
+/// Size in bits of values coded with the given Rice parameter
+fn rice_size(values: &[i32], parameter: u32) -> u64 {
+    values
+        .iter()
+        .map(|&value| (zigzag(value) >> parameter) as u64 + 1 + parameter as u64)
+        .sum()
+}
+
+/// Interleave negative and positive values so small magnitudes become small numbers
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+/// Big-endian writer of individual bits
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits of the last byte in use, from 0 to 7
+    used: u32,
+}
+
+impl BitWriter {
+    /// Write the lowest `count` bits of `value`
+    fn write(&mut self, value: u64, count: u32) {
+        for bit in (0..count).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let byte = self.bytes.last_mut().unwrap();
+            *byte |= (((value >> bit) & 1) as u8) << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    fn write_signed(&mut self, value: i32, count: u32) {
+        self.write(value as u64 & ((1 << count) - 1), count);
+    }
+
+    fn write_rice(&mut self, value: i32, parameter: u32) {
+        let value = zigzag(value);
+        let quotient = value >> parameter;
+        for _ in 0..quotient {
+            self.write(0, 1);
+        }
+        self.write(1, 1);
+        self.write(value as u64, parameter);
+    }
+
+    /// Write a number with the UTF-8 like coding of frame numbers
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+        let extra = (1..6)
+            .find(|&extra| value < 1 << (6 - extra + 6 * extra))
+            .unwrap_or(6);
+        let prefix = (0xFF_u64 << (7 - extra)) & 0xFF;
+        self.write(prefix | (value >> (6 * extra)), 8);
+        for index in (0..extra).rev() {
+            self.write(0x80 | ((value >> (6 * index)) & 0x3F), 8);
+        }
+    }
+
+    /// Pad the last byte with zeros
+    fn align(&mut self) {
+        self.used = 0;
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(samples: &[i16], channels: u16) {
+        let mut bytes = Vec::new();
+        write_flac(&mut bytes, samples, channels, 44100).unwrap();
+        let mut reader = claxon::FlacReader::new(bytes.as_slice()).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.channels, channels as u32);
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.bits_per_sample, 16);
+        assert_eq!(
+            info.samples,
+            Some((samples.len() / channels as usize) as u64)
+        );
+        // claxon checks the CRC of every frame it decodes
+        let decoded: Vec<i16> = reader
+            .samples()
+            .map(|sample| sample.unwrap() as i16)
+            .collect();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn round_trips_silence_and_tones() {
+        let mut samples = vec![0; BLOCK_SIZE * 2];
+        samples.extend((0..BLOCK_SIZE * 3 + 100).flat_map(|frame| {
+            let phase = frame as f32 * 440.0 / 44100.0 * std::f32::consts::TAU;
+            [
+                (phase.sin() * 12000.0) as i16,
+                (phase.cos() * 3000.0) as i16,
+            ]
+        }));
+        round_trip(&samples, 2);
+    }
+
+    #[test]
+    fn round_trips_noise_and_extremes() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let mut samples: Vec<i16> = (0..5000).map(|_| rng.i16(..)).collect();
+        samples.extend([i16::MIN, i16::MAX, i16::MIN, i16::MAX, 7, 7, 7]);
+        round_trip(&samples, 1);
+    }
+
+    #[test]
+    fn round_trips_many_frames() {
+        // Frame numbers past 127 take several bytes in frame headers
+        let samples: Vec<i16> = (0..BLOCK_SIZE * 130)
+            .map(|i| (i % 300) as i16 - 150)
+            .collect();
+        round_trip(&samples, 1);
+    }
+
+    #[test]
+    fn crcs_match_reference_values() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    control::{prepare_controlled_sources, QueuedFilter, SourceOptions},
+    decoder::SourceProgram,
+    midi::{Smf, Song},
+    sequencer::{MidiRender, Sequencer},
+    MidiAudio, MidiError, MidiNote, MidiSource, ScoreEvent, SynthBackend,
+};
+
+/// Source of endless music, played a phrase at a time by a [`MidiGenerativePlayer`]
+pub trait MidiGenerator: Send + Sync + 'static {
+    /// Compose the next phrase, where a beat is a quarter note. An empty phrase ends playback.
+    fn next_phrase(&mut self) -> Vec<ScoreEvent>;
+}
+
+/// Creates a fresh generator for each playback, so seeded generators repeat their music
+type GeneratorFactory = Arc<dyn Fn() -> Box<dyn MidiGenerator> + Send + Sync>;
+
+/// Plugin playing [`MidiGenerativePlayer`]s, added alongside the
+/// [`RustySynthPlugin`](crate::RustySynthPlugin)
+pub struct MidiGenerativePlugin;
+
+impl Plugin for MidiGenerativePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PostUpdate,
+            prepare_generative_players.before(prepare_controlled_sources),
+        );
+    }
+}
+
+/// Component playing the endless music of a [`MidiGenerator`].
+///
+/// Spawn it together with [`PlaybackSettings`]. Each playback starts from a clone of the
+/// generator, so a seeded generator plays the same music every time, and seeking replays its
+/// phrases up to the new position.
+#[derive(Component, Clone)]
+pub struct MidiGenerativePlayer {
+    generator: GeneratorFactory,
+}
+
+impl MidiGenerativePlayer {
+    /// Play the music of the given generator
+    pub fn new<G: MidiGenerator + Clone>(generator: G) -> Self {
+        Self {
+            generator: Arc::new(move || Box::new(generator.clone())),
+        }
+    }
+}
+
+impl fmt::Debug for MidiGenerativePlayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MidiGenerativePlayer")
+            .finish_non_exhaustive()
+    }
+}
+
+/// Generator of a [`MidiGenerativePlayer`], ready for rendering
+pub(crate) struct GenerativeProgram {
+    generator: GeneratorFactory,
+}
+
+impl fmt::Debug for GenerativeProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GenerativeProgram").finish_non_exhaustive()
+    }
+}
+
+pub(crate) struct GenerativeRenderer {
+    synthesizer: Box<dyn SynthBackend>,
+    program: Arc<GenerativeProgram>,
+    generator: Box<dyn MidiGenerator>,
+    /// Sequencer playing the current phrase
+    sequencer: Option<Sequencer>,
+    speed: f64,
+}
+
+impl GenerativeRenderer {
+    pub(crate) fn new(synthesizer: Box<dyn SynthBackend>, program: Arc<GenerativeProgram>) -> Self {
+        Self {
+            synthesizer,
+            generator: (program.generator)(),
+            program,
+            sequencer: None,
+            speed: 1.0,
+        }
+    }
+
+    /// Compose the next phrase, or `None` once the generator has finished
+    fn next_song(&mut self) -> Option<Arc<Song>> {
+        let phrase = self.generator.next_phrase();
+        if phrase.is_empty() {
+            return None;
+        }
+        Some(Arc::new(Song::from_smf(&Smf::from_score(&phrase), None)))
+    }
+}
+
+impl MidiRender for GenerativeRenderer {
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        let mut wrote = 0;
+        while wrote < left.len() {
+            let fresh = self.sequencer.is_none();
+            if fresh {
+                let Some(song) = self.next_song() else {
+                    break;
+                };
+                let mut sequencer = Sequencer::new(song);
+                sequencer.set_speed(self.speed);
+                self.sequencer = Some(sequencer);
+            }
+            let sequencer = self.sequencer.as_mut().unwrap();
+            let len = sequencer.render(
+                &mut *self.synthesizer,
+                &mut left[wrote..],
+                &mut right[wrote..],
+            );
+            wrote += len;
+            if wrote < left.len() {
+                self.sequencer = None;
+                // A phrase without any length would never finish
+                if fresh && len == 0 {
+                    break;
+                }
+            }
+        }
+        wrote
+    }
+
+    fn seek(&mut self, position: f64) {
+        self.synthesizer.reset();
+        self.generator = (self.program.generator)();
+        self.sequencer = None;
+        let mut start = 0.0;
+        while let Some(song) = self.next_song() {
+            if song.length <= 0.0 {
+                break;
+            }
+            if start + song.length > position {
+                let mut sequencer = Sequencer::new(song);
+                sequencer.seek(&mut *self.synthesizer, position - start);
+                sequencer.set_speed(self.speed);
+                self.sequencer = Some(sequencer);
+                break;
+            }
+            start += song.length;
+        }
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.max(0.0);
+        if let Some(sequencer) = &mut self.sequencer {
+            sequencer.set_speed(speed);
+        }
+    }
+
+    fn sounding_notes(&self) -> usize {
+        self.sequencer
+            .as_ref()
+            .map_or(0, |sequencer| sequencer.sounding_notes())
+    }
+
+    fn render_tail(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.synthesizer.render(left, right);
+    }
+}
+
+type QueuedGenerativeFilter = (QueuedFilter, Without<Handle<MidiSource>>);
+
+pub(crate) fn prepare_generative_players(
+    mut commands: Commands,
+    mut sources: ResMut<Assets<MidiSource>>,
+    query: Query<(Entity, &MidiGenerativePlayer, SourceOptions), QueuedGenerativeFilter>,
+) {
+    for (entity, player, (control, sync)) in &query {
+        let control = control.cloned().unwrap_or_default();
+        let source = sources.add(MidiSource {
+            program: SourceProgram::Generative(Arc::new(GenerativeProgram {
+                generator: player.generator.clone(),
+            })),
+            control: control.clone(),
+            sync: sync.cloned(),
+        });
+        commands.entity(entity).insert((source, control));
+    }
+}
+
+/// Number of steps a beat is divided into when learning rhythms
+const STEPS_PER_BEAT: f64 = 8.0;
+
+/// Onsets closer together than this many beats are learned as a single chord
+const CHORD_WINDOW: f64 = 1.0 / 32.0;
+
+/// Keys struck together, how many steps they sound for, and how many steps of silence follow
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct MarkovToken {
+    keys: Vec<u8>,
+    sound: u16,
+    rest: u16,
+}
+
+/// Generator composing melodies and harmonies in the style of the music it was trained on, with
+/// a Markov chain over the chords and rhythms it has heard.
+///
+/// Each step is chosen from what followed the last [`order`](Self::order) steps in the training
+/// music, so higher orders stay closer to it. Generation is seeded, so a generator trained on
+/// the same music with the same seed always composes the same phrases.
+#[derive(Clone, Debug)]
+pub struct MidiMarkovGenerator {
+    /// Number of previous steps each step depends on
+    pub order: usize,
+    /// Approximate length in beats of each phrase
+    pub phrase_beats: f64,
+    /// Tempo in quarter notes per minute
+    pub bpm: f64,
+    /// Channel the music is played on
+    pub channel: i32,
+    /// Preset the music is played with
+    pub preset: i32,
+    /// Velocity of the notes
+    pub velocity: i32,
+    /// Only learn from notes on this channel, or from every channel except percussion
+    pub train_channel: Option<i32>,
+    seed: u64,
+    rng: fastrand::Rng,
+    /// Steps heard after each sequence of `order` steps, repeated as often as they were heard
+    transitions: HashMap<Vec<MarkovToken>, Vec<MarkovToken>>,
+    /// Every sequence of `order` steps heard, in the order they were first heard
+    states: Vec<Vec<MarkovToken>>,
+    /// Last `order` steps composed
+    context: VecDeque<MarkovToken>,
+}
+
+impl MidiMarkovGenerator {
+    /// Create an untrained second order generator with the given seed, playing a piano at 120
+    /// BPM in phrases of 4 bars of 4/4
+    pub fn new(seed: u64) -> Self {
+        Self {
+            order: 2,
+            phrase_beats: 16.0,
+            bpm: 120.0,
+            channel: 0,
+            preset: 0,
+            velocity: 90,
+            train_channel: None,
+            seed,
+            rng: fastrand::Rng::with_seed(seed),
+            transitions: HashMap::new(),
+            states: Vec::new(),
+            context: VecDeque::new(),
+        }
+    }
+
+    /// Depend on the given number of previous steps, which should be set before training
+    pub fn with_order(mut self, order: usize) -> Self {
+        self.order = order.max(1);
+        self
+    }
+
+    /// Compose phrases of about the given number of beats
+    pub fn with_phrase_beats(mut self, beats: f64) -> Self {
+        self.phrase_beats = beats;
+        self
+    }
+
+    /// Play at the given quarter notes per minute
+    pub fn with_bpm(mut self, bpm: f64) -> Self {
+        self.bpm = bpm;
+        self
+    }
+
+    /// Play with the given preset
+    pub fn with_preset(mut self, preset: i32) -> Self {
+        self.preset = preset;
+        self
+    }
+
+    /// Only learn from notes on the given channel
+    pub fn with_train_channel(mut self, channel: i32) -> Self {
+        self.train_channel = Some(channel);
+        self
+    }
+
+    /// Seed the composition was started from
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Restart composing from the given seed
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = fastrand::Rng::with_seed(seed);
+        self.context.clear();
+    }
+
+    /// Whether the generator has learned anything it can compose from
+    pub fn is_trained(&self) -> bool {
+        !self.states.is_empty()
+    }
+
+    /// Learn the chords and rhythms of the given music
+    pub fn train(&mut self, audio: &MidiAudio) -> Result<(), MidiError> {
+        let song = audio.to_song()?;
+        let division = song.tempo.division;
+        let beat = |tick: u64, time: f64| {
+            if division & 0x8000 != 0 {
+                // SMPTE timing has no beats, so count them at the default tempo
+                time * 2.0
+            } else {
+                tick as f64 / division.max(1) as f64
+            }
+        };
+        // Start and end beats of each note, with the start of notes still held
+        let mut notes: Vec<(f64, f64, u8)> = Vec::new();
+        let mut held: HashMap<(u8, u8), Vec<usize>> = HashMap::new();
+        for event in &song.events {
+            let message = event.message;
+            let channel = message.channel();
+            let skip = match self.train_channel {
+                Some(train) => train != channel as i32,
+                None => channel == 9,
+            };
+            if skip {
+                continue;
+            }
+            let now = beat(event.tick, event.time);
+            let key = message.data1;
+            match message.command() {
+                0x90 if message.data2 > 0 => {
+                    held.entry((channel, key)).or_default().push(notes.len());
+                    notes.push((now, f64::INFINITY, key));
+                }
+                0x80 | 0x90 => {
+                    if let Some(index) = held.get_mut(&(channel, key)).and_then(Vec::pop) {
+                        notes[index].1 = now;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let end = beat(song.tempo.ticks(song.length) as u64, song.length);
+        // Group the notes into chords of onsets
+        let mut groups: Vec<(f64, f64, Vec<u8>)> = Vec::new();
+        for (start, stop, key) in notes {
+            let stop = stop.min(end);
+            match groups.last_mut() {
+                Some((onset, release, keys)) if start - *onset < CHORD_WINDOW => {
+                    *release = release.max(stop);
+                    if !keys.contains(&key) {
+                        keys.push(key);
+                    }
+                }
+                _ => groups.push((start, stop, vec![key])),
+            }
+        }
+        let steps =
+            |beats: f64| (beats * STEPS_PER_BEAT).round().clamp(0.0, u16::MAX as f64) as u16;
+        let tokens: Vec<MarkovToken> = groups
+            .iter()
+            .enumerate()
+            .map(|(index, (onset, release, keys))| {
+                let next = groups.get(index + 1).map_or(*release, |(next, _, _)| *next);
+                let mut keys = keys.clone();
+                keys.sort_unstable();
+                let sound = steps(release.min(next) - onset).max(1);
+                MarkovToken {
+                    keys,
+                    sound,
+                    rest: steps(next - onset).saturating_sub(sound),
+                }
+            })
+            .collect();
+        for window in tokens.windows(self.order + 1) {
+            let (state, next) = window.split_at(self.order);
+            let state = state.to_vec();
+            if !self.transitions.contains_key(&state) {
+                self.states.push(state.clone());
+            }
+            self.transitions
+                .entry(state)
+                .or_default()
+                .push(next[0].clone());
+        }
+        Ok(())
+    }
+
+    /// Choose the next step, jumping to a random state heard in training at dead ends
+    fn next_tokens(&mut self) -> Vec<MarkovToken> {
+        let state: Vec<MarkovToken> = self.context.iter().cloned().collect();
+        if let Some(next) = self.transitions.get(&state) {
+            let token = next[self.rng.usize(..next.len())].clone();
+            self.context.pop_front();
+            self.context.push_back(token.clone());
+            return vec![token];
+        }
+        let state = self.states[self.rng.usize(..self.states.len())].clone();
+        self.context = state.iter().cloned().collect();
+        state
+    }
+}
+
+impl MidiGenerator for MidiMarkovGenerator {
+    fn next_phrase(&mut self) -> Vec<ScoreEvent> {
+        if !self.is_trained() {
+            return Vec::new();
+        }
+        let mut events = vec![ScoreEvent::Tempo(self.bpm)];
+        let mut beats = 0.0;
+        while beats < self.phrase_beats {
+            for token in self.next_tokens() {
+                let mut notes = token.keys.iter().map(|&key| MidiNote {
+                    channel: self.channel,
+                    preset: self.preset,
+                    key: key as i32,
+                    velocity: self.velocity,
+                    ..Default::default()
+                });
+                let sound = token.sound as f64 / STEPS_PER_BEAT;
+                events.push(match token.keys.len() {
+                    1 => ScoreEvent::Note {
+                        note: notes.next().unwrap(),
+                        beats: sound,
+                    },
+                    _ => ScoreEvent::Chord {
+                        notes: notes.collect(),
+                        beats: sound,
+                    },
+                });
+                let rest = token.rest as f64 / STEPS_PER_BEAT;
+                if rest > 0.0 {
+                    events.push(ScoreEvent::Rest(rest));
+                }
+                beats += sound + rest;
+            }
+        }
+        events
+    }
+}
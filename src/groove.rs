@@ -0,0 +1,141 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::MidiControl;
+
+/// Component moving the notes of a MIDI source off the beat grid as it plays, so straight
+/// programmatic patterns can swing or groove.
+///
+/// Music is split into steps of [`step`](Self::step) beats, where a beat is a quarter note.
+/// Swing lengthens the first step of each pair and shortens the second, then each step is moved
+/// by its entry of [`offsets`](Self::offsets). Notes keep their lengths. Changing the groove of
+/// a playing source restarts its notes from the current position.
+///
+/// It applies to sources playing a single [`MidiAudio`](crate::MidiAudio) or song, and works
+/// best for music whose notes are quantized to the grid.
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MidiGroove {
+    /// Length of a step in beats, such as `0.5` for eighth notes
+    pub step: f64,
+    /// Share of each pair of steps taken by the first, as a percentage: 50 plays straight, 66 a
+    /// triplet feel and 75 a hard swing
+    pub swing: f64,
+    /// Offset of each step as a fraction of a step, repeating every `offsets.len()` steps, such
+    /// as to push the backbeat slightly late
+    pub offsets: Vec<f64>,
+}
+
+impl Default for MidiGroove {
+    fn default() -> Self {
+        Self {
+            step: 0.5,
+            swing: 50.0,
+            offsets: Vec::new(),
+        }
+    }
+}
+
+impl MidiGroove {
+    /// Swing eighth notes by the given percentage
+    pub fn swing(percent: f64) -> Self {
+        Self {
+            swing: percent,
+            ..default()
+        }
+    }
+
+    /// Use steps of the given number of beats, such as `0.25` for sixteenth notes
+    pub fn with_step(mut self, beats: f64) -> Self {
+        self.step = beats;
+        self
+    }
+
+    /// Move each step by the given fractions of a step, repeating the pattern
+    pub fn with_offsets(mut self, offsets: impl Into<Vec<f64>>) -> Self {
+        self.offsets = offsets.into();
+        self
+    }
+
+    /// Beats by which music at `beat` is moved
+    pub(crate) fn shift(&self, beat: f64) -> f64 {
+        if self.step <= 0.0 || !beat.is_finite() {
+            return 0.0;
+        }
+        let steps = beat / self.step;
+        let index = steps.floor();
+        // Position within the pair of steps, from 0 to 2
+        let paired = index.rem_euclid(2.0) + (steps - index);
+        let first = (self.swing / 100.0).clamp(0.0, 1.0) * 2.0;
+        let swung = if paired < 1.0 {
+            paired * first
+        } else {
+            first + (paired - 1.0) * (2.0 - first)
+        };
+        let offset = match self.offsets.len() {
+            0 => 0.0,
+            len => self.offsets[(index.max(0.0) as usize) % len],
+        };
+        (swung - paired + offset) * self.step
+    }
+}
+
+/// Sources whose groove changed, or which can only now be controlled
+type ChangedGrooveFilter = Or<(Changed<MidiGroove>, Added<MidiControl>)>;
+
+pub(crate) fn apply_grooves(query: Query<(&MidiControl, &MidiGroove), ChangedGrooveFilter>) {
+    for (control, groove) in &query {
+        control.set_groove(Some(groove.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn straight_groove_keeps_the_grid() {
+        let groove = MidiGroove::default();
+        for beat in [0.0, 0.25, 0.5, 1.0, 3.75] {
+            assert_close(groove.shift(beat), 0.0);
+        }
+    }
+
+    #[test]
+    fn swing_delays_the_offbeat() {
+        let groove = MidiGroove::swing(75.0);
+        assert_close(groove.shift(0.0), 0.0);
+        // The second eighth note starts three quarters of the way through the beat
+        assert_close(groove.shift(0.5), 0.25);
+        assert_close(groove.shift(1.5), 0.25);
+        assert_close(groove.shift(1.0), 0.0);
+        // Halfway through the first step is stretched, halfway through the second squeezed
+        assert_close(groove.shift(0.25), 0.125);
+        assert_close(groove.shift(0.75), 0.125);
+    }
+
+    #[test]
+    fn swing_follows_the_step() {
+        let groove = MidiGroove::swing(75.0).with_step(0.25);
+        assert_close(groove.shift(0.25), 0.125);
+        assert_close(groove.shift(0.5), 0.0);
+    }
+
+    #[test]
+    fn offsets_repeat() {
+        let groove = MidiGroove::default().with_offsets([0.0, 0.1]);
+        assert_close(groove.shift(0.0), 0.0);
+        assert_close(groove.shift(0.5), 0.05);
+        assert_close(groove.shift(1.5), 0.05);
+        assert_close(groove.shift(2.0), 0.0);
+    }
+
+    #[test]
+    fn invalid_steps_are_ignored() {
+        assert_close(MidiGroove::swing(75.0).with_step(0.0).shift(0.5), 0.0);
+        assert_close(MidiGroove::swing(75.0).shift(f64::NAN), 0.0);
+    }
+}
@@ -0,0 +1,202 @@
+use std::sync::Mutex;
+
+use bevy::{
+    audio::{PlaybackMode, Source},
+    prelude::*,
+};
+
+use crate::{
+    decoder::SourceProgram, dither_to_i16, settings::render_settings, MidiAudio, MidiControl,
+    MidiFileDecoder, MidiRenderSettings, MidiSource,
+};
+
+/// Plugin playing MIDI sources without an audio device, for dedicated servers and tests.
+///
+/// Add it instead of bevy's `AudioPlugin`. Queued MIDI sources are rendered in real time on the
+/// main thread, and their output is collected in a [`HeadlessMidiOutput`] component instead of
+/// being played. With [`MidiRenderSettings::deterministic`] they advance on `FixedUpdate`.
+#[derive(Debug, Default)]
+pub struct HeadlessMidiPlugin;
+
+impl Plugin for HeadlessMidiPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PostUpdate,
+            start_headless_sources
+                .after(crate::quantize_starts)
+                .after(crate::apply_start_offsets)
+                .after(crate::apply_skip_silence)
+                .after(crate::apply_release_tails)
+                .after(crate::apply_block_lengths)
+                .after(crate::apply_dry_stream_policies)
+                .after(crate::apply_channel_layouts),
+        )
+        .add_systems(Update, pull_headless_sources.run_if(not(deterministic)))
+        .add_systems(FixedUpdate, pull_headless_sources.run_if(deterministic));
+    }
+}
+
+/// Component collecting the rendered output of a MIDI source played by the
+/// [`HeadlessMidiPlugin`].
+///
+/// Sources using [`PlaybackMode::Despawn`] are despawned along with any output not yet drained.
+#[derive(Component)]
+pub struct HeadlessMidiOutput {
+    decoder: Mutex<MidiFileDecoder>,
+    program: SourceProgram,
+    samples: Vec<f32>,
+    frames_due: f64,
+    finished: bool,
+}
+
+impl HeadlessMidiOutput {
+    /// Sample rate of the output
+    pub fn sample_rate(&self) -> u32 {
+        self.decoder.lock().unwrap().sample_rate()
+    }
+
+    /// Number of channels of the output, following the source's
+    /// [`MidiChannelLayout`](crate::MidiChannelLayout)
+    pub fn channels(&self) -> u16 {
+        self.decoder.lock().unwrap().channels()
+    }
+
+    /// Interleaved samples rendered so far and not yet drained, stereo unless the source has
+    /// another [`MidiChannelLayout`](crate::MidiChannelLayout)
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    /// Take the samples rendered so far
+    pub fn drain(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Take the samples rendered so far as 16-bit audio with TPDF dithering
+    pub fn drain_i16(&mut self) -> Vec<i16> {
+        dither_to_i16(&self.drain())
+    }
+
+    /// Whether the source has finished playing
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+type QueuedHeadless = (
+    Entity,
+    Option<&'static Handle<MidiAudio>>,
+    Option<&'static Handle<MidiSource>>,
+);
+
+type QueuedHeadlessFilter = (
+    With<PlaybackSettings>,
+    Without<HeadlessMidiOutput>,
+    Or<(With<Handle<MidiAudio>>, With<Handle<MidiSource>>)>,
+);
+
+fn start_headless_sources(
+    mut commands: Commands,
+    midi_assets: Res<Assets<MidiAudio>>,
+    sources: Res<Assets<MidiSource>>,
+    query: Query<QueuedHeadless, QueuedHeadlessFilter>,
+) {
+    let Some(soundfont) = crate::ready_soundfont() else {
+        return;
+    };
+    for (entity, audio, source) in &query {
+        let (program, control, sync) = match (audio, source) {
+            (_, Some(source)) => {
+                let Some(source) = sources.get(source) else {
+                    continue;
+                };
+                (
+                    source.program.clone(),
+                    source.control.clone(),
+                    source.sync.clone(),
+                )
+            }
+            (Some(audio), _) => {
+                let Some(audio) = midi_assets.get(audio) else {
+                    continue;
+                };
+                let program = SourceProgram::Audio(audio.clone());
+                (program, MidiControl::default(), None)
+            }
+            _ => continue,
+        };
+        let decoder = MidiFileDecoder::offline_program(
+            program.clone(),
+            soundfont.clone(),
+            control,
+            sync,
+            &render_settings(),
+        );
+        commands.entity(entity).insert(HeadlessMidiOutput {
+            decoder: Mutex::new(decoder),
+            program,
+            samples: Vec::new(),
+            frames_due: 0.0,
+            finished: false,
+        });
+    }
+}
+
+/// Whether sources follow the fixed timestep, see [`MidiRenderSettings::deterministic`]
+fn deterministic(settings: Option<Res<MidiRenderSettings>>) -> bool {
+    settings.is_some_and(|settings| settings.deterministic)
+}
+
+fn pull_headless_sources(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut query: Query<(
+        Entity,
+        &mut HeadlessMidiOutput,
+        &PlaybackSettings,
+        Option<&MidiControl>,
+    )>,
+) {
+    let Some(soundfont) = crate::ready_soundfont() else {
+        return;
+    };
+    for (entity, mut output, settings, control) in &mut query {
+        if output.finished || settings.paused {
+            continue;
+        }
+        let output = &mut *output;
+        output.frames_due += time.delta_seconds_f64() * output.sample_rate() as f64;
+        let frames = output.frames_due as usize;
+        output.frames_due -= frames as f64;
+        let decoder = output.decoder.get_mut().unwrap();
+        let before = output.samples.len();
+        let samples = frames * decoder.channels() as usize;
+        output.samples.extend(decoder.by_ref().take(samples));
+        if output.samples.len() - before == samples {
+            continue;
+        }
+        match settings.mode {
+            PlaybackMode::Loop if !control.is_some_and(MidiControl::is_stopped) => {
+                let control = control.cloned().unwrap_or_default();
+                *decoder = MidiFileDecoder::offline_program(
+                    output.program.clone(),
+                    soundfont.clone(),
+                    control,
+                    None,
+                    &render_settings(),
+                );
+            }
+            PlaybackMode::Once | PlaybackMode::Loop => output.finished = true,
+            PlaybackMode::Despawn => {
+                output.finished = true;
+                commands.entity(entity).despawn_recursive();
+            }
+            PlaybackMode::Remove => {
+                output.finished = true;
+                commands
+                    .entity(entity)
+                    .remove::<(PlaybackSettings, Handle<MidiAudio>, Handle<MidiSource>)>();
+            }
+        }
+    }
+}
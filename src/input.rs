@@ -0,0 +1,230 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    control::{QueuedFilter, SourceOptions},
+    decoder::SourceProgram,
+    sequencer::MidiRender,
+    MidiSource, SynthBackend,
+};
+
+/// A channel message played live on a source through [`MidiControl::send_input`], such as one
+/// read from a MIDI controller. Channels are counted from 0.
+///
+/// [`MidiControl::send_input`]: crate::MidiControl::send_input
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiInputMessage {
+    /// Start playing a key
+    NoteOn {
+        /// Channel to play on
+        channel: u8,
+        /// Key to play, where 60 is middle C
+        key: u8,
+        /// Velocity to play the key with
+        velocity: u8,
+    },
+    /// Release a key
+    NoteOff {
+        /// Channel the key is playing on
+        channel: u8,
+        /// Key to release
+        key: u8,
+    },
+    /// Change how hard a held key is pressed (polyphonic aftertouch)
+    PolyPressure {
+        /// Channel the key is playing on
+        channel: u8,
+        /// Key being pressed
+        key: u8,
+        /// Pressure, from 0 to 127
+        pressure: u8,
+    },
+    /// Change a controller (CC)
+    ControlChange {
+        /// Channel of the controller
+        channel: u8,
+        /// Controller number
+        controller: u8,
+        /// New value, from 0 to 127
+        value: u8,
+    },
+    /// Change the preset of a channel
+    ProgramChange {
+        /// Channel to change
+        channel: u8,
+        /// Preset to play
+        program: u8,
+    },
+    /// Change how hard every key of a channel is pressed (channel aftertouch)
+    ChannelPressure {
+        /// Channel being pressed
+        channel: u8,
+        /// Pressure, from 0 to 127
+        pressure: u8,
+    },
+    /// Bend the pitch of a channel
+    PitchBend {
+        /// Channel to bend
+        channel: u8,
+        /// Bend from 0 to 16383, where 8192 leaves the pitch unchanged
+        value: u16,
+    },
+}
+
+impl MidiInputMessage {
+    /// Parse a raw channel message, such as one received from a MIDI input port, returning
+    /// `None` for system messages and incomplete data
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&status, data) = bytes.split_first()?;
+        let channel = status & 0x0F;
+        let byte = |index: usize| data.get(index).map(|&byte| byte & 0x7F);
+        Some(match status & 0xF0 {
+            0x80 => Self::NoteOff {
+                channel,
+                key: byte(0)?,
+            },
+            0x90 => match byte(1)? {
+                0 => Self::NoteOff {
+                    channel,
+                    key: byte(0)?,
+                },
+                velocity => Self::NoteOn {
+                    channel,
+                    key: byte(0)?,
+                    velocity,
+                },
+            },
+            0xA0 => Self::PolyPressure {
+                channel,
+                key: byte(0)?,
+                pressure: byte(1)?,
+            },
+            0xB0 => Self::ControlChange {
+                channel,
+                controller: byte(0)?,
+                value: byte(1)?,
+            },
+            0xC0 => Self::ProgramChange {
+                channel,
+                program: byte(0)?,
+            },
+            0xD0 => Self::ChannelPressure {
+                channel,
+                pressure: byte(0)?,
+            },
+            0xE0 => Self::PitchBend {
+                channel,
+                value: byte(0)? as u16 | (byte(1)? as u16) << 7,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Channel the message is sent on
+    pub fn channel(&self) -> u8 {
+        match *self {
+            Self::NoteOn { channel, .. }
+            | Self::NoteOff { channel, .. }
+            | Self::PolyPressure { channel, .. }
+            | Self::ControlChange { channel, .. }
+            | Self::ProgramChange { channel, .. }
+            | Self::ChannelPressure { channel, .. }
+            | Self::PitchBend { channel, .. } => channel,
+        }
+    }
+
+    /// The same message sent on another channel
+    pub fn with_channel(mut self, to: u8) -> Self {
+        match &mut self {
+            Self::NoteOn { channel, .. }
+            | Self::NoteOff { channel, .. }
+            | Self::PolyPressure { channel, .. }
+            | Self::ControlChange { channel, .. }
+            | Self::ProgramChange { channel, .. }
+            | Self::ChannelPressure { channel, .. }
+            | Self::PitchBend { channel, .. } => *channel = to & 0x0F,
+        }
+        self
+    }
+
+    /// Channel, command, and data bytes of the message, as synthesizers take them
+    pub(crate) fn to_message(self) -> (i32, i32, i32, i32) {
+        let (command, data1, data2) = match self {
+            Self::NoteOn { key, velocity, .. } => (0x90, key, velocity),
+            Self::NoteOff { key, .. } => (0x80, key, 0),
+            Self::PolyPressure { key, pressure, .. } => (0xA0, key, pressure),
+            Self::ControlChange {
+                controller, value, ..
+            } => (0xB0, controller, value),
+            Self::ProgramChange { program, .. } => (0xC0, program, 0),
+            Self::ChannelPressure { pressure, .. } => (0xD0, pressure, 0),
+            Self::PitchBend { value, .. } => {
+                let value = value.min(0x3FFF);
+                (0xE0, (value & 0x7F) as u8, (value >> 7) as u8)
+            }
+        };
+        (
+            (self.channel() & 0x0F) as i32,
+            command,
+            (data1 & 0x7F) as i32,
+            (data2 & 0x7F) as i32,
+        )
+    }
+}
+
+/// Component playing nothing but the messages sent live through its
+/// [`MidiControl`](crate::MidiControl), such as from a MIDI controller for a performance game.
+///
+/// Spawn it together with [`PlaybackSettings`] and a [`MidiControl`](crate::MidiControl) to send
+/// through. It plays until it's stopped, in short blocks so notes sound soon after they're sent.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct MidiLiveInput;
+
+pub(crate) struct LiveRenderer {
+    synthesizer: Box<dyn SynthBackend>,
+}
+
+impl LiveRenderer {
+    pub(crate) fn new(synthesizer: Box<dyn SynthBackend>) -> Self {
+        Self { synthesizer }
+    }
+}
+
+impl MidiRender for LiveRenderer {
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        self.synthesizer.render(left, right);
+        left.len()
+    }
+
+    fn seek(&mut self, _position: f64) {
+        self.synthesizer.note_off_all(true);
+    }
+
+    fn set_speed(&mut self, _speed: f64) {}
+
+    fn render_tail(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.synthesizer.render(left, right);
+    }
+}
+
+type QueuedLiveFilter = (
+    QueuedFilter,
+    With<MidiLiveInput>,
+    Without<Handle<MidiSource>>,
+);
+
+pub(crate) fn prepare_live_inputs(
+    mut commands: Commands,
+    mut sources: ResMut<Assets<MidiSource>>,
+    query: Query<(Entity, SourceOptions), QueuedLiveFilter>,
+) {
+    for (entity, (control, sync)) in &query {
+        let control = control.cloned().unwrap_or_default();
+        let source = sources.add(MidiSource {
+            program: SourceProgram::Live,
+            control: control.clone(),
+            sync: sync.cloned(),
+        });
+        commands.entity(entity).insert((source, control));
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::MidiLayerPlayer;
+
+/// Parameter of a [`MidiLayerPlayer`] driven by an [`IntensityCurve`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum IntensityTarget {
+    /// Volume of the named layer
+    LayerVolume(String),
+    /// Tempo multiplier of all layers
+    Tempo,
+    /// Transposition of all layers in semitones, rounded to the nearest integer
+    Transpose,
+}
+
+/// Piecewise linear mapping from intensity to a parameter value
+#[derive(Clone, Debug)]
+pub struct IntensityCurve {
+    /// Parameter driven by the curve
+    pub target: IntensityTarget,
+    /// `(intensity, value)` points, sorted by intensity
+    pub points: Vec<(f32, f32)>,
+}
+
+impl IntensityCurve {
+    /// Construct a curve through the given `(intensity, value)` points
+    pub fn new(target: IntensityTarget, points: impl IntoIterator<Item = (f32, f32)>) -> Self {
+        let mut points: Vec<(f32, f32)> = points.into_iter().collect();
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { target, points }
+    }
+
+    /// Evaluate the curve, clamping to the first and last points
+    pub fn sample(&self, intensity: f32) -> Option<f32> {
+        let first = self.points.first()?;
+        let last = self.points.last()?;
+        if intensity <= first.0 {
+            return Some(first.1);
+        }
+        if intensity >= last.0 {
+            return Some(last.1);
+        }
+        let (a, b) = self
+            .points
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .find(|(_, b)| intensity <= b.0)?;
+        let t = if b.0 > a.0 {
+            (intensity - a.0) / (b.0 - a.0)
+        } else {
+            1.0
+        };
+        Some(a.1 + (b.1 - a.1) * t)
+    }
+}
+
+/// Component driving the mix of a [`MidiLayerPlayer`] on the same entity from a single
+/// intensity value.
+#[derive(Component, Clone, Debug)]
+pub struct MidiIntensity {
+    /// Target intensity, usually in `0.0..=1.0`
+    pub intensity: f32,
+    /// Time taken for the applied intensity to move by 1.0 towards the target
+    pub smoothing: Duration,
+    /// Curves mapping intensity to player parameters
+    pub curves: Vec<IntensityCurve>,
+    current: Option<f32>,
+}
+
+impl MidiIntensity {
+    /// Construct an intensity driver with the given curves
+    pub fn new(curves: impl IntoIterator<Item = IntensityCurve>) -> Self {
+        Self {
+            intensity: 0.0,
+            smoothing: Duration::from_secs(1),
+            curves: curves.into_iter().collect(),
+            current: None,
+        }
+    }
+
+    /// Intensity currently applied to the player, lagging behind the target while smoothing
+    pub fn current(&self) -> f32 {
+        self.current.unwrap_or(self.intensity)
+    }
+}
+
+pub(crate) fn apply_intensity(
+    time: Res<Time>,
+    mut query: Query<(&mut MidiIntensity, &MidiLayerPlayer)>,
+) {
+    for (mut intensity, player) in &mut query {
+        let target = intensity.intensity;
+        let current = match intensity.current {
+            Some(current) => {
+                let max_step = if intensity.smoothing.is_zero() {
+                    f32::INFINITY
+                } else {
+                    time.delta_seconds() / intensity.smoothing.as_secs_f32()
+                };
+                current + (target - current).clamp(-max_step, max_step)
+            }
+            None => target,
+        };
+        if intensity.current == Some(current) {
+            continue;
+        }
+        intensity.current = Some(current);
+
+        for curve in &intensity.curves {
+            let Some(value) = curve.sample(current) else {
+                continue;
+            };
+            match &curve.target {
+                IntensityTarget::LayerVolume(layer) => {
+                    player.fade_volume(layer, value, time.delta());
+                }
+                IntensityTarget::Tempo => player.set_speed(value as f64),
+                IntensityTarget::Transpose => player.set_transpose(value.round() as i32),
+            }
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use crate::{
+    midi::{EventKind, Smf, Song},
+    MidiAudio,
+};
+
+/// A syllable of karaoke lyrics
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KaraokeSyllable {
+    /// Time at which the syllable is sung
+    pub time: Duration,
+    /// Text of the syllable, without line break markers
+    pub text: String,
+    /// Whether the syllable starts a new line
+    pub new_line: bool,
+    /// Whether the syllable starts a new paragraph
+    pub new_paragraph: bool,
+}
+
+/// Metadata and lyrics of a Soft Karaoke (.kar) file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KaraokeInfo {
+    /// Title lines, usually the song title, artist and sequencer
+    pub title: Vec<String>,
+    /// Further information lines
+    pub info: Vec<String>,
+    /// Language of the lyrics
+    pub language: Option<String>,
+    /// Version of the file format
+    pub version: Option<String>,
+    /// Lyrics in the order they're sung
+    pub syllables: Vec<KaraokeSyllable>,
+}
+
+impl KaraokeInfo {
+    /// Lyrics joined into lines
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        for syllable in &self.syllables {
+            if (syllable.new_line || syllable.new_paragraph) && !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            line.push_str(&syllable.text);
+        }
+        if !line.is_empty() {
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// Decode a text event, which karaoke files usually store in Latin-1
+fn decode_text(data: &[u8]) -> String {
+    String::from_utf8(data.to_vec()).unwrap_or_else(|_| data.iter().map(|&b| b as char).collect())
+}
+
+impl MidiAudio {
+    /// Karaoke metadata and lyrics, if this is a Soft Karaoke file.
+    ///
+    /// Lyrics are read from the track named `Words`, or the second track if none is named so.
+    pub fn karaoke(&self) -> Option<KaraokeInfo> {
+        let MidiAudio::File(data) = self else {
+            return None;
+        };
+        let smf = Smf::parse(data).ok()?;
+        let texts = |index: usize| {
+            smf.tracks
+                .get(index)
+                .into_iter()
+                .flatten()
+                .filter_map(|event| match &event.kind {
+                    EventKind::Meta(0x01, text) => Some((event.tick, decode_text(text))),
+                    _ => None,
+                })
+        };
+
+        let mut info = KaraokeInfo::default();
+        let mut karaoke = false;
+        for index in 0..smf.tracks.len() {
+            for (_, text) in texts(index) {
+                let Some(tag) = text.strip_prefix('@') else {
+                    continue;
+                };
+                let (kind, value) = tag.split_at(tag.chars().next().map_or(0, char::len_utf8));
+                let value = value.trim().to_string();
+                match kind {
+                    "K" => karaoke = true,
+                    "T" => info.title.push(value),
+                    "I" => info.info.push(value),
+                    "L" => info.language = Some(value),
+                    "V" => info.version = Some(value),
+                    _ => {}
+                }
+            }
+        }
+        let named = (0..smf.tracks.len()).find(|&index| smf.track_name(index) == Some("Words"));
+        if !karaoke && named.is_none() {
+            return None;
+        }
+        let words = named.or((smf.tracks.len() > 1).then_some(1));
+
+        let tempo = Song::from_smf(&smf, Some(&[])).tempo;
+        for (tick, text) in words.into_iter().flat_map(texts) {
+            if text.starts_with('@') {
+                continue;
+            }
+            let new_paragraph = text.starts_with('\\');
+            let new_line = text.starts_with('/');
+            let text = text.trim_start_matches(['\\', '/']).to_string();
+            info.syllables.push(KaraokeSyllable {
+                time: Duration::from_secs_f64(tempo.seconds(tick)),
+                text,
+                new_line,
+                new_paragraph,
+            });
+        }
+        Some(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Soft Karaoke file with the given text events in its `Words` track, a beat apart
+    fn kar(texts: &[&[u8]]) -> MidiAudio {
+        let mut words = vec![0x00, 0xFF, 0x03, 0x05];
+        words.extend_from_slice(b"Words");
+        for text in texts {
+            words.extend_from_slice(&[0x83, 0x60, 0xFF, 0x01, text.len() as u8]);
+            words.extend_from_slice(text);
+        }
+        words.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&[0, 0, 0, 6, 0, 1, 0, 1, 0x01, 0xE0]);
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(words.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&words);
+        MidiAudio::File(bytes)
+    }
+
+    #[test]
+    fn reads_tags_and_syllables() {
+        let info = kar(&[
+            b"@KMIDI KARAOKE FILE",
+            b"@TSong",
+            b"@LENG",
+            b"\\Hel",
+            b"lo",
+            b"/world",
+        ])
+        .karaoke()
+        .unwrap();
+        assert_eq!(info.title, ["Song"]);
+        assert_eq!(info.language.as_deref(), Some("ENG"));
+        assert_eq!(info.lines(), ["Hello", "world"]);
+        assert!(info.syllables[0].new_paragraph);
+        assert_eq!(info.syllables[2].time, Duration::from_secs_f64(3.0));
+    }
+
+    #[test]
+    fn multibyte_tags_are_ignored() {
+        // Latin-1 text decodes to characters longer than a byte
+        let info = kar(&[b"@K", b"@\xE9t\xE9", "@\u{1F3A4}".as_bytes(), b"@", b"la"])
+            .karaoke()
+            .unwrap();
+        assert_eq!(info.lines(), ["la"]);
+    }
+}
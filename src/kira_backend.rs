@@ -0,0 +1,229 @@
+use std::{convert::Infallible, sync::Arc};
+
+use bevy::audio::{Decodable, Source};
+#[cfg(feature = "bevy_kira_audio")]
+use bevy::{
+    asset::{io::Reader, AssetLoader, LoadContext},
+    prelude::*,
+};
+use kira::{
+    clock::clock_info::ClockInfoProvider,
+    dsp::Frame,
+    modulator::value_provider::ModulatorValueProvider,
+    sound::{
+        static_sound::{StaticSoundData, StaticSoundSettings},
+        Sound, SoundData,
+    },
+    OutputDestination,
+};
+use rustysynth::SoundFont;
+
+use crate::{decoder::SourceProgram, MidiAudio, MidiControl, MidiFileDecoder, MidiSource};
+#[cfg(feature = "bevy_kira_audio")]
+use crate::{settings::render_settings, MidiAssetLoader, MidiError, MidiLoaderSettings};
+
+/// MIDI playback which can be played through a kira `AudioManager`
+///
+/// Music is streamed from a synthesizer on the async compute pool, the same way sources played
+/// through bevy's audio are. Playing it returns the [`MidiControl`] driving it.
+pub struct MidiSoundData {
+    decoder: MidiFileDecoder,
+    control: MidiControl,
+    output_destination: OutputDestination,
+}
+
+impl MidiSoundData {
+    /// Stream the given MIDI audio using the plugin's soundfont
+    pub fn new(midi: &MidiAudio) -> Self {
+        Self::with_control(midi, MidiControl::default())
+    }
+
+    /// Stream the given MIDI audio, driven by the given control
+    pub fn with_control(midi: &MidiAudio, control: MidiControl) -> Self {
+        let decoder = MidiFileDecoder::with_program(
+            SourceProgram::Audio(midi.clone()),
+            crate::current_soundfont(),
+            control.clone(),
+            None,
+        )
+        .stereo();
+        Self {
+            decoder,
+            control,
+            output_destination: OutputDestination::default(),
+        }
+    }
+
+    /// Stream a controllable source, such as a segment or layer player
+    pub fn from_source(source: &MidiSource) -> Self {
+        Self {
+            decoder: source.decoder(),
+            control: source.control.clone(),
+            output_destination: OutputDestination::default(),
+        }
+    }
+
+    /// Route the music to the given mixer track or emitter
+    pub fn output_destination(mut self, destination: impl Into<OutputDestination>) -> Self {
+        self.output_destination = destination.into();
+        self
+    }
+}
+
+impl SoundData for MidiSoundData {
+    type Error = Infallible;
+
+    type Handle = MidiControl;
+
+    fn into_sound(self) -> Result<(Box<dyn Sound>, Self::Handle), Self::Error> {
+        let sound = MidiSound {
+            sample_rate: self.decoder.sample_rate() as f64,
+            decoder: self.decoder,
+            output_destination: self.output_destination,
+            previous: Frame::ZERO,
+            next: Frame::ZERO,
+            fraction: 0.0,
+            finished: false,
+        };
+        Ok((Box::new(sound), self.control))
+    }
+}
+
+/// Live kira sound pulling frames from a decoder, resampling to the mixer's rate
+struct MidiSound {
+    decoder: MidiFileDecoder,
+    sample_rate: f64,
+    output_destination: OutputDestination,
+    previous: Frame,
+    next: Frame,
+    fraction: f64,
+    finished: bool,
+}
+
+impl MidiSound {
+    fn next_frame(&mut self) -> Frame {
+        match (self.decoder.next(), self.decoder.next()) {
+            (Some(left), Some(right)) => Frame::new(left, right),
+            _ => {
+                self.finished = true;
+                Frame::ZERO
+            }
+        }
+    }
+}
+
+impl Sound for MidiSound {
+    fn output_destination(&mut self) -> OutputDestination {
+        self.output_destination
+    }
+
+    fn process(
+        &mut self,
+        dt: f64,
+        _clock_info_provider: &ClockInfoProvider,
+        _modulator_value_provider: &ModulatorValueProvider,
+    ) -> Frame {
+        self.fraction += dt * self.sample_rate;
+        while self.fraction >= 1.0 && !self.finished {
+            self.fraction -= 1.0;
+            self.previous = self.next;
+            self.next = self.next_frame();
+        }
+        self.previous + (self.next - self.previous) * self.fraction.min(1.0) as f32
+    }
+
+    fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl MidiAudio {
+    /// Render the whole piece into kira sound data, such as for a `bevy_kira_audio` `AudioSource`
+    pub fn to_static_sound(&self, soundfont: Arc<SoundFont>) -> StaticSoundData {
+        static_sound(MidiFileDecoder::offline(self.clone(), soundfont))
+    }
+}
+
+/// Collect everything a decoder renders into kira sound data
+fn static_sound(decoder: MidiFileDecoder) -> StaticSoundData {
+    let sample_rate = decoder.sample_rate();
+    let samples = decoder.collect::<Vec<_>>();
+    let frames = samples
+        .chunks_exact(2)
+        .map(|frame| Frame::new(frame[0], frame[1]))
+        .collect();
+    StaticSoundData {
+        sample_rate,
+        frames,
+        settings: StaticSoundSettings::default(),
+    }
+}
+
+/// Plugin letting MIDI files be loaded as `bevy_kira_audio` [`AudioSource`]s, so they can be
+/// played on its channels with its mixing and tweening.
+///
+/// Add it after `bevy_kira_audio`'s `AudioPlugin` and the [`RustySynthPlugin`], then load files
+/// by their type, as in `asset_server.load::<AudioSource>("music.mid")`. Music is rendered in
+/// full with the plugin's soundfont when it loads, so it doesn't react to a [`MidiControl`].
+/// Without a [`RustySynthPlugin`], loads fail with a [`MidiError::SoundFont`].
+///
+/// [`AudioSource`]: bevy_kira_audio::AudioSource
+/// [`RustySynthPlugin`]: crate::RustySynthPlugin
+#[cfg(feature = "bevy_kira_audio")]
+#[derive(Debug, Default)]
+pub struct KiraMidiPlugin;
+
+#[cfg(feature = "bevy_kira_audio")]
+impl Plugin for KiraMidiPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_asset_loader(KiraMidiLoader);
+    }
+}
+
+/// Asset loader rendering MIDI files into `bevy_kira_audio` [`AudioSource`]s, with the same
+/// settings as the [`MidiAssetLoader`]
+///
+/// [`AudioSource`]: bevy_kira_audio::AudioSource
+#[cfg(feature = "bevy_kira_audio")]
+#[derive(Debug, Default)]
+pub struct KiraMidiLoader;
+
+#[cfg(feature = "bevy_kira_audio")]
+impl AssetLoader for KiraMidiLoader {
+    type Asset = bevy_kira_audio::AudioSource;
+
+    type Settings = MidiLoaderSettings;
+
+    type Error = MidiError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        settings: &'a Self::Settings,
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        // Waiting for a soundfont which no plugin will provide would never finish
+        if !crate::soundfont_configured() {
+            return Err(MidiError::soundfont(
+                "no soundfont is configured, add the RustySynthPlugin",
+            ));
+        }
+        let audio = MidiAssetLoader.load(reader, settings, load_context).await?;
+        // Report invalid files now, as they can't fail once played
+        audio.to_song()?;
+        let decoder = MidiFileDecoder::offline_program(
+            SourceProgram::Audio(audio),
+            crate::soundfont().await,
+            MidiControl::default(),
+            None,
+            &render_settings(),
+        );
+        Ok(bevy_kira_audio::AudioSource {
+            sound: static_sound(decoder),
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        MidiAssetLoader.extensions()
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    control::{QueuedFilter, Ramp, SourceOptions},
+    decoder::SourceProgram,
+    midi::Song,
+    sequencer::{MidiRender, Sequencer, SynthFactory},
+    MidiAudio, MidiSource, SynthBackend,
+};
+
+/// A single layer of a [`MidiLayerPlayer`]
+#[derive(Clone, Debug)]
+pub struct MidiLayer {
+    /// Unique name of the layer
+    pub name: String,
+    /// Audio for the layer
+    pub audio: Handle<MidiAudio>,
+    /// Tracks of a MIDI file to play in this layer, or `None` for all of them
+    pub tracks: Option<Vec<usize>>,
+    /// Initial volume of the layer
+    pub volume: f32,
+}
+
+impl MidiLayer {
+    /// Construct a layer playing all tracks of the given audio at full volume
+    pub fn new(name: impl Into<String>, audio: Handle<MidiAudio>) -> Self {
+        Self {
+            name: name.into(),
+            audio,
+            tracks: None,
+            volume: 1.0,
+        }
+    }
+
+    /// Only play the given tracks of the MIDI file
+    pub fn with_tracks(mut self, tracks: impl IntoIterator<Item = usize>) -> Self {
+        self.tracks = Some(tracks.into_iter().collect());
+        self
+    }
+
+    /// Set the initial volume of the layer
+    pub fn with_volume(mut self, volume: f32) -> Self {
+        self.volume = volume;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct LayerState {
+    requests: Vec<(usize, f32, Duration)>,
+    volumes: Vec<f32>,
+    speed: f64,
+    transpose: i32,
+}
+
+/// Component which plays several layers of MIDI audio in sample-accurate lockstep.
+///
+/// Each layer is rendered by its own synthesizer so its volume can be automated independently.
+/// Spawn it together with [`PlaybackSettings`]; a [`MidiControl`](crate::MidiControl) may be added to control the
+/// overall gain.
+#[derive(Component, Clone, Debug)]
+pub struct MidiLayerPlayer {
+    /// Layers to play
+    pub layers: Vec<MidiLayer>,
+    /// Whether to restart all layers together once the longest one has finished
+    pub looping: bool,
+    state: Arc<Mutex<LayerState>>,
+}
+
+impl MidiLayerPlayer {
+    /// Construct a player for the given layers
+    pub fn new(layers: impl IntoIterator<Item = MidiLayer>) -> Self {
+        let layers: Vec<MidiLayer> = layers.into_iter().collect();
+        let volumes = layers.iter().map(|layer| layer.volume).collect();
+        Self {
+            layers,
+            looping: false,
+            state: Arc::new(Mutex::new(LayerState {
+                requests: Vec::new(),
+                volumes,
+                speed: 1.0,
+                transpose: 0,
+            })),
+        }
+    }
+
+    /// Restart all layers together once the longest one has finished
+    pub fn looping(mut self) -> Self {
+        self.looping = true;
+        self
+    }
+
+    fn index_of(&self, layer: &str) -> Option<usize> {
+        self.layers
+            .iter()
+            .position(|candidate| candidate.name == layer)
+    }
+
+    /// Set the volume of a layer immediately
+    pub fn set_volume(&self, layer: &str, volume: f32) {
+        self.fade_volume(layer, volume, Duration::ZERO);
+    }
+
+    /// Ramp the volume of a layer linearly to `volume` over `duration`
+    pub fn fade_volume(&self, layer: &str, volume: f32, duration: Duration) {
+        let Some(index) = self.index_of(layer) else {
+            warn!("Unknown MIDI layer '{layer}'.");
+            return;
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .push((index, volume, duration));
+    }
+
+    /// Current volume of a layer
+    pub fn volume(&self, layer: &str) -> Option<f32> {
+        let index = self.index_of(layer)?;
+        self.state.lock().unwrap().volumes.get(index).copied()
+    }
+
+    /// Set the tempo multiplier applied to every layer
+    pub fn set_speed(&self, speed: f64) {
+        self.state.lock().unwrap().speed = speed;
+    }
+
+    /// Set the transposition in semitones applied to every layer
+    pub fn set_transpose(&self, semitones: i32) {
+        self.state.lock().unwrap().transpose = semitones;
+    }
+}
+
+/// Component which plays several [`MidiAudio`]s at once through a single synthesizer.
+///
+/// Unlike a [`MidiLayerPlayer`], the parts share one synthesizer, so they share its reverb and
+/// chorus and cost no more to render than a single piece. Parts are combined as by
+/// [`MidiAudio::merge`], so each keeps its own timing and instruments. Spawn it together with
+/// [`PlaybackSettings`]; a [`MidiControl`](crate::MidiControl) may be added to control playback.
+#[derive(Component, Clone, Debug, Default)]
+pub struct MidiOverlayPlayer {
+    /// Audio to play together
+    pub parts: Vec<Handle<MidiAudio>>,
+}
+
+impl MidiOverlayPlayer {
+    /// Construct a player for the given parts
+    pub fn new(parts: impl IntoIterator<Item = Handle<MidiAudio>>) -> Self {
+        Self {
+            parts: parts.into_iter().collect(),
+        }
+    }
+}
+
+/// Layers with all audio resolved, ready for rendering
+#[derive(Debug)]
+pub(crate) struct LayerProgram {
+    songs: Vec<Arc<Song>>,
+    looping: bool,
+    state: Arc<Mutex<LayerState>>,
+}
+
+struct Layer {
+    synthesizer: Box<dyn SynthBackend>,
+    sequencer: Sequencer,
+    volume: Ramp,
+}
+
+impl Layer {
+    /// Add rendered audio of the layer to the output, applying its volume
+    fn mix(
+        &mut self,
+        source_left: &[f32],
+        source_right: &[f32],
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        for (index, (l, r)) in source_left.iter().zip(source_right).enumerate() {
+            let volume = self.volume.next();
+            left[index] += l * volume;
+            right[index] += r * volume;
+        }
+    }
+}
+
+pub(crate) struct LayerRenderer {
+    program: Arc<LayerProgram>,
+    layers: Vec<Layer>,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    speed: f64,
+}
+
+impl LayerRenderer {
+    pub(crate) fn new(synthesizers: &SynthFactory, program: Arc<LayerProgram>) -> Self {
+        let volumes = program.state.lock().unwrap().volumes.clone();
+        let layers = program
+            .songs
+            .iter()
+            .zip(volumes)
+            .map(|(song, volume)| Layer {
+                synthesizer: synthesizers.create(),
+                sequencer: Sequencer::new(song.clone()),
+                volume: Ramp::new(volume),
+            })
+            .collect();
+        Self {
+            program,
+            layers,
+            left: Vec::new(),
+            right: Vec::new(),
+            speed: 1.0,
+        }
+    }
+
+    fn sync_state(&mut self) {
+        let mut state = self.program.state.lock().unwrap();
+        for (index, volume, duration) in state.requests.drain(..) {
+            if let Some(layer) = self.layers.get_mut(index) {
+                let sample_rate = layer.synthesizer.sample_rate() as f32;
+                let frames = (duration.as_secs_f32() * sample_rate) as u64;
+                layer.volume.start(volume, frames);
+            }
+        }
+        for layer in &mut self.layers {
+            layer.sequencer.set_speed(state.speed * self.speed);
+            layer.sequencer.set_transpose(state.transpose);
+        }
+        state.volumes.clear();
+        state
+            .volumes
+            .extend(self.layers.iter().map(|layer| layer.volume.value()));
+    }
+}
+
+impl MidiRender for LayerRenderer {
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        let Some(block_size) = self
+            .layers
+            .first()
+            .map(|layer| layer.synthesizer.block_size())
+        else {
+            return 0;
+        };
+        self.left.resize(block_size, 0.0);
+        self.right.resize(block_size, 0.0);
+        left.fill(0.0);
+        right.fill(0.0);
+
+        let mut wrote = 0;
+        while wrote < left.len() {
+            self.sync_state();
+            if self
+                .layers
+                .iter()
+                .all(|layer| layer.sequencer.end_of_sequence())
+            {
+                if !self.program.looping {
+                    break;
+                }
+                for layer in &mut self.layers {
+                    layer.synthesizer.note_off_all(false);
+                    layer.sequencer = Sequencer::new(layer.sequencer.song().clone());
+                }
+                self.sync_state();
+            }
+
+            let len = block_size.min(left.len() - wrote);
+            for layer in &mut self.layers {
+                let (layer_left, layer_right) = (&mut self.left[..len], &mut self.right[..len]);
+                let rendered =
+                    layer
+                        .sequencer
+                        .render(&mut *layer.synthesizer, layer_left, layer_right);
+                // Let finished layers ring out while the others keep playing
+                layer
+                    .synthesizer
+                    .render(&mut layer_left[rendered..], &mut layer_right[rendered..]);
+                layer.mix(
+                    layer_left,
+                    layer_right,
+                    &mut left[wrote..wrote + len],
+                    &mut right[wrote..wrote + len],
+                );
+            }
+            wrote += len;
+        }
+        wrote
+    }
+
+    fn render_tail(&mut self, left: &mut [f32], right: &mut [f32]) {
+        left.fill(0.0);
+        right.fill(0.0);
+        self.left.resize(left.len(), 0.0);
+        self.right.resize(right.len(), 0.0);
+        self.sync_state();
+        for layer in &mut self.layers {
+            layer.synthesizer.render(&mut self.left, &mut self.right);
+            layer.mix(&self.left, &self.right, left, right);
+        }
+    }
+
+    fn seek(&mut self, position: f64) {
+        for layer in &mut self.layers {
+            layer.sequencer.seek(&mut *layer.synthesizer, position);
+        }
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+
+    fn first_note(&self) -> Option<f64> {
+        self.layers
+            .iter()
+            .filter_map(|layer| layer.sequencer.song().first_note())
+            .min_by(f64::total_cmp)
+    }
+
+    fn sounding_notes(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| layer.sequencer.sounding_notes())
+            .sum()
+    }
+}
+
+type QueuedLayerFilter = (QueuedFilter, Without<Handle<MidiSource>>);
+
+pub(crate) fn prepare_layer_players(
+    mut commands: Commands,
+    midi_assets: Res<Assets<MidiAudio>>,
+    mut sources: ResMut<Assets<MidiSource>>,
+    query: Query<(Entity, &MidiLayerPlayer, SourceOptions), QueuedLayerFilter>,
+) {
+    'players: for (entity, player, (control, sync)) in &query {
+        let mut songs = Vec::with_capacity(player.layers.len());
+        for layer in &player.layers {
+            let Some(audio) = midi_assets.get(&layer.audio) else {
+                continue 'players;
+            };
+            match audio.to_song_tracks(layer.tracks.as_deref()) {
+                Ok(song) => songs.push(Arc::new(song)),
+                Err(error) => {
+                    error!("Failed to read layer '{}': {error}", layer.name);
+                    commands.entity(entity).remove::<MidiLayerPlayer>();
+                    continue 'players;
+                }
+            }
+        }
+
+        let control = control.cloned().unwrap_or_default();
+        let source = sources.add(MidiSource {
+            program: SourceProgram::Layers(Arc::new(LayerProgram {
+                songs,
+                looping: player.looping,
+                state: player.state.clone(),
+            })),
+            control: control.clone(),
+            sync: sync.cloned(),
+        });
+        commands.entity(entity).insert((source, control));
+    }
+}
+
+pub(crate) fn prepare_overlay_players(
+    mut commands: Commands,
+    midi_assets: Res<Assets<MidiAudio>>,
+    mut sources: ResMut<Assets<MidiSource>>,
+    query: Query<(Entity, &MidiOverlayPlayer, SourceOptions), QueuedLayerFilter>,
+) {
+    for (entity, player, (control, sync)) in &query {
+        let Some(parts) = player
+            .parts
+            .iter()
+            .map(|part| midi_assets.get(part))
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        let song = parts
+            .split_first()
+            .map_or(Ok(MidiAudio::Sequence(Vec::new())), |(first, rest)| {
+                rest.iter()
+                    .try_fold((*first).clone(), |overlay, part| overlay.merge(part))
+            })
+            .and_then(|overlay| overlay.to_song());
+        let song = match song {
+            Ok(song) => song,
+            Err(error) => {
+                error!("Failed to overlay MIDI audio: {error}");
+                commands.entity(entity).remove::<MidiOverlayPlayer>();
+                continue;
+            }
+        };
+
+        let control = control.cloned().unwrap_or_default();
+        let source = sources.add(MidiSource {
+            program: SourceProgram::Song(Arc::new(song)),
+            control: control.clone(),
+            sync: sync.cloned(),
+        });
+        commands.entity(entity).insert((source, control));
+    }
+}
@@ -2,44 +2,656 @@
 
 //! A plugin which adds MIDI file and soundfont audio support to the [bevy](https://crates.io/crates/bevy) engine via [rustysynth](https://crates.io/crates/rustysynth).
 
-use bevy::{audio::AddAudioSource, prelude::*};
+use async_channel::{Receiver, Sender};
+#[cfg(target_arch = "wasm32")]
+use bevy::tasks::TaskPool;
+use bevy::{
+    asset::LoadState,
+    audio::{AddAudioSource, AudioPlugin},
+    prelude::*,
+    tasks::AsyncComputeTaskPool,
+    transform::TransformSystem,
+};
 use rustysynth::SoundFont;
 use std::{
-    io::{Cursor, Read},
-    sync::{Arc, OnceLock},
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
 };
 
+mod analysis;
+pub use analysis::*;
+
 mod assets;
 pub use assets::*;
 
+mod automation;
+pub use automation::*;
+
+mod backend;
+pub use backend::*;
+
+mod budget;
+pub use budget::*;
+
+mod channels;
+pub use channels::*;
+
+mod chords;
+pub use chords::*;
+
+mod clock;
+pub use clock::*;
+
+mod control;
+pub use control::*;
+
+mod crossfade;
+pub use crossfade::*;
+
+mod decoder;
+pub use decoder::*;
+
+mod diagnostics;
+pub use diagnostics::*;
+
+mod dither;
+pub use dither::*;
+
+mod drums;
+pub use drums::*;
+
+mod error;
+pub use error::*;
+
+mod euclidean;
+pub use euclidean::*;
+
+mod export;
+pub use export::*;
+
+#[cfg(feature = "flac")]
+mod flac;
+
+#[cfg(feature = "generative")]
+mod generative;
+#[cfg(feature = "generative")]
+pub use generative::*;
+
+mod groove;
+pub use groove::*;
+
+mod headless;
+pub use headless::*;
+
+mod input;
+pub use input::*;
+
+mod intensity;
+pub use intensity::*;
+
+mod karaoke;
+pub use karaoke::*;
+
+#[cfg(feature = "kira")]
+mod kira_backend;
+#[cfg(feature = "kira")]
+pub use kira_backend::*;
+
+mod layers;
+pub use layers::*;
+
+mod metering;
+pub use metering::*;
+
+mod metronome;
+pub use metronome::*;
+
+mod midi;
+
+mod mpe;
+pub use mpe::*;
+
+mod music;
+pub use music::*;
+
+mod netsync;
+pub use netsync::*;
+
+mod oscillator;
+pub use oscillator::*;
+
+mod pianoroll;
+pub use pianoroll::*;
+
+mod playlist;
+pub use playlist::*;
+
+mod pressure;
+pub use pressure::*;
+
+mod programs;
+pub use programs::*;
+
+mod quantize;
+pub use quantize::*;
+
+mod recording;
+pub use recording::*;
+
+#[cfg(feature = "reflect")]
+mod reflect;
+#[cfg(feature = "reflect")]
+pub use reflect::*;
+
+mod reload;
+pub use reload::*;
+
+mod render;
+
+mod resample;
+
+mod routing;
+pub use routing::*;
+
+mod sampler;
+pub use sampler::*;
+
+mod segments;
+pub use segments::*;
+
+mod sequencer;
+
+mod settings;
+pub use settings::*;
+
+mod sfx;
+pub use sfx::*;
+
+mod soundfont;
+pub use soundfont::*;
+
+#[cfg(feature = "state")]
+mod state;
+#[cfg(feature = "state")]
+pub use state::*;
+
+mod stems;
+pub use stems::*;
+
+mod subset;
+pub use subset::*;
+
+mod sync;
+pub use sync::*;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+mod transport;
+pub use transport::*;
+
+mod velocity;
+pub use velocity::*;
+
 #[cfg(feature = "hl4mgm")]
 pub(crate) static HL4MGM: &[u8] = include_bytes!("./embedded_assets/hl4mgm.sf2");
 
-pub(crate) static SOUNDFONT: OnceLock<Arc<SoundFont>> = OnceLock::new();
+/// Tiny soundfont playing every instrument as a sine wave, for tests and examples which don't
+/// need realistic sound. It's built by `cargo run --example test_soundfont`.
+#[cfg(feature = "test-sf")]
+pub static TEST_SOUNDFONT: &[u8] = include_bytes!("./embedded_assets/test.sf2");
+
+/// Soundfont of the plugin, which is `None` if it was configured without one
+pub(crate) static SOUNDFONT: OnceLock<Option<Arc<SoundFont>>> = OnceLock::new();
+
+/// Whether a [`RustySynthPlugin`] was built, so [`SOUNDFONT`] will be set
+static SOUNDFONT_CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+/// Soundfont chosen through [`MidiActiveSoundFont`], replacing the plugin's for new playback
+static ACTIVE_SOUNDFONT: Mutex<Option<Arc<SoundFont>>> = Mutex::new(None);
+
+type PendingSoundFont = Box<dyn FnOnce() -> Option<SoundFont> + Send>;
+
+/// Parses the soundfont of a plugin configured with [`RustySynthPlugin::lazy`], until the first
+/// source which needs it takes it
+static PENDING_SOUNDFONT: Mutex<Option<PendingSoundFont>> = Mutex::new(None);
+
+/// Channel closed once [`SOUNDFONT`] has been set, waking render tasks waiting for it
+static SOUNDFONT_READY: OnceLock<(Sender<()>, Receiver<()>)> = OnceLock::new();
+
+fn soundfont_ready() -> &'static (Sender<()>, Receiver<()>) {
+    SOUNDFONT_READY.get_or_init(|| async_channel::bounded(1))
+}
+
+fn set_soundfont(soundfont: Option<SoundFont>) {
+    let _ = SOUNDFONT.set(soundfont.map(Arc::new));
+    soundfont_ready().0.close();
+}
+
+/// Start parsing a lazily parsed soundfont in the background, if that hasn't started yet
+fn parse_pending_soundfont() {
+    let Some(parse) = PENDING_SOUNDFONT.lock().unwrap().take() else {
+        return;
+    };
+    AsyncComputeTaskPool::get()
+        .spawn(async move { set_soundfont(parse()) })
+        .detach();
+}
+
+fn set_active_soundfont(soundfont: Option<Arc<SoundFont>>) {
+    *ACTIVE_SOUNDFONT.lock().unwrap() = soundfont;
+}
+
+/// Soundfont new playback should use, or `None` if the plugin's is still loading
+pub(crate) fn ready_soundfont() -> Option<Option<Arc<SoundFont>>> {
+    match ACTIVE_SOUNDFONT.lock().unwrap().clone() {
+        Some(soundfont) => Some(Some(soundfont)),
+        None => {
+            parse_pending_soundfont();
+            SOUNDFONT.get().cloned()
+        }
+    }
+}
+
+/// Soundfont new playback should use, without waiting for the plugin's to finish loading
+pub(crate) fn current_soundfont() -> Option<Arc<SoundFont>> {
+    ready_soundfont().flatten()
+}
+
+/// Whether a soundfont was chosen for new playback, which may still be loading
+#[cfg(feature = "bevy_kira_audio")]
+pub(crate) fn soundfont_configured() -> bool {
+    SOUNDFONT_CONFIGURED.load(Ordering::Acquire) || ACTIVE_SOUNDFONT.lock().unwrap().is_some()
+}
+
+/// Wait for the soundfont new playback should use to finish loading
+pub(crate) async fn soundfont() -> Option<Arc<SoundFont>> {
+    if let Some(soundfont) = ACTIVE_SOUNDFONT.lock().unwrap().clone() {
+        return Some(soundfont);
+    }
+    if SOUNDFONT.get().is_none() {
+        parse_pending_soundfont();
+        let _ = soundfont_ready().1.recv().await;
+    }
+    SOUNDFONT.get().unwrap().clone()
+}
+
+/// Load state of the plugin's soundfont, which hasn't started loading while it's waiting to be
+/// parsed lazily
+pub(crate) fn soundfont_load_state() -> LoadState {
+    if SOUNDFONT.get().is_some() {
+        LoadState::Loaded
+    } else if PENDING_SOUNDFONT.lock().unwrap().is_some() {
+        LoadState::NotLoaded
+    } else {
+        LoadState::Loading
+    }
+}
+
+/// Read the plugin's soundfont, treating empty data as no soundfont
+fn read_soundfont(
+    mut reader: impl Read,
+    presets: Option<&SoundFontPresets>,
+) -> Result<Option<SoundFont>, MidiError> {
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(MidiError::SoundFont)?;
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    if let Some(presets) = presets {
+        bytes = presets.subset(&bytes)?;
+    }
+    Ok(Some(
+        SoundFont::new(&mut bytes.as_slice()).map_err(MidiError::soundfont)?,
+    ))
+}
+
+/// Error reading the plugin's soundfont, until it's reported as a [`MidiSoundFontError`]
+static SOUNDFONT_ERROR: Mutex<Option<MidiError>> = Mutex::new(None);
+
+/// Read the plugin's soundfont, falling back to no soundfont if it can't be read
+fn load_soundfont(reader: impl Read, presets: Option<&SoundFontPresets>) -> Option<SoundFont> {
+    read_soundfont(reader, presets).unwrap_or_else(|error| {
+        error!("{error}, playing music without a soundfont");
+        *SOUNDFONT_ERROR.lock().unwrap() = Some(error);
+        None
+    })
+}
+
+/// Error reading the plugin's soundfont, if it couldn't be read and hasn't been reported yet
+pub(crate) fn take_soundfont_error() -> Option<MidiError> {
+    SOUNDFONT_ERROR.lock().unwrap().take()
+}
 
 /// This plugin configures the soundfont used for playback and registers MIDI assets.
+///
+/// Without a soundfont, music is played by a simple [`OscillatorSynth`].
 #[derive(Debug)]
 pub struct RustySynthPlugin<R: Read + Send + Sync + Clone + 'static> {
     /// Reader for soundfont data.
     pub soundfont: R,
+    /// Whether to parse the soundfont in the background once music first needs it, instead of
+    /// during startup. Startup is faster, but the first music waits for the soundfont to parse.
+    pub lazy: bool,
+    /// Banks and presets to keep from the soundfont, discarding the rest to save memory, or
+    /// `None` to keep all of them
+    pub presets: Option<SoundFontPresets>,
+}
+
+impl<R: Read + Send + Sync + Clone + 'static> RustySynthPlugin<R> {
+    /// Parse the soundfont in the background once music first needs it, instead of during
+    /// startup
+    pub fn lazy(mut self) -> Self {
+        self.lazy = true;
+        self
+    }
+
+    /// Only keep the given banks and presets of the soundfont, discarding the rest to save memory
+    pub fn with_presets(mut self, presets: SoundFontPresets) -> Self {
+        self.presets = Some(presets);
+        self
+    }
+}
+
+impl RustySynthPlugin<std::io::Cursor<Arc<[u8]>>> {
+    /// Read and parse a soundfont up front, so a corrupt one can be handled before adding the
+    /// plugin.
+    ///
+    /// A plugin whose soundfont can't be read while building plays music without it instead,
+    /// reporting a [`MidiSoundFontError`].
+    pub fn try_new(mut reader: impl Read) -> Result<Self, MidiError> {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(MidiError::SoundFont)?;
+        SoundFont::new(&mut bytes.as_slice()).map_err(MidiError::soundfont)?;
+        Ok(Self {
+            soundfont: std::io::Cursor::new(bytes.into()),
+            lazy: false,
+            presets: None,
+        })
+    }
 }
 
 #[cfg(feature = "hl4mgm")]
-impl Default for RustySynthPlugin<Cursor<&[u8]>> {
+impl Default for RustySynthPlugin<std::io::Cursor<&[u8]>> {
     fn default() -> Self {
         Self {
-            soundfont: Cursor::new(HL4MGM),
+            soundfont: std::io::Cursor::new(HL4MGM),
+            lazy: false,
+            presets: None,
+        }
+    }
+}
+
+impl RustySynthPlugin<std::io::Empty> {
+    /// Play music without a soundfont, using the built-in [`OscillatorSynth`]
+    pub fn without_soundfont() -> Self {
+        Self {
+            soundfont: std::io::empty(),
+            lazy: false,
+            presets: None,
+        }
+    }
+}
+
+#[cfg(feature = "test-sf")]
+impl RustySynthPlugin<std::io::Cursor<&'static [u8]>> {
+    /// Use the tiny embedded [`TEST_SOUNDFONT`]
+    pub fn test_soundfont() -> Self {
+        Self {
+            soundfont: std::io::Cursor::new(TEST_SOUNDFONT),
+            lazy: false,
+            presets: None,
         }
     }
 }
 
 impl<R: Read + Send + Sync + Clone + 'static> Plugin for RustySynthPlugin<R> {
     fn build(&self, app: &mut App) {
-        let _ = SOUNDFONT.set(Arc::new(
-            SoundFont::new(&mut self.soundfont.clone()).unwrap(),
-        ));
-        app.add_audio_source::<MidiAudio>()
-            .init_asset::<MidiAudio>()
-            .init_asset_loader::<MidiAssetLoader>();
+        SOUNDFONT_CONFIGURED.store(true, Ordering::Release);
+        if self.lazy {
+            let soundfont = self.soundfont.clone();
+            let presets = self.presets.clone();
+            *PENDING_SOUNDFONT.lock().unwrap() = Some(Box::new(move || {
+                load_soundfont(soundfont, presets.as_ref())
+            }));
+        } else {
+            // Parsing a large soundfont would stall the browser's main thread during startup, so
+            // it's done on a task there, with sources waiting for it before they render
+            #[cfg(target_arch = "wasm32")]
+            {
+                let soundfont = self.soundfont.clone();
+                let presets = self.presets.clone();
+                AsyncComputeTaskPool::get_or_init(TaskPool::default)
+                    .spawn(
+                        async move { set_soundfont(load_soundfont(soundfont, presets.as_ref())) },
+                    )
+                    .detach();
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            set_soundfont(load_soundfont(
+                self.soundfont.clone(),
+                self.presets.as_ref(),
+            ));
+        }
+        // Without bevy's audio output, sources can still be played by the `HeadlessMidiPlugin`
+        if app.is_plugin_added::<AudioPlugin>() {
+            app.add_audio_source::<MidiAudio>()
+                .add_audio_source::<MidiSource>();
+        }
+        app.init_asset::<MidiAudio>()
+            .init_asset::<MidiSource>()
+            .init_asset_loader::<MidiAssetLoader>()
+            .init_asset_loader::<MidiScoreLoader>()
+            .register_asset_processor::<MidiBakeProcessor>(MidiAudioSaver.into())
+            .add_plugins((
+                SourcePlugin,
+                SoundFontPlugin,
+                RenderingPlugin,
+                TransportPlugin,
+                ChannelPlugin,
+                MeteringPlugin,
+                NetworkSyncPlugin,
+            ));
+    }
+}
+
+/// Systems spawning the playback of sources, applied before their settings
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct PrepareSources;
+
+/// Playback of sources, segment graphs, layers, playlists and the music manager
+struct SourcePlugin;
+
+impl Plugin for SourcePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<MidiSegmentGraph>()
+            .init_asset::<MidiPlaylist>()
+            .init_resource::<MidiMusicManager>()
+            .init_resource::<MidiHotReload>()
+            .init_resource::<MidiSfx>()
+            .add_event::<MidiPlaylistEvent>()
+            .add_event::<MidiPlaybackError>()
+            .add_systems(
+                PostUpdate,
+                (
+                    prepare_controlled_sources,
+                    prepare_segment_players,
+                    prepare_layer_players,
+                    prepare_overlay_players,
+                    prepare_metronomes,
+                    prepare_live_inputs,
+                    spawn_sfx_synthesizer,
+                )
+                    .in_set(PrepareSources),
+            )
+            .configure_sets(
+                PostUpdate,
+                PrepareSources.before(TransformSystem::TransformPropagate),
+            )
+            .add_systems(
+                PostUpdate,
+                (update_music_manager, track_control_entities).before(prepare_controlled_sources),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    apply_start_offsets,
+                    apply_skip_silence,
+                    apply_release_tails,
+                    apply_block_lengths,
+                    apply_dry_stream_policies,
+                    apply_channel_layouts,
+                )
+                    .after(PrepareSources)
+                    .before(TransformSystem::TransformPropagate),
+            )
+            .add_systems(
+                Update,
+                (
+                    despawn_finished_sources,
+                    update_playlist_players,
+                    apply_intensity,
+                    apply_virtual_time,
+                    reload_modified_sources,
+                    report_playback_errors,
+                ),
+            );
+    }
+}
+
+/// Soundfont assets, their routing to sources and the status of the plugin's soundfont
+struct SoundFontPlugin;
+
+impl Plugin for SoundFontPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<MidiSoundFont>()
+            .init_asset_loader::<SoundFontLoader>()
+            .init_resource::<MidiSoundFontStatus>()
+            .add_event::<MidiSoundFontError>()
+            .add_systems(
+                Update,
+                (
+                    apply_active_soundfont,
+                    apply_soundfont_routing,
+                    update_soundfont_status,
+                    report_soundfont_errors,
+                ),
+            );
+    }
+}
+
+/// Render settings, synthesizer backends and the render budget
+struct RenderingPlugin;
+
+impl Plugin for RenderingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MidiRenderSettings>()
+            .init_resource::<MidiSynthBackend>()
+            .add_event::<MidiUnderrunEvent>()
+            .add_systems(
+                Update,
+                (
+                    apply_render_settings,
+                    apply_synth_backend,
+                    enforce_render_budget,
+                    report_underruns,
+                    pause_on_suspend,
+                ),
+            )
+            .add_systems(
+                Update,
+                prewarm_synthesizers
+                    .after(apply_render_settings)
+                    .after(apply_synth_backend),
+            )
+            .add_systems(
+                PostUpdate,
+                apply_priorities
+                    .after(PrepareSources)
+                    .before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+/// The shared transport, sync groups, quantized starts and MIDI clock
+struct TransportPlugin;
+
+impl Plugin for TransportPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MidiTransport>()
+            .add_event::<MidiClockEvent>()
+            .add_systems(Update, (start_sync_groups, update_transport))
+            .add_systems(Update, send_midi_clock.after(update_transport))
+            .add_systems(
+                PostUpdate,
+                quantize_starts
+                    .after(PrepareSources)
+                    .before(TransformSystem::TransformPropagate),
+            );
+        #[cfg(feature = "midi-out")]
+        app.add_systems(Update, forward_midi_clock.after(send_midi_clock));
+    }
+}
+
+/// Per-channel controls of sources: controllers, programs, velocity, drums, pressure, MPE,
+/// grooves and recording
+struct ChannelPlugin;
+
+impl Plugin for ChannelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<MidiProgramChangeEvent>().add_systems(
+            Update,
+            (
+                animate_controllers,
+                apply_channel_controls,
+                watch_program_changes,
+                apply_velocity_curves,
+                apply_drum_channels,
+                apply_pressure_targets,
+                apply_mpe_zones,
+                apply_grooves,
+                attach_recorders,
+            ),
+        );
+    }
+}
+
+/// Levels, analyzers, envelope followers and piano rolls
+struct MeteringPlugin;
+
+impl Plugin for MeteringPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<MidiEnvelopeEvent>()
+            .add_systems(
+                Update,
+                (
+                    update_levels,
+                    update_analyzers,
+                    follow_envelopes,
+                    update_piano_rolls,
+                ),
+            )
+            .add_systems(
+                PostUpdate,
+                attach_analyzers
+                    .after(PrepareSources)
+                    .before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+/// Capturing and applying the playback state of sources shared over the network
+struct NetworkSyncPlugin;
+
+impl Plugin for NetworkSyncPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (capture_sync_states, apply_sync_states));
     }
 }
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::MidiControl;
+
+/// Number of frames each level measurement covers
+const METER_WINDOW: usize = 1024;
+
+/// Component exposing the output levels of a MIDI source, for VU meters and reactive visuals.
+///
+/// Levels are measured over short windows of the output after gain has been applied, and are
+/// updated every frame while the source plays.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct MidiLevels {
+    /// Peak absolute sample value of the left and right channels
+    pub peak: [f32; 2],
+    /// Root mean square of the left and right channels
+    pub rms: [f32; 2],
+}
+
+impl MidiLevels {
+    /// Highest peak of both channels
+    pub fn max_peak(&self) -> f32 {
+        self.peak[0].max(self.peak[1])
+    }
+
+    /// Root mean square of both channels combined
+    pub fn mean_rms(&self) -> f32 {
+        ((self.rms[0] * self.rms[0] + self.rms[1] * self.rms[1]) / 2.0).sqrt()
+    }
+}
+
+/// Accumulates output samples into level measurements
+#[derive(Debug, Default)]
+pub(crate) struct LevelMeter {
+    frames: usize,
+    sum_squares: [f32; 2],
+    peak: [f32; 2],
+}
+
+impl LevelMeter {
+    /// Add a sample of the given channel, returning the levels once a window is complete
+    pub(crate) fn push(&mut self, channel: usize, value: f32) -> Option<MidiLevels> {
+        self.sum_squares[channel] += value * value;
+        self.peak[channel] = self.peak[channel].max(value.abs());
+        if channel == 0 {
+            return None;
+        }
+        self.frames += 1;
+        if self.frames < METER_WINDOW {
+            return None;
+        }
+        let levels = MidiLevels {
+            peak: self.peak,
+            rms: self
+                .sum_squares
+                .map(|sum| (sum / self.frames as f32).sqrt()),
+        };
+        *self = Self::default();
+        Some(levels)
+    }
+}
+
+/// Component making a MIDI source periodically send [`MidiEnvelopeEvent`]s with the loudness of
+/// its output, smoothed by an envelope follower.
+#[derive(Component, Clone, Debug)]
+pub struct MidiEnvelopeFollower {
+    /// Time between events
+    pub interval: Duration,
+    /// Time taken for the envelope to rise to a louder level
+    pub attack: Duration,
+    /// Time taken for the envelope to fall to a quieter level
+    pub release: Duration,
+    envelope: f32,
+    elapsed: Duration,
+}
+
+impl Default for MidiEnvelopeFollower {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(50),
+            attack: Duration::from_millis(10),
+            release: Duration::from_millis(300),
+            envelope: 0.0,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+impl MidiEnvelopeFollower {
+    /// Construct a follower sending an event every `interval`
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            ..default()
+        }
+    }
+
+    /// Current value of the envelope
+    pub fn envelope(&self) -> f32 {
+        self.envelope
+    }
+}
+
+/// Event carrying the amplitude envelope of a source with a [`MidiEnvelopeFollower`]
+#[derive(Event, Clone, Copy, Debug)]
+pub struct MidiEnvelopeEvent {
+    /// Entity playing the source
+    pub entity: Entity,
+    /// Smoothed root mean square level of the output
+    pub envelope: f32,
+    /// Peak level of the output since the previous measurement
+    pub peak: f32,
+}
+
+pub(crate) fn follow_envelopes(
+    time: Res<Time>,
+    mut query: Query<(Entity, &MidiControl, &mut MidiEnvelopeFollower)>,
+    mut events: EventWriter<MidiEnvelopeEvent>,
+) {
+    for (entity, control, mut follower) in &mut query {
+        let levels = control.levels();
+        let target = levels.mean_rms();
+        let smoothing = if target > follower.envelope {
+            follower.attack
+        } else {
+            follower.release
+        };
+        let amount = if smoothing.is_zero() {
+            1.0
+        } else {
+            1.0 - (-time.delta_seconds() / smoothing.as_secs_f32()).exp()
+        };
+        follower.envelope += (target - follower.envelope) * amount;
+
+        follower.elapsed += time.delta();
+        if follower.elapsed < follower.interval {
+            continue;
+        }
+        follower.elapsed = Duration::ZERO;
+        events.send(MidiEnvelopeEvent {
+            entity,
+            envelope: follower.envelope,
+            peak: levels.max_peak(),
+        });
+    }
+}
+
+pub(crate) fn update_levels(mut query: Query<(&MidiControl, &mut MidiLevels)>) {
+    for (control, mut levels) in &mut query {
+        levels.set_if_neq(control.levels());
+    }
+}
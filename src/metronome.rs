@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+
+use crate::{
+    control::{QueuedFilter, SourceOptions},
+    decoder::SourceProgram,
+    midi::{GridUnit, TempoMap},
+    sequencer::MidiRender,
+    FollowTransport, MidiSource, MidiTransport, SynthBackend,
+};
+
+/// Percussion sounds used for metronome clicks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MetronomeClicks {
+    /// Percussion key played on the first beat of each bar
+    pub accent_key: u8,
+    /// Velocity of the accented click
+    pub accent_velocity: u8,
+    /// Percussion key played on every other beat
+    pub key: u8,
+    /// Velocity of the regular click
+    pub velocity: u8,
+}
+
+impl Default for MetronomeClicks {
+    fn default() -> Self {
+        Self {
+            accent_key: 76,
+            accent_velocity: 127,
+            key: 77,
+            velocity: 100,
+        }
+    }
+}
+
+impl MetronomeClicks {
+    /// Key and velocity of a click
+    pub(crate) fn click(&self, accent: bool) -> (u8, u8) {
+        if accent {
+            (self.accent_key.min(127), self.accent_velocity.min(127))
+        } else {
+            (self.key.min(127), self.velocity.min(127))
+        }
+    }
+}
+
+/// Component which plays a metronome following the [`MidiTransport`].
+///
+/// Spawn it together with [`PlaybackSettings`]. Clicks use the tempo and time signature of the
+/// transport at the time playback starts.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct MidiMetronome {
+    /// Sounds of the clicks
+    pub clicks: MetronomeClicks,
+}
+
+/// Component which counts in before a [`Handle<MidiAudio>`](crate::MidiAudio) on the same
+/// entity starts, using the audio's initial tempo and time signature.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct MidiCountIn {
+    /// Number of bars to count in
+    pub bars: u32,
+    /// Sounds of the clicks
+    pub clicks: MetronomeClicks,
+}
+
+impl MidiCountIn {
+    /// Count in for the given number of bars
+    pub fn bars(bars: u32) -> Self {
+        Self {
+            bars,
+            clicks: MetronomeClicks::default(),
+        }
+    }
+}
+
+/// Metronome with its tempo resolved, ready for rendering
+#[derive(Debug)]
+pub(crate) struct MetronomeProgram {
+    tempo: TempoMap,
+    clicks: MetronomeClicks,
+}
+
+pub(crate) struct MetronomeRenderer {
+    synthesizer: Box<dyn SynthBackend>,
+    program: Arc<MetronomeProgram>,
+    position: f64,
+    next_beat: u64,
+    block_wrote: usize,
+    speed: f64,
+}
+
+impl MetronomeRenderer {
+    pub(crate) fn new(synthesizer: Box<dyn SynthBackend>, program: Arc<MetronomeProgram>) -> Self {
+        Self {
+            block_wrote: synthesizer.block_size(),
+            synthesizer,
+            program,
+            position: 0.0,
+            next_beat: 0,
+            speed: 1.0,
+        }
+    }
+
+    fn play_clicks(&mut self) {
+        let tempo = &self.program.tempo;
+        while tempo.seconds(self.next_beat) <= self.position {
+            let (_, beat, _) = tempo.bar_beat_tick(self.next_beat);
+            let (key, velocity) = self.program.clicks.click(beat == 0);
+            self.synthesizer.note_off(9, key as i32);
+            self.synthesizer.note_on(9, key as i32, velocity as i32);
+            self.next_beat = tempo.next_boundary(self.next_beat + 1, GridUnit::Beat);
+        }
+    }
+}
+
+impl MidiRender for MetronomeRenderer {
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        let block_size = self.synthesizer.block_size();
+        let sample_rate = self.synthesizer.sample_rate() as f64;
+        let mut wrote = 0;
+        while wrote < left.len() {
+            if self.block_wrote >= block_size {
+                self.play_clicks();
+                self.position += self.speed * block_size as f64 / sample_rate;
+                self.block_wrote = 0;
+            }
+            let len = (block_size - self.block_wrote).min(left.len() - wrote);
+            self.synthesizer.render(
+                &mut left[wrote..wrote + len],
+                &mut right[wrote..wrote + len],
+            );
+            self.block_wrote += len;
+            wrote += len;
+        }
+        wrote
+    }
+
+    fn seek(&mut self, position: f64) {
+        self.synthesizer.reset();
+        let tempo = &self.program.tempo;
+        self.position = position.max(0.0);
+        let tick = tempo.ticks(self.position).ceil() as u64;
+        self.next_beat = tempo.next_boundary(tick, GridUnit::Beat);
+        self.block_wrote = self.synthesizer.block_size();
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.max(0.0);
+    }
+
+    fn render_tail(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.synthesizer.render(left, right);
+    }
+}
+
+type QueuedMetronomeFilter = (QueuedFilter, Without<Handle<MidiSource>>);
+
+pub(crate) fn prepare_metronomes(
+    mut commands: Commands,
+    transport: Res<MidiTransport>,
+    mut sources: ResMut<Assets<MidiSource>>,
+    query: Query<(Entity, &MidiMetronome, SourceOptions), QueuedMetronomeFilter>,
+) {
+    for (entity, metronome, (control, sync)) in &query {
+        let control = control.cloned().unwrap_or_default();
+        let source = sources.add(MidiSource {
+            program: SourceProgram::Metronome(Arc::new(MetronomeProgram {
+                tempo: transport.tempo().clone(),
+                clicks: metronome.clicks,
+            })),
+            control: control.clone(),
+            sync: sync.cloned(),
+        });
+        commands
+            .entity(entity)
+            .insert((source, control, FollowTransport));
+    }
+}
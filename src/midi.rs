@@ -0,0 +1,1892 @@
+use std::io;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use crate::{
+    MetronomeClicks, MidiAudio, MidiDynamics, MidiError, MidiGroove, MidiNote, MidiQuantize,
+    ScoreEvent, TimedMidiNote,
+};
+
+/// Ticks per quarter note used for songs built from note sequences
+pub(crate) const SEQUENCE_DIVISION: u16 = 480;
+
+/// Default tempo of a standard MIDI file in microseconds per quarter note
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Length of a beat of sequences and timelines, which play at the default tempo of 120 BPM
+const SEQUENCE_BEAT: Duration = Duration::from_millis(500);
+
+/// Ticks between the tempo changes making up a tempo ramp of a score, a 32nd note
+const TEMPO_RAMP_STEP: u64 = SEQUENCE_DIVISION as u64 / 8;
+
+/// Bank select values (CC0, CC32) choosing a soundfont bank on a channel, where the percussion
+/// banks of channel 9 are numbered from 128
+pub(crate) fn bank_select(channel: u8, bank: i32) -> (u8, u8) {
+    let bank = bank.clamp(0, 0x3FFF);
+    match bank {
+        0..=127 => (bank as u8, 0),
+        128..=255 if channel == 9 => ((bank - 128) as u8, 0),
+        _ => ((bank >> 7) as u8, (bank & 0x7F) as u8),
+    }
+}
+
+/// Soundfont bank chosen by bank select values on a channel, the reverse of [`bank_select`]
+pub(crate) fn bank_number(channel: u8, msb: u8, lsb: u8) -> u16 {
+    match (channel, lsb) {
+        (9, 0) => 128 + msb as u16,
+        (_, 0) => msb as u16,
+        _ => (msb as u16) << 7 | lsb as u16,
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// A channel voice message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct MidiMessage {
+    pub(crate) status: u8,
+    pub(crate) data1: u8,
+    pub(crate) data2: u8,
+}
+
+impl MidiMessage {
+    pub(crate) fn channel(&self) -> u8 {
+        self.status & 0x0F
+    }
+
+    pub(crate) fn command(&self) -> u8 {
+        self.status & 0xF0
+    }
+
+    /// Name of the kind of message, for tracing
+    #[cfg(feature = "tracing")]
+    pub(crate) fn kind(&self) -> &'static str {
+        match self.command() {
+            0x80 => "note_off",
+            0x90 if self.data2 == 0 => "note_off",
+            0x90 => "note_on",
+            0xA0 => "key_pressure",
+            0xB0 => "control_change",
+            0xC0 => "program_change",
+            0xD0 => "channel_pressure",
+            _ => "pitch_bend",
+        }
+    }
+}
+
+/// Number of data bytes following a channel message status byte
+fn data_len(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+/// An event within a track of a standard MIDI file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum EventKind {
+    Channel(MidiMessage),
+    Meta(u8, Vec<u8>),
+    SysEx(Vec<u8>),
+}
+
+/// An event with its absolute tick within the track
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TrackEvent {
+    pub(crate) tick: u64,
+    pub(crate) kind: EventKind,
+}
+
+impl TrackEvent {
+    /// The message of a note-on event
+    fn note_on(&self) -> Option<&MidiMessage> {
+        match &self.kind {
+            EventKind::Channel(message) if message.command() == 0x90 && message.data2 > 0 => {
+                Some(message)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parsed contents of a standard MIDI file
+#[derive(Clone, Debug)]
+pub(crate) struct Smf {
+    /// 0 for a single track, 1 for simultaneous tracks, 2 for independent songs
+    pub(crate) format: u16,
+    pub(crate) division: u16,
+    pub(crate) tracks: Vec<Vec<TrackEvent>>,
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn u8(&mut self) -> io::Result<u8> {
+        let byte = *self
+            .data
+            .get(self.position)
+            .ok_or_else(|| invalid("Unexpected end of MIDI data."))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self.position + len;
+        let bytes = self
+            .data
+            .get(self.position..end)
+            .ok_or_else(|| invalid("Unexpected end of MIDI data."))?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn var_len(&mut self) -> io::Result<u32> {
+        let mut value = 0_u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("Variable length value is too long."))
+    }
+}
+
+/// Find the standard MIDI file wrapped in an RMID (RIFF MIDI) file
+fn unwrap_rmid(data: &[u8]) -> Option<&[u8]> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"RMID" {
+        return None;
+    }
+    let mut position = 12;
+    while let Some(header) = data.get(position..position + 8) {
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let chunk = data.get(position + 8..position + 8 + len)?;
+        if &header[0..4] == b"data" {
+            return Some(chunk);
+        }
+        // Chunks are padded to an even length
+        position += 8 + len + len % 2;
+    }
+    None
+}
+
+impl Smf {
+    /// Parse a standard MIDI file, which may be wrapped in an RMID file
+    pub(crate) fn parse(data: &[u8]) -> io::Result<Self> {
+        Self::parse_with(data, None)
+    }
+
+    /// Parse a standard MIDI file, tolerating common defects such as truncated data and stray
+    /// bytes. Returns a description of each defect found alongside the file.
+    pub(crate) fn parse_lenient(data: &[u8]) -> io::Result<(Self, Vec<String>)> {
+        let mut warnings = Vec::new();
+        let smf = Self::parse_with(data, Some(&mut warnings))?;
+        Ok((smf, warnings))
+    }
+
+    /// Parse a file, collecting defects in `warnings` instead of failing if it is given
+    fn parse_with(data: &[u8], mut warnings: Option<&mut Vec<String>>) -> io::Result<Self> {
+        let data = unwrap_rmid(data).unwrap_or(data);
+        let mut reader = ByteReader { data, position: 0 };
+        if reader.bytes(4)? != b"MThd" {
+            return Err(invalid("Missing MThd chunk."));
+        }
+        let header_len = reader.u32()? as usize;
+        if header_len < 6 {
+            return Err(invalid("MThd chunk is too short."));
+        }
+        let format = reader.u16()?;
+        let track_count = reader.u16()?;
+        let division = reader.u16()?;
+        reader.bytes(header_len - 6)?;
+        if format > 2 {
+            return Err(invalid("Unsupported MIDI file format."));
+        }
+
+        let mut tracks = Vec::with_capacity(track_count as usize);
+        while tracks.len() < track_count as usize {
+            let lenient = warnings.is_some();
+            if lenient && reader.position + 8 > data.len() {
+                warn(
+                    &mut warnings,
+                    format!("File ends after {} of {track_count} tracks.", tracks.len()),
+                );
+                break;
+            }
+            let chunk_type = reader.bytes(4)?;
+            let len = reader.u32()? as usize;
+            let chunk = match reader.bytes(len) {
+                Ok(chunk) => chunk,
+                Err(_) if lenient => {
+                    warn(
+                        &mut warnings,
+                        format!("Track {} is truncated.", tracks.len()),
+                    );
+                    let chunk = &data[reader.position..];
+                    reader.position = data.len();
+                    chunk
+                }
+                Err(error) => return Err(error),
+            };
+            if chunk_type == b"MTrk" {
+                tracks.push(Self::parse_track(chunk, warnings.as_deref_mut())?);
+            }
+        }
+        if reader.position < data.len() {
+            let trailing = data.len() - reader.position;
+            warn(
+                &mut warnings,
+                format!("Ignored {trailing} bytes after the last track."),
+            );
+        }
+
+        Ok(Self {
+            format,
+            division,
+            tracks,
+        })
+    }
+
+    fn parse_track(
+        data: &[u8],
+        mut warnings: Option<&mut Vec<String>>,
+    ) -> io::Result<Vec<TrackEvent>> {
+        let mut reader = ByteReader { data, position: 0 };
+        let mut events = Vec::new();
+        let mut tick = 0_u64;
+        let mut running_status = 0_u8;
+        while reader.position < data.len() {
+            let event = Self::parse_event(&mut reader, &mut running_status, &mut warnings);
+            let (delta, kind) = match event {
+                Ok(event) => event,
+                Err(error) if warnings.is_none() => return Err(error),
+                Err(error) => {
+                    warn(&mut warnings, format!("Track cut short: {error}"));
+                    break;
+                }
+            };
+            tick += delta as u64;
+            let Some(kind) = kind else {
+                continue;
+            };
+            let end = matches!(kind, EventKind::Meta(0x2F, _));
+            events.push(TrackEvent { tick, kind });
+            if end {
+                break;
+            }
+        }
+        Ok(events)
+    }
+
+    /// Read an event and the time since the previous one. Events skipped by lenient parsing are
+    /// returned as `None`, with a warning.
+    fn parse_event(
+        reader: &mut ByteReader,
+        running_status: &mut u8,
+        warnings: &mut Option<&mut Vec<String>>,
+    ) -> io::Result<(u32, Option<EventKind>)> {
+        let lenient = warnings.is_some();
+        let delta = reader.var_len()?;
+        let first = reader.u8()?;
+        let kind = match first {
+            // Meta and system exclusive events cancel running status
+            0xFF => {
+                *running_status = 0;
+                let kind = reader.u8()?;
+                let len = reader.var_len()? as usize;
+                EventKind::Meta(kind, reader.bytes(len)?.to_vec())
+            }
+            0xF0 | 0xF7 => {
+                *running_status = 0;
+                let len = reader.var_len()? as usize;
+                EventKind::SysEx(reader.bytes(len)?.to_vec())
+            }
+            // System common and real-time messages don't belong in files
+            0xF1..=0xFE if lenient => {
+                reader.bytes(match first {
+                    0xF2 => 2,
+                    0xF1 | 0xF3 => 1,
+                    _ => 0,
+                })?;
+                warn(warnings, "Skipped system messages.".to_string());
+                return Ok((delta, None));
+            }
+            0xF1..=0xFE => return Err(invalid("System message in a track.")),
+            _ => {
+                let (status, data1) = if first & 0x80 == 0 {
+                    if *running_status == 0 {
+                        if lenient {
+                            warn(warnings, "Skipped data bytes without a status.".to_string());
+                            return Ok((delta, None));
+                        }
+                        return Err(invalid("Running status without a previous status."));
+                    }
+                    (*running_status, first)
+                } else {
+                    (first, reader.u8()?)
+                };
+                *running_status = status;
+                let data2 = if data_len(status) == 2 {
+                    reader.u8()?
+                } else {
+                    0
+                };
+                EventKind::Channel(MidiMessage {
+                    status,
+                    data1,
+                    data2,
+                })
+            }
+        };
+        Ok((delta, Some(kind)))
+    }
+}
+
+/// Record a defect found by lenient parsing, once per distinct message
+fn warn(warnings: &mut Option<&mut Vec<String>>, warning: String) {
+    if let Some(warnings) = warnings {
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+}
+
+impl Smf {
+    /// Name given to a track by its first track name event
+    pub(crate) fn track_name(&self, index: usize) -> Option<&str> {
+        self.tracks
+            .get(index)?
+            .iter()
+            .find_map(|event| match &event.kind {
+                EventKind::Meta(0x03, name) => std::str::from_utf8(name).ok(),
+                _ => None,
+            })
+    }
+
+    /// One of the independent songs of a format 2 file, as a file whose only track, at index 0,
+    /// is the song's track
+    pub(crate) fn song(&self, index: usize) -> Option<Smf> {
+        Some(Smf {
+            format: 0,
+            division: self.division,
+            tracks: vec![self.tracks.get(index)?.clone()],
+        })
+    }
+
+    /// Remove the channel events of a track, keeping its tempo, marker and other meta events
+    pub(crate) fn silence_track(&mut self, index: usize) {
+        if let Some(track) = self.tracks.get_mut(index) {
+            track.retain(|event| !matches!(event.kind, EventKind::Channel(_)));
+        }
+    }
+
+    /// Remove the notes for which `keep` returns `false`, given the index of their track and
+    /// their channel, keeping every other event so instruments and controllers are set as usual
+    pub(crate) fn retain_notes(&mut self, mut keep: impl FnMut(usize, u8) -> bool) {
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            track.retain(|event| match &event.kind {
+                EventKind::Channel(message) if matches!(message.command(), 0x80 | 0x90) => {
+                    keep(index, message.channel())
+                }
+                _ => true,
+            });
+        }
+    }
+
+    /// Whether the track at `index` plays any notes
+    pub(crate) fn track_has_notes(&self, index: usize) -> bool {
+        self.tracks
+            .get(index)
+            .is_some_and(|track| track.iter().any(|event| event.note_on().is_some()))
+    }
+
+    /// Channels which any note is played on
+    pub(crate) fn note_channels(&self) -> [bool; 16] {
+        let mut channels = [false; 16];
+        for message in self.tracks.iter().flatten().filter_map(TrackEvent::note_on) {
+            channels[message.channel() as usize] = true;
+        }
+        channels
+    }
+
+    /// Move the channel events of each `(from, to)` pair of channels to the `to` channel
+    pub(crate) fn remap_channels(&mut self, map: &[(u8, u8)]) {
+        if map.is_empty() {
+            return;
+        }
+        for event in self.tracks.iter_mut().flatten() {
+            if let EventKind::Channel(message) = &mut event.kind {
+                if let Some((_, to)) = map.iter().find(|(from, _)| *from == message.channel()) {
+                    message.status = message.command() | (to & 0x0F);
+                }
+            }
+        }
+    }
+
+    /// Shift every note by the given number of semitones, excluding the percussion channel
+    pub(crate) fn transpose(&mut self, semitones: i32) {
+        if semitones == 0 {
+            return;
+        }
+        for event in self.tracks.iter_mut().flatten() {
+            if let EventKind::Channel(message) = &mut event.kind {
+                if matches!(message.command(), 0x80 | 0x90 | 0xA0) && message.channel() != 9 {
+                    message.data1 = (message.data1 as i32 + semitones).clamp(0, 127) as u8;
+                }
+            }
+        }
+    }
+
+    /// Compress or expand the velocities of every note
+    pub(crate) fn apply_dynamics(&mut self, dynamics: &MidiDynamics) {
+        if dynamics.is_identity() {
+            return;
+        }
+        for event in self.tracks.iter_mut().flatten() {
+            if let EventKind::Channel(message) = &mut event.kind {
+                if message.command() == 0x90 {
+                    message.data2 = dynamics.apply(message.data2);
+                }
+            }
+        }
+    }
+
+    /// Move the notes of every track towards a beat grid, keeping their lengths
+    pub(crate) fn quantize(&mut self, quantize: &MidiQuantize) {
+        // SMPTE timing has no beats
+        if self.division & 0x8000 != 0 {
+            return;
+        }
+        let division = self.division as f64;
+        for track in &mut self.tracks {
+            // Moves of notes which haven't been released yet, applied to their releases too
+            let mut held: HashMap<(u8, u8), VecDeque<i64>> = HashMap::new();
+            for event in track.iter_mut() {
+                let EventKind::Channel(message) = &event.kind else {
+                    continue;
+                };
+                let key = (message.channel(), message.data1);
+                let shift = match message.command() {
+                    0x90 if message.data2 > 0 => {
+                        let beat = quantize.beat(event.tick as f64 / division);
+                        let shift = (beat * division).round() as i64 - event.tick as i64;
+                        held.entry(key).or_default().push_back(shift);
+                        shift
+                    }
+                    0x80 | 0x90 => held
+                        .get_mut(&key)
+                        .and_then(VecDeque::pop_front)
+                        .unwrap_or(0),
+                    _ => 0,
+                };
+                event.tick = event.tick.saturating_add_signed(shift);
+            }
+            // The end of the track stays last, even if notes moved past it
+            track.sort_by_key(|event| (matches!(event.kind, EventKind::Meta(0x2F, _)), event.tick));
+        }
+    }
+
+    /// Multiply the tempo of the file by `scale`
+    pub(crate) fn scale_tempo(&mut self, scale: f64) {
+        if scale == 1.0 || scale <= 0.0 || self.tracks.is_empty() {
+            return;
+        }
+        let scaled = |micros: u32| ((micros as f64 / scale).round() as u32).clamp(1, 0xFF_FFFF);
+        let mut initial = false;
+        for event in self.tracks.iter_mut().flatten() {
+            if let EventKind::Meta(0x51, data) = &mut event.kind {
+                if let [a, b, c] = data[..] {
+                    initial |= event.tick == 0;
+                    let micros = scaled(u32::from_be_bytes([0, a, b, c]));
+                    data.copy_from_slice(&micros.to_be_bytes()[1..]);
+                }
+            }
+        }
+        // Without a tempo at the start, the file plays at the default tempo until its first one
+        if !initial {
+            self.tracks[0].insert(
+                0,
+                TrackEvent {
+                    tick: 0,
+                    kind: EventKind::Meta(0x51, scaled(DEFAULT_TEMPO).to_be_bytes()[1..].to_vec()),
+                },
+            );
+        }
+    }
+
+    /// Last tick of any track, including end of track events
+    pub(crate) fn end_tick(&self) -> u64 {
+        self.tracks
+            .iter()
+            .filter_map(|track| track.last())
+            .map(|event| event.tick)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Channels which any channel event is sent on
+    fn channels(&self) -> [bool; 16] {
+        let mut channels = [false; 16];
+        for event in self.tracks.iter().flatten() {
+            if let EventKind::Channel(message) = &event.kind {
+                channels[message.channel() as usize] = true;
+            }
+        }
+        channels
+    }
+
+    /// Change the ticks per quarter note, keeping every event at the same musical position
+    fn set_division(&mut self, division: u16) -> io::Result<()> {
+        if division == self.division {
+            return Ok(());
+        }
+        if (division | self.division) & 0x8000 != 0 {
+            return Err(invalid("Can't combine files with different SMPTE timing."));
+        }
+        for event in self.tracks.iter_mut().flatten() {
+            event.tick = event.tick * division as u64 / self.division.max(1) as u64;
+        }
+        self.division = division;
+        Ok(())
+    }
+
+    /// Encode as a standard MIDI file
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let format = match self.tracks.len() {
+            0 | 1 => 0,
+            _ => self.format.max(1),
+        };
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&6_u32.to_be_bytes());
+        bytes.extend_from_slice(&format.to_be_bytes());
+        bytes.extend_from_slice(&(self.tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.division.to_be_bytes());
+        for track in &self.tracks {
+            let mut chunk = Vec::new();
+            let mut tick = 0;
+            for event in track {
+                write_var_len(&mut chunk, (event.tick.saturating_sub(tick)) as u32);
+                tick = tick.max(event.tick);
+                match &event.kind {
+                    EventKind::Channel(message) => {
+                        chunk.push(message.status);
+                        chunk.push(message.data1);
+                        if data_len(message.status) == 2 {
+                            chunk.push(message.data2);
+                        }
+                    }
+                    EventKind::Meta(kind, data) => {
+                        chunk.extend_from_slice(&[0xFF, *kind]);
+                        write_var_len(&mut chunk, data.len() as u32);
+                        chunk.extend_from_slice(data);
+                    }
+                    EventKind::SysEx(data) => {
+                        chunk.push(0xF0);
+                        write_var_len(&mut chunk, data.len() as u32);
+                        chunk.extend_from_slice(data);
+                    }
+                }
+            }
+            if !matches!(
+                track.last(),
+                Some(TrackEvent {
+                    kind: EventKind::Meta(0x2F, _),
+                    ..
+                })
+            ) {
+                chunk.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+            }
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&chunk);
+        }
+        bytes
+    }
+}
+
+fn write_var_len(bytes: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        groups.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.extend(groups.iter().rev());
+}
+
+/// A tempo change at a given tick
+#[derive(Clone, Copy, Debug)]
+struct TempoChange {
+    tick: u64,
+    time: f64,
+    micros_per_quarter: u32,
+}
+
+/// A time signature change at a given tick
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TimeSignature {
+    pub(crate) tick: u64,
+    pub(crate) numerator: u8,
+    pub(crate) denominator: u8,
+}
+
+/// Musical unit used to find boundaries in a song
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum GridUnit {
+    Beat,
+    Bar,
+}
+
+/// Mapping between ticks, seconds, beats and bars of a song
+#[derive(Clone, Debug)]
+pub(crate) struct TempoMap {
+    pub(crate) division: u16,
+    tempos: Vec<TempoChange>,
+    pub(crate) signatures: Vec<TimeSignature>,
+}
+
+impl TempoMap {
+    fn new(division: u16, mut tempos: Vec<(u64, u32)>, mut signatures: Vec<TimeSignature>) -> Self {
+        tempos.sort_by_key(|(tick, _)| *tick);
+        if tempos.first().map_or(true, |(tick, _)| *tick > 0) {
+            tempos.insert(0, (0, DEFAULT_TEMPO));
+        }
+        let mut changes: Vec<TempoChange> = Vec::with_capacity(tempos.len());
+        for (tick, micros_per_quarter) in tempos {
+            let time = match changes.last() {
+                Some(last) => {
+                    last.time
+                        + Self::ticks_to_seconds(
+                            division,
+                            last.micros_per_quarter,
+                            tick - last.tick,
+                        )
+                }
+                None => 0.0,
+            };
+            changes.push(TempoChange {
+                tick,
+                time,
+                micros_per_quarter,
+            });
+        }
+        signatures.sort_by_key(|signature| signature.tick);
+        if signatures
+            .first()
+            .map_or(true, |signature| signature.tick > 0)
+        {
+            signatures.insert(
+                0,
+                TimeSignature {
+                    tick: 0,
+                    numerator: 4,
+                    denominator: 4,
+                },
+            );
+        }
+        Self {
+            division,
+            tempos: changes,
+            signatures,
+        }
+    }
+
+    /// Construct a tempo map with a single tempo and time signature
+    pub(crate) fn constant(bpm: f64, numerator: u8, denominator: u8) -> Self {
+        let micros_per_quarter = (60_000_000.0 / bpm.max(f64::EPSILON)) as u32;
+        Self::new(
+            SEQUENCE_DIVISION,
+            vec![(0, micros_per_quarter.max(1))],
+            vec![TimeSignature {
+                tick: 0,
+                numerator,
+                denominator,
+            }],
+        )
+    }
+
+    fn ticks_to_seconds(division: u16, micros_per_quarter: u32, ticks: u64) -> f64 {
+        if division & 0x8000 != 0 {
+            // SMPTE timing: frames per second in the high byte, ticks per frame in the low byte
+            let fps = -((division >> 8) as u8 as i8) as f64;
+            let ticks_per_frame = (division & 0xFF) as f64;
+            ticks as f64 / (fps * ticks_per_frame)
+        } else {
+            ticks as f64 * micros_per_quarter as f64 / (division.max(1) as f64 * 1_000_000.0)
+        }
+    }
+
+    fn tempo_at_tick(&self, tick: u64) -> &TempoChange {
+        let index = self.tempos.partition_point(|change| change.tick <= tick);
+        &self.tempos[index.saturating_sub(1)]
+    }
+
+    fn tempo_at_time(&self, time: f64) -> &TempoChange {
+        let index = self.tempos.partition_point(|change| change.time <= time);
+        &self.tempos[index.saturating_sub(1)]
+    }
+
+    /// Convert a tick to seconds
+    pub(crate) fn seconds(&self, tick: u64) -> f64 {
+        let change = self.tempo_at_tick(tick);
+        change.time
+            + Self::ticks_to_seconds(self.division, change.micros_per_quarter, tick - change.tick)
+    }
+
+    /// Convert a fractional tick to seconds
+    pub(crate) fn fractional_seconds(&self, tick: f64) -> f64 {
+        let change = self.tempo_at_tick(tick.max(0.0) as u64);
+        change.time
+            + (tick - change.tick as f64)
+                * Self::ticks_to_seconds(self.division, change.micros_per_quarter, 1)
+    }
+
+    /// Convert seconds to a (fractional) tick
+    pub(crate) fn ticks(&self, time: f64) -> f64 {
+        let change = self.tempo_at_time(time);
+        let ticks_per_second =
+            1.0 / Self::ticks_to_seconds(self.division, change.micros_per_quarter, 1);
+        change.tick as f64 + (time - change.time).max(0.0) * ticks_per_second
+    }
+
+    /// Tempo in quarter notes per minute at the given time
+    pub(crate) fn bpm(&self, time: f64) -> f64 {
+        60_000_000.0 / self.tempo_at_time(time).micros_per_quarter as f64
+    }
+
+    /// Convert a tick to a zero-based `(bar, beat, tick within the beat)`
+    pub(crate) fn bar_beat_tick(&self, tick: u64) -> (u32, u32, u32) {
+        let mut bar = 0;
+        for (index, signature) in self.signatures.iter().enumerate() {
+            let beat = self.beat_ticks(signature);
+            let bar_len = beat * signature.numerator.max(1) as u64;
+            match self.signatures.get(index + 1) {
+                Some(next) if next.tick <= tick => {
+                    // A partial bar before a signature change still counts as a bar
+                    bar += (next.tick - signature.tick).div_ceil(bar_len);
+                }
+                _ => {
+                    let offset = tick.saturating_sub(signature.tick);
+                    bar += offset / bar_len;
+                    let within = offset % bar_len;
+                    return (bar as u32, (within / beat) as u32, (within % beat) as u32);
+                }
+            }
+        }
+        (bar as u32, 0, 0)
+    }
+
+    /// Tick at which the given zero-based bar starts
+    pub(crate) fn bar_start(&self, bar: u32) -> u64 {
+        let mut remaining = bar as u64;
+        for (index, signature) in self.signatures.iter().enumerate() {
+            let bar_len = self.beat_ticks(signature) * signature.numerator.max(1) as u64;
+            match self.signatures.get(index + 1) {
+                Some(next) => {
+                    let bars = (next.tick - signature.tick).div_ceil(bar_len);
+                    if remaining < bars {
+                        return signature.tick + remaining * bar_len;
+                    }
+                    remaining -= bars;
+                }
+                None => return signature.tick + remaining * bar_len,
+            }
+        }
+        0
+    }
+
+    fn beat_ticks(&self, signature: &TimeSignature) -> u64 {
+        (self.division as u64 * 4 / signature.denominator.max(1) as u64).max(1)
+    }
+
+    /// Find the first beat or bar boundary at or after `tick`
+    pub(crate) fn next_boundary(&self, tick: u64, unit: GridUnit) -> u64 {
+        let index = self
+            .signatures
+            .partition_point(|signature| signature.tick <= tick);
+        let signature = self.signatures[index.saturating_sub(1)];
+        let beat = self.beat_ticks(&signature);
+        let step = match unit {
+            GridUnit::Beat => beat,
+            GridUnit::Bar => beat * signature.numerator.max(1) as u64,
+        };
+        let boundary = signature.tick + (tick - signature.tick).div_ceil(step) * step;
+        match self.signatures.get(index) {
+            Some(next) if next.tick < boundary => next.tick,
+            _ => boundary,
+        }
+    }
+}
+
+/// A named marker within a song
+#[derive(Clone, Debug)]
+pub(crate) struct Marker {
+    pub(crate) tick: u64,
+    pub(crate) time: f64,
+}
+
+/// A channel message scheduled at an absolute position
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SongEvent {
+    pub(crate) tick: u64,
+    pub(crate) time: f64,
+    pub(crate) message: MidiMessage,
+}
+
+/// A fully merged, time-resolved piece of music ready for sequencing
+#[derive(Clone, Debug)]
+pub(crate) struct Song {
+    pub(crate) events: Vec<SongEvent>,
+    pub(crate) markers: Vec<Marker>,
+    pub(crate) tempo: TempoMap,
+    pub(crate) length: f64,
+}
+
+impl Song {
+    /// Merge the tracks of a parsed MIDI file into a single timeline.
+    ///
+    /// If `tracks` is given, channel events are only taken from those tracks. Tempo, time
+    /// signature and marker events are always taken from every track.
+    pub(crate) fn from_smf(smf: &Smf, tracks: Option<&[usize]>) -> Self {
+        // The songs of a format 2 file don't play together, so only the first is played
+        if smf.format == 2 && smf.tracks.len() > 1 {
+            return Self::from_smf(&smf.song(0).unwrap(), tracks);
+        }
+        let mut tempos = Vec::new();
+        let mut signatures = Vec::new();
+        let mut end_tick = 0;
+        for event in smf.tracks.iter().flatten() {
+            end_tick = end_tick.max(event.tick);
+            if let EventKind::Meta(kind, data) = &event.kind {
+                match (kind, data.as_slice()) {
+                    (0x51, [a, b, c]) => {
+                        tempos.push((event.tick, u32::from_be_bytes([0, *a, *b, *c])));
+                    }
+                    (0x58, [numerator, denominator, ..]) => signatures.push(TimeSignature {
+                        tick: event.tick,
+                        numerator: *numerator,
+                        denominator: 1 << denominator.min(&6),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        let tempo = TempoMap::new(smf.division, tempos, signatures);
+
+        let mut events = Vec::new();
+        let mut markers = Vec::new();
+        for (index, track) in smf.tracks.iter().enumerate() {
+            let included = tracks.map_or(true, |tracks| tracks.contains(&index));
+            for event in track {
+                match &event.kind {
+                    EventKind::Channel(message) if included => events.push(SongEvent {
+                        tick: event.tick,
+                        time: 0.0,
+                        message: *message,
+                    }),
+                    EventKind::Meta(0x06, _) => markers.push(Marker {
+                        tick: event.tick,
+                        time: tempo.seconds(event.tick),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        events.sort_by_key(|event| event.tick);
+        for event in &mut events {
+            event.time = tempo.seconds(event.tick);
+        }
+        markers.sort_by_key(|marker| marker.tick);
+
+        Self {
+            events,
+            markers,
+            length: tempo.seconds(end_tick),
+            tempo,
+        }
+    }
+
+    /// Build a song which plays the given notes one after another
+    pub(crate) fn from_notes(notes: &[MidiNote]) -> Self {
+        let tempo = TempoMap::new(SEQUENCE_DIVISION, Vec::new(), Vec::new());
+        let mut events = Vec::new();
+        let mut time = 0.0;
+        let mut push = |time: f64, status: u8, data1: i32, data2: i32| {
+            events.push(SongEvent {
+                tick: tempo.ticks(time).round() as u64,
+                time,
+                message: MidiMessage {
+                    status,
+                    data1: data1.clamp(0, 127) as u8,
+                    data2: data2.clamp(0, 127) as u8,
+                },
+            })
+        };
+        for note in notes {
+            let channel = note.channel.clamp(0, 15) as u8;
+            let (msb, lsb) = bank_select(channel, note.bank);
+            push(time, 0xB0 | channel, 0x00, msb as i32);
+            push(time, 0xB0 | channel, 0x20, lsb as i32);
+            push(time, 0xC0 | channel, note.preset, 0);
+            push(time, 0x90 | channel, note.key, note.velocity);
+            time += note.duration.as_secs_f64();
+            push(time, 0x80 | channel, note.key, 0);
+        }
+        Self {
+            events,
+            markers: Vec::new(),
+            tempo,
+            length: time,
+        }
+    }
+}
+
+impl Song {
+    /// Copy of the song with its notes moved by a groove template, keeping their lengths
+    pub(crate) fn with_groove(&self, groove: &MidiGroove) -> Self {
+        // SMPTE timing has no beats
+        if self.tempo.division & 0x8000 != 0 {
+            return self.clone();
+        }
+        let division = self.tempo.division as f64;
+        let shift = |tick: u64| groove.shift(tick as f64 / division) * division;
+        // Shifts of notes which haven't been released yet, applied to their releases too
+        let mut held: HashMap<(u8, u8), VecDeque<f64>> = HashMap::new();
+        let mut events = self
+            .events
+            .iter()
+            .map(|event| {
+                let message = event.message;
+                let key = (message.channel(), message.data1);
+                let shift = match message.command() {
+                    0x90 if message.data2 > 0 => {
+                        let shift = shift(event.tick);
+                        held.entry(key).or_default().push_back(shift);
+                        shift
+                    }
+                    0x80 | 0x90 => held
+                        .get_mut(&key)
+                        .and_then(VecDeque::pop_front)
+                        .unwrap_or_else(|| shift(event.tick)),
+                    _ => shift(event.tick),
+                };
+                let tick = (event.tick as f64 + shift).round().max(0.0) as u64;
+                SongEvent {
+                    tick,
+                    time: self.tempo.seconds(tick),
+                    ..*event
+                }
+            })
+            .collect::<Vec<_>>();
+        events.sort_by_key(|event| event.tick);
+        let length = events
+            .last()
+            .map_or(self.length, |event| event.time.max(self.length));
+        Self {
+            events,
+            markers: self.markers.clone(),
+            tempo: self.tempo.clone(),
+            length,
+        }
+    }
+
+    /// Time of the first sounding note
+    pub(crate) fn first_note(&self) -> Option<f64> {
+        self.events
+            .iter()
+            .find(|event| event.message.command() == 0x90 && event.message.data2 > 0)
+            .map(|event| event.time)
+    }
+
+    /// Prefix the song with `bars` bars of metronome clicks at its initial tempo and time
+    /// signature
+    pub(crate) fn with_count_in(&self, bars: u32, clicks: &MetronomeClicks) -> Self {
+        let signature = self.tempo.signatures[0];
+        let beat = self.tempo.beat_ticks(&signature);
+        let beats = bars as u64 * signature.numerator.max(1) as u64;
+        let offset = beat * beats;
+
+        // The initial tempo and signature also cover the count-in
+        let shift = |tick: u64| if tick == 0 { 0 } else { tick + offset };
+        let tempos = self
+            .tempo
+            .tempos
+            .iter()
+            .map(|change| (shift(change.tick), change.micros_per_quarter))
+            .collect();
+        let signatures = self
+            .tempo
+            .signatures
+            .iter()
+            .map(|signature| TimeSignature {
+                tick: shift(signature.tick),
+                ..*signature
+            })
+            .collect();
+        let tempo = TempoMap::new(self.tempo.division, tempos, signatures);
+        let count_in = tempo.seconds(offset);
+
+        let mut events = Vec::with_capacity(self.events.len() + beats as usize * 2);
+        for index in 0..beats {
+            let (key, velocity) = clicks.click(index % signature.numerator.max(1) as u64 == 0);
+            for (tick, status, velocity) in [
+                (index * beat, 0x99, velocity),
+                (index * beat + beat / 2, 0x89, 0),
+            ] {
+                events.push(SongEvent {
+                    tick,
+                    time: tempo.seconds(tick),
+                    message: MidiMessage {
+                        status,
+                        data1: key,
+                        data2: velocity,
+                    },
+                });
+            }
+        }
+        events.extend(self.events.iter().map(|event| SongEvent {
+            tick: event.tick + offset,
+            time: event.time + count_in,
+            ..*event
+        }));
+
+        Self {
+            events,
+            markers: self
+                .markers
+                .iter()
+                .map(|marker| Marker {
+                    tick: marker.tick + offset,
+                    time: marker.time + count_in,
+                })
+                .collect(),
+            tempo,
+            length: self.length + count_in,
+        }
+    }
+}
+
+impl Smf {
+    /// Encode channel messages with their times in seconds as a single track file, releasing
+    /// notes which are still held at the end
+    pub(crate) fn from_messages(messages: &[(f64, MidiMessage)]) -> Self {
+        let ticks_per_second = SEQUENCE_DIVISION as f64 * 2.0;
+        let mut sorted = messages.to_vec();
+        sorted.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        let mut held = [[false; 128]; 16];
+        let mut track = Vec::with_capacity(sorted.len());
+        for (time, message) in &sorted {
+            let (channel, key) = (message.channel() as usize, (message.data1 & 0x7F) as usize);
+            match message.command() {
+                0x90 if message.data2 > 0 => held[channel][key] = true,
+                0x80 | 0x90 => held[channel][key] = false,
+                // All sound off and all notes off
+                0xB0 if matches!(message.data1, 120 | 123) => held[channel] = [false; 128],
+                _ => {}
+            }
+            track.push(TrackEvent {
+                tick: (time.max(0.0) * ticks_per_second).round() as u64,
+                kind: EventKind::Channel(*message),
+            });
+        }
+        let end = track.last().map_or(0, |event| event.tick);
+        for (channel, keys) in held.iter().enumerate() {
+            for key in (0..128).filter(|&key| keys[key]) {
+                track.push(TrackEvent {
+                    tick: end,
+                    kind: EventKind::Channel(MidiMessage {
+                        status: 0x80 | channel as u8,
+                        data1: key as u8,
+                        data2: 0,
+                    }),
+                });
+            }
+        }
+        Smf {
+            format: 0,
+            division: SEQUENCE_DIVISION,
+            tracks: vec![track],
+        }
+    }
+
+    /// Encode notes with absolute start times as a single track file
+    fn from_notes(notes: &[TimedMidiNote]) -> Self {
+        // At the default tempo of 120 BPM, each second lasts two quarter notes
+        let ticks_per_second = SEQUENCE_DIVISION as f64 * 2.0;
+        let tick = |time: Duration| (time.as_secs_f64() * ticks_per_second).round() as u64;
+        let notes = notes
+            .iter()
+            .map(|timed| {
+                let end = tick(timed.start + timed.note.duration);
+                (tick(timed.start), end, &timed.note)
+            })
+            .collect();
+        Self::from_ticked_notes(notes, Vec::new())
+    }
+
+    /// Encode a score as a single track file, with its tempo changes
+    pub(crate) fn from_score(events: &[ScoreEvent]) -> Self {
+        let ticks = |beats: f64| (beats.max(0.0) * SEQUENCE_DIVISION as f64).round() as u64;
+        let mut cursor = 0;
+        let mut notes = Vec::new();
+        let mut tempos = vec![(0, DEFAULT_TEMPO)];
+        let mut signatures = Vec::new();
+        // Later tempo changes replace those of a ramp which hasn't finished yet
+        let set_tempo = |tempos: &mut Vec<(u64, u32)>, tick: u64, bpm: f64| {
+            tempos.retain(|&(change, _)| change < tick);
+            tempos.push((tick, (60_000_000.0 / bpm.max(1.0)).round() as u32));
+        };
+        for event in events {
+            match *event {
+                ScoreEvent::Note { ref note, beats } => {
+                    let end = cursor + ticks(beats);
+                    notes.push((cursor, end, note));
+                    cursor = end;
+                }
+                ScoreEvent::Chord {
+                    notes: ref chord,
+                    beats,
+                } => {
+                    let end = cursor + ticks(beats);
+                    notes.extend(chord.iter().map(|note| (cursor, end, note)));
+                    cursor = end;
+                }
+                ScoreEvent::Rest(beats) => cursor += ticks(beats),
+                ScoreEvent::Tempo(bpm) => set_tempo(&mut tempos, cursor, bpm),
+                ScoreEvent::TimeSignature {
+                    numerator,
+                    denominator,
+                } => {
+                    // Denominators are stored as powers of two
+                    let denominator = denominator.max(1).next_power_of_two().trailing_zeros();
+                    signatures.retain(|(tick, _)| *tick < cursor);
+                    signatures.push((
+                        cursor,
+                        EventKind::Meta(0x58, vec![numerator.max(1), denominator as u8, 24, 8]),
+                    ));
+                }
+                ScoreEvent::TempoRamp { bpm, beats } => {
+                    let micros = tempos
+                        .iter()
+                        .rev()
+                        .find(|&&(tick, _)| tick <= cursor)
+                        .map_or(DEFAULT_TEMPO, |&(_, micros)| micros);
+                    let from = 60_000_000.0 / micros as f64;
+                    let length = ticks(beats);
+                    let steps = length.div_ceil(TEMPO_RAMP_STEP).max(1);
+                    for step in 1..=steps {
+                        let progress = step as f64 / steps as f64;
+                        let tick =
+                            cursor + (length as f64 * (step - 1) as f64 / steps as f64) as u64;
+                        set_tempo(&mut tempos, tick, from + (bpm - from) * progress);
+                    }
+                }
+            }
+        }
+        let meta = tempos
+            .into_iter()
+            .map(|(tick, micros)| {
+                (
+                    tick,
+                    EventKind::Meta(0x51, micros.to_be_bytes()[1..].to_vec()),
+                )
+            })
+            .chain(signatures)
+            .collect();
+        Self::from_ticked_notes(notes, meta)
+    }
+
+    /// Encode notes with start and end ticks as a single track file, along with meta events
+    fn from_ticked_notes(
+        mut notes: Vec<(u64, u64, &MidiNote)>,
+        meta: Vec<(u64, EventKind)>,
+    ) -> Self {
+        let mut events = Vec::with_capacity(notes.len() * 4 + meta.len());
+        events.extend(meta.into_iter().map(|(tick, kind)| (tick, 0, kind)));
+        let mut programs = [None; 16];
+        notes.sort_by_key(|(start, _, _)| *start);
+        for (start, end, note) in notes {
+            let channel = note.channel.clamp(0, 15) as u8;
+            let data = |value: i32| value.clamp(0, 127) as u8;
+            let message = |status: u8, data1: u8, data2: u8| {
+                EventKind::Channel(MidiMessage {
+                    status: status | channel,
+                    data1,
+                    data2,
+                })
+            };
+            let program = (bank_select(channel, note.bank), data(note.preset));
+            if programs[channel as usize] != Some(program) {
+                programs[channel as usize] = Some(program);
+                let ((msb, lsb), preset) = program;
+                events.push((start, 1, message(0xB0, 0x00, msb)));
+                events.push((start, 1, message(0xB0, 0x20, lsb)));
+                events.push((start, 1, message(0xC0, preset, 0)));
+            }
+            events.push((start, 2, message(0x90, data(note.key), data(note.velocity))));
+            events.push((end, 0, message(0x80, data(note.key), 0)));
+        }
+        // Releases come before program changes and new notes at the same tick
+        events.sort_by_key(|(tick, order, _)| (*tick, *order));
+        let track = events
+            .into_iter()
+            .map(|(tick, _, kind)| TrackEvent { tick, kind })
+            .collect();
+        Smf {
+            format: 0,
+            division: SEQUENCE_DIVISION,
+            tracks: vec![track],
+        }
+    }
+}
+
+/// Start time, velocity and (bank, preset) of a note which hasn't been released yet
+type HeldNote = (f64, u8, (u16, u8));
+
+impl MidiAudio {
+    /// Flatten into notes with absolute start times, so the music can be edited before being
+    /// turned back into audio with [`MidiAudio::from_notes`].
+    ///
+    /// Only notes are kept; controller changes such as volume and pitch bend are dropped.
+    pub fn to_notes(&self) -> Result<Vec<TimedMidiNote>, MidiError> {
+        let song = self.to_song()?;
+        // Bank select MSB and LSB, and preset, of each channel
+        let mut programs = [(0_u8, 0_u8, 0_u8); 16];
+        let mut held: HashMap<(u8, u8), Vec<HeldNote>> = HashMap::new();
+        let mut notes = Vec::new();
+        let mut finish =
+            |start: f64, end: f64, channel: u8, key: u8, velocity, program: (u16, u8)| {
+                notes.push(TimedMidiNote {
+                    start: Duration::from_secs_f64(start),
+                    note: MidiNote {
+                        channel: channel as i32,
+                        preset: program.1 as i32,
+                        bank: program.0 as i32,
+                        key: key as i32,
+                        velocity: velocity as i32,
+                        duration: Duration::from_secs_f64((end - start).max(0.0)),
+                    },
+                })
+            };
+        for event in &song.events {
+            let message = event.message;
+            let channel = message.channel();
+            match message.command() {
+                0x90 if message.data2 > 0 => {
+                    let (msb, lsb, preset) = programs[channel as usize];
+                    let program = (bank_number(channel, msb, lsb), preset);
+                    held.entry((channel, message.data1)).or_default().push((
+                        event.time,
+                        message.data2,
+                        program,
+                    ));
+                }
+                0x80 | 0x90 => {
+                    let Some(starts) = held.get_mut(&(channel, message.data1)) else {
+                        continue;
+                    };
+                    if !starts.is_empty() {
+                        let (start, velocity, program) = starts.remove(0);
+                        finish(start, event.time, channel, message.data1, velocity, program);
+                    }
+                }
+                0xB0 if message.data1 == 0x00 => programs[channel as usize].0 = message.data2,
+                0xB0 if message.data1 == 0x20 => programs[channel as usize].1 = message.data2,
+                0xC0 => programs[channel as usize].2 = message.data1,
+                _ => {}
+            }
+        }
+        // Notes still held at the end last until the end of the song
+        for ((channel, key), starts) in held {
+            for (start, velocity, program) in starts {
+                finish(start, song.length, channel, key, velocity, program);
+            }
+        }
+        notes.sort_by_key(|note| note.start);
+        Ok(notes)
+    }
+
+    /// Build a MIDI file which plays the given notes at their start times
+    pub fn from_notes(notes: &[TimedMidiNote]) -> Self {
+        MidiAudio::File(Smf::from_notes(notes).to_bytes())
+    }
+
+    /// Play `next` once this audio has finished, such as to stitch an intro onto a loop.
+    ///
+    /// Each part keeps its own tempo changes. Channels played by `next` have their controllers
+    /// and instruments reset when it starts, as they would be if it were played on its own.
+    pub fn concat(&self, next: &MidiAudio) -> Result<MidiAudio, MidiError> {
+        let mut smf = self.to_smf()?;
+        let mut next = next.to_smf()?;
+        next.set_division(smf.division).map_err(MidiError::parse)?;
+        let offset = smf.end_tick();
+        let tempo_map = Song::from_smf(&next, Some(&[])).tempo;
+        let at_start = |kind: u8| {
+            next.tracks.iter().flatten().any(|event| {
+                event.tick == 0 && matches!(event.kind, EventKind::Meta(k, _) if k == kind)
+            })
+        };
+        let mut start = Vec::new();
+        if !at_start(0x51) {
+            let micros = tempo_map.tempos[0].micros_per_quarter;
+            start.push(EventKind::Meta(0x51, micros.to_be_bytes()[1..].to_vec()));
+        }
+        if !at_start(0x58) {
+            start.push(EventKind::Meta(0x58, vec![4, 2, 24, 8]));
+        }
+        for (channel, _) in next
+            .channels()
+            .iter()
+            .enumerate()
+            .filter(|(_, used)| **used)
+        {
+            let status = channel as u8;
+            for (status, data1) in [(0xB0 | status, 121), (0xB0 | status, 0), (0xC0 | status, 0)] {
+                start.push(EventKind::Channel(MidiMessage {
+                    status,
+                    data1,
+                    data2: 0,
+                }));
+            }
+        }
+        let mut tracks = next.tracks;
+        if tracks.is_empty() {
+            tracks.push(Vec::new());
+        }
+        for event in tracks.iter_mut().flatten() {
+            event.tick += offset;
+        }
+        tracks[0].splice(
+            0..0,
+            start
+                .into_iter()
+                .map(|kind| TrackEvent { tick: offset, kind }),
+        );
+        smf.tracks.extend(tracks);
+        smf.format = 1;
+        Ok(MidiAudio::File(smf.to_bytes()))
+    }
+
+    /// Play `other` at the same time as this audio, such as to layer a countermelody.
+    ///
+    /// `other` is retimed to follow this audio's tempo changes, so both keep their original
+    /// timing. Channels of `other` which this audio also uses are moved to unused channels where
+    /// possible, so each part keeps its own instruments.
+    pub fn merge(&self, other: &MidiAudio) -> Result<MidiAudio, MidiError> {
+        let mut smf = self.to_smf()?;
+        let mut other = other.to_smf()?;
+        let tempo = Song::from_smf(&smf, Some(&[])).tempo;
+        let other_tempo = Song::from_smf(&other, Some(&[])).tempo;
+
+        let used = smf.channels();
+        let mut free = (0..16_u8).filter(|&channel| channel != 9 && !used[channel as usize]);
+        let map = other
+            .channels()
+            .iter()
+            .enumerate()
+            .filter(|&(channel, &other_used)| other_used && used[channel] && channel != 9)
+            .map_while(|(channel, _)| Some((channel as u8, free.next()?)))
+            .collect::<Vec<_>>();
+        other.remap_channels(&map);
+
+        for track in &mut other.tracks {
+            // This audio's tempo changes and time signatures apply to both parts
+            track.retain(|event| !matches!(event.kind, EventKind::Meta(0x51 | 0x58, _)));
+            for event in track {
+                let time = other_tempo.seconds(event.tick);
+                event.tick = tempo.ticks(time).round() as u64;
+            }
+        }
+        smf.tracks.extend(other.tracks);
+        smf.format = 1;
+        Ok(MidiAudio::File(smf.to_bytes()))
+    }
+
+    /// Copy of this audio with the velocities of its notes compressed or expanded
+    pub fn with_dynamics(&self, dynamics: &MidiDynamics) -> Result<MidiAudio, MidiError> {
+        match self {
+            MidiAudio::File(_) => {
+                let mut smf = self.to_smf()?;
+                smf.apply_dynamics(dynamics);
+                Ok(MidiAudio::File(smf.to_bytes()))
+            }
+            MidiAudio::Sequence(notes) => Ok(MidiAudio::Sequence(
+                notes
+                    .iter()
+                    .map(|note| MidiNote {
+                        velocity: dynamics.apply(note.velocity.clamp(0, 127) as u8) as i32,
+                        ..note.clone()
+                    })
+                    .collect(),
+            )),
+            MidiAudio::Timeline(notes) => Ok(MidiAudio::Timeline(
+                notes
+                    .iter()
+                    .map(|timed| TimedMidiNote {
+                        start: timed.start,
+                        note: MidiNote {
+                            velocity: dynamics.apply(timed.note.velocity.clamp(0, 127) as u8)
+                                as i32,
+                            ..timed.note.clone()
+                        },
+                    })
+                    .collect(),
+            )),
+            MidiAudio::Score(events) => Ok(MidiAudio::Score(
+                events
+                    .iter()
+                    .map(|event| {
+                        let apply = |note: &MidiNote| MidiNote {
+                            velocity: dynamics.apply(note.velocity.clamp(0, 127) as u8) as i32,
+                            ..note.clone()
+                        };
+                        match event {
+                            ScoreEvent::Note { note, beats } => ScoreEvent::Note {
+                                note: apply(note),
+                                beats: *beats,
+                            },
+                            ScoreEvent::Chord { notes, beats } => ScoreEvent::Chord {
+                                notes: notes.iter().map(apply).collect(),
+                                beats: *beats,
+                            },
+                            event => event.clone(),
+                        }
+                    })
+                    .collect(),
+            )),
+        }
+    }
+
+    /// Copy of this audio with its notes moved towards a beat grid, such as to clean up
+    /// live-recorded input.
+    ///
+    /// Sequences and timelines are returned as [`MidiAudio::Timeline`]s, whose beats last half a
+    /// second as at 120 BPM, and scores as MIDI files.
+    pub fn quantize(&self, quantize: &MidiQuantize) -> Result<MidiAudio, MidiError> {
+        match self {
+            MidiAudio::File(_) | MidiAudio::Score(_) => {
+                let mut smf = self.to_smf()?;
+                smf.quantize(quantize);
+                Ok(MidiAudio::File(smf.to_bytes()))
+            }
+            MidiAudio::Sequence(_) | MidiAudio::Timeline(_) => {
+                let beat = SEQUENCE_BEAT.as_secs_f64();
+                let mut notes = self.to_notes()?;
+                for timed in &mut notes {
+                    let start = quantize.beat(timed.start.as_secs_f64() / beat) * beat;
+                    timed.start = Duration::from_secs_f64(start.max(0.0));
+                }
+                notes.sort_by_key(|timed| timed.start);
+                Ok(MidiAudio::Timeline(notes))
+            }
+        }
+    }
+
+    /// Parse this audio as a MIDI file, encoding sequences as one
+    pub(crate) fn to_smf(&self) -> Result<Smf, MidiError> {
+        match self {
+            MidiAudio::File(data) => {
+                let smf = Smf::parse(data).map_err(MidiError::parse)?;
+                // The songs of a format 2 file don't play together, so only the first is played
+                match smf.format {
+                    2 => Ok(smf.song(0).unwrap_or(smf)),
+                    _ => Ok(smf),
+                }
+            }
+            MidiAudio::Sequence(_) => Ok(Smf::from_notes(&self.to_notes()?)),
+            MidiAudio::Timeline(notes) => Ok(Smf::from_notes(notes)),
+            MidiAudio::Score(events) => Ok(Smf::from_score(events)),
+        }
+    }
+
+    /// Resolve this audio into a song for sequencing
+    pub(crate) fn to_song(&self) -> Result<Song, MidiError> {
+        self.to_song_tracks(None)
+    }
+
+    /// Resolve this audio into a song, keeping only the given tracks of a MIDI file
+    pub(crate) fn to_song_tracks(&self, tracks: Option<&[usize]>) -> Result<Song, MidiError> {
+        match self {
+            MidiAudio::File(data) => {
+                let smf = Smf::parse(data).map_err(MidiError::parse)?;
+                Ok(Song::from_smf(&smf, tracks))
+            }
+            MidiAudio::Sequence(notes) => Ok(Song::from_notes(notes)),
+            MidiAudio::Timeline(notes) => Ok(Song::from_smf(&Smf::from_notes(notes), None)),
+            MidiAudio::Score(events) => Ok(Song::from_smf(&Smf::from_score(events), None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A standard MIDI file with the given track chunks
+    fn smf_bytes(division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&6_u32.to_be_bytes());
+        bytes.extend_from_slice(&1_u16.to_be_bytes());
+        bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&division.to_be_bytes());
+        for track in tracks {
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(track);
+        }
+        bytes
+    }
+
+    fn channel(tick: u64, status: u8, data1: u8, data2: u8) -> TrackEvent {
+        TrackEvent {
+            tick,
+            kind: EventKind::Channel(MidiMessage {
+                status,
+                data1,
+                data2,
+            }),
+        }
+    }
+
+    #[test]
+    fn running_status() {
+        let track = [
+            0x00, 0x90, 60, 100, // note on
+            0x60, 64, 100, // running status note on, 96 ticks later
+            0x00, 0xC0, 5, // program change
+            0x10, 6, // running status program change
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let smf = Smf::parse(&smf_bytes(96, &[&track])).unwrap();
+        assert_eq!(
+            smf.tracks[0][..4],
+            [
+                channel(0, 0x90, 60, 100),
+                channel(96, 0x90, 64, 100),
+                channel(96, 0xC0, 5, 0),
+                channel(112, 0xC0, 6, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn meta_and_sysex() {
+        let track = [
+            0x00, 0xFF, 0x03, 0x04, b'L', b'e', b'a', b'd', // track name
+            0x00, 0xF0, 0x03, 0x7E, 0x09, 0x01, // sysex
+            0x00, 0x90, 60, 100, // meta and sysex events cancel running status in files
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let smf = Smf::parse(&smf_bytes(96, &[&track])).unwrap();
+        assert_eq!(
+            smf.tracks[0][0].kind,
+            EventKind::Meta(0x03, b"Lead".to_vec())
+        );
+        assert_eq!(
+            smf.tracks[0][1].kind,
+            EventKind::SysEx(vec![0x7E, 0x09, 0x01])
+        );
+        assert_eq!(smf.tracks[0][3].kind, EventKind::Meta(0x2F, Vec::new()));
+    }
+
+    #[test]
+    fn meta_and_sysex_cancel_running_status() {
+        let after_meta = [
+            0x00, 0x90, 60, 100, 0x00, 0xFF, 0x01, 0x00, // empty text
+            0x00, 64, 100, 0x00, 0xFF, 0x2F, 0x00,
+        ];
+        assert!(Smf::parse(&smf_bytes(96, &[&after_meta])).is_err());
+        let after_sysex = [
+            0x00, 0x90, 60, 100, 0x00, 0xF0, 0x01, 0xF7, // empty sysex
+            0x00, 64, 100, 0x00, 0xFF, 0x2F, 0x00,
+        ];
+        assert!(Smf::parse(&smf_bytes(96, &[&after_sysex])).is_err());
+    }
+
+    #[test]
+    fn songs_are_reindexed_from_zero() {
+        let first = [0x00, 0xFF, 0x03, 0x01, b'A', 0x00, 0xFF, 0x2F, 0x00];
+        let second = [0x00, 0xFF, 0x03, 0x01, b'B', 0x00, 0xFF, 0x2F, 0x00];
+        let mut data = smf_bytes(96, &[&first, &second]);
+        // Format 2
+        data[9] = 2;
+        let smf = Smf::parse(&data).unwrap();
+        let song = smf.song(1).unwrap();
+        assert_eq!(song.tracks.len(), 1);
+        assert_eq!(song.track_name(0), Some("B"));
+        assert!(smf.song(2).is_none());
+    }
+
+    #[test]
+    fn events_after_end_of_track_are_ignored() {
+        let track = [0x00, 0xFF, 0x2F, 0x00, 0x00, 0x90, 60, 100];
+        let smf = Smf::parse(&smf_bytes(96, &[&track])).unwrap();
+        assert_eq!(smf.tracks[0].len(), 1);
+    }
+
+    #[test]
+    fn strict_rejects_defects() {
+        let truncated = smf_bytes(96, &[&[0x00, 0x90, 60]]);
+        assert!(Smf::parse(&truncated).is_err());
+        let orphan_data = smf_bytes(96, &[&[0x00, 60, 100]]);
+        assert!(Smf::parse(&orphan_data).is_err());
+        let system = smf_bytes(96, &[&[0x00, 0xF8, 0x00, 0x90, 60, 100]]);
+        assert!(Smf::parse(&system).is_err());
+        let mut short_chunk = smf_bytes(96, &[&[0x00, 0x90, 60, 100]]);
+        short_chunk.truncate(short_chunk.len() - 2);
+        assert!(Smf::parse(&short_chunk).is_err());
+    }
+
+    #[test]
+    fn lenient_repairs_defects() {
+        let mut data = smf_bytes(96, &[&[0x00, 0xF8, 0x00, 0x90, 60, 100, 0x60, 0x80, 60]]);
+        data.truncate(data.len() - 1);
+        let (smf, warnings) = Smf::parse_lenient(&data).unwrap();
+        assert_eq!(smf.tracks[0], [channel(0, 0x90, 60, 100)]);
+        assert!(!warnings.is_empty());
+    }
+
+    #[test]
+    fn lenient_warns_about_skipped_bytes() {
+        let system = smf_bytes(
+            96,
+            &[&[0x00, 0xF8, 0x00, 0x90, 60, 100, 0x00, 0xFF, 0x2F, 0x00]],
+        );
+        let (smf, warnings) = Smf::parse_lenient(&system).unwrap();
+        assert_eq!(smf.tracks[0][0], channel(0, 0x90, 60, 100));
+        assert_eq!(warnings, ["Skipped system messages."]);
+
+        let orphan = smf_bytes(
+            96,
+            &[&[0x00, 60, 0x00, 0x90, 60, 100, 0x00, 0xFF, 0x2F, 0x00]],
+        );
+        let (smf, warnings) = Smf::parse_lenient(&orphan).unwrap();
+        assert_eq!(smf.tracks[0][0], channel(0, 0x90, 60, 100));
+        assert_eq!(warnings, ["Skipped data bytes without a status."]);
+    }
+
+    #[test]
+    fn rmid() {
+        let smf = smf_bytes(96, &[&[0x00, 0x90, 60, 100, 0x00, 0xFF, 0x2F, 0x00]]);
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(4 + 8 + smf.len() as u32).to_le_bytes());
+        data.extend_from_slice(b"RMID");
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&(smf.len() as u32).to_le_bytes());
+        data.extend_from_slice(&smf);
+        let parsed = Smf::parse(&data).unwrap();
+        assert_eq!(parsed.tracks[0][0], channel(0, 0x90, 60, 100));
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = smf_bytes(
+            480,
+            &[&[
+                0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, 0x00, 0x90, 60, 100, 0x83, 0x60, 0x80,
+                60, 0x00, 0x00, 0xFF, 0x2F, 0x00,
+            ]],
+        );
+        let smf = Smf::parse(&data).unwrap();
+        let reparsed = Smf::parse(&smf.to_bytes()).unwrap();
+        assert_eq!(reparsed.tracks, smf.tracks);
+        assert_eq!(reparsed.division, 480);
+    }
+
+    #[test]
+    fn tempo_map() {
+        // 120 BPM for the first bar, then 60 BPM
+        let tempo = TempoMap::new(480, vec![(1920, 1_000_000), (0, 500_000)], Vec::new());
+        assert_eq!(tempo.seconds(0), 0.0);
+        assert_eq!(tempo.seconds(960), 1.0);
+        assert_eq!(tempo.seconds(1920), 2.0);
+        assert_eq!(tempo.seconds(2400), 3.0);
+        assert_eq!(tempo.ticks(1.0), 960.0);
+        assert_eq!(tempo.ticks(3.0), 2400.0);
+    }
+
+    #[test]
+    fn bpm_follows_tempo_changes() {
+        let tempo = TempoMap::new(480, vec![(1920, 1_000_000), (0, 500_000)], Vec::new());
+        assert_eq!(tempo.bpm(1.0), 120.0);
+        assert_eq!(tempo.bpm(2.5), 60.0);
+    }
+
+    #[test]
+    fn fractional_ticks_keep_their_fraction() {
+        let tempo = TempoMap::new(480, vec![(1920, 1_000_000), (0, 500_000)], Vec::new());
+        assert!((tempo.fractional_seconds(960.5) - (1.0 + 0.5 / 960.0)).abs() < 1e-12);
+        assert!((tempo.fractional_seconds(2160.0) - 2.5).abs() < 1e-12);
+        // A MIDI clock pulse at 100 ticks per quarter note isn't a whole number of ticks, so a
+        // thousand quarter notes of pulses would drift if each were rounded
+        let tempo = TempoMap::new(100, Vec::new(), Vec::new());
+        let pulse = 100.0 / 24.0;
+        assert!((tempo.fractional_seconds(pulse * 24_000.0) - 500.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn tempo_map_defaults_to_120_bpm() {
+        let tempo = TempoMap::new(96, Vec::new(), Vec::new());
+        assert_eq!(tempo.seconds(96), 0.5);
+        assert_eq!(tempo.signatures[0].numerator, 4);
+    }
+
+    #[test]
+    fn smpte_timing() {
+        // 25 frames per second, 40 ticks per frame
+        let division = ((-25_i8 as u8 as u16) << 8) | 40;
+        let tempo = TempoMap::new(division, Vec::new(), Vec::new());
+        assert_eq!(tempo.seconds(1000), 1.0);
+    }
+
+    #[test]
+    fn bars_and_beats() {
+        let signatures = vec![
+            TimeSignature {
+                tick: 0,
+                numerator: 3,
+                denominator: 4,
+            },
+            TimeSignature {
+                tick: 2880,
+                numerator: 6,
+                denominator: 8,
+            },
+        ];
+        let tempo = TempoMap::new(480, Vec::new(), signatures);
+        assert_eq!(tempo.bar_beat_tick(1440 + 480 + 10), (1, 1, 10));
+        assert_eq!(tempo.bar_beat_tick(2880 + 1440 + 240), (3, 1, 0));
+        assert_eq!(tempo.bar_start(3), 2880 + 1440);
+        assert_eq!(tempo.next_boundary(1, GridUnit::Bar), 1440);
+        assert_eq!(tempo.next_boundary(2881, GridUnit::Beat), 3120);
+    }
+
+    #[test]
+    fn song_merges_tracks_in_time() {
+        let conductor = [
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 60 BPM
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let notes = [
+            0x00, 0x91, 60, 100, 0x81, 0x40, 0x81, 60, 0, 0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let smf = Smf::parse(&smf_bytes(96, &[&conductor, &notes])).unwrap();
+        let song = Song::from_smf(&smf, None);
+        assert_eq!(song.events.len(), 2);
+        assert_eq!(song.events[1].tick, 192);
+        assert_eq!(song.events[1].time, 2.0);
+        assert_eq!(song.length, 2.0);
+    }
+
+    #[test]
+    fn silenced_tracks_keep_meta_events() {
+        let tempo = TrackEvent {
+            tick: 0,
+            kind: EventKind::Meta(0x51, vec![0x07, 0xA1, 0x20]),
+        };
+        let mut smf = Smf {
+            format: 1,
+            division: 96,
+            tracks: vec![
+                vec![
+                    tempo.clone(),
+                    channel(0, 0x90, 60, 100),
+                    channel(96, 0x80, 60, 0),
+                ],
+                vec![channel(0, 0x91, 64, 100)],
+            ],
+        };
+        smf.silence_track(0);
+        smf.silence_track(5);
+        assert_eq!(smf.tracks[0], [tempo]);
+        assert_eq!(smf.tracks[1], [channel(0, 0x91, 64, 100)]);
+    }
+
+    #[test]
+    fn remapped_channels_keep_their_commands() {
+        let mut smf = Smf {
+            format: 0,
+            division: 96,
+            tracks: vec![vec![
+                channel(0, 0x90, 60, 100),
+                channel(0, 0xC1, 5, 0),
+                channel(0, 0xB2, 7, 90),
+            ]],
+        };
+        smf.remap_channels(&[(0, 3), (1, 0)]);
+        assert_eq!(
+            smf.tracks[0],
+            [
+                channel(0, 0x93, 60, 100),
+                channel(0, 0xC0, 5, 0),
+                channel(0, 0xB2, 7, 90),
+            ]
+        );
+    }
+
+    #[test]
+    fn transpose_skips_percussion() {
+        let mut smf = Smf {
+            format: 0,
+            division: 96,
+            tracks: vec![vec![
+                channel(0, 0x90, 60, 100),
+                channel(0, 0x99, 36, 100),
+                channel(0, 0x90, 126, 100),
+                channel(0, 0xB0, 7, 90),
+            ]],
+        };
+        smf.transpose(3);
+        assert_eq!(
+            smf.tracks[0],
+            [
+                channel(0, 0x90, 63, 100),
+                channel(0, 0x99, 36, 100),
+                channel(0, 0x90, 127, 100),
+                channel(0, 0xB0, 7, 90),
+            ]
+        );
+    }
+
+    #[test]
+    fn scaled_tempo_starts_from_the_default() {
+        let tempo = |tick, micros: u32| TrackEvent {
+            tick,
+            kind: EventKind::Meta(0x51, micros.to_be_bytes()[1..].to_vec()),
+        };
+        let mut smf = Smf {
+            format: 0,
+            division: 96,
+            tracks: vec![vec![channel(0, 0x90, 60, 100), tempo(96, 1_000_000)]],
+        };
+        smf.scale_tempo(2.0);
+        assert_eq!(
+            smf.tracks[0],
+            [
+                tempo(0, 250_000),
+                channel(0, 0x90, 60, 100),
+                tempo(96, 500_000),
+            ]
+        );
+    }
+}
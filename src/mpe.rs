@@ -0,0 +1,260 @@
+use std::ops::Range;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{MidiControl, MidiInputMessage};
+
+/// Center of the pitch bend range, which leaves the pitch unchanged
+const BEND_CENTER: i32 = 8192;
+
+/// Component playing live input from an MPE (MIDI Polyphonic Expression) controller, such as a
+/// LinnStrument or Seaboard, which plays each note on its own member channel so it can be bent
+/// and pressed on its own.
+///
+/// A lower zone is led by the master channel 1 with member channels from 2 up, and an upper
+/// zone by the master channel 16 with member channels from 15 down. Programs and controllers
+/// sent on a master channel apply to every member channel of its zone, and its pitch bend is
+/// added to the bend of each note. Controllers which announce their zones with an MPE
+/// Configuration Message change them as they play.
+///
+/// It applies to messages sent with [`MidiControl::send_input`].
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MidiMpe {
+    /// Number of member channels of the lower zone, from 0 to 15
+    pub lower: u8,
+    /// Number of member channels of the upper zone, from 0 to 15, limited to the channels the
+    /// lower zone leaves
+    pub upper: u8,
+    /// Pitch bend range of member channels in semitones
+    pub member_bend_range: u8,
+    /// Pitch bend range of master channels in semitones
+    pub master_bend_range: u8,
+}
+
+impl Default for MidiMpe {
+    fn default() -> Self {
+        Self::lower(15)
+    }
+}
+
+impl MidiMpe {
+    /// A lower zone with the given number of member channels
+    pub fn lower(members: u8) -> Self {
+        Self {
+            lower: members.min(15),
+            upper: 0,
+            member_bend_range: 48,
+            master_bend_range: 2,
+        }
+    }
+
+    /// An upper zone with the given number of member channels
+    pub fn upper(members: u8) -> Self {
+        Self {
+            lower: 0,
+            upper: members.min(15),
+            ..Self::lower(0)
+        }
+    }
+
+    /// Bend member channels by up to the given number of semitones
+    pub fn with_member_bend_range(mut self, semitones: u8) -> Self {
+        self.member_bend_range = semitones;
+        self
+    }
+
+    /// Bend master channels by up to the given number of semitones
+    pub fn with_master_bend_range(mut self, semitones: u8) -> Self {
+        self.master_bend_range = semitones;
+        self
+    }
+
+    /// Member channels of the upper zone, after the lower zone has taken its channels
+    fn upper_members(&self) -> u8 {
+        let lower = self.lower.min(15);
+        match lower {
+            0 => self.upper.min(15),
+            _ => self.upper.min(14_u8.saturating_sub(lower)),
+        }
+    }
+
+    /// Master channel of the zone a channel belongs to, counting from 0
+    fn master(&self, channel: u8) -> Option<u8> {
+        let lower = self.lower.min(15);
+        let upper = self.upper_members();
+        if lower > 0 && channel <= lower {
+            Some(0)
+        } else if upper > 0 && channel >= 15 - upper {
+            Some(15)
+        } else {
+            None
+        }
+    }
+
+    /// Member channels of the zone led by a master channel
+    fn members(&self, master: u8) -> Range<u8> {
+        match master {
+            0 => 1..self.lower.min(15) + 1,
+            15 => 15 - self.upper_members()..15,
+            _ => 0..0,
+        }
+    }
+
+    /// Whether the channel plays notes of a zone
+    pub(crate) fn covers(&self, channel: u8) -> bool {
+        self.master(channel).is_some()
+    }
+}
+
+/// Sources whose MPE zones changed, or which can only now be controlled
+type ChangedMpeFilter = Or<(Changed<MidiMpe>, Added<MidiControl>)>;
+
+pub(crate) fn apply_mpe_zones(query: Query<(&MidiControl, &MidiMpe), ChangedMpeFilter>) {
+    for (control, mpe) in &query {
+        control.set_mpe(Some(*mpe));
+    }
+}
+
+/// Live input of an MPE controller, turned into messages for a synthesizer with one bend per
+/// channel
+#[derive(Debug)]
+pub(crate) struct MpeState {
+    zones: Option<MidiMpe>,
+    /// Pitch bend last sent on each channel
+    bends: [i32; 16],
+    /// Registered parameter selected on each channel, MSB and LSB
+    parameters: [(u8, u8); 16],
+}
+
+impl Default for MpeState {
+    fn default() -> Self {
+        Self {
+            zones: None,
+            bends: [BEND_CENTER; 16],
+            parameters: [(127, 127); 16],
+        }
+    }
+}
+
+/// Channel, command, and data bytes of a message for a synthesizer
+type SynthMessage = (i32, i32, i32, i32);
+
+impl MpeState {
+    /// Zones the input is played with, if any
+    pub(crate) fn zones(&self) -> Option<&MidiMpe> {
+        self.zones.as_ref()
+    }
+
+    /// Play with new zones, returning the messages setting up their channels
+    pub(crate) fn set_zones(&mut self, zones: Option<MidiMpe>) -> Vec<SynthMessage> {
+        self.zones = zones;
+        self.bends = [BEND_CENTER; 16];
+        let Some(zones) = zones else {
+            return Vec::new();
+        };
+        let mut messages = Vec::new();
+        for master in [0, 15] {
+            if zones.members(master).is_empty() {
+                continue;
+            }
+            messages.extend(bend_range(master, zones.master_bend_range));
+            for member in zones.members(master) {
+                messages.extend(bend_range(member, zones.member_bend_range));
+            }
+        }
+        messages
+    }
+
+    /// Messages playing a live input message
+    pub(crate) fn expand(&mut self, message: MidiInputMessage) -> Vec<SynthMessage> {
+        let Some(zones) = self.zones else {
+            return vec![message.to_message()];
+        };
+        let channel = message.channel() & 0x0F;
+        if let MidiInputMessage::ControlChange {
+            controller, value, ..
+        } = message
+        {
+            let parameter = &mut self.parameters[channel as usize];
+            match controller {
+                101 => parameter.0 = value,
+                100 => parameter.1 = value,
+                // An MPE Configuration Message on a master channel sets up its zone
+                6 if *parameter == (0, 6) && matches!(channel, 0 | 15) => {
+                    let mut zones = zones;
+                    match channel {
+                        0 => zones.lower = value.min(15),
+                        _ => zones.upper = value.min(15),
+                    }
+                    return self.set_zones(Some(zones));
+                }
+                // Pitch bend range, which the zone's bends are added up with
+                6 if *parameter == (0, 0) && zones.covers(channel) => {
+                    let mut zones = zones;
+                    match channel {
+                        0 | 15 => zones.master_bend_range = value,
+                        _ => zones.member_bend_range = value,
+                    }
+                    return self.set_zones(Some(zones));
+                }
+                _ => {}
+            }
+        }
+        let Some(master) = zones.master(channel) else {
+            return vec![message.to_message()];
+        };
+        match message {
+            MidiInputMessage::PitchBend { value, .. } => {
+                self.bends[channel as usize] = value.min(0x3FFF) as i32;
+                if channel == master {
+                    // The master's bend moves every note of the zone
+                    zones
+                        .members(master)
+                        .map(|member| self.member_bend(&zones, master, member))
+                        .collect()
+                } else {
+                    vec![self.member_bend(&zones, master, channel)]
+                }
+            }
+            // Zone-wide messages apply to every member channel
+            MidiInputMessage::ControlChange { .. }
+            | MidiInputMessage::ProgramChange { .. }
+            | MidiInputMessage::ChannelPressure { .. }
+                if channel == master =>
+            {
+                std::iter::once(master)
+                    .chain(zones.members(master))
+                    .map(|to| message.with_channel(to).to_message())
+                    .collect()
+            }
+            _ => vec![message.to_message()],
+        }
+    }
+
+    /// Pitch bend of a member channel, adding the master's bend to its own
+    fn member_bend(&self, zones: &MidiMpe, master: u8, member: u8) -> SynthMessage {
+        let semitones = |bend: i32, range: u8| (bend - BEND_CENTER) as f64 / 8192.0 * range as f64;
+        let total = semitones(self.bends[member as usize], zones.member_bend_range)
+            + semitones(self.bends[master as usize], zones.master_bend_range);
+        let range = zones.member_bend_range.max(1) as f64;
+        let bend = (BEND_CENTER as f64 + total / range * 8192.0)
+            .round()
+            .clamp(0.0, 16383.0) as i32;
+        (member as i32, 0xE0, bend & 0x7F, bend >> 7)
+    }
+}
+
+/// Messages setting the pitch bend range of a channel through registered parameter 0
+fn bend_range(channel: u8, semitones: u8) -> [SynthMessage; 6] {
+    let channel = channel as i32;
+    [
+        (channel, 0xB0, 101, 0),
+        (channel, 0xB0, 100, 0),
+        (channel, 0xB0, 6, semitones.min(127) as i32),
+        (channel, 0xB0, 38, 0),
+        // Deselect the parameter so later data entry doesn't change it
+        (channel, 0xB0, 101, 127),
+        (channel, 0xB0, 100, 127),
+    ]
+}
@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use bevy::{audio::PlaybackMode, prelude::*};
+
+use crate::{
+    crossfade::{output_finished, SourceOutput},
+    FadeOutMidi, MidiAudio, MidiControl,
+};
+
+/// Fade applied when music starts playing
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FadeIn(pub Duration);
+
+/// Fade applied when music stops playing
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FadeOut(pub Duration);
+
+#[derive(Debug)]
+enum MusicRequest {
+    Play(Handle<MidiAudio>, FadeIn),
+    Stop(FadeOut),
+}
+
+#[derive(Debug)]
+struct NowPlaying {
+    entity: Option<Entity>,
+    handle: Handle<MidiAudio>,
+    control: MidiControl,
+}
+
+/// Resource managing a single background music track.
+///
+/// Requests are applied by the plugin at the end of the frame. Starting a new track while
+/// another is playing fades the old one out over the new track's fade-in.
+#[derive(Resource, Debug)]
+pub struct MidiMusicManager {
+    settings: PlaybackSettings,
+    /// Whether tracks start again from the beginning when they end, looped by the decoder so
+    /// they can still be faded out and stopped
+    pub looping: bool,
+    requests: Vec<MusicRequest>,
+    now_playing: Option<NowPlaying>,
+    paused: bool,
+}
+
+impl Default for MidiMusicManager {
+    fn default() -> Self {
+        Self {
+            settings: PlaybackSettings::DESPAWN,
+            looping: true,
+            requests: Vec::new(),
+            now_playing: None,
+            paused: false,
+        }
+    }
+}
+
+impl MidiMusicManager {
+    /// Play a track, replacing whatever is currently playing
+    pub fn play(&mut self, handle: Handle<MidiAudio>, fade: FadeIn) {
+        self.requests.push(MusicRequest::Play(handle, fade));
+    }
+
+    /// Stop the current track
+    pub fn stop(&mut self, fade: FadeOut) {
+        self.requests.push(MusicRequest::Stop(fade));
+    }
+
+    /// Playback settings used when spawning music sources
+    pub fn settings(&self) -> &PlaybackSettings {
+        &self.settings
+    }
+
+    /// Set the playback settings used when spawning music sources.
+    ///
+    /// The playback mode is ignored: the manager despawns tracks once they end, and
+    /// [`looping`](Self::looping) decides whether they start again.
+    pub fn set_settings(&mut self, settings: PlaybackSettings) {
+        self.settings = PlaybackSettings {
+            mode: PlaybackMode::Despawn,
+            ..settings
+        };
+    }
+
+    /// Pause the current track
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resume the current track after a pause
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Whether music is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Handle of the track currently playing, if any
+    pub fn now_playing(&self) -> Option<&Handle<MidiAudio>> {
+        self.now_playing.as_ref().map(|playing| &playing.handle)
+    }
+
+    /// Entity of the source currently playing, once it has been spawned
+    pub fn entity(&self) -> Option<Entity> {
+        self.now_playing.as_ref().and_then(|playing| playing.entity)
+    }
+}
+
+pub(crate) fn update_music_manager(
+    mut commands: Commands,
+    mut manager: ResMut<MidiMusicManager>,
+    outputs: Query<SourceOutput>,
+) {
+    let manager = &mut *manager;
+    for request in manager.requests.drain(..) {
+        match request {
+            MusicRequest::Play(handle, FadeIn(duration)) => {
+                if let Some(NowPlaying {
+                    entity: Some(entity),
+                    ..
+                }) = manager.now_playing.take()
+                {
+                    commands.add(FadeOutMidi { entity, duration });
+                }
+                let control = MidiControl::with_gain(0.0);
+                control.fade_to(1.0, duration);
+                control.set_looping(manager.looping);
+                manager.now_playing = Some(NowPlaying {
+                    entity: None,
+                    handle,
+                    control,
+                });
+            }
+            MusicRequest::Stop(FadeOut(duration)) => {
+                if let Some(NowPlaying {
+                    entity: Some(entity),
+                    ..
+                }) = manager.now_playing.take()
+                {
+                    commands.add(FadeOutMidi { entity, duration });
+                }
+            }
+        }
+    }
+
+    let Some(playing) = manager.now_playing.as_mut() else {
+        return;
+    };
+    // Tracks which aren't looping end on their own
+    if let Some(entity) = playing.entity {
+        if outputs.get(entity).map_or(true, output_finished) {
+            if let Some(entity) = commands.get_entity(entity) {
+                entity.despawn_recursive();
+            }
+            manager.now_playing = None;
+            return;
+        }
+    }
+    playing.entity.get_or_insert_with(|| {
+        commands
+            .spawn((
+                AudioSourceBundle {
+                    source: playing.handle.clone(),
+                    settings: manager.settings,
+                },
+                playing.control.clone(),
+            ))
+            .id()
+    });
+    if manager.paused && !playing.control.is_paused() {
+        playing.control.pause();
+    } else if !manager.paused && playing.control.is_paused() {
+        playing.control.resume();
+    }
+}
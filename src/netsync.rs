@@ -0,0 +1,315 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::Duration,
+};
+
+use bevy::{
+    audio::{AudioSink, AudioSinkPlayback, SpatialAudioSink},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    midi::TempoMap, reload::MidiSourceOrigin, HeadlessMidiOutput, MidiAudio, MidiControl,
+    MidiLayerPlayer, MidiSource,
+};
+
+/// Drift beyond which a [`MidiSyncClient`] seeks instead of adjusting its speed
+const DEFAULT_MAX_DRIFT: Duration = Duration::from_millis(250);
+
+/// Largest relative speed change a [`MidiSyncClient`] makes to catch up with the host
+const DEFAULT_MAX_NUDGE: f64 = 0.03;
+
+/// Time over which a [`MidiSyncClient`] aims to make up its drift
+const CATCH_UP_SECONDS: f64 = 2.0;
+
+/// Fade applied to layer volume changes received from the host, avoiding clicks
+const LAYER_FADE: Duration = Duration::from_millis(50);
+
+/// Compact snapshot of a source's playback, sent by a host to keep clients' music in sync.
+///
+/// It's serializable with any serde format, so it can be sent over whichever networking crate
+/// the game uses.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MidiSyncState {
+    /// Asset path of the music playing, if it was loaded from one
+    pub song: Option<String>,
+    /// Position in the music, in MIDI ticks
+    pub tick: u64,
+    /// Tempo in quarter notes per minute, including the host's playback speed
+    pub bpm: f32,
+    /// Whether the music is playing
+    pub playing: bool,
+    /// Volumes of the layers of a [`MidiLayerPlayer`], by name
+    pub layers: Vec<(String, f32)>,
+}
+
+/// Component exposing the [`MidiSyncState`] of a MIDI source with a [`MidiControl`], for a host
+/// to send to its clients.
+#[derive(Component, Clone, Debug, Default)]
+pub struct MidiSyncHost {
+    state: Option<MidiSyncState>,
+}
+
+impl MidiSyncHost {
+    /// State of the source as of this frame, once its music has loaded
+    pub fn state(&self) -> Option<&MidiSyncState> {
+        self.state.as_ref()
+    }
+}
+
+/// Component keeping a MIDI source with a [`MidiControl`] in sync with states received from a
+/// [`MidiSyncHost`].
+///
+/// Small drift is corrected by briefly playing slightly faster or slower, and larger drift by
+/// seeking, so the client owns the source's [`MidiControl::set_speed`]. A different song is
+/// loaded and started in place of the current one.
+#[derive(Component, Clone, Debug)]
+pub struct MidiSyncClient {
+    /// Drift beyond which the source seeks to the host's position
+    pub max_drift: Duration,
+    /// Largest relative speed change used to catch up, e.g. `0.03` for 3%
+    pub max_nudge: f64,
+    state: Option<MidiSyncState>,
+    /// Time elapsed at the host since the state was captured
+    elapsed: Duration,
+    /// Whether the state hasn't been applied yet
+    received: bool,
+    drift: Option<f64>,
+}
+
+impl Default for MidiSyncClient {
+    fn default() -> Self {
+        Self {
+            max_drift: DEFAULT_MAX_DRIFT,
+            max_nudge: DEFAULT_MAX_NUDGE,
+            state: None,
+            elapsed: Duration::ZERO,
+            received: false,
+            drift: None,
+        }
+    }
+}
+
+impl MidiSyncClient {
+    /// Follow a state received from the host, captured `latency` ago
+    pub fn apply(&mut self, state: MidiSyncState, latency: Duration) {
+        self.state = Some(state);
+        self.elapsed = latency;
+        self.received = true;
+    }
+
+    /// Last state received from the host
+    pub fn state(&self) -> Option<&MidiSyncState> {
+        self.state.as_ref()
+    }
+
+    /// Seconds the source was behind the host on the last frame, negative when ahead, once
+    /// both are playing the same loaded music
+    pub fn drift(&self) -> Option<f64> {
+        self.drift
+    }
+
+    /// Position of the host now, in seconds of music
+    fn host_position(&self, state: &MidiSyncState, tempo: &TempoMap) -> f64 {
+        let position = tempo.seconds(state.tick);
+        if !state.playing {
+            return position;
+        }
+        position + self.elapsed.as_secs_f64() * host_speed(state, tempo, position)
+    }
+}
+
+/// Speed of the host's playback relative to the music's own tempo
+fn host_speed(state: &MidiSyncState, tempo: &TempoMap, position: f64) -> f64 {
+    let bpm = tempo.bpm(position);
+    if bpm > 0.0 {
+        state.bpm as f64 / bpm
+    } else {
+        1.0
+    }
+}
+
+/// Tempo maps of loaded MIDI assets, kept up to date as they're modified
+#[derive(Default)]
+pub(crate) struct TempoMaps(HashMap<AssetId<MidiAudio>, Option<TempoMap>>);
+
+impl TempoMaps {
+    fn update(&mut self, events: &mut EventReader<AssetEvent<MidiAudio>>) {
+        for event in events.read() {
+            match *event {
+                AssetEvent::Modified { id }
+                | AssetEvent::Removed { id }
+                | AssetEvent::Unused { id } => {
+                    self.0.remove(&id);
+                }
+                AssetEvent::Added { .. } | AssetEvent::LoadedWithDependencies { .. } => {}
+            }
+        }
+    }
+
+    fn get(&mut self, assets: &Assets<MidiAudio>, id: AssetId<MidiAudio>) -> Option<&TempoMap> {
+        match self.0.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut().as_ref(),
+            Entry::Vacant(entry) => {
+                let audio = assets.get(id)?;
+                entry
+                    .insert(audio.to_song().ok().map(|song| song.tempo))
+                    .as_ref()
+            }
+        }
+    }
+}
+
+type HostQuery = (
+    &'static mut MidiSyncHost,
+    &'static MidiControl,
+    Option<&'static Handle<MidiAudio>>,
+    Option<&'static MidiSourceOrigin>,
+    Option<&'static MidiLayerPlayer>,
+);
+
+/// Audio a source plays, or the first layer of a layer player
+fn source_audio<'a>(
+    handle: Option<&'a Handle<MidiAudio>>,
+    origin: Option<&'a MidiSourceOrigin>,
+    layers: Option<&'a MidiLayerPlayer>,
+) -> Option<&'a Handle<MidiAudio>> {
+    handle
+        .or(origin.map(|origin| &origin.0))
+        .or(layers.and_then(|player| player.layers.first().map(|layer| &layer.audio)))
+}
+
+/// Capture the state of every [`MidiSyncHost`]
+pub(crate) fn capture_sync_states(
+    asset_server: Res<AssetServer>,
+    midi_assets: Res<Assets<MidiAudio>>,
+    mut events: EventReader<AssetEvent<MidiAudio>>,
+    mut tempo_maps: Local<TempoMaps>,
+    mut query: Query<HostQuery>,
+) {
+    tempo_maps.update(&mut events);
+    for (mut host, control, handle, origin, layers) in &mut query {
+        let Some(audio) = source_audio(handle, origin, layers) else {
+            continue;
+        };
+        let Some(tempo) = tempo_maps.get(&midi_assets, audio.id()) else {
+            continue;
+        };
+        let position = control.position().as_secs_f64();
+        let layers = layers.map_or_else(Vec::new, |player| {
+            player
+                .layers
+                .iter()
+                .filter_map(|layer| Some((layer.name.clone(), player.volume(&layer.name)?)))
+                .collect()
+        });
+        host.state = Some(MidiSyncState {
+            song: asset_server
+                .get_path(audio.id())
+                .map(|path| path.to_string()),
+            tick: tempo.ticks(position) as u64,
+            bpm: (tempo.bpm(position) * control.playback_speed()) as f32,
+            playing: !control.is_paused() && !control.is_stopped(),
+            layers,
+        });
+    }
+}
+
+type ClientQuery = (
+    Entity,
+    &'static mut MidiSyncClient,
+    &'static MidiControl,
+    Option<&'static Handle<MidiAudio>>,
+    Option<&'static MidiSourceOrigin>,
+    Option<&'static MidiLayerPlayer>,
+    Option<&'static AudioSink>,
+    Option<&'static SpatialAudioSink>,
+);
+
+/// Move every [`MidiSyncClient`] towards its host's position
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn apply_sync_states(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    asset_server: Res<AssetServer>,
+    midi_assets: Res<Assets<MidiAudio>>,
+    mut events: EventReader<AssetEvent<MidiAudio>>,
+    mut tempo_maps: Local<TempoMaps>,
+    mut query: Query<ClientQuery>,
+) {
+    tempo_maps.update(&mut events);
+    for (entity, mut client, control, handle, origin, layers, sink, spatial_sink) in &mut query {
+        let client = &mut *client;
+        let Some(state) = client.state.as_ref() else {
+            continue;
+        };
+        if state.playing {
+            client.elapsed += time.delta();
+        }
+        let received = std::mem::take(&mut client.received);
+
+        let audio = source_audio(handle, origin, layers);
+        let song = audio.and_then(|audio| asset_server.get_path(audio.id()));
+        if let (Some(path), false) = (&state.song, layers.is_some()) {
+            if song.map_or(true, |song| song.to_string() != *path) {
+                // Start the host's song in place of the current one, as on hot reload
+                if let Some(sink) = sink {
+                    sink.stop();
+                }
+                if let Some(sink) = spatial_sink {
+                    sink.stop();
+                }
+                commands
+                    .entity(entity)
+                    .remove::<(
+                        AudioSink,
+                        SpatialAudioSink,
+                        HeadlessMidiOutput,
+                        Handle<MidiSource>,
+                        MidiSourceOrigin,
+                    )>()
+                    .insert(asset_server.load::<MidiAudio>(path.clone()));
+                client.received = true;
+                client.drift = None;
+                continue;
+            }
+        }
+
+        if received {
+            if let Some(player) = layers {
+                for (name, volume) in &state.layers {
+                    if player
+                        .volume(name)
+                        .is_some_and(|current| current != *volume)
+                    {
+                        player.fade_volume(name, *volume, LAYER_FADE);
+                    }
+                }
+            }
+            if state.playing {
+                control.resume();
+            } else {
+                control.pause();
+            }
+        }
+
+        let Some(tempo) = audio.and_then(|audio| tempo_maps.get(&midi_assets, audio.id())) else {
+            continue;
+        };
+        let host = client.host_position(state, tempo);
+        let drift = host - control.position().as_secs_f64();
+        client.drift = Some(drift);
+        let speed = host_speed(state, tempo, host);
+        if control.is_seeking() {
+            continue;
+        }
+        if drift.abs() > client.max_drift.as_secs_f64() {
+            control.seek(Duration::from_secs_f64(host.max(0.0)));
+            control.set_speed(speed);
+        } else if state.playing {
+            let nudge = (drift / CATCH_UP_SECONDS).clamp(-client.max_nudge, client.max_nudge);
+            control.set_speed(speed * (1.0 + nudge));
+        }
+    }
+}
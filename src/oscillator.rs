@@ -0,0 +1,245 @@
+use std::f32::consts::{FRAC_PI_2, TAU};
+
+use crate::SynthBackend;
+
+/// Number of frames rendered between handling messages
+const BLOCK_SIZE: usize = 64;
+/// Time taken for a note to reach full volume
+const ATTACK: f32 = 0.005;
+/// Time taken for a released note to fade by about 60dB
+const RELEASE: f32 = 0.15;
+/// Length of a percussion hit
+const PERCUSSION_DECAY: f32 = 0.08;
+/// Headroom left for several notes sounding at once
+const MASTER_GAIN: f32 = 0.2;
+
+/// Shape of the wave played by an [`OscillatorSynth`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Waveform {
+    /// Smooth sine wave
+    #[default]
+    Sine,
+    /// Buzzy square wave
+    Square,
+}
+
+/// Tiny built-in synthesizer playing each note as a plain oscillator with a simple envelope.
+///
+/// Used instead of rustysynth when the plugin has no soundfont, so music can be heard without
+/// any assets. Notes on the percussion channel play short bursts of noise.
+pub struct OscillatorSynth {
+    sample_rate: i32,
+    polyphony: usize,
+    waveform: Waveform,
+    voices: Vec<Voice>,
+    channels: [Channel; 16],
+    noise: u32,
+}
+
+/// Controller state of a MIDI channel played by one of the built-in backends
+#[derive(Clone, Copy)]
+pub(crate) struct Channel {
+    volume: f32,
+    expression: f32,
+    pan: f32,
+    /// Pitch bend in semitones
+    pub(crate) bend: f32,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            volume: 100.0 / 127.0,
+            expression: 1.0,
+            pan: 0.5,
+            bend: 0.0,
+        }
+    }
+}
+
+impl Channel {
+    /// Apply a volume, pan, expression or reset controller
+    pub(crate) fn control_change(&mut self, controller: u8, value: u8) {
+        let value = value as f32 / 127.0;
+        match controller {
+            7 => self.volume = value,
+            10 => self.pan = value,
+            11 => self.expression = value,
+            121 => {
+                *self = Self {
+                    volume: self.volume,
+                    ..Self::default()
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Apply a pitch bend message, with a range of two semitones
+    pub(crate) fn pitch_bend(&mut self, data1: u8, data2: u8) {
+        let bend = ((data2 as i32) << 7 | data1 as i32) - 8192;
+        self.bend = bend as f32 / 8192.0 * 2.0;
+    }
+
+    /// Gain of the left and right outputs
+    pub(crate) fn gains(&self) -> (f32, f32) {
+        let gain = self.volume * self.expression;
+        let angle = self.pan * FRAC_PI_2;
+        (gain * angle.cos(), gain * angle.sin())
+    }
+}
+
+struct Voice {
+    channel: usize,
+    key: u8,
+    gain: f32,
+    phase: f32,
+    envelope: f32,
+    released: bool,
+}
+
+impl OscillatorSynth {
+    /// Create a synthesizer rendering at `sample_rate`, playing at most `polyphony` notes at once
+    pub fn new(sample_rate: i32, polyphony: usize, waveform: Waveform) -> Self {
+        Self {
+            sample_rate,
+            polyphony: polyphony.max(1),
+            waveform,
+            voices: Vec::new(),
+            channels: [Channel::default(); 16],
+            noise: 0x1234_5678,
+        }
+    }
+
+    fn start_note(&mut self, channel: usize, key: u8, velocity: u8) {
+        if self.voices.len() >= self.polyphony {
+            self.voices.remove(0);
+        }
+        let velocity = velocity as f32 / 127.0;
+        self.voices.push(Voice {
+            channel,
+            key,
+            gain: velocity * velocity,
+            phase: 0.0,
+            envelope: 0.0,
+            released: false,
+        });
+    }
+
+    fn release_note(&mut self, channel: usize, key: u8) {
+        for voice in &mut self.voices {
+            if voice.channel == channel && voice.key == key {
+                voice.released = true;
+            }
+        }
+    }
+
+    fn release_channel(&mut self, channel: usize, immediate: bool) {
+        if immediate {
+            self.voices.retain(|voice| voice.channel != channel);
+        } else {
+            for voice in &mut self.voices {
+                if voice.channel == channel {
+                    voice.released = true;
+                }
+            }
+        }
+    }
+
+    fn control_change(&mut self, channel: usize, controller: u8, value: u8) {
+        match controller {
+            120 => self.release_channel(channel, true),
+            123 => self.release_channel(channel, false),
+            _ => self.channels[channel].control_change(controller, value),
+        }
+    }
+
+    /// Next value of a simple xorshift noise generator, between -1 and 1
+    fn next_noise(noise: &mut u32) -> f32 {
+        *noise ^= *noise << 13;
+        *noise ^= *noise >> 17;
+        *noise ^= *noise << 5;
+        *noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+impl SynthBackend for OscillatorSynth {
+    fn process_midi_message(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        let channel = channel.clamp(0, 15) as usize;
+        let (data1, data2) = (data1.clamp(0, 127) as u8, data2.clamp(0, 127) as u8);
+        match command {
+            0x90 if data2 > 0 => self.start_note(channel, data1, data2),
+            0x80 | 0x90 => self.release_note(channel, data1),
+            0xB0 => self.control_change(channel, data1, data2),
+            0xE0 => self.channels[channel].pitch_bend(data1, data2),
+            _ => {}
+        }
+    }
+
+    fn note_off_all(&mut self, immediate: bool) {
+        for channel in 0..16 {
+            self.release_channel(channel, immediate);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.voices.clear();
+        self.channels = [Channel::default(); 16];
+    }
+
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        left.fill(0.0);
+        right.fill(0.0);
+        let sample_rate = self.sample_rate as f32;
+        let attack = 1.0 / (ATTACK * sample_rate);
+        let release = (-6.9 / (RELEASE * sample_rate)).exp();
+        let percussion_decay = (-6.9 / (PERCUSSION_DECAY * sample_rate)).exp();
+        for voice in &mut self.voices {
+            let channel = self.channels[voice.channel];
+            let percussion = voice.channel == 9;
+            let pitch = voice.key as f32 - 69.0 + channel.bend;
+            let step = 440.0 * (pitch / 12.0).exp2() / sample_rate;
+            let gain = voice.gain * MASTER_GAIN;
+            let (pan_left, pan_right) = channel.gains();
+            for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+                if percussion {
+                    voice.envelope = if voice.phase == 0.0 {
+                        1.0
+                    } else {
+                        voice.envelope * percussion_decay
+                    };
+                    voice.phase = 1.0;
+                } else if voice.released {
+                    voice.envelope *= release;
+                } else {
+                    voice.envelope = (voice.envelope + attack).min(1.0);
+                }
+                let value = if percussion {
+                    Self::next_noise(&mut self.noise)
+                } else {
+                    voice.phase = (voice.phase + step).fract();
+                    match self.waveform {
+                        Waveform::Sine => (voice.phase * TAU).sin(),
+                        Waveform::Square if voice.phase < 0.5 => 0.5,
+                        Waveform::Square => -0.5,
+                    }
+                };
+                let value = value * voice.envelope * gain;
+                *l += value * pan_left;
+                *r += value * pan_right;
+            }
+        }
+        self.voices.retain(|voice| {
+            let finished = voice.released || voice.channel == 9;
+            !finished || voice.envelope > 1e-4
+        });
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn sample_rate(&self) -> i32 {
+        self.sample_rate
+    }
+}
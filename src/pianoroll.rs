@@ -0,0 +1,266 @@
+use std::{sync::Arc, time::Duration};
+
+use bevy::prelude::*;
+
+use crate::{
+    midi::{GridUnit, TempoMap},
+    reload::MidiSourceOrigin,
+    MidiAudio, MidiControl, TimedMidiNote,
+};
+
+/// Most grid lines collected for a window, should the time signature be unreasonably fine
+const MAX_GRID_LINES: usize = 1024;
+
+/// A beat or bar line of a [`MidiPianoRoll`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MidiGridLine {
+    /// Time of the line in the music
+    pub time: Duration,
+    /// Whether the line starts a bar, rather than just a beat
+    pub bar: bool,
+}
+
+#[derive(Debug)]
+struct PianoRollSong {
+    audio: AssetId<MidiAudio>,
+    notes: Vec<TimedMidiNote>,
+    tempo: TempoMap,
+    /// Lowest and highest keys played, if there are any notes
+    keys: Option<(u8, u8)>,
+}
+
+/// Component collecting the notes and beat grid around the playhead of a MIDI source with a
+/// [`MidiControl`], for drawing a scrolling piano roll with gizmos or UI while building rhythm
+/// mechanics.
+///
+/// Only sources playing a single [`MidiAudio`] are followed.
+#[derive(Component, Clone, Debug)]
+pub struct MidiPianoRoll {
+    /// How far after the playhead notes and grid lines are collected
+    pub lookahead: Duration,
+    /// How far before the playhead notes and grid lines are kept
+    pub lookbehind: Duration,
+    playhead: Duration,
+    notes: Vec<TimedMidiNote>,
+    grid: Vec<MidiGridLine>,
+    song: Option<Arc<PianoRollSong>>,
+}
+
+impl Default for MidiPianoRoll {
+    fn default() -> Self {
+        Self {
+            lookahead: Duration::from_secs(4),
+            lookbehind: Duration::from_secs(1),
+            playhead: Duration::ZERO,
+            notes: Vec::new(),
+            grid: Vec::new(),
+            song: None,
+        }
+    }
+}
+
+impl MidiPianoRoll {
+    /// Position of the playhead in the music
+    pub fn playhead(&self) -> Duration {
+        self.playhead
+    }
+
+    /// Notes sounding within the window around the playhead, ordered by start time
+    pub fn notes(&self) -> &[TimedMidiNote] {
+        &self.notes
+    }
+
+    /// Beat and bar lines within the window around the playhead, in order
+    pub fn grid(&self) -> &[MidiGridLine] {
+        &self.grid
+    }
+
+    /// Lowest and highest keys played anywhere in the music, to keep the roll's scale steady
+    pub fn key_range(&self) -> Option<(u8, u8)> {
+        self.song.as_ref()?.keys
+    }
+
+    /// Collect the window around `playhead`
+    fn update(&mut self, playhead: Duration) {
+        self.playhead = playhead;
+        self.notes.clear();
+        self.grid.clear();
+        let Some(song) = &self.song else {
+            return;
+        };
+        let start = playhead.saturating_sub(self.lookbehind);
+        let end = playhead + self.lookahead;
+        let upcoming = song.notes.partition_point(|note| note.start <= end);
+        self.notes.extend(
+            song.notes[..upcoming]
+                .iter()
+                .filter(|note| note.start + note.note.duration >= start)
+                .cloned(),
+        );
+
+        let tempo = &song.tempo;
+        // SMPTE timing has no beats
+        if tempo.division & 0x8000 != 0 {
+            return;
+        }
+        let mut tick = tempo.next_boundary(
+            tempo.ticks(start.as_secs_f64()).ceil() as u64,
+            GridUnit::Beat,
+        );
+        while self.grid.len() < MAX_GRID_LINES {
+            let time = Duration::from_secs_f64(tempo.seconds(tick));
+            if time > end {
+                break;
+            }
+            self.grid.push(MidiGridLine {
+                time,
+                bar: tempo.next_boundary(tick, GridUnit::Bar) == tick,
+            });
+            tick = tempo.next_boundary(tick + 1, GridUnit::Beat);
+        }
+    }
+}
+
+type PianoRollQuery = (
+    &'static mut MidiPianoRoll,
+    &'static MidiControl,
+    Option<&'static Handle<MidiAudio>>,
+    Option<&'static MidiSourceOrigin>,
+);
+
+pub(crate) fn update_piano_rolls(
+    midi_assets: Res<Assets<MidiAudio>>,
+    mut events: EventReader<AssetEvent<MidiAudio>>,
+    mut query: Query<PianoRollQuery>,
+) {
+    let modified: Vec<_> = events
+        .read()
+        .filter_map(|event| match *event {
+            AssetEvent::Modified { id } => Some(id),
+            _ => None,
+        })
+        .collect();
+    for (mut roll, control, handle, origin) in &mut query {
+        let Some(audio) = handle.or(origin.map(|origin| &origin.0)) else {
+            continue;
+        };
+        let id = audio.id();
+        let stale = roll
+            .song
+            .as_ref()
+            .map_or(true, |song| song.audio != id || modified.contains(&id));
+        if stale {
+            roll.song = midi_assets.get(id).and_then(|audio| {
+                let notes = audio.to_notes().ok()?;
+                let keys = notes.iter().map(|note| note.note.key.clamp(0, 127) as u8);
+                let keys = keys.clone().min().zip(keys.max());
+                Some(Arc::new(PianoRollSong {
+                    audio: id,
+                    tempo: audio.to_song().ok()?.tempo,
+                    notes,
+                    keys,
+                }))
+            });
+        }
+        roll.update(control.position());
+    }
+}
+
+/// Plugin drawing every [`MidiPianoRoll`] with gizmos, as a debug overlay for building rhythm
+/// mechanics.
+///
+/// Notes scroll from right to left past the playhead, with beat and bar lines behind them. Its
+/// layout and colors are set by the [`MidiPianoRollGizmos`] resource.
+#[cfg(feature = "gizmos")]
+#[derive(Debug, Default)]
+pub struct MidiPianoRollGizmoPlugin;
+
+#[cfg(feature = "gizmos")]
+impl Plugin for MidiPianoRollGizmoPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MidiPianoRollGizmos>().add_systems(
+            Update,
+            draw_piano_rolls
+                .after(update_piano_rolls)
+                .run_if(|gizmos: Res<MidiPianoRollGizmos>| gizmos.enabled),
+        );
+    }
+}
+
+/// Layout and colors of the piano rolls drawn by the [`MidiPianoRollGizmoPlugin`]
+#[cfg(feature = "gizmos")]
+#[derive(Resource, Clone, Debug)]
+pub struct MidiPianoRollGizmos {
+    /// Whether piano rolls are drawn
+    pub enabled: bool,
+    /// Position of the playhead, halfway up the keys played
+    pub origin: Vec2,
+    /// Horizontal distance covered by a second of music
+    pub pixels_per_second: f32,
+    /// Vertical distance between neighbouring keys
+    pub key_height: f32,
+    /// Color of the notes
+    pub note_color: Color,
+    /// Color of beat lines
+    pub beat_color: Color,
+    /// Color of bar lines
+    pub bar_color: Color,
+    /// Color of the playhead
+    pub playhead_color: Color,
+}
+
+#[cfg(feature = "gizmos")]
+impl Default for MidiPianoRollGizmos {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            origin: Vec2::ZERO,
+            pixels_per_second: 200.0,
+            key_height: 8.0,
+            note_color: Color::srgb(0.2, 0.8, 1.0),
+            beat_color: Color::srgb(0.3, 0.3, 0.3),
+            bar_color: Color::WHITE,
+            playhead_color: Color::srgb(1.0, 0.2, 0.2),
+        }
+    }
+}
+
+#[cfg(feature = "gizmos")]
+fn draw_piano_rolls(
+    mut gizmos: Gizmos,
+    settings: Res<MidiPianoRollGizmos>,
+    query: Query<&MidiPianoRoll>,
+) {
+    for roll in &query {
+        // Keys in the middle of a song without notes
+        let (low, high) = roll.key_range().unwrap_or((60, 72));
+        let middle = (low as f32 + high as f32) / 2.0;
+        let y = |key: f32| settings.origin.y + (key - middle) * settings.key_height;
+        let x = |time: Duration| {
+            settings.origin.x
+                + (time.as_secs_f32() - roll.playhead().as_secs_f32()) * settings.pixels_per_second
+        };
+        // Lines span the keys played, with a key to spare on each side
+        let (bottom, top) = (y(low as f32 - 1.0), y(high as f32 + 1.0));
+        for line in roll.grid() {
+            let color = if line.bar {
+                settings.bar_color
+            } else {
+                settings.beat_color
+            };
+            let x = x(line.time);
+            gizmos.line_2d(Vec2::new(x, bottom), Vec2::new(x, top), color);
+        }
+        for note in roll.notes() {
+            let y = y(note.note.key as f32);
+            let (start, end) = (x(note.start), x(note.start + note.note.duration));
+            gizmos.line_2d(Vec2::new(start, y), Vec2::new(end, y), settings.note_color);
+        }
+        let x = settings.origin.x;
+        gizmos.line_2d(
+            Vec2::new(x, bottom),
+            Vec2::new(x, top),
+            settings.playhead_color,
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use std::time::Duration;
+
+use bevy::{asset::LoadState, prelude::*};
+
+use crate::{
+    crossfade::{output_finished, SourceOutput},
+    MidiAudio, MidiControl,
+};
+
+/// A single entry in a [`MidiPlaylist`]
+#[derive(Clone, Debug)]
+pub struct PlaylistItem {
+    /// Audio to play
+    pub audio: Handle<MidiAudio>,
+    /// Number of times to play the item back to back before moving on
+    pub plays: u32,
+    /// Silence inserted after the item has finished
+    pub gap: Duration,
+}
+
+impl PlaylistItem {
+    /// Construct an item which plays once with no gap
+    pub fn new(audio: Handle<MidiAudio>) -> Self {
+        Self {
+            audio,
+            plays: 1,
+            gap: Duration::ZERO,
+        }
+    }
+}
+
+/// Ordered list of MIDI audio to be played by a [`MidiPlaylistPlayer`]
+#[derive(Asset, TypePath, Clone, Debug, Default)]
+pub struct MidiPlaylist {
+    /// Items in playback order
+    pub items: Vec<PlaylistItem>,
+}
+
+/// How a [`MidiPlaylistPlayer`] behaves after reaching the end of an item or the playlist
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaylistRepeat {
+    /// Stop after the last item
+    #[default]
+    Off,
+    /// Repeat the current item forever
+    One,
+    /// Start over from the first item after the last
+    All,
+}
+
+/// Events emitted by [`MidiPlaylistPlayer`]s
+#[derive(Event, Clone, Debug)]
+pub enum MidiPlaylistEvent {
+    /// A new item started playing
+    ItemStarted {
+        /// Entity of the player
+        player: Entity,
+        /// Index of the item in the playlist
+        index: usize,
+    },
+    /// The player reached the end of the playlist
+    Finished {
+        /// Entity of the player
+        player: Entity,
+    },
+}
+
+/// Component which plays through a [`MidiPlaylist`], spawning a child source for each item.
+///
+/// Items whose audio fails to load are skipped.
+#[derive(Component, Debug)]
+pub struct MidiPlaylistPlayer {
+    /// Playlist to play
+    pub playlist: Handle<MidiPlaylist>,
+    /// Repeat mode
+    pub repeat: PlaylistRepeat,
+    /// Whether to play items in a random order, reshuffled on each pass
+    pub shuffle: bool,
+    /// Seed of the shuffled order, so the same seed always plays the items in the same order, or
+    /// `None` for a different order every run
+    pub shuffle_seed: Option<u64>,
+    /// Generator of the shuffled order, with the seed it started from
+    rng: Option<(Option<u64>, fastrand::Rng)>,
+    order: Vec<usize>,
+    position: usize,
+    plays: u32,
+    source: Option<Entity>,
+    gap: Option<Duration>,
+    skip: bool,
+    finished: bool,
+}
+
+impl MidiPlaylistPlayer {
+    /// Construct a player for the given playlist
+    pub fn new(playlist: Handle<MidiPlaylist>) -> Self {
+        Self {
+            playlist,
+            repeat: PlaylistRepeat::Off,
+            shuffle: false,
+            shuffle_seed: None,
+            rng: None,
+            order: Vec::new(),
+            position: 0,
+            plays: 0,
+            source: None,
+            gap: None,
+            skip: false,
+            finished: false,
+        }
+    }
+
+    /// Index into the playlist of the current item
+    pub fn current_index(&self) -> Option<usize> {
+        self.order.get(self.position).copied()
+    }
+
+    /// Whether the player has reached the end of the playlist
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Skip to the next item
+    pub fn skip(&mut self) {
+        self.skip = true;
+    }
+
+    fn reorder(&mut self, len: usize) {
+        self.order = (0..len).collect();
+        if self.shuffle {
+            let seed = self.shuffle_seed;
+            let (_, rng) = match &mut self.rng {
+                Some(rng) if rng.0 == seed => rng,
+                rng => rng.insert((
+                    seed,
+                    seed.map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed),
+                )),
+            };
+            rng.shuffle(&mut self.order);
+        }
+        self.position = 0;
+    }
+}
+
+pub(crate) fn update_playlist_players(
+    mut commands: Commands,
+    time: Res<Time>,
+    asset_server: Res<AssetServer>,
+    playlists: Res<Assets<MidiPlaylist>>,
+    outputs: Query<SourceOutput>,
+    mut players: Query<(Entity, &mut MidiPlaylistPlayer)>,
+    mut events: EventWriter<MidiPlaylistEvent>,
+) {
+    for (player_entity, mut player) in &mut players {
+        if player.finished {
+            continue;
+        }
+        let Some(playlist) = playlists.get(&player.playlist) else {
+            continue;
+        };
+        if playlist.items.is_empty() {
+            player.finished = true;
+            events.send(MidiPlaylistEvent::Finished {
+                player: player_entity,
+            });
+            continue;
+        }
+        if player.order.len() != playlist.items.len() {
+            player.reorder(playlist.items.len());
+        }
+
+        let mut skip = std::mem::take(&mut player.skip);
+        if let Some(source) = player.source {
+            let audio = &playlist.items[player.order[player.position]].audio;
+            // An item which can't load never gets an output, so move past it
+            skip |= matches!(asset_server.load_state(audio), LoadState::Failed(_));
+            if !skip && !outputs.get(source).is_ok_and(output_finished) {
+                continue;
+            }
+            commands.entity(source).despawn_recursive();
+            player.source = None;
+            player.plays += 1;
+            let item = &playlist.items[player.order[player.position]];
+            if skip {
+                player.gap = Some(Duration::ZERO);
+            } else if player.plays >= item.plays {
+                player.gap = Some(item.gap);
+            }
+        }
+
+        if let Some(gap) = player.gap {
+            let remaining = if skip {
+                Duration::ZERO
+            } else {
+                gap.saturating_sub(time.delta())
+            };
+            if !remaining.is_zero() {
+                player.gap = Some(remaining);
+                continue;
+            }
+            player.gap = None;
+            player.plays = 0;
+            if skip || player.repeat != PlaylistRepeat::One {
+                player.position += 1;
+            }
+            if player.position >= player.order.len() {
+                if player.repeat == PlaylistRepeat::All {
+                    player.reorder(playlist.items.len());
+                } else {
+                    player.finished = true;
+                    events.send(MidiPlaylistEvent::Finished {
+                        player: player_entity,
+                    });
+                    continue;
+                }
+            }
+        }
+
+        if player.source.is_none() {
+            let index = player.order[player.position];
+            let source = commands
+                .spawn((
+                    AudioSourceBundle {
+                        source: playlist.items[index].audio.clone(),
+                        settings: PlaybackSettings::ONCE,
+                    },
+                    MidiControl::default(),
+                ))
+                .set_parent(player_entity)
+                .id();
+            player.source = Some(source);
+            if player.plays == 0 {
+                events.send(MidiPlaylistEvent::ItemStarted {
+                    player: player_entity,
+                    index,
+                });
+            }
+        }
+    }
+}
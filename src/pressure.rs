@@ -0,0 +1,44 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::MidiControl;
+
+/// Component choosing what aftertouch does on a MIDI source, since soundfont synthesizers don't
+/// respond to it themselves.
+///
+/// Channel pressure and the pressure of each held key (polyphonic aftertouch) drive a controller
+/// of their channel, which follows whichever is higher of the music's own value and the
+/// strongest pressure. It applies to music and to messages sent with
+/// [`MidiControl::send_input`]. Sources without this component ignore pressure.
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiPressureTarget {
+    /// Pressure adds vibrato through the modulation wheel (CC1)
+    Modulation,
+    /// Pressure drives the given controller
+    Controller(u8),
+    /// Pressure is ignored
+    #[default]
+    Ignore,
+}
+
+impl MidiPressureTarget {
+    /// Controller driven by pressure, if any
+    pub fn controller(&self) -> Option<u8> {
+        match *self {
+            Self::Modulation => Some(1),
+            Self::Controller(controller) => Some(controller & 0x7F),
+            Self::Ignore => None,
+        }
+    }
+}
+
+/// Sources whose pressure target changed, or which can only now be controlled
+type ChangedPressureFilter = Or<(Changed<MidiPressureTarget>, Added<MidiControl>)>;
+
+pub(crate) fn apply_pressure_targets(
+    query: Query<(&MidiControl, &MidiPressureTarget), ChangedPressureFilter>,
+) {
+    for (control, target) in &query {
+        control.set_pressure_target(*target);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{sync::Arc, time::Duration};
+
+use bevy::prelude::*;
+
+use crate::{
+    decoder::SourceProgram,
+    midi::{bank_number, Song},
+    MidiControl, MidiSource,
+};
+
+/// Component making a MIDI source send a [`MidiProgramChangeEvent`] whenever the music it plays
+/// changes the instrument of a channel, as the change is heard.
+///
+/// Only sources playing a single piece, such as a [`MidiAudio`](crate::MidiAudio), send events.
+/// After a seek backwards, such as when a looping source restarts, the latest program of each
+/// channel at the new position is sent again.
+#[derive(Component, Clone, Debug, Default)]
+pub struct MidiProgramWatcher {
+    song: Option<Arc<Song>>,
+    /// Index of the next event to check
+    index: usize,
+    /// Position in seconds the events have been checked up to
+    position: f64,
+    /// Bank select MSB and LSB of each channel
+    banks: [(u8, u8); 16],
+}
+
+/// Event sent when the music of a source with a [`MidiProgramWatcher`] changes the instrument of
+/// a channel
+#[derive(Event, Clone, Copy, Debug)]
+pub struct MidiProgramChangeEvent {
+    /// Entity playing the source
+    pub entity: Entity,
+    /// Channel whose instrument changed, counting from 0
+    pub channel: u8,
+    /// Bank of the new instrument, with percussion kits on channel 9 numbered from 128
+    pub bank: u16,
+    /// Preset of the new instrument
+    pub preset: u8,
+    /// Time of the change in the music
+    pub time: Duration,
+}
+
+impl MidiProgramWatcher {
+    /// Check the events up to `position`, calling `send` with each program change as
+    /// `(channel, bank, preset, time)`
+    fn advance(&mut self, position: f64, mut send: impl FnMut(u8, u16, u8, f64)) {
+        let Some(song) = self.song.clone() else {
+            return;
+        };
+        let seeked = position < self.position;
+        if seeked {
+            self.index = 0;
+            self.banks = [(0, 0); 16];
+        }
+        let mut latest = [None; 16];
+        while let Some(event) = song.events.get(self.index) {
+            if event.time > position {
+                break;
+            }
+            let message = event.message;
+            let channel = message.channel();
+            let (msb, lsb) = &mut self.banks[channel as usize];
+            match message.command() {
+                0xB0 if message.data1 == 0x00 => *msb = message.data2,
+                0xB0 if message.data1 == 0x20 => *lsb = message.data2,
+                0xC0 => {
+                    let change = (bank_number(channel, *msb, *lsb), message.data1, event.time);
+                    if seeked && event.time < position {
+                        latest[channel as usize] = Some(change);
+                    } else {
+                        send(channel, change.0, change.1, change.2);
+                    }
+                }
+                _ => {}
+            }
+            self.index += 1;
+        }
+        for (channel, change) in latest.into_iter().enumerate() {
+            if let Some((bank, preset, time)) = change {
+                send(channel as u8, bank, preset, time);
+            }
+        }
+        self.position = position;
+    }
+}
+
+pub(crate) fn watch_program_changes(
+    sources: Res<Assets<MidiSource>>,
+    mut query: Query<(
+        Entity,
+        &MidiControl,
+        Ref<Handle<MidiSource>>,
+        &mut MidiProgramWatcher,
+    )>,
+    mut events: EventWriter<MidiProgramChangeEvent>,
+) {
+    for (entity, control, source, mut watcher) in &mut query {
+        // A new source, such as a reloaded one, starts from the beginning of its own music
+        if source.is_changed() && watcher.song.is_some() {
+            *watcher = MidiProgramWatcher::default();
+        }
+        if watcher.song.is_none() {
+            let Some(source) = sources.get(&*source) else {
+                continue;
+            };
+            watcher.song = match &source.program {
+                SourceProgram::Audio(audio) => audio.to_song().ok().map(Arc::new),
+                SourceProgram::Song(song) => Some(song.clone()),
+                _ => None,
+            };
+            if watcher.song.is_none() {
+                continue;
+            }
+        }
+        let position = control.position().as_secs_f64();
+        watcher.advance(position, |channel, bank, preset, time| {
+            events.send(MidiProgramChangeEvent {
+                entity,
+                channel,
+                bank,
+                preset,
+                time: Duration::from_secs_f64(time),
+            });
+        });
+    }
+}
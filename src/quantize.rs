@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+use crate::MidiGroove;
+
+/// Settings for moving notes towards a beat grid with
+/// [`MidiAudio::quantize`](crate::MidiAudio::quantize), such as to clean up live-recorded input
+/// before saving or playing it.
+///
+/// A beat is a quarter note. Notes keep their lengths.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MidiQuantize {
+    /// Spacing of the grid in beats, such as `0.25` for sixteenth notes
+    pub grid: f64,
+    /// How far notes move towards the grid, from 0 for not at all to 1 for onto it
+    pub strength: f64,
+    /// Share of each pair of grid steps taken by the first, as a percentage: 50 keeps the grid
+    /// straight and 66 gives it a triplet feel
+    pub swing: f64,
+}
+
+impl Default for MidiQuantize {
+    fn default() -> Self {
+        Self {
+            grid: 0.25,
+            strength: 1.0,
+            swing: 50.0,
+        }
+    }
+}
+
+impl MidiQuantize {
+    /// Quantize fully to a straight grid of the given number of beats
+    pub fn new(grid: f64) -> Self {
+        Self {
+            grid,
+            ..Self::default()
+        }
+    }
+
+    /// Only move notes part of the way to the grid, keeping some of their feel
+    pub fn with_strength(mut self, strength: f64) -> Self {
+        self.strength = strength;
+        self
+    }
+
+    /// Swing every second step of the grid by the given percentage
+    pub fn with_swing(mut self, percent: f64) -> Self {
+        self.swing = percent;
+        self
+    }
+
+    /// Beat a note starting at `beat` is moved to
+    pub fn beat(&self, beat: f64) -> f64 {
+        if self.grid <= 0.0 || !beat.is_finite() {
+            return beat;
+        }
+        let groove = MidiGroove::swing(self.swing).with_step(self.grid);
+        // The nearest line of the swung grid, among those around the beat
+        let step = (beat / self.grid).floor();
+        let target = (-1..=2)
+            .map(|offset| {
+                let line = (step + offset as f64) * self.grid;
+                line + groove.shift(line)
+            })
+            .min_by(|a, b| (a - beat).abs().total_cmp(&(b - beat).abs()))
+            .unwrap_or(beat);
+        beat + (target - beat) * self.strength.clamp(0.0, 1.0)
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    midi::{MidiMessage, Smf},
+    MidiAudio, MidiControl, MidiError,
+};
+
+/// Component recording every MIDI message a source's synthesizer receives, so generated music
+/// a player liked can be saved as a MIDI file and replayed exactly.
+///
+/// Keep a clone to read the recording while or after the source plays. Messages are recorded
+/// as they're heard, after transposition, pinned programs, controller changes and velocity
+/// curves, timed to the synthesizer's render blocks.
+#[derive(Component, Clone, Debug, Default)]
+pub struct MidiRecorder(Arc<Mutex<Vec<(f64, MidiMessage)>>>);
+
+impl MidiRecorder {
+    /// Number of messages recorded
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// Whether nothing has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    /// Discard everything recorded so far
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    /// Standard MIDI file of the recording, with notes still held at the end released
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Smf::from_messages(&self.0.lock().unwrap()).to_bytes()
+    }
+
+    /// The recording as audio, to be played again
+    pub fn to_audio(&self) -> MidiAudio {
+        MidiAudio::File(self.to_bytes())
+    }
+
+    /// Save the recording as a standard MIDI file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), MidiError> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+
+    /// Record a message sent `time` seconds after the synthesizer started
+    pub(crate) fn record(&self, time: f64, channel: i32, command: i32, data1: i32, data2: i32) {
+        self.0.lock().unwrap().push((
+            time,
+            MidiMessage {
+                status: (command as u8 & 0xF0) | (channel as u8 & 0x0F),
+                data1: data1.clamp(0, 127) as u8,
+                data2: data2.clamp(0, 127) as u8,
+            },
+        ));
+    }
+}
+
+type RecorderChanged = Or<(Changed<MidiRecorder>, Added<MidiControl>)>;
+
+pub(crate) fn attach_recorders(query: Query<(&MidiControl, &MidiRecorder), RecorderChanged>) {
+    for (control, recorder) in &query {
+        control.set_recorder(Some(recorder.clone()));
+    }
+}
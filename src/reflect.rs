@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::{current_soundfont, MidiAudio, MidiControl, MidiNote, ScoreEvent, TimedMidiNote};
+
+/// Plugin registering MIDI types for reflection.
+///
+/// It doesn't draw anything itself, but reflection-based editors such as `bevy-inspector-egui`
+/// can then view and edit [`MidiAudio`] assets, including the notes of a
+/// [`MidiAudio::Sequence`]. Every source with a [`MidiControl`] is given a [`MidiSourceInfo`]
+/// showing what it's playing.
+#[derive(Debug, Default)]
+pub struct MidiReflectPlugin;
+
+impl Plugin for MidiReflectPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<MidiNote>()
+            .register_type::<TimedMidiNote>()
+            .register_type::<ScoreEvent>()
+            .register_asset_reflect::<MidiAudio>()
+            .register_type::<MidiSourceInfo>()
+            .register_type::<MidiChannelInfo>()
+            .add_systems(Update, update_source_info);
+    }
+}
+
+/// Component showing the live state of a MIDI source, added and kept up to date by the
+/// [`MidiReflectPlugin`]
+#[derive(Component, Reflect, Clone, Debug, Default, PartialEq)]
+#[reflect(Component)]
+pub struct MidiSourceInfo {
+    /// Playback position of the audio output
+    pub playhead: Duration,
+    /// Whether the source is paused
+    pub paused: bool,
+    /// Gain applied to the output
+    pub gain: f32,
+    /// Instruments of the channels, counting from 0
+    pub channels: Vec<MidiChannelInfo>,
+}
+
+/// Instrument and held keys of a channel of a [`MidiSourceInfo`]
+#[derive(Reflect, Clone, Debug, Default, PartialEq)]
+pub struct MidiChannelInfo {
+    /// Bank, with percussion kits on channel 9 numbered from 128
+    pub bank: u16,
+    /// Preset within the bank
+    pub preset: u8,
+    /// Name of the preset in the active soundfont, if it has one
+    pub program: Option<String>,
+    /// Keys held on the channel
+    pub notes: Vec<u8>,
+}
+
+fn update_source_info(
+    mut commands: Commands,
+    mut query: Query<(Entity, &MidiControl, Option<&mut MidiSourceInfo>)>,
+) {
+    let soundfont = current_soundfont();
+    let program_name = |bank: u16, preset: u8| {
+        soundfont
+            .as_ref()?
+            .get_presets()
+            .iter()
+            .find(|p| p.get_bank_number() == bank as i32 && p.get_patch_number() == preset as i32)
+            .map(|preset| preset.get_name().to_string())
+    };
+    for (entity, control, info) in &mut query {
+        let notes = control.active_notes();
+        let channels = (0..16)
+            .map(|channel| {
+                let (bank, preset) = control.channel_program(channel);
+                MidiChannelInfo {
+                    bank,
+                    preset,
+                    program: program_name(bank, preset),
+                    notes: notes
+                        .iter()
+                        .filter(|(note_channel, _)| *note_channel == channel)
+                        .map(|(_, key)| *key)
+                        .collect(),
+                }
+            })
+            .collect();
+        let source_info = MidiSourceInfo {
+            playhead: control.position(),
+            paused: control.is_paused(),
+            gain: control.gain(),
+            channels,
+        };
+        match info {
+            // Only touch the component when it changes, so change detection stays meaningful
+            Some(mut info) => {
+                info.set_if_neq(source_info);
+            }
+            None => {
+                commands.entity(entity).insert(source_info);
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::{collections::HashMap, time::Duration};
+
+use bevy::{
+    audio::{AudioSink, AudioSinkPlayback, SpatialAudioSink},
+    prelude::*,
+};
+
+use crate::{
+    midi::TempoMap, HeadlessMidiOutput, MidiAudio, MidiControl, MidiLayerPlayer, MidiOverlayPlayer,
+    MidiSource,
+};
+
+/// Resource choosing what happens to playing sources when their MIDI asset is modified, such
+/// as when a file is saved with asset hot reloading enabled.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiHotReload {
+    /// Keep playing the old version of the asset
+    #[default]
+    Ignore,
+    /// Restart affected sources from the beginning
+    Restart,
+    /// Restart affected sources at the same bar and beat of the new version.
+    ///
+    /// Only sources with a [`MidiControl`] know their position; others restart from the
+    /// beginning.
+    Resume,
+}
+
+/// Asset a controlled source was prepared from, so it can be prepared again on reload
+#[derive(Component, Clone, Debug)]
+pub(crate) struct MidiSourceOrigin(pub(crate) Handle<MidiAudio>);
+
+type ReloadTarget = (
+    Entity,
+    Option<&'static Handle<MidiAudio>>,
+    Option<&'static MidiSourceOrigin>,
+    Option<&'static MidiLayerPlayer>,
+    Option<&'static MidiOverlayPlayer>,
+    Option<&'static MidiControl>,
+    Option<&'static AudioSink>,
+    Option<&'static SpatialAudioSink>,
+);
+
+type PlayingFilter = Or<(
+    With<AudioSink>,
+    With<SpatialAudioSink>,
+    With<HeadlessMidiOutput>,
+)>;
+
+/// Restart sources playing modified MIDI assets, following the [`MidiHotReload`] policy
+pub(crate) fn reload_modified_sources(
+    mut commands: Commands,
+    policy: Res<MidiHotReload>,
+    mut events: EventReader<AssetEvent<MidiAudio>>,
+    midi_assets: Res<Assets<MidiAudio>>,
+    mut tempo_maps: Local<HashMap<AssetId<MidiAudio>, TempoMap>>,
+    query: Query<ReloadTarget, PlayingFilter>,
+) {
+    if *policy == MidiHotReload::Ignore {
+        events.clear();
+        tempo_maps.clear();
+        return;
+    }
+    let tempo_map = |id| Some(midi_assets.get(id)?.to_song().ok()?.tempo);
+    let mut modified = HashMap::new();
+    for event in events.read() {
+        match *event {
+            AssetEvent::Added { id } => {
+                tempo_maps.extend(tempo_map(id).map(|map| (id, map)));
+            }
+            AssetEvent::Modified { id } => {
+                let old = tempo_maps.remove(&id);
+                tempo_maps.extend(tempo_map(id).map(|map| (id, map)));
+                modified.insert(id, old);
+            }
+            AssetEvent::Removed { id } | AssetEvent::Unused { id } => {
+                tempo_maps.remove(&id);
+            }
+            AssetEvent::LoadedWithDependencies { .. } => {}
+        }
+    }
+    if modified.is_empty() {
+        return;
+    }
+
+    for (entity, handle, origin, layers, overlay, control, sink, spatial_sink) in &query {
+        let audio = handle.or(origin.map(|origin| &origin.0));
+        let uses = |id: AssetId<MidiAudio>| {
+            audio.is_some_and(|audio| audio.id() == id)
+                || layers.is_some_and(|player| player.layers.iter().any(|l| l.audio.id() == id))
+                || overlay.is_some_and(|player| player.parts.iter().any(|p| p.id() == id))
+        };
+        let Some((&id, old)) = modified.iter().find(|(&id, _)| uses(id)) else {
+            continue;
+        };
+
+        if let Some(sink) = sink {
+            sink.stop();
+        }
+        if let Some(sink) = spatial_sink {
+            sink.stop();
+        }
+        let mut entity = commands.entity(entity);
+        entity.remove::<(
+            AudioSink,
+            SpatialAudioSink,
+            HeadlessMidiOutput,
+            Handle<MidiSource>,
+        )>();
+        if let Some(origin) = origin {
+            entity.remove::<MidiSourceOrigin>().insert(origin.0.clone());
+        }
+
+        let Some(control) = control else {
+            continue;
+        };
+        let position = match (*policy, old, tempo_maps.get(&id)) {
+            (MidiHotReload::Resume, Some(old), Some(new)) => {
+                musical_position(old, new, control.position())
+            }
+            _ => Duration::ZERO,
+        };
+        control.seek(position);
+    }
+}
+
+/// Time in the `new` version of a piece at the same musical position as `time` in the `old` one
+fn musical_position(old: &TempoMap, new: &TempoMap, time: Duration) -> Duration {
+    // SMPTE timing has no musical position, so the time is kept as it is
+    if (old.division | new.division) & 0x8000 != 0 {
+        return time;
+    }
+    let quarters = old.ticks(time.as_secs_f64()) / old.division.max(1) as f64;
+    let tick = (quarters * new.division as f64).round() as u64;
+    Duration::from_secs_f64(new.seconds(tick))
+}
@@ -0,0 +1,210 @@
+use std::{sync::Arc, time::Duration};
+
+use bevy::utils::Instant;
+
+use crate::{diagnostics::SourceStats, sequencer::MidiRender, MidiControl, MidiGroove};
+
+/// Length of audio rendered each time the scrub position changes
+const SCRUB_WINDOW: Duration = Duration::from_millis(100);
+
+/// Length of the fade at the end of a scrub window, avoiding clicks
+const SCRUB_FADE: Duration = Duration::from_millis(10);
+
+/// Render what is left of a scrub window, followed by silence
+fn render_scrub_window(
+    renderer: &mut dyn MidiRender,
+    left: &mut [f32],
+    right: &mut [f32],
+    remaining: &mut usize,
+    sample_rate: usize,
+) {
+    let len = (*remaining).min(left.len());
+    let wrote = renderer.render(&mut left[..len], &mut right[..len]);
+    left[wrote..].fill(0.0);
+    right[wrote..].fill(0.0);
+    let fade = (SCRUB_FADE.as_secs_f64() * sample_rate as f64) as usize;
+    for index in 0..wrote {
+        let until_end = *remaining - index;
+        if until_end <= fade {
+            let gain = until_end as f32 / fade as f32;
+            left[index] *= gain;
+            right[index] *= gain;
+        }
+    }
+    *remaining -= len;
+}
+
+/// Interleaved stereo samples of a rendered block
+#[derive(Debug, Default)]
+pub(crate) struct RenderedBlock {
+    pub(crate) samples: Vec<f32>,
+    /// Frame at which the music started again from the beginning, if it looped
+    pub(crate) restart: Option<usize>,
+}
+
+/// Drives a renderer one block at a time, applying the seeks, speed changes, scrubbing and
+/// release tail requested through its control
+pub(crate) struct RenderLoop {
+    renderer: Box<dyn MidiRender>,
+    control: MidiControl,
+    sample_rate: usize,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    /// Interleaved samples of the last rendered block
+    interleaved: Vec<f32>,
+    /// Frame of the last rendered block at which looping music started again
+    restart: Option<usize>,
+    /// Frames of release tail left to render once the music has ended
+    tail: Option<usize>,
+    /// Frames of the current scrub window left to render
+    scrub_window: usize,
+    /// Version of the control's channel settings last checked for a new groove
+    channels_version: u64,
+    /// Groove the renderer plays with
+    groove: Option<MidiGroove>,
+    stats: Arc<SourceStats>,
+}
+
+impl RenderLoop {
+    pub(crate) fn new(
+        mut renderer: Box<dyn MidiRender>,
+        control: MidiControl,
+        sample_rate: usize,
+        block: usize,
+        stats: Arc<SourceStats>,
+    ) -> Self {
+        if control.take_skip_silence() {
+            if let Some(first_note) = renderer.first_note() {
+                renderer.seek(first_note);
+                control.finish_seek(Duration::from_secs_f64(first_note));
+            }
+        }
+        Self {
+            renderer,
+            control,
+            sample_rate,
+            left: vec![0.0; block],
+            right: vec![0.0; block],
+            interleaved: Vec::with_capacity(block * 2),
+            restart: None,
+            tail: None,
+            scrub_window: 0,
+            channels_version: 0,
+            groove: None,
+            stats,
+        }
+    }
+
+    /// Render the next block, returning the number of frames written or `None` once the music
+    /// and its release tail have ended
+    pub(crate) fn render_block(&mut self) -> Option<usize> {
+        if self.tail == Some(0) {
+            return None;
+        }
+        #[cfg(feature = "tracing")]
+        let _span = bevy::utils::tracing::debug_span!(
+            "midi_block",
+            entity = ?self.control.entity(),
+            position = self.control.position().as_secs_f64(),
+        )
+        .entered();
+        let control = &self.control;
+        let (left, right) = (&mut self.left, &mut self.right);
+        if let Some(position) = control.take_seek() {
+            self.renderer.seek(position.as_secs_f64());
+            control.finish_seek(position);
+            self.tail = None;
+            self.scrub_window = (SCRUB_WINDOW.as_secs_f64() * self.sample_rate as f64) as usize;
+        }
+        self.renderer.set_speed(control.playback_speed());
+        if let Some((version, channels)) = control.channels_since(self.channels_version) {
+            self.channels_version = version;
+            // Changing the groove rebuilds the song, so other channel settings leave it be
+            if channels.groove != self.groove {
+                self.groove = channels.groove;
+                self.renderer.set_groove(self.groove.as_ref());
+            }
+        }
+        let started = Instant::now();
+        let mut wrote = match self.tail {
+            _ if control.is_scrubbing() => {
+                render_scrub_window(
+                    &mut *self.renderer,
+                    left,
+                    right,
+                    &mut self.scrub_window,
+                    self.sample_rate,
+                );
+                left.len()
+            }
+            Some(_) => 0,
+            None => self.renderer.render(left, right),
+        };
+        self.restart = None;
+        // Looping music starts again instead of ringing out
+        while wrote < left.len() && self.tail.is_none() && control.is_looping() {
+            self.renderer.rewind();
+            self.restart = Some(wrote);
+            let more = self
+                .renderer
+                .render(&mut left[wrote..], &mut right[wrote..]);
+            if more == 0 {
+                break;
+            }
+            wrote += more;
+        }
+        if wrote < left.len() {
+            let settings = control.release_tail();
+            let remaining = self.tail.get_or_insert(
+                (settings.max_duration.as_secs_f64() * self.sample_rate as f64) as usize,
+            );
+            let len = (left.len() - wrote).min(*remaining);
+            let range = wrote..wrote + len;
+            self.renderer
+                .render_tail(&mut left[range.clone()], &mut right[range.clone()]);
+            // Stop after the last sample above the threshold
+            let audible = range.rev().find(|&index| {
+                left[index].abs() >= settings.threshold || right[index].abs() >= settings.threshold
+            });
+            match audible {
+                Some(index) if index + 1 == wrote + len => {
+                    *remaining -= len;
+                    wrote += len;
+                }
+                _ => {
+                    *remaining = 0;
+                    wrote = audible.map_or(wrote, |index| index + 1);
+                }
+            }
+        }
+        self.interleaved.clear();
+        self.interleaved.extend(
+            left[..wrote]
+                .iter()
+                .zip(&right[..wrote])
+                .flat_map(|(&left, &right)| [left, right]),
+        );
+        self.stats
+            .rendered(wrote, started.elapsed(), self.renderer.sounding_notes());
+        Some(wrote)
+    }
+
+    /// Interleaved stereo samples of the last rendered block
+    pub(crate) fn block(&self) -> &[f32] {
+        &self.interleaved
+    }
+
+    /// Frame of the last rendered block at which looping music started again
+    pub(crate) fn restart(&self) -> Option<usize> {
+        self.restart
+    }
+
+    /// Take the last rendered block to send it elsewhere
+    pub(crate) fn take_block(&mut self) -> RenderedBlock {
+        let capacity = self.left.len() * 2;
+        RenderedBlock {
+            samples: std::mem::replace(&mut self.interleaved, Vec::with_capacity(capacity)),
+            restart: self.restart,
+        }
+    }
+}
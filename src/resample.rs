@@ -0,0 +1,158 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use crate::MidiResampler;
+
+/// Number of input frames on each side of an output frame weighed by the sinc resampler
+const SINC_TAPS: usize = 16;
+
+/// Converts interleaved stereo audio from one sample rate to another
+#[derive(Debug)]
+pub(crate) struct Resampler {
+    quality: MidiResampler,
+    /// Output sample rate
+    rate: u32,
+    /// Input frames per output frame
+    step: f64,
+    /// Cutoff of the sinc filter relative to the input rate, removing what the output can't hold
+    cutoff: f64,
+    /// Input frames on each side of an output frame weighed by the filter
+    taps: usize,
+    /// Recent input frames, starting `taps - 1` frames before the one at `position`
+    frames: VecDeque<[f32; 2]>,
+    /// Position of the next output frame in `frames`
+    position: f64,
+    /// Number of frames in `frames` taken from the input, once it has ended
+    end: Option<usize>,
+    /// Right sample of the last output frame, not yet handed out
+    right: Option<f32>,
+}
+
+impl Resampler {
+    pub(crate) fn new(quality: MidiResampler, from: u32, to: u32) -> Self {
+        let taps = match quality {
+            MidiResampler::Linear => 1,
+            MidiResampler::Sinc => SINC_TAPS,
+        };
+        let step = from as f64 / to as f64;
+        Self {
+            quality,
+            rate: to,
+            step,
+            cutoff: (1.0 / step).min(1.0),
+            taps,
+            frames: std::iter::repeat([0.0; 2]).take(taps - 1).collect(),
+            position: (taps - 1) as f64,
+            end: None,
+            right: None,
+        }
+    }
+
+    /// Output sample rate
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Ratio of the output rate to the input rate
+    pub(crate) fn ratio(&self) -> f64 {
+        1.0 / self.step
+    }
+
+    /// Next interleaved output sample, pulling input frames from `input` as needed
+    pub(crate) fn next(&mut self, mut input: impl FnMut() -> Option<[f32; 2]>) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let index = self.position as usize;
+        while self.frames.len() <= index + self.taps {
+            match (self.end, input()) {
+                (None, Some(frame)) => self.frames.push_back(frame),
+                (None, None) => {
+                    self.end = Some(self.frames.len());
+                    self.frames.push_back([0.0; 2]);
+                }
+                (Some(_), _) => self.frames.push_back([0.0; 2]),
+            }
+        }
+        if self.end.is_some_and(|end| index >= end) {
+            return None;
+        }
+        let [left, right] = self.interpolate(index);
+        self.position += self.step;
+        let consumed = (self.position as usize + 1).saturating_sub(self.taps);
+        self.frames.drain(..consumed.min(self.frames.len()));
+        self.position -= consumed as f64;
+        self.end = self.end.map(|end| end.saturating_sub(consumed));
+        self.right = Some(right);
+        Some(left)
+    }
+
+    /// Frame at `position`, whose whole part is `index`
+    fn interpolate(&self, index: usize) -> [f32; 2] {
+        let first = index + 1 - self.taps;
+        let mut frame = [0.0; 2];
+        for (offset, input) in self.frames.range(first..=index + self.taps).enumerate() {
+            let weight = self.weight(self.position - (first + offset) as f64) as f32;
+            frame[0] += input[0] * weight;
+            frame[1] += input[1] * weight;
+        }
+        frame
+    }
+
+    /// Weight of an input frame `distance` frames from the output frame
+    fn weight(&self, distance: f64) -> f64 {
+        match self.quality {
+            MidiResampler::Linear => (1.0 - distance.abs()).max(0.0),
+            MidiResampler::Sinc => {
+                let window = distance / self.taps as f64;
+                if window.abs() >= 1.0 {
+                    return 0.0;
+                }
+                let x = PI * self.cutoff * distance;
+                let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+                // Blackman window
+                let blackman = 0.42 + 0.5 * (PI * window).cos() + 0.08 * (2.0 * PI * window).cos();
+                self.cutoff * sinc * blackman
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resample `frames` frames of a constant signal, returning the interleaved output
+    fn resample(quality: MidiResampler, from: u32, to: u32, frames: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(quality, from, to);
+        let mut input = std::iter::repeat([0.5, -0.25]).take(frames);
+        std::iter::from_fn(|| resampler.next(|| input.next())).collect()
+    }
+
+    #[test]
+    fn output_length_follows_the_ratio() {
+        for quality in [MidiResampler::Linear, MidiResampler::Sinc] {
+            for (from, to) in [(44100, 48000), (48000, 44100), (44100, 44100)] {
+                let output = resample(quality, from, to, 44100);
+                let expected = 44100.0 * to as f64 / from as f64;
+                assert_eq!(output.len() % 2, 0);
+                let frames = (output.len() / 2) as f64;
+                assert!((frames - expected).abs() <= 2.0, "{frames} != {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn constant_signals_keep_their_level() {
+        for quality in [MidiResampler::Linear, MidiResampler::Sinc] {
+            for (from, to) in [(44100, 48000), (48000, 44100), (22050, 48000)] {
+                let output = resample(quality, from, to, 4096);
+                // Away from the edges, where the filter reaches past the input
+                let middle = &output[256..output.len() - 256];
+                for frame in middle.chunks(2) {
+                    assert!((frame[0] - 0.5).abs() < 1e-2, "{}", frame[0]);
+                    assert!((frame[1] + 0.25).abs() < 1e-2, "{}", frame[1]);
+                }
+            }
+        }
+    }
+}
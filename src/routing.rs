@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use rustysynth::SoundFont;
+
+use crate::{MidiSoundFont, SoundFontPresets, SynthBackend};
+
+/// Routes used by newly started render tasks, mirroring the [`MidiSoundFontRouting`] resource
+static SOUNDFONT_ROUTES: Mutex<Option<Arc<SoundFontRoutes>>> = Mutex::new(None);
+
+/// Loaded soundfonts to play ranges of presets with, in order of priority
+pub(crate) type SoundFontRoutes = Vec<(SoundFontPresets, Arc<SoundFont>)>;
+
+/// Resource playing some banks and presets with other soundfonts than the usual one, so the
+/// best instruments of several soundfonts can be combined for one piece.
+///
+/// Each channel is played with the first route including its current bank and preset, or the
+/// usual soundfont when none does. Routes whose soundfont hasn't loaded yet are skipped. Changes
+/// apply to sources started afterwards.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct MidiSoundFontRouting {
+    /// Presets to play with each soundfont, in order of priority
+    pub routes: Vec<(SoundFontPresets, Handle<MidiSoundFont>)>,
+}
+
+impl MidiSoundFontRouting {
+    /// Play the given presets with `soundfont`
+    pub fn route(mut self, presets: SoundFontPresets, soundfont: Handle<MidiSoundFont>) -> Self {
+        self.routes.push((presets, soundfont));
+        self
+    }
+}
+
+/// Routes to start a new render task with, or `None` if nothing is routed
+pub(crate) fn soundfont_routes() -> Option<Arc<SoundFontRoutes>> {
+    SOUNDFONT_ROUTES.lock().unwrap().clone()
+}
+
+pub(crate) fn apply_soundfont_routing(
+    routing: Option<Res<MidiSoundFontRouting>>,
+    mut events: EventReader<AssetEvent<MidiSoundFont>>,
+    soundfonts: Res<Assets<MidiSoundFont>>,
+) {
+    let changed = events.read().any(|event| match (event, &routing) {
+        (AssetEvent::Added { id } | AssetEvent::Modified { id }, Some(routing)) => routing
+            .routes
+            .iter()
+            .any(|(_, soundfont)| soundfont.id() == *id),
+        _ => false,
+    });
+    let Some(routing) = routing else {
+        *SOUNDFONT_ROUTES.lock().unwrap() = None;
+        return;
+    };
+    if changed || routing.is_changed() {
+        let routes: SoundFontRoutes = routing
+            .routes
+            .iter()
+            .filter_map(|(presets, handle)| {
+                Some((presets.clone(), soundfonts.get(handle)?.0.clone()))
+            })
+            .collect();
+        *SOUNDFONT_ROUTES.lock().unwrap() = (!routes.is_empty()).then(|| Arc::new(routes));
+    }
+}
+
+/// Synthesizer playing each channel with the synthesizer of the first route including its
+/// current bank and preset, falling back to the first synthesizer
+pub(crate) struct RoutedSynth {
+    /// Synthesizers, the first of which plays unrouted presets
+    synthesizers: Vec<Box<dyn SynthBackend>>,
+    /// Presets each synthesizer after the first plays
+    routes: Vec<SoundFontPresets>,
+    /// Bank and preset selected on each channel
+    programs: [(u16, u16); 16],
+    /// Synthesizer each held key was started on, so it's released on the same one
+    held: [[usize; 128]; 16],
+    buffers: (Vec<f32>, Vec<f32>),
+}
+
+impl RoutedSynth {
+    pub(crate) fn new(
+        synthesizer: Box<dyn SynthBackend>,
+        routed: Vec<(SoundFontPresets, Box<dyn SynthBackend>)>,
+    ) -> Self {
+        let (routes, synthesizers): (Vec<_>, Vec<_>) = routed.into_iter().unzip();
+        Self {
+            synthesizers: [synthesizer].into_iter().chain(synthesizers).collect(),
+            routes,
+            programs: Self::initial_programs(),
+            held: [[0; 128]; 16],
+            buffers: (Vec::new(), Vec::new()),
+        }
+    }
+
+    /// Programs of freshly reset channels, with percussion on channel 10 using bank 128
+    fn initial_programs() -> [(u16, u16); 16] {
+        std::array::from_fn(|channel| (if channel == 9 { 128 } else { 0 }, 0))
+    }
+
+    /// Synthesizer currently playing the given channel
+    fn route(&self, channel: usize) -> usize {
+        let (bank, preset) = self.programs[channel];
+        self.routes
+            .iter()
+            .position(|presets| presets.contains(bank, preset))
+            .map_or(0, |index| index + 1)
+    }
+}
+
+impl SynthBackend for RoutedSynth {
+    fn process_midi_message(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        let index = (channel & 0xF) as usize;
+        let key = (data1 & 0x7F) as usize;
+        match command & 0xF0 {
+            0x90 if data2 > 0 => {
+                let route = self.route(index);
+                self.held[index][key] = route;
+                self.synthesizers[route].process_midi_message(channel, command, data1, data2);
+                return;
+            }
+            0x80 | 0x90 | 0xA0 => {
+                let route = self.held[index][key];
+                self.synthesizers[route].process_midi_message(channel, command, data1, data2);
+                return;
+            }
+            0xB0 if data1 == 0x00 => {
+                let bank = data2 as u16 + if index == 9 { 128 } else { 0 };
+                self.programs[index].0 = bank;
+            }
+            0xC0 => self.programs[index].1 = data1 as u16,
+            _ => {}
+        }
+        // Every synthesizer follows the channel's controllers, so a channel switching between
+        // soundfonts keeps its volume, pan and pitch bend
+        for synthesizer in &mut self.synthesizers {
+            synthesizer.process_midi_message(channel, command, data1, data2);
+        }
+    }
+
+    fn note_off_all(&mut self, immediate: bool) {
+        for synthesizer in &mut self.synthesizers {
+            synthesizer.note_off_all(immediate);
+        }
+    }
+
+    fn reset(&mut self) {
+        for synthesizer in &mut self.synthesizers {
+            synthesizer.reset();
+        }
+        self.programs = Self::initial_programs();
+    }
+
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let (first, rest) = self.synthesizers.split_first_mut().unwrap();
+        first.render(left, right);
+        let (buffer_left, buffer_right) = &mut self.buffers;
+        buffer_left.resize(left.len(), 0.0);
+        buffer_right.resize(right.len(), 0.0);
+        for synthesizer in rest {
+            synthesizer.render(buffer_left, buffer_right);
+            for (sample, routed) in left.iter_mut().zip(buffer_left.iter()) {
+                *sample += routed;
+            }
+            for (sample, routed) in right.iter_mut().zip(buffer_right.iter()) {
+                *sample += routed;
+            }
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        self.synthesizers[0].block_size()
+    }
+
+    fn sample_rate(&self) -> i32 {
+        self.synthesizers[0].sample_rate()
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    io::{self, Read},
+    ops::{Range, RangeInclusive},
+    sync::Arc,
+    time::Duration,
+};
+
+use hound::{SampleFormat, WavReader};
+
+use crate::{
+    oscillator::Channel, MidiError, SynthBackend, SynthBackendFactory, SynthBackendSettings,
+};
+
+/// Number of frames rendered between handling messages
+const BLOCK_SIZE: usize = 64;
+/// Bank which notes on the percussion channel are looked up in
+const PERCUSSION_BANK: u8 = 128;
+
+/// Audio played by a [`SamplerZone`]
+#[derive(Clone, Debug)]
+pub struct SamplerSample {
+    left: Vec<f32>,
+    right: Vec<f32>,
+    sample_rate: u32,
+}
+
+impl SamplerSample {
+    /// Read a mono or stereo WAV file
+    pub fn from_wav(reader: impl Read) -> Result<Self, MidiError> {
+        let reader = WavReader::new(reader).map_err(wav_error)?;
+        let spec = reader.spec();
+        let samples = match spec.sample_format {
+            SampleFormat::Float => reader
+                .into_samples::<f32>()
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(wav_error)?,
+            SampleFormat::Int => {
+                let scale = 1.0 / (1u32 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .into_samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample as f32 * scale))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(wav_error)?
+            }
+        };
+        let channels = spec.channels.max(1) as usize;
+        let left = samples.iter().step_by(channels).copied().collect();
+        let right = samples
+            .iter()
+            .skip(channels.min(2) - 1)
+            .step_by(channels)
+            .copied()
+            .collect();
+        Ok(Self {
+            left,
+            right,
+            sample_rate: spec.sample_rate,
+        })
+    }
+
+    /// Number of frames of audio
+    pub fn len(&self) -> usize {
+        self.left.len()
+    }
+
+    /// Whether the sample has no audio
+    pub fn is_empty(&self) -> bool {
+        self.left.is_empty()
+    }
+
+    /// Sample rate of the audio
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Linearly interpolated frame at a fractional position
+    fn frame(&self, position: f64) -> (f32, f32) {
+        let index = position as usize;
+        let fraction = (position - index as f64) as f32;
+        let at = |channel: &[f32]| {
+            let current = channel.get(index).copied().unwrap_or(0.0);
+            let next = channel.get(index + 1).copied().unwrap_or(0.0);
+            current + (next - current) * fraction
+        };
+        (at(&self.left), at(&self.right))
+    }
+}
+
+pub(crate) fn wav_error(error: hound::Error) -> io::Error {
+    match error {
+        hound::Error::IoError(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidData, error),
+    }
+}
+
+/// Range of keys of an instrument played by a single sample
+#[derive(Clone, Debug)]
+pub struct SamplerZone {
+    /// Audio to play
+    pub sample: Arc<SamplerSample>,
+    /// Keys which play the sample
+    pub keys: RangeInclusive<u8>,
+    /// Key at which the sample plays at its original pitch
+    pub root_key: u8,
+    /// Preset (instrument) the zone belongs to, or `None` for every preset
+    pub preset: Option<u8>,
+    /// Bank the zone belongs to, or `None` for every bank. Notes on the percussion channel use
+    /// bank 128.
+    pub bank: Option<u8>,
+    /// Frames repeated while the note is held, or `None` to play the sample once
+    pub looping: Option<Range<usize>>,
+    /// Time taken to fade out once the note is released
+    pub release: Duration,
+    /// Volume multiplier
+    pub volume: f32,
+}
+
+impl SamplerZone {
+    /// Play the sample on every key of every instrument, at its original pitch on middle C
+    pub fn new(sample: Arc<SamplerSample>) -> Self {
+        Self {
+            sample,
+            keys: 0..=127,
+            root_key: 60,
+            preset: None,
+            bank: None,
+            looping: None,
+            release: Duration::from_millis(100),
+            volume: 1.0,
+        }
+    }
+
+    fn matches(&self, bank: u8, preset: u8, key: u8) -> bool {
+        self.keys.contains(&key)
+            && self.preset.map_or(true, |zone| zone == preset)
+            && self.bank.map_or(true, |zone| zone == bank)
+    }
+}
+
+/// Simple sampler mapping key ranges of instruments to WAV samples, for custom instruments
+/// without authoring a soundfont.
+///
+/// Insert it as a [`MidiSynthBackend`](crate::MidiSynthBackend) to play MIDI through it. The
+/// first zone matching a note's bank, preset and key plays it.
+#[derive(Clone, Debug, Default)]
+pub struct MidiSampler {
+    /// Zones of every instrument
+    pub zones: Vec<SamplerZone>,
+}
+
+impl MidiSampler {
+    /// Create an empty sampler
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a zone to the sampler
+    pub fn with_zone(mut self, zone: SamplerZone) -> Self {
+        self.zones.push(zone);
+        self
+    }
+}
+
+impl SynthBackendFactory for MidiSampler {
+    fn create(&self, settings: &SynthBackendSettings) -> Box<dyn SynthBackend> {
+        Box::new(SamplerSynth {
+            zones: self.zones.clone().into(),
+            sample_rate: settings.sample_rate,
+            polyphony: settings.polyphony.max(1),
+            voices: Vec::new(),
+            channels: [SamplerChannel::default(); 16],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct SamplerChannel {
+    bank: u8,
+    preset: u8,
+    controls: Channel,
+}
+
+struct SamplerVoice {
+    zone: usize,
+    channel: usize,
+    key: u8,
+    gain: f32,
+    position: f64,
+    envelope: f32,
+    released: bool,
+}
+
+/// Synthesizer playing the zones of a [`MidiSampler`]
+struct SamplerSynth {
+    zones: Arc<[SamplerZone]>,
+    sample_rate: i32,
+    polyphony: usize,
+    voices: Vec<SamplerVoice>,
+    channels: [SamplerChannel; 16],
+}
+
+impl SamplerSynth {
+    fn start_note(&mut self, channel: usize, key: u8, velocity: u8) {
+        let state = self.channels[channel];
+        let bank = if channel == 9 {
+            PERCUSSION_BANK
+        } else {
+            state.bank
+        };
+        let Some(zone) = self
+            .zones
+            .iter()
+            .position(|zone| zone.matches(bank, state.preset, key))
+        else {
+            return;
+        };
+        if self.voices.len() >= self.polyphony {
+            self.voices.remove(0);
+        }
+        let velocity = velocity as f32 / 127.0;
+        self.voices.push(SamplerVoice {
+            zone,
+            channel,
+            key,
+            gain: velocity * velocity * self.zones[zone].volume,
+            position: 0.0,
+            envelope: 1.0,
+            released: false,
+        });
+    }
+
+    fn release(&mut self, channel: Option<usize>, key: Option<u8>, immediate: bool) {
+        let matches = |voice: &SamplerVoice| {
+            channel.map_or(true, |channel| voice.channel == channel)
+                && key.map_or(true, |key| voice.key == key)
+        };
+        if immediate {
+            self.voices.retain(|voice| !matches(voice));
+        } else {
+            for voice in self.voices.iter_mut().filter(|voice| matches(voice)) {
+                voice.released = true;
+            }
+        }
+    }
+}
+
+impl SynthBackend for SamplerSynth {
+    fn process_midi_message(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        let channel = channel.clamp(0, 15) as usize;
+        let (data1, data2) = (data1.clamp(0, 127) as u8, data2.clamp(0, 127) as u8);
+        match (command, data1) {
+            (0x90, _) if data2 > 0 => self.start_note(channel, data1, data2),
+            (0x80 | 0x90, _) => self.release(Some(channel), Some(data1), false),
+            (0xB0, 0) => self.channels[channel].bank = data2,
+            (0xB0, 120) => self.release(Some(channel), None, true),
+            (0xB0, 123) => self.release(Some(channel), None, false),
+            (0xB0, _) => self.channels[channel].controls.control_change(data1, data2),
+            (0xC0, _) => self.channels[channel].preset = data1,
+            (0xE0, _) => self.channels[channel].controls.pitch_bend(data1, data2),
+            _ => {}
+        }
+    }
+
+    fn note_off_all(&mut self, immediate: bool) {
+        self.release(None, None, immediate);
+    }
+
+    fn reset(&mut self) {
+        self.voices.clear();
+        self.channels = [SamplerChannel::default(); 16];
+    }
+
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        left.fill(0.0);
+        right.fill(0.0);
+        let sample_rate = self.sample_rate as f32;
+        for voice in &mut self.voices {
+            let zone = &self.zones[voice.zone];
+            let controls = self.channels[voice.channel].controls;
+            let pitch = voice.key as f32 - zone.root_key as f32 + controls.bend;
+            let step =
+                (pitch / 12.0).exp2() as f64 * zone.sample.sample_rate as f64 / sample_rate as f64;
+            let release = (-6.9 / (zone.release.as_secs_f32() * sample_rate).max(1.0)).exp();
+            let (gain_left, gain_right) = controls.gains();
+            for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+                if let Some(looping) = zone.looping.as_ref().filter(|_| !voice.released) {
+                    if voice.position >= looping.end as f64 && looping.end > looping.start {
+                        voice.position -= (looping.end - looping.start) as f64;
+                    }
+                }
+                if voice.position >= zone.sample.len() as f64 {
+                    voice.envelope = 0.0;
+                    break;
+                }
+                if voice.released {
+                    voice.envelope *= release;
+                }
+                let (sample_left, sample_right) = zone.sample.frame(voice.position);
+                let gain = voice.gain * voice.envelope;
+                *l += sample_left * gain * gain_left;
+                *r += sample_right * gain * gain_right;
+                voice.position += step;
+            }
+        }
+        self.voices.retain(|voice| voice.envelope > 1e-4);
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn sample_rate(&self) -> i32 {
+        self.sample_rate
+    }
+}
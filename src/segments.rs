@@ -0,0 +1,561 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+
+use crate::{
+    control::{QueuedFilter, SourceOptions},
+    decoder::SourceProgram,
+    midi::{GridUnit, Song},
+    sequencer::{MidiRender, Sequencer, SynthFactory},
+    MidiAudio, MidiSource, SynthBackend,
+};
+
+/// Point in the current segment at which a transition is allowed to happen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransitionSync {
+    /// Switch as soon as possible
+    Immediate,
+    /// Switch on the next beat
+    NextBeat,
+    /// Switch on the next bar line
+    #[default]
+    NextBar,
+    /// Switch on the next marker meta event, or at the end if there are none left
+    NextMarker,
+    /// Switch once the current segment has finished
+    EndOfSegment,
+}
+
+/// An allowed transition out of a [`MidiSegment`]
+#[derive(Clone, Debug)]
+pub struct SegmentTransition {
+    /// Name of the segment to transition to
+    pub to: String,
+    /// When the transition may happen
+    pub sync: TransitionSync,
+    /// Name of a stinger from the graph to play over the seam
+    pub stinger: Option<String>,
+}
+
+/// A piece of adaptive music within a [`MidiSegmentGraph`]
+#[derive(Clone, Debug)]
+pub struct MidiSegment {
+    /// Unique name of the segment
+    pub name: String,
+    /// Audio for the segment
+    pub audio: Handle<MidiAudio>,
+    /// Segment to continue with when this one ends, or `None` to loop it
+    pub next: Option<String>,
+    /// Transitions which may be requested while this segment is playing
+    pub transitions: Vec<SegmentTransition>,
+}
+
+impl MidiSegment {
+    /// Construct a looping segment with no transitions
+    pub fn new(name: impl Into<String>, audio: Handle<MidiAudio>) -> Self {
+        Self {
+            name: name.into(),
+            audio,
+            next: None,
+            transitions: Vec::new(),
+        }
+    }
+
+    /// Set the segment which follows this one when it ends
+    pub fn with_next(mut self, next: impl Into<String>) -> Self {
+        self.next = Some(next.into());
+        self
+    }
+
+    /// Allow a transition to another segment
+    pub fn with_transition(mut self, to: impl Into<String>, sync: TransitionSync) -> Self {
+        self.transitions.push(SegmentTransition {
+            to: to.into(),
+            sync,
+            stinger: None,
+        });
+        self
+    }
+
+    /// Allow a transition to another segment, playing a stinger as it happens
+    pub fn with_stinger_transition(
+        mut self,
+        to: impl Into<String>,
+        sync: TransitionSync,
+        stinger: impl Into<String>,
+    ) -> Self {
+        self.transitions.push(SegmentTransition {
+            to: to.into(),
+            sync,
+            stinger: Some(stinger.into()),
+        });
+        self
+    }
+}
+
+/// Short piece of MIDI audio played over the music of a [`MidiSegmentGraph`].
+///
+/// Stingers are rendered by their own synthesizer, so their program changes do not affect the
+/// segments they are played over.
+#[derive(Clone, Debug)]
+pub struct MidiStinger {
+    /// Unique name of the stinger
+    pub name: String,
+    /// Audio for the stinger
+    pub audio: Handle<MidiAudio>,
+}
+
+impl MidiStinger {
+    /// Construct a stinger
+    pub fn new(name: impl Into<String>, audio: Handle<MidiAudio>) -> Self {
+        Self {
+            name: name.into(),
+            audio,
+        }
+    }
+}
+
+/// Adaptive music authored as segments with allowed transitions between them.
+///
+/// Playback starts with the first segment.
+#[derive(Asset, TypePath, Clone, Debug, Default)]
+pub struct MidiSegmentGraph {
+    /// Segments of the graph
+    pub segments: Vec<MidiSegment>,
+    /// Stingers which transitions or [`MidiSegmentPlayer::play_stinger`] may play
+    pub stingers: Vec<MidiStinger>,
+}
+
+#[derive(Debug, Default)]
+struct SegmentState {
+    requested: Option<String>,
+    stingers: Vec<String>,
+    pending: Option<String>,
+    current: Option<String>,
+}
+
+/// Component which plays a [`MidiSegmentGraph`], switching segments on request.
+///
+/// Spawn it together with [`PlaybackSettings`]; a [`MidiControl`](crate::MidiControl) may be added to control gain.
+#[derive(Component, Clone, Debug)]
+pub struct MidiSegmentPlayer {
+    /// Graph to play
+    pub graph: Handle<MidiSegmentGraph>,
+    state: Arc<Mutex<SegmentState>>,
+}
+
+impl MidiSegmentPlayer {
+    /// Construct a player for the given graph
+    pub fn new(graph: Handle<MidiSegmentGraph>) -> Self {
+        Self {
+            graph,
+            state: Arc::default(),
+        }
+    }
+
+    /// Request a transition to the named segment at its next allowed sync point
+    pub fn transition_to(&self, segment: impl Into<String>) {
+        self.state.lock().unwrap().requested = Some(segment.into());
+    }
+
+    /// Play the named stinger over the music, starting on the next beat
+    pub fn play_stinger(&self, stinger: impl Into<String>) {
+        self.state.lock().unwrap().stingers.push(stinger.into());
+    }
+
+    /// Name of the segment currently being rendered
+    pub fn current_segment(&self) -> Option<String> {
+        self.state.lock().unwrap().current.clone()
+    }
+
+    /// Name of the segment waiting for its transition point, if any
+    pub fn pending_segment(&self) -> Option<String> {
+        self.state.lock().unwrap().pending.clone()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ResolvedTransition {
+    to: usize,
+    sync: TransitionSync,
+    stinger: Option<usize>,
+}
+
+#[derive(Debug)]
+struct ResolvedSegment {
+    name: String,
+    song: Arc<Song>,
+    next: Option<usize>,
+    transitions: Vec<ResolvedTransition>,
+}
+
+/// Segment graph with all audio resolved, ready for rendering
+#[derive(Debug)]
+pub(crate) struct SegmentProgram {
+    segments: Vec<ResolvedSegment>,
+    stingers: Vec<(String, Arc<Song>)>,
+    state: Arc<Mutex<SegmentState>>,
+}
+
+impl SegmentProgram {
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.segments
+            .iter()
+            .position(|segment| segment.name == name)
+    }
+
+    fn stinger_index_of(&self, name: &str) -> Option<usize> {
+        self.stingers
+            .iter()
+            .position(|(stinger, _)| stinger == name)
+    }
+}
+
+/// Stinger playback on top of the segments
+struct StingerVoice {
+    synthesizer: Box<dyn SynthBackend>,
+    sequencer: Option<Sequencer>,
+    pending: Vec<(usize, f64)>,
+    left: Vec<f32>,
+    right: Vec<f32>,
+}
+
+impl StingerVoice {
+    fn start(&mut self, song: Arc<Song>, speed: f64) {
+        self.synthesizer.note_off_all(false);
+        let mut sequencer = Sequencer::new(song);
+        sequencer.set_speed(speed);
+        self.sequencer = Some(sequencer);
+    }
+
+    /// Render the stinger and let it ring out, mixing it into the given buffers
+    fn mix(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let len = left.len();
+        self.left.resize(len, 0.0);
+        self.right.resize(len, 0.0);
+        let rendered = match &mut self.sequencer {
+            Some(sequencer) => {
+                sequencer.render(&mut *self.synthesizer, &mut self.left, &mut self.right)
+            }
+            None => 0,
+        };
+        if rendered < len {
+            self.sequencer = None;
+        }
+        self.synthesizer
+            .render(&mut self.left[rendered..], &mut self.right[rendered..]);
+        for (out, value) in left.iter_mut().zip(&self.left) {
+            *out += value;
+        }
+        for (out, value) in right.iter_mut().zip(&self.right) {
+            *out += value;
+        }
+    }
+}
+
+pub(crate) struct SegmentRenderer {
+    synthesizer: Box<dyn SynthBackend>,
+    program: Arc<SegmentProgram>,
+    current: usize,
+    sequencer: Sequencer,
+    pending: Option<(usize, f64)>,
+    stinger: Option<StingerVoice>,
+    speed: f64,
+}
+
+impl SegmentRenderer {
+    pub(crate) fn new(synthesizers: &SynthFactory, program: Arc<SegmentProgram>) -> Self {
+        let sequencer = Sequencer::new(program.segments[0].song.clone());
+        program.state.lock().unwrap().current = Some(program.segments[0].name.clone());
+        let stinger = (!program.stingers.is_empty()).then(|| StingerVoice {
+            synthesizer: synthesizers.create(),
+            sequencer: None,
+            pending: Vec::new(),
+            left: Vec::new(),
+            right: Vec::new(),
+        });
+        Self {
+            synthesizer: synthesizers.create(),
+            program,
+            current: 0,
+            sequencer,
+            pending: None,
+            stinger,
+            speed: 1.0,
+        }
+    }
+
+    fn poll_requests(&mut self) {
+        let (requested, stingers) = {
+            let mut state = self.program.state.lock().unwrap();
+            (state.requested.take(), std::mem::take(&mut state.stingers))
+        };
+        for name in stingers {
+            let Some(index) = self.program.stinger_index_of(&name) else {
+                warn!("Unknown stinger '{name}'.");
+                continue;
+            };
+            let at = self.sync_point(TransitionSync::NextBeat);
+            if let Some(voice) = &mut self.stinger {
+                voice.pending.push((index, at));
+            }
+        }
+
+        let Some(requested) = requested else {
+            return;
+        };
+        let segment = &self.program.segments[self.current];
+        let Some(transition) = self.program.index_of(&requested).and_then(|target| {
+            segment
+                .transitions
+                .iter()
+                .find(|transition| transition.to == target)
+                .copied()
+        }) else {
+            warn!(
+                "No transition from segment '{}' to '{}'.",
+                segment.name, requested
+            );
+            return;
+        };
+
+        let at = self.sync_point(transition.sync);
+        self.pending = Some((transition.to, at));
+        if let (Some(index), Some(voice)) = (transition.stinger, &mut self.stinger) {
+            voice.pending.push((index, at));
+        }
+        self.program.state.lock().unwrap().pending = Some(requested);
+    }
+
+    /// Time in the current segment of the next sync point
+    fn sync_point(&self, sync: TransitionSync) -> f64 {
+        let song = self.sequencer.song();
+        let position = self.sequencer.position();
+        let at = match sync {
+            TransitionSync::Immediate => position,
+            TransitionSync::NextBeat | TransitionSync::NextBar => {
+                let unit = if sync == TransitionSync::NextBeat {
+                    GridUnit::Beat
+                } else {
+                    GridUnit::Bar
+                };
+                let tick = song.tempo.ticks(position).ceil() as u64;
+                song.tempo.seconds(song.tempo.next_boundary(tick, unit))
+            }
+            TransitionSync::NextMarker => song
+                .markers
+                .iter()
+                .find(|marker| marker.time > position)
+                .map_or(song.length, |marker| marker.time),
+            TransitionSync::EndOfSegment => song.length,
+        };
+        at.min(song.length)
+    }
+
+    /// Start stingers whose sync point has been reached
+    fn start_stingers(&mut self, switching: bool) {
+        let Some(voice) = &mut self.stinger else {
+            return;
+        };
+        let position = self.sequencer.position();
+        // Stingers aligned to the old segment start on the seam at the latest
+        let due = voice
+            .pending
+            .iter()
+            .rposition(|(_, at)| switching || *at <= position);
+        if let Some(due) = due {
+            let (index, _) = voice.pending[due];
+            voice.pending.drain(..=due);
+            voice.start(self.program.stingers[index].1.clone(), self.speed);
+        }
+    }
+
+    fn switch(&mut self, target: usize) {
+        self.start_stingers(true);
+        self.synthesizer.note_off_all(false);
+        self.current = target;
+        self.sequencer = Sequencer::new(self.program.segments[target].song.clone());
+        self.sequencer.set_speed(self.speed);
+        let mut state = self.program.state.lock().unwrap();
+        state.current = Some(self.program.segments[target].name.clone());
+        state.pending = None;
+    }
+}
+
+impl MidiRender for SegmentRenderer {
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        let block_size = self.synthesizer.block_size();
+        let mut wrote = 0;
+        while wrote < left.len() {
+            self.poll_requests();
+            match self.pending {
+                Some((target, at)) if self.sequencer.position() >= at => {
+                    self.pending = None;
+                    self.switch(target);
+                }
+                _ if self.sequencer.end_of_sequence() => {
+                    let segment = &self.program.segments[self.current];
+                    let target = segment.next.unwrap_or(self.current);
+                    self.switch(target);
+                }
+                _ => {}
+            }
+            self.start_stingers(false);
+
+            let len = block_size.min(left.len() - wrote);
+            let range = wrote..wrote + len;
+            let mut rendered = self.sequencer.render(
+                &mut *self.synthesizer,
+                &mut left[range.clone()],
+                &mut right[range.clone()],
+            );
+            if rendered == 0 {
+                // Empty segment, keep time moving with silence
+                self.synthesizer
+                    .render(&mut left[range.clone()], &mut right[range]);
+                rendered = len;
+            }
+            if let Some(voice) = &mut self.stinger {
+                let range = wrote..wrote + rendered;
+                voice.mix(&mut left[range.clone()], &mut right[range]);
+            }
+            wrote += rendered;
+        }
+        wrote
+    }
+
+    fn seek(&mut self, position: f64) {
+        self.sequencer.seek(&mut *self.synthesizer, position);
+    }
+
+    fn first_note(&self) -> Option<f64> {
+        self.sequencer.song().first_note()
+    }
+
+    fn sounding_notes(&self) -> usize {
+        let stinger = self
+            .stinger
+            .as_ref()
+            .and_then(|voice| voice.sequencer.as_ref())
+            .map_or(0, Sequencer::sounding_notes);
+        self.sequencer.sounding_notes() + stinger
+    }
+
+    fn render_tail(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.synthesizer.render(left, right);
+        if let Some(voice) = &mut self.stinger {
+            voice.mix(left, right);
+        }
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+        self.sequencer.set_speed(speed);
+        if let Some(sequencer) = self
+            .stinger
+            .as_mut()
+            .and_then(|voice| voice.sequencer.as_mut())
+        {
+            sequencer.set_speed(speed);
+        }
+    }
+}
+
+type QueuedSegmentFilter = (QueuedFilter, Without<Handle<MidiSource>>);
+
+pub(crate) fn prepare_segment_players(
+    mut commands: Commands,
+    graphs: Res<Assets<MidiSegmentGraph>>,
+    midi_assets: Res<Assets<MidiAudio>>,
+    mut sources: ResMut<Assets<MidiSource>>,
+    query: Query<(Entity, &MidiSegmentPlayer, SourceOptions), QueuedSegmentFilter>,
+) {
+    'players: for (entity, player, (control, sync)) in &query {
+        let Some(graph) = graphs.get(&player.graph) else {
+            continue;
+        };
+        if graph.segments.is_empty() {
+            continue;
+        }
+        let mut songs = Vec::with_capacity(graph.segments.len());
+        for segment in &graph.segments {
+            let Some(audio) = midi_assets.get(&segment.audio) else {
+                continue 'players;
+            };
+            match audio.to_song() {
+                Ok(song) => songs.push(Arc::new(song)),
+                Err(error) => {
+                    error!("Failed to read segment '{}': {error}", segment.name);
+                    commands.entity(entity).remove::<MidiSegmentPlayer>();
+                    continue 'players;
+                }
+            }
+        }
+
+        let mut stingers = Vec::with_capacity(graph.stingers.len());
+        for stinger in &graph.stingers {
+            let Some(audio) = midi_assets.get(&stinger.audio) else {
+                continue 'players;
+            };
+            match audio.to_song() {
+                Ok(song) => stingers.push((stinger.name.clone(), Arc::new(song))),
+                Err(error) => {
+                    error!("Failed to read stinger '{}': {error}", stinger.name);
+                    commands.entity(entity).remove::<MidiSegmentPlayer>();
+                    continue 'players;
+                }
+            }
+        }
+
+        let index_of = |name: &str| {
+            let index = graph
+                .segments
+                .iter()
+                .position(|segment| segment.name == name);
+            if index.is_none() {
+                warn!("Unknown segment '{name}' in segment graph.");
+            }
+            index
+        };
+        let segments = graph
+            .segments
+            .iter()
+            .zip(songs)
+            .map(|(segment, song)| ResolvedSegment {
+                name: segment.name.clone(),
+                song,
+                next: segment.next.as_deref().and_then(index_of),
+                transitions: segment
+                    .transitions
+                    .iter()
+                    .filter_map(|transition| {
+                        let stinger = transition.stinger.as_deref().and_then(|name| {
+                            let index = stingers.iter().position(|(stinger, _)| stinger == name);
+                            if index.is_none() {
+                                warn!("Unknown stinger '{name}' in segment graph.");
+                            }
+                            index
+                        });
+                        index_of(&transition.to).map(|to| ResolvedTransition {
+                            to,
+                            sync: transition.sync,
+                            stinger,
+                        })
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let control = control.cloned().unwrap_or_default();
+        let source = sources.add(MidiSource {
+            program: SourceProgram::Segments(Arc::new(SegmentProgram {
+                segments,
+                stingers,
+                state: player.state.clone(),
+            })),
+            control: control.clone(),
+            sync: sync.cloned(),
+        });
+        commands.entity(entity).insert((source, control));
+    }
+}
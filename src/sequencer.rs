@@ -0,0 +1,963 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::log::error;
+use rustysynth::SoundFont;
+
+use crate::{
+    backend::{synth_backend, SilentSynth},
+    control::{ChannelState, ControllerValues},
+    decoder::SAMPLE_RATE,
+    midi::{bank_number, Song},
+    mpe::MpeState,
+    routing::{soundfont_routes, RoutedSynth, SoundFontRoutes},
+    settings::render_settings,
+    MidiControl, MidiDrumChannel, MidiError, MidiGroove, MidiPressureTarget, MidiRecorder,
+    MidiRenderSettings, SynthBackend, SynthBackendFactory, SynthBackendSettings,
+};
+
+/// Idle synthesizers kept for playing sequences or created ahead of time, with the factory which
+/// created them
+static SYNTH_POOL: Mutex<Vec<(SynthFactory, Box<dyn SynthBackend>)>> = Mutex::new(Vec::new());
+
+/// Something which drives synthesizers to produce audio, one block at a time
+pub(crate) trait MidiRender: Send + 'static {
+    /// Render into the given buffers, returning the number of frames written.
+    ///
+    /// Writing fewer frames than requested signals the end of playback.
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) -> usize;
+
+    /// Jump to the given position in seconds
+    fn seek(&mut self, position: f64);
+
+    /// Set the speed multiplier of the music's clock
+    fn set_speed(&mut self, speed: f64);
+
+    /// Move notes off the beat grid with a groove template, or play them as written
+    fn set_groove(&mut self, _groove: Option<&MidiGroove>) {}
+
+    /// Start again from the beginning, as when the music loops
+    fn rewind(&mut self) {
+        self.seek(0.0);
+    }
+
+    /// Render the synthesizers without sequencing, letting notes and effects ring out
+    fn render_tail(&mut self, left: &mut [f32], right: &mut [f32]);
+
+    /// Time of the first sounding note, if known
+    fn first_note(&self) -> Option<f64> {
+        None
+    }
+
+    /// Number of notes currently held by the sequencers
+    fn sounding_notes(&self) -> usize {
+        0
+    }
+}
+
+/// Creates synthesizers for renderers on the render task
+#[derive(Clone)]
+pub(crate) struct SynthFactory {
+    pub(crate) backend: Arc<dyn SynthBackendFactory>,
+    pub(crate) settings: SynthBackendSettings,
+    /// Soundfonts some presets are played with instead
+    routes: Option<Arc<SoundFontRoutes>>,
+    /// Control whose pinned programs the synthesizers play
+    control: Option<MidiControl>,
+    /// First failure to create a synthesizer, which was replaced by a silent one
+    error: Arc<Mutex<Option<MidiError>>>,
+}
+
+impl SynthFactory {
+    /// Create synthesizers with the current backend and the given render settings
+    pub(crate) fn new(soundfont: Option<Arc<SoundFont>>, settings: &MidiRenderSettings) -> Self {
+        Self {
+            backend: synth_backend(),
+            settings: SynthBackendSettings {
+                soundfont,
+                sample_rate: SAMPLE_RATE as i32,
+                polyphony: settings.polyphony,
+                reverb_and_chorus: settings.reverb_and_chorus,
+            },
+            routes: soundfont_routes(),
+            control: None,
+            error: Arc::default(),
+        }
+    }
+
+    /// Make synthesizers follow the programs and controllers set through `control`
+    pub(crate) fn with_control(mut self, control: MidiControl) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Create a new synthesizer, routing presets to their soundfonts
+    fn build(&self) -> Box<dyn SynthBackend> {
+        let synthesizer = self.engine(&self.settings);
+        let Some(routes) = &self.routes else {
+            return synthesizer;
+        };
+        let routed = routes
+            .iter()
+            .map(|(presets, soundfont)| {
+                let settings = SynthBackendSettings {
+                    soundfont: Some(soundfont.clone()),
+                    ..self.settings.clone()
+                };
+                (presets.clone(), self.engine(&settings))
+            })
+            .collect();
+        Box::new(RoutedSynth::new(synthesizer, routed))
+    }
+
+    /// Create an engine, falling back to a silent one and keeping the error if it fails
+    fn engine(&self, settings: &SynthBackendSettings) -> Box<dyn SynthBackend> {
+        self.backend.try_create(settings).unwrap_or_else(|error| {
+            self.error.lock().unwrap().get_or_insert(error);
+            Box::new(SilentSynth::new(settings.sample_rate))
+        })
+    }
+
+    /// Take the first failure to create a synthesizer, if any
+    pub(crate) fn take_error(&self) -> Option<MidiError> {
+        self.error.lock().unwrap().take()
+    }
+
+    /// Take an idle synthesizer made by this factory from the pool, or create a new one
+    pub(crate) fn create(&self) -> Box<dyn SynthBackend> {
+        self.controlled(self.take())
+    }
+
+    fn take(&self) -> Box<dyn SynthBackend> {
+        let mut pool = SYNTH_POOL.lock().unwrap();
+        match pool.iter().position(|(factory, _)| factory.same_as(self)) {
+            Some(index) => pool.remove(index).1,
+            None => {
+                drop(pool);
+                self.build()
+            }
+        }
+    }
+
+    /// Take or create a synthesizer which returns to the pool when it is dropped
+    pub(crate) fn acquire(&self) -> Box<dyn SynthBackend> {
+        self.controlled(Box::new(PooledSynth {
+            synthesizer: Some(self.take()),
+            factory: SynthFactory {
+                control: None,
+                ..self.clone()
+            },
+        }))
+    }
+
+    fn controlled(&self, synthesizer: Box<dyn SynthBackend>) -> Box<dyn SynthBackend> {
+        match &self.control {
+            Some(control) => Box::new(ControlledSynth::new(
+                synthesizer,
+                control.clone(),
+                SynthFactory {
+                    control: None,
+                    ..self.clone()
+                },
+            )),
+            None => synthesizer,
+        }
+    }
+
+    /// Create synthesizers ahead of time, so the next ones needed are ready immediately
+    pub(crate) fn prewarm(&self, count: usize) {
+        for _ in 0..count {
+            let synthesizer = self.build();
+            if let Some(error) = self.take_error() {
+                error!("{error}");
+                return;
+            }
+            SYNTH_POOL.lock().unwrap().push((self.clone(), synthesizer));
+        }
+    }
+
+    /// Whether synthesizers created by both factories are interchangeable
+    fn same_as(&self, other: &SynthFactory) -> bool {
+        let (settings, other_settings) = (&self.settings, &other.settings);
+        Arc::ptr_eq(&self.backend, &other.backend)
+            && match (&settings.soundfont, &other_settings.soundfont) {
+                (Some(soundfont), Some(other)) => Arc::ptr_eq(soundfont, other),
+                (None, None) => true,
+                _ => false,
+            }
+            && match (&self.routes, &other.routes) {
+                (Some(routes), Some(other)) => Arc::ptr_eq(routes, other),
+                (None, None) => true,
+                _ => false,
+            }
+            && settings.sample_rate == other_settings.sample_rate
+            && settings.polyphony == other_settings.polyphony
+            && settings.reverb_and_chorus == other_settings.reverb_and_chorus
+    }
+}
+
+/// Synthesizer borrowed from the pool, returned silenced once dropped
+struct PooledSynth {
+    synthesizer: Option<Box<dyn SynthBackend>>,
+    factory: SynthFactory,
+}
+
+impl PooledSynth {
+    fn synthesizer(&self) -> &dyn SynthBackend {
+        self.synthesizer.as_deref().unwrap()
+    }
+
+    fn synthesizer_mut(&mut self) -> &mut dyn SynthBackend {
+        self.synthesizer.as_deref_mut().unwrap()
+    }
+}
+
+impl SynthBackend for PooledSynth {
+    fn process_midi_message(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        self.synthesizer_mut()
+            .process_midi_message(channel, command, data1, data2);
+    }
+
+    fn note_on(&mut self, channel: i32, key: i32, velocity: i32) {
+        self.synthesizer_mut().note_on(channel, key, velocity);
+    }
+
+    fn note_off(&mut self, channel: i32, key: i32) {
+        self.synthesizer_mut().note_off(channel, key);
+    }
+
+    fn note_off_all(&mut self, immediate: bool) {
+        self.synthesizer_mut().note_off_all(immediate);
+    }
+
+    fn reset(&mut self) {
+        self.synthesizer_mut().reset();
+    }
+
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.synthesizer_mut().render(left, right);
+    }
+
+    fn block_size(&self) -> usize {
+        self.synthesizer().block_size()
+    }
+
+    fn sample_rate(&self) -> i32 {
+        self.synthesizer().sample_rate()
+    }
+}
+
+impl Drop for PooledSynth {
+    fn drop(&mut self) {
+        let Some(mut synthesizer) = self.synthesizer.take() else {
+            return;
+        };
+        let capacity = render_settings().synth_pool;
+        if capacity == 0 {
+            return;
+        }
+        synthesizer.note_off_all(true);
+        synthesizer.reset();
+        let mut pool = SYNTH_POOL.lock().unwrap();
+        // Synthesizers of old soundfonts and settings make way for the latest ones
+        if pool.len() >= capacity {
+            pool.remove(0);
+        }
+        pool.push((self.factory.clone(), synthesizer));
+    }
+}
+
+/// Synthesizer playing the controller changes set through a [`MidiControl`], whose channels play
+/// the programs pinned through it instead of the music's own program changes
+struct ControlledSynth {
+    synthesizer: Box<dyn SynthBackend>,
+    control: MidiControl,
+    /// Version of the channel settings last taken from the control
+    channels_version: u64,
+    /// Channel settings last taken from the control, applied by [`sync`](Self::sync)
+    channels: ChannelState,
+    /// Programs pinned on each channel as last applied
+    pinned: [Option<(u8, u8)>; 16],
+    /// Bank select and program the music last chose on each channel
+    music: [(u8, u8); 16],
+    /// Number of changes made through the control when they were last applied
+    changes: u64,
+    /// Controller values as last applied
+    controllers: Box<ControllerValues>,
+    recorder: Option<MidiRecorder>,
+    /// Number of frames rendered, timing recorded messages
+    frames: u64,
+    /// Bank select MSB and LSB sent to each channel
+    banks: [(u8, u8); 16],
+    /// Drum channel handling as last applied
+    drums: MidiDrumChannel,
+    /// Creates the synthesizer playing channel 10 with melodic presets
+    factory: SynthFactory,
+    /// Synthesizer playing channel 10 on its first channel, once it's played melodically
+    melodic_drums: Option<Box<dyn SynthBackend>>,
+    buffers: (Vec<f32>, Vec<f32>),
+    /// Pressure target as last applied
+    pressure_target: MidiPressureTarget,
+    /// Channel pressure, and the pressure of each held key, of each channel
+    pressure: Box<[(u8, [u8; 128]); 16]>,
+    /// Value of the pressure target's controller on each channel, before pressure is applied
+    pressure_base: [u8; 16],
+    mpe: MpeState,
+}
+
+impl ControlledSynth {
+    fn new(
+        synthesizer: Box<dyn SynthBackend>,
+        control: MidiControl,
+        factory: SynthFactory,
+    ) -> Self {
+        Self {
+            synthesizer,
+            control,
+            channels_version: u64::MAX,
+            channels: ChannelState::default(),
+            pinned: [None; 16],
+            music: [(0, 0); 16],
+            controllers: Box::new([[None; 128]; 16]),
+            changes: u64::MAX,
+            recorder: None,
+            frames: 0,
+            banks: [(0, 0); 16],
+            drums: MidiDrumChannel::Percussion,
+            factory,
+            melodic_drums: None,
+            buffers: (Vec::new(), Vec::new()),
+            pressure_target: MidiPressureTarget::Ignore,
+            pressure: Box::new([(0, [0; 128]); 16]),
+            pressure_base: [0; 16],
+            mpe: MpeState::default(),
+        }
+    }
+
+    /// Strongest pressure on a channel, from the channel or any of its held keys
+    fn strongest_pressure(&self, channel: usize) -> u8 {
+        let (channel_pressure, keys) = &self.pressure[channel];
+        keys.iter().copied().fold(*channel_pressure, u8::max)
+    }
+
+    /// Follow the pressure changes of a message, returning whether the pressure of its channel
+    /// changed
+    fn track_pressure(&mut self, channel: usize, command: i32, data1: i32, data2: i32) -> bool {
+        let (channel_pressure, keys) = &mut self.pressure[channel];
+        let slot = match command & 0xF0 {
+            0xD0 => channel_pressure,
+            0x80 | 0x90 | 0xA0 => &mut keys[(data1 & 0x7F) as usize],
+            _ => return false,
+        };
+        let pressure = match command & 0xF0 {
+            0xD0 => data1,
+            0xA0 => data2,
+            // A struck or released key starts without pressure
+            _ => 0,
+        } as u8;
+        std::mem::replace(slot, pressure) != pressure
+    }
+
+    /// Drive the pressure target's controller of a channel from its strongest pressure
+    fn apply_pressure(&mut self, channel: usize) {
+        if let Some(controller) = self.pressure_target.controller() {
+            let value = self.pressure_base[channel].max(self.strongest_pressure(channel));
+            self.forward(channel as i32, 0xB0, controller as i32, value as i32);
+        }
+    }
+
+    /// Play a message on the synthesizer, handling channel 10 as chosen through the control
+    fn forward(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        if channel & 0xF != 9 {
+            self.synthesizer
+                .process_midi_message(channel, command, data1, data2);
+            return;
+        }
+        // Notes of an MPE zone are melodic wherever they land
+        let drums = match self.mpe.zones() {
+            Some(zones) if zones.covers(9) => MidiDrumChannel::Melodic,
+            _ => self.drums,
+        };
+        match drums {
+            MidiDrumChannel::Percussion => self
+                .synthesizer
+                .process_midi_message(channel, command, data1, data2),
+            MidiDrumChannel::Melodic => {
+                // The engine treats channel 10 as percussion, so another plays it on its first
+                let factory = &self.factory;
+                self.melodic_drums
+                    .get_or_insert_with(|| factory.build())
+                    .process_midi_message(0, command, data1, data2);
+            }
+            MidiDrumChannel::Remap(target) => {
+                self.synthesizer
+                    .process_midi_message((target & 0xF) as i32, command, data1, data2)
+            }
+            MidiDrumChannel::Mute if command & 0xF0 == 0x90 && data2 > 0 => {}
+            MidiDrumChannel::Mute => self
+                .synthesizer
+                .process_midi_message(channel, command, data1, data2),
+        }
+    }
+
+    /// Send a message to the synthesizer, recording it if a recorder is attached
+    fn send(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        if let Some(recorder) = &self.recorder {
+            let time = self.frames as f64 / self.synthesizer.sample_rate() as f64;
+            recorder.record(time, channel, command, data1, data2);
+        }
+        let index = (channel & 0xF) as usize;
+        match command & 0xF0 {
+            0xB0 if data1 == 0x00 => self.banks[index].0 = data2 as u8,
+            0xB0 if data1 == 0x20 => self.banks[index].1 = data2 as u8,
+            0x90 => self
+                .control
+                .set_note_active(index as u8, data1 as u8, data2 > 0),
+            0x80 => self
+                .control
+                .set_note_active(index as u8, data1 as u8, false),
+            // All sound off and all notes off
+            0xB0 if data1 == 120 || data1 == 123 => self.control.release_notes(Some(index as u8)),
+            0xC0 => {
+                let (msb, lsb) = self.banks[index];
+                let bank = bank_number(index as u8, msb, lsb);
+                self.control
+                    .set_channel_program(index as u8, bank, data1 as u8);
+            }
+            _ => {}
+        }
+        let pressure_changed = self.track_pressure(index, command, data1, data2);
+        let data2 = match self.pressure_target.controller() {
+            Some(controller) if command & 0xF0 == 0xB0 && data1 == controller as i32 => {
+                self.pressure_base[index] = data2 as u8;
+                data2.max(self.strongest_pressure(index) as i32)
+            }
+            _ => data2,
+        };
+        self.forward(channel, command, data1, data2);
+        if pressure_changed {
+            self.apply_pressure(index);
+        }
+    }
+
+    /// Whether anything was changed through the control since it was last checked
+    fn changed(&mut self) -> bool {
+        let changes = self.control.channel_changes();
+        changes != std::mem::replace(&mut self.changes, changes)
+    }
+
+    /// Send the controller values which changed since they were last applied
+    fn sync_controllers(&mut self) {
+        for channel in 0..16 {
+            for controller in 0..128 {
+                let value = self.channels.controllers[channel][controller];
+                let old = &mut self.controllers[channel][controller];
+                if value == *old {
+                    continue;
+                }
+                *old = value;
+                if let Some(value) = value {
+                    self.send(channel as i32, 0xB0, controller as i32, value as i32);
+                }
+            }
+        }
+    }
+
+    /// Apply changes to the channel settings other than controllers, returning unpinned
+    /// channels to the music's program
+    fn sync(&mut self) {
+        self.recorder = self.control.recorder();
+        if let Some((version, channels)) = self.control.channels_since(self.channels_version) {
+            self.channels_version = version;
+            self.channels = channels;
+        }
+        let drums = self.channels.drum_channel;
+        if drums != self.drums {
+            // All notes off where channel 10 was played, before it's played elsewhere
+            self.send(9, 0xB0, 123, 0);
+            self.drums = drums;
+        }
+        let target = self.channels.pressure_target;
+        if target != self.pressure_target {
+            // Return the old target to the music's values before pressure drives the new one
+            let old = self.pressure_target.controller();
+            for channel in 0..16 {
+                if let Some(controller) = old.filter(|_| self.strongest_pressure(channel) > 0) {
+                    let base = self.pressure_base[channel] as i32;
+                    self.forward(channel as i32, 0xB0, controller as i32, base);
+                }
+            }
+            self.pressure_target = target;
+            self.pressure_base = [0; 16];
+            for channel in 0..16 {
+                if self.strongest_pressure(channel) > 0 {
+                    self.apply_pressure(channel);
+                }
+            }
+        }
+        let zones = self.channels.mpe;
+        if zones.as_ref() != self.mpe.zones() {
+            for (channel, command, data1, data2) in self.mpe.set_zones(zones) {
+                self.send(channel, command, data1, data2);
+            }
+        }
+        for channel in 0..16 {
+            let pinned = self.channels.programs[channel];
+            if pinned == std::mem::replace(&mut self.pinned[channel], pinned) {
+                continue;
+            }
+            let (bank, preset) = pinned.unwrap_or(self.music[channel]);
+            self.send(channel as i32, 0xB0, 0x00, bank as i32);
+            self.send(channel as i32, 0xC0, preset as i32, 0);
+        }
+    }
+}
+
+impl SynthBackend for ControlledSynth {
+    fn process_midi_message(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+        // Controllers are left for the next render, which marks the changes as applied
+        if self.control.channel_changes() != self.changes {
+            self.sync();
+        }
+        let index = (channel & 0xF) as usize;
+        let program = match command & 0xF0 {
+            0xB0 if data1 == 0x00 => {
+                self.music[index].0 = data2 as u8;
+                true
+            }
+            0xB0 if data1 == 0x20 => true,
+            0xC0 => {
+                self.music[index].1 = data1 as u8;
+                true
+            }
+            _ => false,
+        };
+        if program && self.pinned[index].is_some() {
+            return;
+        }
+        let data2 = if command & 0xF0 == 0x90 {
+            self.channels.velocity_curve.apply(data2 as u8) as i32
+        } else {
+            data2
+        };
+        self.send(channel, command, data1, data2);
+    }
+
+    fn note_off_all(&mut self, immediate: bool) {
+        if let Some(recorder) = &self.recorder {
+            // All sound off, or all notes off
+            let controller = if immediate { 120 } else { 123 };
+            let time = self.frames as f64 / self.synthesizer.sample_rate() as f64;
+            for channel in 0..16 {
+                recorder.record(time, channel, 0xB0, controller, 0);
+            }
+        }
+        self.control.release_notes(None);
+        self.synthesizer.note_off_all(immediate);
+        // Released keys lose their pressure
+        for channel in 0..16 {
+            let keys = &mut self.pressure[channel].1;
+            if keys.iter().any(|&pressure| pressure > 0) {
+                keys.fill(0);
+                self.apply_pressure(channel);
+            }
+        }
+        if let Some(synthesizer) = &mut self.melodic_drums {
+            synthesizer.note_off_all(immediate);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.synthesizer.reset();
+        if let Some(synthesizer) = &mut self.melodic_drums {
+            synthesizer.reset();
+        }
+        self.music = [(0, 0); 16];
+        self.banks = [(0, 0); 16];
+        self.control.reset_channel_programs();
+        self.control.release_notes(None);
+        // Pins are applied again on the next message, and controllers on the next render
+        self.pinned = [None; 16];
+        *self.controllers = [[None; 128]; 16];
+        self.changes = u64::MAX;
+        // The reset synthesizer lost the bend ranges of the MPE zones, which are sent again
+        self.mpe.set_zones(None);
+        *self.pressure = [(0, [0; 128]); 16];
+        self.pressure_base = [0; 16];
+    }
+
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        if self.changed() {
+            self.sync();
+            self.sync_controllers();
+            for message in self.control.take_input() {
+                for (channel, command, data1, data2) in self.mpe.expand(message) {
+                    self.process_midi_message(channel, command, data1, data2);
+                }
+            }
+        }
+        self.synthesizer.render(left, right);
+        if let Some(synthesizer) = &mut self.melodic_drums {
+            let (buffer_left, buffer_right) = &mut self.buffers;
+            buffer_left.resize(left.len(), 0.0);
+            buffer_right.resize(right.len(), 0.0);
+            synthesizer.render(buffer_left, buffer_right);
+            for (sample, drums) in left.iter_mut().zip(buffer_left.iter()) {
+                *sample += drums;
+            }
+            for (sample, drums) in right.iter_mut().zip(buffer_right.iter()) {
+                *sample += drums;
+            }
+        }
+        self.frames += left.len() as u64;
+    }
+
+    fn block_size(&self) -> usize {
+        self.synthesizer.block_size()
+    }
+
+    fn sample_rate(&self) -> i32 {
+        self.synthesizer.sample_rate()
+    }
+}
+
+/// Plays the events of a [`Song`] through a synthesizer
+#[derive(Debug)]
+pub(crate) struct Sequencer {
+    song: Arc<Song>,
+    /// Song as written, before any groove was applied
+    original: Arc<Song>,
+    index: usize,
+    position: f64,
+    block_wrote: Option<usize>,
+    speed: f64,
+    transpose: i32,
+    sounding: Box<[[Option<u8>; 128]; 16]>,
+}
+
+impl Sequencer {
+    pub(crate) fn new(song: Arc<Song>) -> Self {
+        Self {
+            original: song.clone(),
+            song,
+            index: 0,
+            position: 0.0,
+            block_wrote: None,
+            speed: 1.0,
+            transpose: 0,
+            sounding: Box::new([[None; 128]; 16]),
+        }
+    }
+
+    /// Set the playback speed multiplier
+    pub(crate) fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.max(0.0);
+    }
+
+    /// Set the number of semitones notes are shifted by, excluding the percussion channel.
+    ///
+    /// Notes which are already sounding are released at the key they were started with.
+    pub(crate) fn set_transpose(&mut self, semitones: i32) {
+        self.transpose = semitones;
+    }
+
+    /// Play the song with a groove template, or as written, restarting notes from the current
+    /// position if it has started
+    pub(crate) fn set_groove(
+        &mut self,
+        synthesizer: &mut dyn SynthBackend,
+        groove: Option<&MidiGroove>,
+    ) {
+        self.song = match groove {
+            Some(groove) => Arc::new(self.original.with_groove(groove)),
+            None => self.original.clone(),
+        };
+        if self.index > 0 || self.position > 0.0 {
+            self.seek(synthesizer, self.position);
+        }
+    }
+
+    /// Song being played
+    pub(crate) fn song(&self) -> &Arc<Song> {
+        &self.song
+    }
+
+    /// Current playback position in seconds
+    pub(crate) fn position(&self) -> f64 {
+        self.position
+    }
+
+    /// Number of notes which have been started and not yet released
+    pub(crate) fn sounding_notes(&self) -> usize {
+        self.sounding
+            .iter()
+            .flatten()
+            .filter(|key| key.is_some())
+            .count()
+    }
+
+    /// Whether every event has been played and the song's length has been reached
+    pub(crate) fn end_of_sequence(&self) -> bool {
+        self.index >= self.song.events.len() && self.position >= self.song.length
+    }
+
+    /// Jump to `position` in seconds, silencing the synthesizer and replaying every controller and
+    /// program change before it
+    pub(crate) fn seek(&mut self, synthesizer: &mut dyn SynthBackend, position: f64) {
+        #[cfg(feature = "tracing")]
+        bevy::utils::tracing::debug!(from = self.position, to = position, "MIDI seek");
+        synthesizer.reset();
+        let position = position.clamp(0.0, self.song.length);
+        self.index = 0;
+        self.block_wrote = None;
+        self.sounding.iter_mut().for_each(|keys| keys.fill(None));
+        while let Some(event) = self.song.events.get(self.index) {
+            if event.time >= position {
+                break;
+            }
+            let message = event.message;
+            if !matches!(message.command(), 0x80 | 0x90 | 0xA0) {
+                synthesizer.process_midi_message(
+                    message.channel() as i32,
+                    message.command() as i32,
+                    message.data1 as i32,
+                    message.data2 as i32,
+                );
+            }
+            self.index += 1;
+        }
+        self.position = position;
+    }
+
+    /// Start again from the beginning without silencing the synthesizer, so effects still
+    /// ringing at the end carry over into the next pass
+    pub(crate) fn rewind(&mut self, synthesizer: &mut dyn SynthBackend) {
+        synthesizer.note_off_all(false);
+        self.index = 0;
+        self.block_wrote = None;
+        self.sounding.iter_mut().for_each(|keys| keys.fill(None));
+        self.position = 0.0;
+    }
+
+    fn process_events(&mut self, synthesizer: &mut dyn SynthBackend) {
+        while let Some(event) = self.song.events.get(self.index) {
+            if event.time > self.position {
+                break;
+            }
+            let message = event.message;
+            let channel = message.channel() as usize;
+            let mut key = message.data1;
+            match message.command() {
+                0x90 if message.data2 > 0 => {
+                    let transposed = match channel {
+                        9 => key,
+                        _ => (key as i32 + self.transpose).clamp(0, 127) as u8,
+                    };
+                    self.sounding[channel][key as usize] = Some(transposed);
+                    key = transposed;
+                }
+                0x80 | 0x90 => {
+                    if let Some(transposed) = self.sounding[channel][key as usize].take() {
+                        key = transposed;
+                    }
+                }
+                0xA0 => {
+                    if let Some(transposed) = self.sounding[channel][key as usize] {
+                        key = transposed;
+                    }
+                }
+                _ => {}
+            }
+            #[cfg(feature = "tracing")]
+            bevy::utils::tracing::trace!(
+                tick = event.tick,
+                time = event.time,
+                channel,
+                kind = message.kind(),
+                data1 = key,
+                data2 = message.data2,
+                "MIDI event",
+            );
+            synthesizer.process_midi_message(
+                channel as i32,
+                message.command() as i32,
+                key as i32,
+                message.data2 as i32,
+            );
+            self.index += 1;
+        }
+    }
+}
+
+impl Sequencer {
+    /// Render the song through the synthesizer, stopping early at the end of the sequence
+    pub(crate) fn render(
+        &mut self,
+        synthesizer: &mut dyn SynthBackend,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) -> usize {
+        let block_size = synthesizer.block_size();
+        let sample_rate = synthesizer.sample_rate() as f64;
+        let mut wrote = 0;
+        while wrote < left.len() {
+            let block_wrote = match self.block_wrote {
+                Some(block_wrote) if block_wrote < block_size => block_wrote,
+                _ => {
+                    if self.end_of_sequence() {
+                        break;
+                    }
+                    self.process_events(synthesizer);
+                    self.position += self.speed * block_size as f64 / sample_rate;
+                    0
+                }
+            };
+            let len = (block_size - block_wrote).min(left.len() - wrote);
+            synthesizer.render(
+                &mut left[wrote..wrote + len],
+                &mut right[wrote..wrote + len],
+            );
+            self.block_wrote = Some(block_wrote + len);
+            wrote += len;
+        }
+        wrote
+    }
+}
+
+/// Plays a single song through its own synthesizer
+pub(crate) struct SongRenderer {
+    pub(crate) synthesizer: Box<dyn SynthBackend>,
+    pub(crate) sequencer: Sequencer,
+}
+
+impl MidiRender for SongRenderer {
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        self.sequencer.render(&mut *self.synthesizer, left, right)
+    }
+
+    fn seek(&mut self, position: f64) {
+        self.sequencer.seek(&mut *self.synthesizer, position);
+    }
+
+    fn rewind(&mut self) {
+        self.sequencer.rewind(&mut *self.synthesizer);
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.sequencer.set_speed(speed);
+    }
+
+    fn set_groove(&mut self, groove: Option<&MidiGroove>) {
+        self.sequencer.set_groove(&mut *self.synthesizer, groove);
+    }
+
+    fn first_note(&self) -> Option<f64> {
+        self.sequencer.song().first_note()
+    }
+
+    fn sounding_notes(&self) -> usize {
+        self.sequencer.sounding_notes()
+    }
+
+    fn render_tail(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.synthesizer.render(left, right);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::MidiNote;
+
+    /// Backend which records the messages it's sent
+    #[derive(Default)]
+    struct RecordingSynth {
+        messages: Vec<(i32, i32, i32, i32)>,
+        resets: usize,
+    }
+
+    impl SynthBackend for RecordingSynth {
+        fn process_midi_message(&mut self, channel: i32, command: i32, data1: i32, data2: i32) {
+            self.messages.push((channel, command, data1, data2));
+        }
+
+        fn note_off_all(&mut self, _immediate: bool) {}
+
+        fn reset(&mut self) {
+            self.resets += 1;
+            self.messages.clear();
+        }
+
+        fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+            left.fill(0.0);
+            right.fill(0.0);
+        }
+
+        fn block_size(&self) -> usize {
+            64
+        }
+
+        fn sample_rate(&self) -> i32 {
+            SAMPLE_RATE as i32
+        }
+    }
+
+    fn note(preset: i32, key: i32) -> MidiNote {
+        MidiNote {
+            preset,
+            key,
+            duration: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn seek_replays_controllers_but_not_notes() {
+        let song = Song::from_notes(&[note(1, 60), note(2, 62)]);
+        let mut sequencer = Sequencer::new(Arc::new(song));
+        let mut synth = RecordingSynth::default();
+        sequencer.seek(&mut synth, 1.5);
+        assert_eq!(synth.resets, 1);
+        assert_eq!(sequencer.position(), 1.5);
+        assert_eq!(sequencer.sounding_notes(), 0);
+        // Bank selects and both program changes, without the notes
+        let commands: Vec<_> = synth.messages.iter().map(|m| (m.1, m.2)).collect();
+        assert_eq!(
+            commands,
+            [
+                (0xB0, 0),
+                (0xB0, 0x20),
+                (0xC0, 1),
+                (0xB0, 0),
+                (0xB0, 0x20),
+                (0xC0, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn seek_clamps_to_the_song() {
+        let song = Song::from_notes(&[note(0, 60)]);
+        let mut sequencer = Sequencer::new(Arc::new(song));
+        let mut synth = RecordingSynth::default();
+        sequencer.seek(&mut synth, 10.0);
+        assert_eq!(sequencer.position(), 1.0);
+        sequencer.seek(&mut synth, -1.0);
+        assert_eq!(sequencer.position(), 0.0);
+    }
+
+    #[test]
+    fn render_plays_notes_from_the_seek_position() {
+        let song = Song::from_notes(&[note(0, 60), note(0, 62)]);
+        let mut sequencer = Sequencer::new(Arc::new(song));
+        let mut synth = RecordingSynth::default();
+        sequencer.seek(&mut synth, 1.0);
+        synth.messages.clear();
+        let (mut left, mut right) = (vec![0.0; 64], vec![0.0; 64]);
+        sequencer.render(&mut synth, &mut left, &mut right);
+        assert!(synth.messages.contains(&(0, 0x90, 62, 100)));
+        assert!(!synth.messages.contains(&(0, 0x90, 60, 100)));
+        assert_eq!(sequencer.sounding_notes(), 1);
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use bevy::{
+    audio::{AudioSink, AudioSinkPlayback, SpatialAudioSink},
+    ecs::event::ManualEventReader,
+    prelude::*,
+    tasks::{AsyncComputeTaskPool, IoTaskPool, TaskPool, TaskPoolBuilder},
+    window::AppLifecycle,
+};
+
+use crate::{sequencer::SynthFactory, MidiAudio, MidiSource};
+
+/// Settings used by newly started render tasks, mirroring the [`MidiRenderSettings`] resource
+static RENDER_SETTINGS: Mutex<MidiRenderSettings> = Mutex::new(MidiRenderSettings::platform());
+
+/// Thread rendering sources with [`MidiRenderThread::Dedicated`], created once first needed
+static RENDER_THREAD: OnceLock<TaskPool> = OnceLock::new();
+
+/// Pool used by [`MidiRenderThread::Pool`]
+static RENDER_POOL: OnceLock<TaskPool> = OnceLock::new();
+
+/// Resource configuring how MIDI sources are rendered.
+///
+/// Changes apply to sources started afterwards. The default depends on the target platform; see
+/// [`MidiRenderSettings::desktop`], [`MidiRenderSettings::web`] and
+/// [`MidiRenderSettings::mobile`].
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct MidiRenderSettings {
+    /// Length of audio rendered ahead of playback, rounded up to whole blocks. Longer buffers
+    /// survive stalls better but use more memory and delay control changes.
+    pub buffer_length: Duration,
+    /// Length of the blocks rendered at a time. Control changes such as mutes, transposition
+    /// and controllers are heard from the next block, so shorter blocks react sooner and use
+    /// less memory, while longer blocks render with less overhead. Sources can override it with
+    /// a [`MidiBlockLength`](crate::MidiBlockLength).
+    pub block_length: Duration,
+    /// Maximum number of voices each synthesizer plays at once
+    pub polyphony: usize,
+    /// Whether synthesizers apply the soundfont's reverb and chorus effects
+    pub reverb_and_chorus: bool,
+    /// Whether MIDI sources are paused while the app is suspended in the background
+    pub pause_on_suspend: bool,
+    /// Number of idle synthesizers kept to play notes built in code, such as
+    /// [`MidiAudio::Sequence`]s, saving the cost of creating one each time a short sequence
+    /// plays. Pooling is disabled when zero.
+    pub synth_pool: usize,
+    /// Number of synthesizers created in the background when the app starts, so the first
+    /// sources to play don't pay to create them
+    pub prewarm_synthesizers: usize,
+    /// Maximum number of sources played at once, guarding against a bug spawning hundreds of
+    /// synthesizers, or `None` for no limit. Sources rendered inline, such as sound effects and
+    /// headless playback, aren't counted.
+    pub max_sources: Option<usize>,
+    /// Which source is stopped when one starts while `max_sources` are already playing
+    pub eviction: MidiSourceEviction,
+    /// Maximum number of notes held across every source, beyond which the sources with the
+    /// lowest [`MidiPriority`](crate::MidiPriority) are faded out, or `None` for no limit.
+    /// Ignored in [`deterministic`](Self::deterministic) mode.
+    pub max_voices: Option<usize>,
+    /// Maximum fraction of real time spent rendering across every source, such as 0.5 for half
+    /// of one core, beyond which the sources with the lowest
+    /// [`MidiPriority`](crate::MidiPriority) are faded out, or `None` for no limit. Ignored in
+    /// [`deterministic`](Self::deterministic) mode.
+    pub max_render_load: Option<f32>,
+    /// Where sources are rendered
+    pub render_thread: MidiRenderThread,
+    /// Sample rate of the audio handed to the output, such as the rate of the audio device, or
+    /// `None` to hand over the 44.1kHz audio the synthesizers render
+    pub output_sample_rate: Option<u32>,
+    /// How audio is converted to `output_sample_rate`
+    pub resampler: MidiResampler,
+    /// Whether offline and headless playback only depend on their inputs, for lockstep
+    /// multiplayer and replays.
+    ///
+    /// Rendering is already bit-identical given the same music, soundfont, settings and control
+    /// changes at the same samples. This mode also removes what depends on the wall clock or on
+    /// how far the render tasks got: `max_render_load` and `max_voices` are ignored, and the
+    /// [`HeadlessMidiPlugin`](crate::HeadlessMidiPlugin) advances sources on `FixedUpdate` by the
+    /// fixed timestep, so their positions, and the events derived from them, follow the
+    /// simulation rather than the frame rate.
+    ///
+    /// Only offline decoders and headless sources are reproducible. Sources played on an audio
+    /// device follow the device's clock, so when control changes land depends on its timing.
+    pub deterministic: bool,
+}
+
+/// How [`MidiRenderSettings`] converts rendered audio to another sample rate
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiResampler {
+    /// Interpolate between neighbouring samples, which is cheap but dulls high notes and lets
+    /// some aliasing through
+    Linear,
+    /// Windowed sinc interpolation, which keeps the whole audible range clean at a higher cost
+    #[default]
+    Sinc,
+}
+
+/// Where [`MidiRenderSettings`] has sources rendered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiRenderThread {
+    /// On bevy's [`AsyncComputeTaskPool`], shared with other background work
+    #[default]
+    TaskPool,
+    /// On a thread of their own, shared by every source, so heavy tasks elsewhere in the pool
+    /// can't starve the audio.
+    ///
+    /// The thread is created the first time it's needed, with the priority requested then.
+    /// Raising its priority is only supported on Linux and Android, and needs permission to do
+    /// so; otherwise a warning is logged and it keeps the usual priority.
+    Dedicated {
+        /// Whether to raise the priority of the thread
+        raised_priority: bool,
+    },
+    /// On bevy's [`IoTaskPool`], keeping the audio clear of heavy compute tasks when the app
+    /// does little IO
+    IoTaskPool,
+    /// On a pool of threads of their own, shared by every source, so sources render in parallel
+    /// away from the rest of the app.
+    ///
+    /// The pool is created the first time it's needed, with the number of threads requested
+    /// then.
+    Pool {
+        /// Number of threads in the pool, at least one
+        threads: usize,
+    },
+}
+
+/// Which source is stopped when one starts while [`MidiRenderSettings::max_sources`] are
+/// already playing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiSourceEviction {
+    /// Fade out the source which started first
+    #[default]
+    StopOldest,
+    /// Fade out the source with the lowest [`MidiPriority`](crate::MidiPriority), oldest first,
+    /// or stop the new source if every other has a higher priority
+    LowestPriority,
+    /// Stop the new source before it plays
+    RefuseNew,
+}
+
+impl Default for MidiRenderSettings {
+    fn default() -> Self {
+        Self::platform()
+    }
+}
+
+impl MidiRenderSettings {
+    /// Settings for desktop platforms, with generous buffers
+    pub const fn desktop() -> Self {
+        Self {
+            buffer_length: Duration::from_secs(1),
+            block_length: Duration::from_secs(1),
+            polyphony: 64,
+            reverb_and_chorus: true,
+            pause_on_suspend: false,
+            synth_pool: 0,
+            prewarm_synthesizers: 0,
+            max_sources: None,
+            eviction: MidiSourceEviction::StopOldest,
+            max_voices: None,
+            max_render_load: None,
+            render_thread: MidiRenderThread::TaskPool,
+            output_sample_rate: None,
+            resampler: MidiResampler::Sinc,
+            deterministic: false,
+        }
+    }
+
+    /// Settings for browsers, which run the render task on the main thread and so render in
+    /// short bursts
+    pub const fn web() -> Self {
+        Self {
+            buffer_length: Duration::from_millis(250),
+            block_length: Duration::from_millis(50),
+            ..Self::desktop()
+        }
+    }
+
+    /// Low latency and low memory settings for phones, which also pause playback while the app
+    /// is in the background
+    pub const fn mobile() -> Self {
+        Self {
+            buffer_length: Duration::from_millis(300),
+            block_length: Duration::from_millis(100),
+            polyphony: 32,
+            reverb_and_chorus: false,
+            pause_on_suspend: true,
+            ..Self::desktop()
+        }
+    }
+
+    /// Settings for lockstep simulations and replays, with short blocks so control changes are
+    /// heard soon after they're made. See [`MidiRenderSettings::deterministic`].
+    pub const fn deterministic() -> Self {
+        Self {
+            block_length: Duration::from_millis(10),
+            deterministic: true,
+            ..Self::desktop()
+        }
+    }
+
+    /// Settings for the target platform
+    const fn platform() -> Self {
+        if cfg!(target_arch = "wasm32") {
+            Self::web()
+        } else if cfg!(any(target_os = "android", target_os = "ios")) {
+            Self::mobile()
+        } else {
+            Self::desktop()
+        }
+    }
+}
+
+/// Settings to start a new render task with
+pub(crate) fn render_settings() -> MidiRenderSettings {
+    *RENDER_SETTINGS.lock().unwrap()
+}
+
+/// Pool to run render tasks on with the given settings
+pub(crate) fn render_pool(settings: &MidiRenderSettings) -> &'static TaskPool {
+    match settings.render_thread {
+        MidiRenderThread::TaskPool => AsyncComputeTaskPool::get(),
+        MidiRenderThread::IoTaskPool => IoTaskPool::get(),
+        MidiRenderThread::Dedicated { raised_priority } => RENDER_THREAD.get_or_init(|| {
+            let pool = TaskPoolBuilder::new()
+                .num_threads(1)
+                .thread_name("MIDI render thread".to_string())
+                .build();
+            if raised_priority && cfg!(not(target_arch = "wasm32")) {
+                pool.spawn(async { raise_thread_priority() }).detach();
+            }
+            pool
+        }),
+        MidiRenderThread::Pool { threads } => RENDER_POOL.get_or_init(|| {
+            TaskPoolBuilder::new()
+                .num_threads(threads.max(1))
+                .thread_name("MIDI render pool".to_string())
+                .build()
+        }),
+    }
+}
+
+/// Raise the priority of the current thread, as far as the platform allows
+fn raise_thread_priority() {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        // Threads have their own nice value on Linux, set through their thread ID
+        let thread = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, thread, -10) } == 0 {
+            return;
+        }
+    }
+    warn!("Couldn't raise the priority of the MIDI render thread.");
+}
+
+pub(crate) fn apply_render_settings(settings: Res<MidiRenderSettings>) {
+    if settings.is_changed() {
+        *RENDER_SETTINGS.lock().unwrap() = *settings;
+    }
+}
+
+/// Create the synthesizers requested by [`MidiRenderSettings::prewarm_synthesizers`] once the
+/// soundfont has loaded
+pub(crate) fn prewarm_synthesizers(settings: Res<MidiRenderSettings>, mut done: Local<bool>) {
+    if *done {
+        return;
+    }
+    *done = true;
+    let settings = *settings;
+    if settings.prewarm_synthesizers == 0 {
+        return;
+    }
+    render_pool(&settings)
+        .spawn(async move {
+            let soundfont = crate::soundfont().await;
+            SynthFactory::new(soundfont, &settings).prewarm(settings.prewarm_synthesizers);
+        })
+        .detach();
+}
+
+/// Marks a source paused while the app was suspended
+#[derive(Component)]
+pub(crate) struct PausedBySuspend;
+
+type SourceSinks = (
+    Entity,
+    Option<&'static AudioSink>,
+    Option<&'static SpatialAudioSink>,
+);
+
+type MidiSinkFilter = (
+    Or<(With<Handle<MidiAudio>>, With<Handle<MidiSource>>)>,
+    Or<(With<AudioSink>, With<SpatialAudioSink>)>,
+);
+
+/// Pauses MIDI sources while the app is suspended and resumes them afterwards
+pub(crate) fn pause_on_suspend(
+    mut commands: Commands,
+    settings: Res<MidiRenderSettings>,
+    lifecycle: Option<Res<Events<AppLifecycle>>>,
+    mut reader: Local<ManualEventReader<AppLifecycle>>,
+    sources: Query<SourceSinks, MidiSinkFilter>,
+    suspended: Query<SourceSinks, With<PausedBySuspend>>,
+) {
+    let Some(lifecycle) = lifecycle else {
+        return;
+    };
+    let Some(event) = reader.read(&lifecycle).last() else {
+        return;
+    };
+    if !settings.pause_on_suspend {
+        return;
+    }
+    if matches!(event, AppLifecycle::WillSuspend | AppLifecycle::Suspended) {
+        for (entity, sink, spatial_sink) in &sources {
+            let sink: &dyn AudioSinkPlayback = match (sink, spatial_sink) {
+                (Some(sink), _) => sink,
+                (_, Some(sink)) => sink,
+                _ => continue,
+            };
+            if !sink.is_paused() {
+                sink.pause();
+                commands.entity(entity).insert(PausedBySuspend);
+            }
+        }
+    } else {
+        for (entity, sink, spatial_sink) in &suspended {
+            if let Some(sink) = sink {
+                sink.play();
+            }
+            if let Some(sink) = spatial_sink {
+                sink.play();
+            }
+            commands.entity(entity).remove::<PausedBySuspend>();
+        }
+    }
+}
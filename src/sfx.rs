@@ -0,0 +1,659 @@
+use std::{sync::Arc, time::Duration};
+
+use async_channel::{Receiver, Sender};
+use bevy::prelude::*;
+
+use crate::{
+    decoder::SourceProgram,
+    sequencer::{MidiRender, SynthFactory},
+    MidiControl, MidiSource, SynthBackend,
+};
+
+/// Channel which General MIDI reserves for percussion
+const PERCUSSION_CHANNEL: u8 = 9;
+
+/// Instrument presets of the General MIDI standard, numbered from 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum GmPreset {
+    /// Acoustic grand piano
+    AcousticGrandPiano,
+    /// Bright acoustic piano
+    BrightAcousticPiano,
+    /// Electric grand piano
+    ElectricGrandPiano,
+    /// Honky-tonk piano
+    HonkyTonkPiano,
+    /// Electric piano 1
+    ElectricPiano1,
+    /// Electric piano 2
+    ElectricPiano2,
+    /// Harpsichord
+    Harpsichord,
+    /// Clavinet
+    Clavinet,
+    /// Celesta
+    Celesta,
+    /// Glockenspiel
+    Glockenspiel,
+    /// Music box
+    MusicBox,
+    /// Vibraphone
+    Vibraphone,
+    /// Marimba
+    Marimba,
+    /// Xylophone
+    Xylophone,
+    /// Tubular bells
+    TubularBells,
+    /// Dulcimer
+    Dulcimer,
+    /// Drawbar organ
+    DrawbarOrgan,
+    /// Percussive organ
+    PercussiveOrgan,
+    /// Rock organ
+    RockOrgan,
+    /// Church organ
+    ChurchOrgan,
+    /// Reed organ
+    ReedOrgan,
+    /// Accordion
+    Accordion,
+    /// Harmonica
+    Harmonica,
+    /// Tango accordion
+    TangoAccordion,
+    /// Acoustic guitar (nylon)
+    AcousticGuitarNylon,
+    /// Acoustic guitar (steel)
+    AcousticGuitarSteel,
+    /// Electric guitar (jazz)
+    ElectricGuitarJazz,
+    /// Electric guitar (clean)
+    ElectricGuitarClean,
+    /// Electric guitar (muted)
+    ElectricGuitarMuted,
+    /// Overdriven guitar
+    OverdrivenGuitar,
+    /// Distortion guitar
+    DistortionGuitar,
+    /// Guitar harmonics
+    GuitarHarmonics,
+    /// Acoustic bass
+    AcousticBass,
+    /// Electric bass (finger)
+    ElectricBassFinger,
+    /// Electric bass (pick)
+    ElectricBassPick,
+    /// Fretless bass
+    FretlessBass,
+    /// Slap bass 1
+    SlapBass1,
+    /// Slap bass 2
+    SlapBass2,
+    /// Synth bass 1
+    SynthBass1,
+    /// Synth bass 2
+    SynthBass2,
+    /// Violin
+    Violin,
+    /// Viola
+    Viola,
+    /// Cello
+    Cello,
+    /// Contrabass
+    Contrabass,
+    /// Tremolo strings
+    TremoloStrings,
+    /// Pizzicato strings
+    PizzicatoStrings,
+    /// Orchestral harp
+    OrchestralHarp,
+    /// Timpani
+    Timpani,
+    /// String ensemble 1
+    StringEnsemble1,
+    /// String ensemble 2
+    StringEnsemble2,
+    /// Synth strings 1
+    SynthStrings1,
+    /// Synth strings 2
+    SynthStrings2,
+    /// Choir aahs
+    ChoirAahs,
+    /// Voice oohs
+    VoiceOohs,
+    /// Synth voice
+    SynthVoice,
+    /// Orchestra hit
+    OrchestraHit,
+    /// Trumpet
+    Trumpet,
+    /// Trombone
+    Trombone,
+    /// Tuba
+    Tuba,
+    /// Muted trumpet
+    MutedTrumpet,
+    /// French horn
+    FrenchHorn,
+    /// Brass section
+    BrassSection,
+    /// Synth brass 1
+    SynthBrass1,
+    /// Synth brass 2
+    SynthBrass2,
+    /// Soprano sax
+    SopranoSax,
+    /// Alto sax
+    AltoSax,
+    /// Tenor sax
+    TenorSax,
+    /// Baritone sax
+    BaritoneSax,
+    /// Oboe
+    Oboe,
+    /// English horn
+    EnglishHorn,
+    /// Bassoon
+    Bassoon,
+    /// Clarinet
+    Clarinet,
+    /// Piccolo
+    Piccolo,
+    /// Flute
+    Flute,
+    /// Recorder
+    Recorder,
+    /// Pan flute
+    PanFlute,
+    /// Blown bottle
+    BlownBottle,
+    /// Shakuhachi
+    Shakuhachi,
+    /// Whistle
+    Whistle,
+    /// Ocarina
+    Ocarina,
+    /// Lead 1 (square)
+    Lead1Square,
+    /// Lead 2 (sawtooth)
+    Lead2Sawtooth,
+    /// Lead 3 (calliope)
+    Lead3Calliope,
+    /// Lead 4 (chiff)
+    Lead4Chiff,
+    /// Lead 5 (charang)
+    Lead5Charang,
+    /// Lead 6 (voice)
+    Lead6Voice,
+    /// Lead 7 (fifths)
+    Lead7Fifths,
+    /// Lead 8 (bass and lead)
+    Lead8BassAndLead,
+    /// Pad 1 (new age)
+    Pad1NewAge,
+    /// Pad 2 (warm)
+    Pad2Warm,
+    /// Pad 3 (polysynth)
+    Pad3Polysynth,
+    /// Pad 4 (choir)
+    Pad4Choir,
+    /// Pad 5 (bowed)
+    Pad5Bowed,
+    /// Pad 6 (metallic)
+    Pad6Metallic,
+    /// Pad 7 (halo)
+    Pad7Halo,
+    /// Pad 8 (sweep)
+    Pad8Sweep,
+    /// FX 1 (rain)
+    Fx1Rain,
+    /// FX 2 (soundtrack)
+    Fx2Soundtrack,
+    /// FX 3 (crystal)
+    Fx3Crystal,
+    /// FX 4 (atmosphere)
+    Fx4Atmosphere,
+    /// FX 5 (brightness)
+    Fx5Brightness,
+    /// FX 6 (goblins)
+    Fx6Goblins,
+    /// FX 7 (echoes)
+    Fx7Echoes,
+    /// FX 8 (sci-fi)
+    Fx8SciFi,
+    /// Sitar
+    Sitar,
+    /// Banjo
+    Banjo,
+    /// Shamisen
+    Shamisen,
+    /// Koto
+    Koto,
+    /// Kalimba
+    Kalimba,
+    /// Bagpipe
+    Bagpipe,
+    /// Fiddle
+    Fiddle,
+    /// Shanai
+    Shanai,
+    /// Tinkle bell
+    TinkleBell,
+    /// Agogo
+    Agogo,
+    /// Steel drums
+    SteelDrums,
+    /// Woodblock
+    Woodblock,
+    /// Taiko drum
+    TaikoDrum,
+    /// Melodic tom
+    MelodicTom,
+    /// Synth drum
+    SynthDrum,
+    /// Reverse cymbal
+    ReverseCymbal,
+    /// Guitar fret noise
+    GuitarFretNoise,
+    /// Breath noise
+    BreathNoise,
+    /// Seashore
+    Seashore,
+    /// Bird tweet
+    BirdTweet,
+    /// Telephone ring
+    TelephoneRing,
+    /// Helicopter
+    Helicopter,
+    /// Applause
+    Applause,
+    /// Gunshot
+    Gunshot,
+}
+
+impl GmPreset {
+    /// Program number of the preset
+    pub fn program(self) -> u8 {
+        self as u8
+    }
+}
+
+/// A MIDI key number, where 60 is middle C ([`Key::C4`])
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key(pub u8);
+
+impl Key {
+    /// C in octave 0
+    pub const C0: Key = Key(12);
+    /// D in octave 0
+    pub const D0: Key = Key(14);
+    /// E in octave 0
+    pub const E0: Key = Key(16);
+    /// F in octave 0
+    pub const F0: Key = Key(17);
+    /// G in octave 0
+    pub const G0: Key = Key(19);
+    /// A in octave 0
+    pub const A0: Key = Key(21);
+    /// B in octave 0
+    pub const B0: Key = Key(23);
+    /// C in octave 1
+    pub const C1: Key = Key(24);
+    /// D in octave 1
+    pub const D1: Key = Key(26);
+    /// E in octave 1
+    pub const E1: Key = Key(28);
+    /// F in octave 1
+    pub const F1: Key = Key(29);
+    /// G in octave 1
+    pub const G1: Key = Key(31);
+    /// A in octave 1
+    pub const A1: Key = Key(33);
+    /// B in octave 1
+    pub const B1: Key = Key(35);
+    /// C in octave 2
+    pub const C2: Key = Key(36);
+    /// D in octave 2
+    pub const D2: Key = Key(38);
+    /// E in octave 2
+    pub const E2: Key = Key(40);
+    /// F in octave 2
+    pub const F2: Key = Key(41);
+    /// G in octave 2
+    pub const G2: Key = Key(43);
+    /// A in octave 2
+    pub const A2: Key = Key(45);
+    /// B in octave 2
+    pub const B2: Key = Key(47);
+    /// C in octave 3
+    pub const C3: Key = Key(48);
+    /// D in octave 3
+    pub const D3: Key = Key(50);
+    /// E in octave 3
+    pub const E3: Key = Key(52);
+    /// F in octave 3
+    pub const F3: Key = Key(53);
+    /// G in octave 3
+    pub const G3: Key = Key(55);
+    /// A in octave 3
+    pub const A3: Key = Key(57);
+    /// B in octave 3
+    pub const B3: Key = Key(59);
+    /// C in octave 4, middle C
+    pub const C4: Key = Key(60);
+    /// D in octave 4
+    pub const D4: Key = Key(62);
+    /// E in octave 4
+    pub const E4: Key = Key(64);
+    /// F in octave 4
+    pub const F4: Key = Key(65);
+    /// G in octave 4
+    pub const G4: Key = Key(67);
+    /// A in octave 4, the 440 Hz tuning A
+    pub const A4: Key = Key(69);
+    /// B in octave 4
+    pub const B4: Key = Key(71);
+    /// C in octave 5
+    pub const C5: Key = Key(72);
+    /// D in octave 5
+    pub const D5: Key = Key(74);
+    /// E in octave 5
+    pub const E5: Key = Key(76);
+    /// F in octave 5
+    pub const F5: Key = Key(77);
+    /// G in octave 5
+    pub const G5: Key = Key(79);
+    /// A in octave 5
+    pub const A5: Key = Key(81);
+    /// B in octave 5
+    pub const B5: Key = Key(83);
+    /// C in octave 6
+    pub const C6: Key = Key(84);
+    /// D in octave 6
+    pub const D6: Key = Key(86);
+    /// E in octave 6
+    pub const E6: Key = Key(88);
+    /// F in octave 6
+    pub const F6: Key = Key(89);
+    /// G in octave 6
+    pub const G6: Key = Key(91);
+    /// A in octave 6
+    pub const A6: Key = Key(93);
+    /// B in octave 6
+    pub const B6: Key = Key(95);
+    /// C in octave 7
+    pub const C7: Key = Key(96);
+    /// D in octave 7
+    pub const D7: Key = Key(98);
+    /// E in octave 7
+    pub const E7: Key = Key(100);
+    /// F in octave 7
+    pub const F7: Key = Key(101);
+    /// G in octave 7
+    pub const G7: Key = Key(103);
+    /// A in octave 7
+    pub const A7: Key = Key(105);
+    /// B in octave 7
+    pub const B7: Key = Key(107);
+    /// C in octave 8
+    pub const C8: Key = Key(108);
+    /// D in octave 8
+    pub const D8: Key = Key(110);
+    /// E in octave 8
+    pub const E8: Key = Key(112);
+    /// F in octave 8
+    pub const F8: Key = Key(113);
+    /// G in octave 8
+    pub const G8: Key = Key(115);
+    /// A in octave 8
+    pub const A8: Key = Key(117);
+    /// B in octave 8
+    pub const B8: Key = Key(119);
+
+    /// The key a semitone higher
+    pub const fn sharp(self) -> Self {
+        Key(if self.0 < 127 { self.0 + 1 } else { 127 })
+    }
+
+    /// The key a semitone lower
+    pub const fn flat(self) -> Self {
+        Key(self.0.saturating_sub(1))
+    }
+}
+
+/// A note requested through [`MidiSfx`]
+#[derive(Clone, Copy, Debug)]
+struct SfxNote {
+    /// Bank and preset to play, or `None` for percussion
+    program: Option<(u8, u8)>,
+    key: u8,
+    velocity: u8,
+    duration: Duration,
+}
+
+/// Resource playing fire-and-forget notes through a persistent synthesizer, for UI blips and
+/// pickups which don't warrant an asset and audio entity each.
+///
+/// The synthesizer's audio entity is spawned when the first note is played.
+#[derive(Resource, Clone, Debug)]
+pub struct MidiSfx {
+    sender: Sender<SfxNote>,
+    receiver: Receiver<SfxNote>,
+    control: MidiControl,
+}
+
+impl Default for MidiSfx {
+    fn default() -> Self {
+        let (sender, receiver) = async_channel::unbounded();
+        Self {
+            sender,
+            receiver,
+            control: MidiControl::default(),
+        }
+    }
+}
+
+impl MidiSfx {
+    /// Play a note with a General MIDI instrument, releasing it after `duration`
+    pub fn play_note(&self, preset: GmPreset, key: Key, velocity: u8, duration: Duration) {
+        self.play_program(0, preset.program(), key, velocity, duration);
+    }
+
+    /// Play a note with any bank and preset of the soundfont, releasing it after `duration`
+    pub fn play_program(&self, bank: u8, preset: u8, key: Key, velocity: u8, duration: Duration) {
+        self.send(SfxNote {
+            program: Some((bank.min(127), preset.min(127))),
+            key: key.0,
+            velocity,
+            duration,
+        });
+    }
+
+    /// Play a percussion sound, such as [`Key::C2`] for a bass drum
+    pub fn play_percussion(&self, key: Key, velocity: u8) {
+        self.send(SfxNote {
+            program: None,
+            key: key.0,
+            velocity,
+            duration: Duration::ZERO,
+        });
+    }
+
+    /// Control of the synthesizer's output, such as to change the volume of every note
+    pub fn control(&self) -> &MidiControl {
+        &self.control
+    }
+
+    fn send(&self, note: SfxNote) {
+        let _ = self.sender.try_send(SfxNote {
+            key: note.key.min(127),
+            velocity: note.velocity.min(127),
+            ..note
+        });
+    }
+}
+
+/// Notes waiting to be played by the persistent synthesizer
+#[derive(Debug)]
+pub(crate) struct SfxProgram {
+    notes: Receiver<SfxNote>,
+}
+
+pub(crate) struct SfxRenderer {
+    synthesizers: SynthFactory,
+    synthesizer: Option<Box<dyn SynthBackend>>,
+    program: Arc<SfxProgram>,
+    /// Frames rendered so far
+    frame: u64,
+    block_wrote: usize,
+    /// Bank and preset of each channel, and the frame it was last used
+    channels: [(Option<(u8, u8)>, u64); 16],
+    /// Frame, channel and key of each note waiting to be released
+    releases: Vec<(u64, u8, u8)>,
+}
+
+impl SfxRenderer {
+    pub(crate) fn new(synthesizers: &SynthFactory, program: Arc<SfxProgram>) -> Self {
+        Self {
+            synthesizers: synthesizers.clone(),
+            synthesizer: None,
+            program,
+            frame: 0,
+            block_wrote: usize::MAX,
+            channels: [(None, 0); 16],
+            releases: Vec::new(),
+        }
+    }
+
+    /// The synthesizer, once the soundfont it plays has loaded
+    fn synthesizer(&mut self) -> Option<&mut dyn SynthBackend> {
+        if self.synthesizer.is_none() {
+            if self.synthesizers.settings.soundfont.is_none() {
+                self.synthesizers.settings.soundfont = crate::ready_soundfont()?;
+            }
+            self.synthesizer = Some(self.synthesizers.create());
+        }
+        self.synthesizer.as_deref_mut()
+    }
+
+    /// Channel to play the given bank and preset on, preferring one already playing it
+    fn channel(&mut self, program: (u8, u8)) -> (u8, bool) {
+        let melodic = (0..16).filter(|&channel| channel != PERCUSSION_CHANNEL as usize);
+        if let Some(channel) = melodic
+            .clone()
+            .find(|&channel| self.channels[channel].0 == Some(program))
+        {
+            return (channel as u8, false);
+        }
+        let channel = melodic
+            .min_by_key(|&channel| self.channels[channel].1)
+            .unwrap();
+        self.channels[channel].0 = Some(program);
+        (channel as u8, true)
+    }
+
+    fn play_notes(&mut self) {
+        let frame = self.frame;
+        let sample_rate = self.synthesizers.settings.sample_rate as f64;
+        while let Ok(note) = self.program.notes.try_recv() {
+            let (channel, change) = match note.program {
+                Some(program) => self.channel(program),
+                None => (PERCUSSION_CHANNEL, false),
+            };
+            self.channels[channel as usize].1 = frame;
+            let release = frame + (note.duration.as_secs_f64() * sample_rate) as u64;
+            let Some(synthesizer) = self.synthesizer() else {
+                continue;
+            };
+            if let (true, Some((bank, preset))) = (change, note.program) {
+                synthesizer.process_midi_message(channel as i32, 0xB0, 0, bank as i32);
+                synthesizer.process_midi_message(channel as i32, 0xC0, preset as i32, 0);
+            }
+            synthesizer.note_on(channel as i32, note.key as i32, note.velocity as i32);
+            if note.program.is_some() {
+                self.releases.push((release, channel, note.key));
+            }
+        }
+        let mut releases = std::mem::take(&mut self.releases);
+        releases.retain(|&(release, channel, key)| {
+            if release > frame {
+                return true;
+            }
+            if let Some(synthesizer) = self.synthesizer() {
+                synthesizer.note_off(channel as i32, key as i32);
+            }
+            false
+        });
+        self.releases = releases;
+    }
+}
+
+impl MidiRender for SfxRenderer {
+    fn render(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        let mut wrote = 0;
+        while wrote < left.len() {
+            let Some(block_size) = self
+                .synthesizer()
+                .map(|synthesizer| synthesizer.block_size())
+            else {
+                // Silent until the soundfont has loaded
+                left[wrote..].fill(0.0);
+                right[wrote..].fill(0.0);
+                break;
+            };
+            if self.block_wrote >= block_size {
+                self.play_notes();
+                self.block_wrote = 0;
+            }
+            let len = (block_size - self.block_wrote).min(left.len() - wrote);
+            if let Some(synthesizer) = self.synthesizer() {
+                synthesizer.render(
+                    &mut left[wrote..wrote + len],
+                    &mut right[wrote..wrote + len],
+                );
+            }
+            self.block_wrote += len;
+            self.frame += len as u64;
+            wrote += len;
+        }
+        left.len()
+    }
+
+    fn seek(&mut self, _position: f64) {}
+
+    fn set_speed(&mut self, _speed: f64) {}
+
+    fn render_tail(&mut self, left: &mut [f32], right: &mut [f32]) {
+        match self.synthesizer() {
+            Some(synthesizer) => synthesizer.render(left, right),
+            None => {
+                left.fill(0.0);
+                right.fill(0.0);
+            }
+        }
+    }
+}
+
+/// Spawn the audio entity of the [`MidiSfx`] synthesizer once the first note is played
+pub(crate) fn spawn_sfx_synthesizer(
+    mut commands: Commands,
+    sfx: Res<MidiSfx>,
+    mut sources: ResMut<Assets<MidiSource>>,
+    mut spawned: Local<bool>,
+) {
+    if *spawned || sfx.receiver.is_empty() {
+        return;
+    }
+    *spawned = true;
+    let source = sources.add(MidiSource {
+        program: SourceProgram::Sfx(Arc::new(SfxProgram {
+            notes: sfx.receiver.clone(),
+        })),
+        control: sfx.control.clone(),
+        sync: None,
+    });
+    commands.spawn((source, PlaybackSettings::ONCE, sfx.control.clone()));
+}
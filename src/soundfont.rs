@@ -0,0 +1,167 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext, LoadState},
+    prelude::*,
+};
+use rustysynth::SoundFont;
+use serde::{Deserialize, Serialize};
+
+use crate::{subset::soundfont_banks, MidiError, SoundFontPresets};
+
+/// Soundfont asset, loaded from a `.sf2` file
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct MidiSoundFont(pub Arc<SoundFont>);
+
+/// Settings for loading soundfonts
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SoundFontLoaderSettings {
+    /// Banks and presets to keep, discarding the rest to save memory, or `None` to keep all of
+    /// them
+    pub presets: Option<SoundFontPresets>,
+    /// Extra labelled soundfonts to load from the file, each keeping only some of its banks and
+    /// presets
+    pub labels: Vec<(String, SoundFontPresets)>,
+    /// Whether to also load each bank of the file on its own, labelled `bank{number}`
+    pub bank_labels: bool,
+}
+
+/// AssetLoader for soundfonts (.sf2)
+///
+/// [`SoundFontLoaderSettings::labels`] loads subsets of the file alongside it, and
+/// [`SoundFontLoaderSettings::bank_labels`] each of its banks, labelled `bank{number}`, e.g.
+/// `gm.sf2#bank128` for the percussion kits of a General MIDI soundfont. Unused labelled
+/// soundfonts are dropped once loading finishes, so one large file can provide small subsets to
+/// different scenes.
+#[derive(Default, Debug)]
+pub struct SoundFontLoader;
+
+impl AssetLoader for SoundFontLoader {
+    type Asset = MidiSoundFont;
+
+    type Settings = SoundFontLoaderSettings;
+
+    type Error = MidiError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        settings: &'a Self::Settings,
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        let banks = if settings.bank_labels {
+            soundfont_banks(&bytes)?
+        } else {
+            BTreeSet::new()
+        };
+        for bank in banks {
+            let subset = SoundFontPresets::new().bank(bank).subset(&bytes)?;
+            load_context.add_labeled_asset(format!("bank{bank}"), parse_soundfont(&subset)?);
+        }
+        for (label, presets) in &settings.labels {
+            let subset = presets.subset(&bytes)?;
+            load_context.add_labeled_asset(label.clone(), parse_soundfont(&subset)?);
+        }
+        if let Some(presets) = &settings.presets {
+            bytes = presets.subset(&bytes)?;
+        }
+        parse_soundfont(&bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["sf2"]
+    }
+}
+
+fn parse_soundfont(bytes: &[u8]) -> Result<MidiSoundFont, MidiError> {
+    let soundfont = SoundFont::new(&mut &bytes[..]).map_err(MidiError::soundfont)?;
+    Ok(MidiSoundFont(Arc::new(soundfont)))
+}
+
+/// Resource choosing a soundfont asset to play new music with instead of the plugin's.
+///
+/// Until the asset has loaded, the plugin's soundfont is used. When the asset changes, such as
+/// when the file is saved with asset hot reloading enabled, music started afterwards uses the
+/// new version, so instruments can be auditioned without restarting.
+#[derive(Resource, Clone, Debug)]
+pub struct MidiActiveSoundFont(pub Handle<MidiSoundFont>);
+
+pub(crate) fn apply_active_soundfont(
+    active: Option<Res<MidiActiveSoundFont>>,
+    mut events: EventReader<AssetEvent<MidiSoundFont>>,
+    soundfonts: Res<Assets<MidiSoundFont>>,
+) {
+    let changed = events.read().any(|event| match (event, &active) {
+        (AssetEvent::Added { id } | AssetEvent::Modified { id }, Some(active)) => {
+            *id == active.0.id()
+        }
+        _ => false,
+    });
+    let Some(active) = active else {
+        crate::set_active_soundfont(None);
+        return;
+    };
+    if changed || active.is_changed() {
+        let soundfont = soundfonts
+            .get(&active.0)
+            .map(|soundfont| soundfont.0.clone());
+        crate::set_active_soundfont(soundfont);
+    }
+}
+
+/// Resource reporting whether the plugin's soundfont is ready to play music with, so loading
+/// screens can wait for it alongside their other assets.
+///
+/// A soundfont parsed [lazily](crate::RustySynthPlugin::lazy) stays [`LoadState::NotLoaded`]
+/// until music first needs it. A [`MidiActiveSoundFont`] is an ordinary asset, whose load state
+/// the [`AssetServer`] reports.
+#[derive(Resource, Clone, Debug, PartialEq, Eq)]
+pub struct MidiSoundFontStatus {
+    /// Load state of the plugin's soundfont
+    pub load_state: LoadState,
+}
+
+impl Default for MidiSoundFontStatus {
+    fn default() -> Self {
+        Self {
+            load_state: crate::soundfont_load_state(),
+        }
+    }
+}
+
+impl MidiSoundFontStatus {
+    /// Whether the soundfont has loaded
+    pub fn is_loaded(&self) -> bool {
+        self.load_state == LoadState::Loaded
+    }
+}
+
+/// Run condition which is true once the plugin's soundfont has loaded
+pub fn midi_soundfont_loaded(status: Res<MidiSoundFontStatus>) -> bool {
+    status.is_loaded()
+}
+
+pub(crate) fn update_soundfont_status(mut status: ResMut<MidiSoundFontStatus>) {
+    status.set_if_neq(MidiSoundFontStatus {
+        load_state: crate::soundfont_load_state(),
+    });
+}
+
+/// Event sent when the plugin's soundfont can't be read, such as when it's corrupt.
+///
+/// Music is then played without a soundfont, by the [`OscillatorSynth`](crate::OscillatorSynth).
+#[derive(Event, Clone, Debug)]
+pub struct MidiSoundFontError {
+    /// What went wrong
+    pub error: Arc<MidiError>,
+}
+
+pub(crate) fn report_soundfont_errors(mut events: EventWriter<MidiSoundFontError>) {
+    if let Some(error) = crate::take_soundfont_error() {
+        events.send(MidiSoundFontError {
+            error: Arc::new(error),
+        });
+    }
+}
@@ -0,0 +1,128 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    audio::{AudioSink, AudioSinkPlayback, SpatialAudioSink},
+    prelude::*,
+};
+
+use crate::{MidiAudio, MidiSource};
+
+/// Which MIDI sources a [`MidiStatePausePlugin`] pauses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiPauseScope {
+    /// Every MIDI source
+    #[default]
+    All,
+    /// Only sources tagged with [`PauseWithState`]
+    Tagged,
+}
+
+/// Component tagging a MIDI source to be paused by a [`MidiStatePausePlugin`] using
+/// [`MidiPauseScope::Tagged`]
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct PauseWithState;
+
+/// Marks a source paused by the plugin for state `S`, so only those are resumed
+#[derive(Component)]
+struct PausedByState<S: States>(PhantomData<S>);
+
+#[derive(Resource)]
+struct MidiStatePauseScope<S: States> {
+    scope: MidiPauseScope,
+    _state: PhantomData<S>,
+}
+
+/// Plugin which pauses MIDI sources while the app is in the given state and resumes them when it
+/// is left.
+///
+/// Paused sources stop pulling audio from their decoders, so the background render tasks halt as
+/// soon as their buffers are full. Sources the user paused themselves are left alone.
+#[derive(Debug)]
+pub struct MidiStatePausePlugin<S: States> {
+    /// State in which sources are paused
+    pub state: S,
+    /// Sources to pause
+    pub scope: MidiPauseScope,
+}
+
+impl<S: States> MidiStatePausePlugin<S> {
+    /// Pause every MIDI source while in `state`
+    pub fn new(state: S) -> Self {
+        Self {
+            state,
+            scope: MidiPauseScope::All,
+        }
+    }
+
+    /// Only pause sources tagged with [`PauseWithState`]
+    pub fn tagged(mut self) -> Self {
+        self.scope = MidiPauseScope::Tagged;
+        self
+    }
+}
+
+impl<S: States> Plugin for MidiStatePausePlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(MidiStatePauseScope::<S> {
+            scope: self.scope,
+            _state: PhantomData,
+        })
+        .add_systems(
+            PostUpdate,
+            pause_sources::<S>.run_if(in_state(self.state.clone())),
+        )
+        .add_systems(OnExit(self.state.clone()), resume_sources::<S>);
+    }
+}
+
+type SourceSinks = (
+    Entity,
+    Option<&'static AudioSink>,
+    Option<&'static SpatialAudioSink>,
+);
+
+type UnpausedMidiFilter<S> = (
+    Or<(With<Handle<MidiAudio>>, With<Handle<MidiSource>>)>,
+    Or<(With<AudioSink>, With<SpatialAudioSink>)>,
+    Without<PausedByState<S>>,
+);
+
+/// Pauses playing sources, including ones which start while in the state
+fn pause_sources<S: States>(
+    mut commands: Commands,
+    scope: Res<MidiStatePauseScope<S>>,
+    query: Query<(SourceSinks, Has<PauseWithState>), UnpausedMidiFilter<S>>,
+) {
+    for ((entity, sink, spatial_sink), tagged) in &query {
+        if scope.scope == MidiPauseScope::Tagged && !tagged {
+            continue;
+        }
+        let sink: &dyn AudioSinkPlayback = match (sink, spatial_sink) {
+            (Some(sink), _) => sink,
+            (_, Some(sink)) => sink,
+            _ => continue,
+        };
+        if sink.is_paused() {
+            continue;
+        }
+        sink.pause();
+        commands
+            .entity(entity)
+            .insert(PausedByState::<S>(PhantomData));
+    }
+}
+
+fn resume_sources<S: States>(
+    mut commands: Commands,
+    query: Query<SourceSinks, With<PausedByState<S>>>,
+) {
+    for (entity, sink, spatial_sink) in &query {
+        if let Some(sink) = sink {
+            sink.play();
+        }
+        if let Some(sink) = spatial_sink {
+            sink.play();
+        }
+        commands.entity(entity).remove::<PausedByState<S>>();
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustysynth::SoundFont;
+
+use crate::{write_audio, MidiAudio, MidiError, MidiExportFormat};
+
+/// How [`MidiAudio::stems`] splits music into parts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MidiStemSplit {
+    /// One stem for each track which plays notes
+    #[default]
+    Tracks,
+    /// One stem for each channel which plays notes
+    Channels,
+}
+
+/// A part of a piece of music, playing only some of its notes
+#[derive(Clone, Debug)]
+pub struct MidiStem {
+    /// Name of the track, or `Track{index}` or `Channel{index}`
+    pub name: String,
+    /// The music with every other part's notes removed
+    pub audio: MidiAudio,
+}
+
+impl MidiAudio {
+    /// Split into stems, each keeping the notes of one track or channel.
+    ///
+    /// Every stem keeps the tempo changes, instruments and controllers of the whole piece, so it
+    /// plays exactly as it does in the mix.
+    pub fn stems(&self, split: MidiStemSplit) -> Result<Vec<MidiStem>, MidiError> {
+        let smf = self.to_smf()?;
+        let stem = |name: String, keep: &dyn Fn(usize, u8) -> bool| {
+            let mut smf = smf.clone();
+            smf.retain_notes(keep);
+            MidiStem {
+                name,
+                audio: MidiAudio::File(smf.to_bytes()),
+            }
+        };
+        Ok(match split {
+            MidiStemSplit::Tracks => (0..smf.tracks.len())
+                .filter(|&index| smf.track_has_notes(index))
+                .map(|index| {
+                    let name = match smf.track_name(index) {
+                        Some(name) => name.to_string(),
+                        None => format!("Track{index}"),
+                    };
+                    stem(name, &|track, _| track == index)
+                })
+                .collect(),
+            MidiStemSplit::Channels => (0..16_u8)
+                .filter(|&channel| smf.note_channels()[channel as usize])
+                .map(|channel| stem(format!("Channel{channel}"), &|_, other| other == channel))
+                .collect(),
+        })
+    }
+
+    /// Render each stem to a file in `directory`, for mixing in a DAW.
+    ///
+    /// Stems are rendered with the plugin's [`MidiRenderSettings`](crate::MidiRenderSettings)
+    /// and padded with silence to the same length, so they line up when imported together.
+    /// Files are numbered in order and named after their stems, and their paths are returned.
+    pub fn export_stems(
+        &self,
+        soundfont: Arc<SoundFont>,
+        split: MidiStemSplit,
+        format: MidiExportFormat,
+        directory: impl AsRef<Path>,
+    ) -> Result<Vec<PathBuf>, MidiError> {
+        let directory = directory.as_ref();
+        fs::create_dir_all(directory)?;
+        let rendered = self
+            .stems(split)?
+            .into_iter()
+            .map(|stem| (stem.name, stem.audio.render(Some(soundfont.clone()))))
+            .collect::<Vec<_>>();
+        let length = rendered.iter().map(|(_, (samples, _))| samples.len()).max();
+        let mut paths = Vec::new();
+        for (index, (name, (mut samples, sample_rate))) in rendered.into_iter().enumerate() {
+            samples.resize(length.unwrap_or_default(), 0.0);
+            let path = directory.join(format!(
+                "{:02}-{}.{}",
+                index + 1,
+                file_name(&name),
+                format.extension()
+            ));
+            write_audio(&path, &samples, 2, sample_rate, format)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+/// Name of a stem with characters which aren't allowed in file names replaced
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
@@ -0,0 +1,496 @@
+use std::{borrow::Cow, collections::BTreeSet, io, ops::RangeInclusive};
+
+use serde::{Deserialize, Serialize};
+
+use crate::MidiError;
+
+/// Generator holding the instrument of a preset zone
+const INSTRUMENT_GENERATOR: u16 = 41;
+/// Generator holding the sample of an instrument zone
+const SAMPLE_ID_GENERATOR: u16 = 53;
+/// Silent sample points the SF2 spec requires after each sample
+const SAMPLE_PADDING: usize = 46;
+
+const PHDR_SIZE: usize = 38;
+const BAG_SIZE: usize = 4;
+const MOD_SIZE: usize = 10;
+const GEN_SIZE: usize = 4;
+const INST_SIZE: usize = 22;
+const SHDR_SIZE: usize = 46;
+
+/// Range of presets to keep from a range of banks
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PresetRange {
+    /// Banks the presets are kept from, with percussion usually in bank 128
+    pub banks: RangeInclusive<u16>,
+    /// Presets (instruments) to keep
+    pub presets: RangeInclusive<u16>,
+}
+
+/// Banks and presets to keep when loading a large soundfont.
+///
+/// Instruments and sample data only used by other presets are discarded, so a project which only
+/// needs a piano and some strings doesn't keep a whole General MIDI set in memory.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SoundFontPresets(pub Vec<PresetRange>);
+
+impl SoundFontPresets {
+    /// Keep no presets, to add some with the other methods
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep a single preset of a bank
+    pub fn preset(self, bank: u16, preset: u16) -> Self {
+        self.range(bank..=bank, preset..=preset)
+    }
+
+    /// Keep every preset of a bank
+    pub fn bank(self, bank: u16) -> Self {
+        self.range(bank..=bank, 0..=u16::MAX)
+    }
+
+    /// Keep a range of presets from a range of banks
+    pub fn range(mut self, banks: RangeInclusive<u16>, presets: RangeInclusive<u16>) -> Self {
+        self.0.push(PresetRange { banks, presets });
+        self
+    }
+
+    /// Whether a preset of a bank is kept
+    pub fn contains(&self, bank: u16, preset: u16) -> bool {
+        self.0
+            .iter()
+            .any(|range| range.banks.contains(&bank) && range.presets.contains(&preset))
+    }
+
+    /// Rewrite SF2 data to only hold the kept presets, with the instruments and samples they use
+    pub fn subset(&self, sf2: &[u8]) -> Result<Vec<u8>, MidiError> {
+        let subset = Sf2File::parse(sf2).and_then(|file| file.subset(self));
+        Ok(subset.map_err(MidiError::SoundFont)?.write())
+    }
+}
+
+/// Banks holding at least one preset of SF2 data
+pub(crate) fn soundfont_banks(sf2: &[u8]) -> Result<BTreeSet<u16>, MidiError> {
+    let file = Sf2File::parse(sf2).map_err(MidiError::SoundFont)?;
+    Ok(file
+        .presets
+        .headers
+        .iter()
+        .map(|header| u16_at(header, 22))
+        .collect())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn set_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Split a chunk's contents into their `(id, data)` subchunks
+fn chunks(mut bytes: &[u8]) -> io::Result<Vec<([u8; 4], &[u8])>> {
+    let mut chunks = Vec::new();
+    while bytes.len() >= 8 {
+        let id = bytes[..4].try_into().unwrap();
+        let size = u32_at(bytes, 4) as usize;
+        let data = bytes
+            .get(8..8 + size)
+            .ok_or_else(|| invalid("truncated soundfont chunk"))?;
+        chunks.push((id, data));
+        bytes = bytes.get(8 + size + size % 2..).unwrap_or_default();
+    }
+    Ok(chunks)
+}
+
+/// Fixed-size records of a `pdta` chunk, without their terminal record
+fn records(data: &[u8], size: usize) -> io::Result<Vec<Vec<u8>>> {
+    if data.len() % size != 0 || data.len() < size {
+        return Err(invalid("malformed soundfont preset data"));
+    }
+    Ok(data[..data.len() - size]
+        .chunks(size)
+        .map(<[u8]>::to_vec)
+        .collect())
+}
+
+/// Start of each record's range into another list, followed by the end of the last one
+fn indices(records: &[Vec<u8>], offset: usize, terminal: usize) -> Vec<usize> {
+    records
+        .iter()
+        .map(|record| u16_at(record, offset) as usize)
+        .chain([terminal])
+        .collect()
+}
+
+/// Records of one level of the SF2 hierarchy: headers with zones, whose generators and modulators
+/// point into the level below
+#[derive(Default)]
+struct Level {
+    headers: Vec<Vec<u8>>,
+    terminal: Vec<u8>,
+    bags: Vec<Vec<u8>>,
+    mods: Vec<Vec<u8>>,
+    gens: Vec<Vec<u8>>,
+}
+
+impl Level {
+    fn parse(
+        headers: &[u8],
+        header_size: usize,
+        bags: &[u8],
+        mods: &[u8],
+        gens: &[u8],
+    ) -> io::Result<Self> {
+        Ok(Self {
+            headers: records(headers, header_size)?,
+            terminal: headers[headers.len() - header_size..].to_vec(),
+            bags: records(bags, BAG_SIZE)?,
+            mods: records(mods, MOD_SIZE)?,
+            gens: records(gens, GEN_SIZE)?,
+        })
+    }
+
+    /// Copy the headers at `keep`, with their zones, remapping the generator `linked` through
+    /// `remap`
+    fn subset(
+        &self,
+        keep: &[usize],
+        bag_offset: usize,
+        linked: u16,
+        remap: impl Fn(u16) -> u16,
+    ) -> io::Result<Self> {
+        let header_bags = indices(&self.headers, bag_offset, self.bags.len());
+        let bag_gens = indices(&self.bags, 0, self.gens.len());
+        let bag_mods = indices(&self.bags, 2, self.mods.len());
+        let range = |indices: &[usize], index: usize| {
+            let (start, end) = (indices[index], indices[index + 1]);
+            (start <= end && end <= indices[indices.len() - 1])
+                .then_some(start..end)
+                .ok_or_else(|| invalid("malformed soundfont zone indices"))
+        };
+
+        let mut level = Level {
+            terminal: self.terminal.clone(),
+            ..Default::default()
+        };
+        for &header in keep {
+            let mut record = self.headers[header].clone();
+            set_u16(&mut record, bag_offset, level.bags.len() as u16);
+            level.headers.push(record);
+            for bag in range(&header_bags, header)? {
+                let mut record = self.bags[bag].clone();
+                set_u16(&mut record, 0, level.gens.len() as u16);
+                set_u16(&mut record, 2, level.mods.len() as u16);
+                level.bags.push(record);
+                for generator in range(&bag_gens, bag)? {
+                    let mut record = self.gens[generator].clone();
+                    if u16_at(&record, 0) == linked {
+                        let target = remap(u16_at(&record, 2));
+                        set_u16(&mut record, 2, target);
+                    }
+                    level.gens.push(record);
+                }
+                level
+                    .mods
+                    .extend(range(&bag_mods, bag)?.map(|modulator| self.mods[modulator].clone()));
+            }
+        }
+        set_u16(&mut level.terminal, bag_offset, level.bags.len() as u16);
+        Ok(level)
+    }
+
+    /// Values of the generator `linked` in the zones of the given headers
+    fn linked(&self, keep: &[usize], bag_offset: usize, linked: u16) -> BTreeSet<usize> {
+        let header_bags = indices(&self.headers, bag_offset, self.bags.len());
+        let bag_gens = indices(&self.bags, 0, self.gens.len());
+        keep.iter()
+            .flat_map(|&header| header_bags[header]..header_bags[header + 1])
+            .filter(|&bag| bag < self.bags.len())
+            .flat_map(|bag| bag_gens[bag]..bag_gens[bag + 1])
+            .filter_map(|generator| self.gens.get(generator))
+            .filter(|record| u16_at(record, 0) == linked)
+            .map(|record| u16_at(record, 2) as usize)
+            .collect()
+    }
+
+    fn write(&self, pdta: &mut Vec<u8>, ids: [&[u8; 4]; 4]) {
+        let terminal = |size| vec![0; size];
+        let [headers, bags, mods, gens] = ids;
+        write_records(pdta, headers, &self.headers, &self.terminal);
+        write_records(pdta, bags, &self.bags, &{
+            let mut record = terminal(BAG_SIZE);
+            set_u16(&mut record, 0, self.gens.len() as u16);
+            set_u16(&mut record, 2, self.mods.len() as u16);
+            record
+        });
+        write_records(pdta, mods, &self.mods, &terminal(MOD_SIZE));
+        write_records(pdta, gens, &self.gens, &terminal(GEN_SIZE));
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn write_records(out: &mut Vec<u8>, id: &[u8; 4], records: &[Vec<u8>], terminal: &[u8]) {
+    let data: Vec<u8> = records.iter().flatten().chain(terminal).copied().collect();
+    write_chunk(out, id, &data);
+}
+
+fn write_list(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    write_chunk(out, b"LIST", &[kind.as_slice(), data].concat());
+}
+
+/// Chunks of an SF2 file needed to rewrite it
+struct Sf2File<'a> {
+    info: &'a [u8],
+    samples: Cow<'a, [u8]>,
+    presets: Level,
+    instruments: Level,
+    sample_headers: Vec<Vec<u8>>,
+}
+
+impl<'a> Sf2File<'a> {
+    fn parse(sf2: &'a [u8]) -> io::Result<Self> {
+        let [(riff, body)] = chunks(sf2)?[..] else {
+            return Err(invalid("not a soundfont"));
+        };
+        if &riff != b"RIFF" || body.get(..4) != Some(b"sfbk") {
+            return Err(invalid("not a soundfont"));
+        }
+        let mut info: &[u8] = &[];
+        let mut samples: &[u8] = &[];
+        let mut pdta = Vec::new();
+        for (id, data) in chunks(&body[4..])? {
+            if &id != b"LIST" || data.len() < 4 {
+                continue;
+            }
+            match &data[..4] {
+                b"INFO" => info = &data[4..],
+                b"sdta" => {
+                    samples = chunks(&data[4..])?
+                        .into_iter()
+                        .find(|(id, _)| id == b"smpl")
+                        .map(|(_, data)| data)
+                        .unwrap_or_default();
+                }
+                b"pdta" => pdta = chunks(&data[4..])?,
+                _ => {}
+            }
+        }
+        let chunk = |name: &[u8; 4]| {
+            pdta.iter()
+                .find(|(id, _)| id == name)
+                .map(|(_, data)| *data)
+                .ok_or_else(|| invalid("soundfont is missing preset data"))
+        };
+        Ok(Self {
+            info,
+            samples: Cow::Borrowed(samples),
+            presets: Level::parse(
+                chunk(b"phdr")?,
+                PHDR_SIZE,
+                chunk(b"pbag")?,
+                chunk(b"pmod")?,
+                chunk(b"pgen")?,
+            )?,
+            instruments: Level::parse(
+                chunk(b"inst")?,
+                INST_SIZE,
+                chunk(b"ibag")?,
+                chunk(b"imod")?,
+                chunk(b"igen")?,
+            )?,
+            sample_headers: {
+                let data = chunk(b"shdr")?;
+                records(data, SHDR_SIZE)?
+            },
+        })
+    }
+
+    fn subset(&self, presets: &SoundFontPresets) -> io::Result<Sf2File<'a>> {
+        let kept_presets: Vec<usize> = (0..self.presets.headers.len())
+            .filter(|&index| {
+                let header = &self.presets.headers[index];
+                presets.contains(u16_at(header, 22), u16_at(header, 20))
+            })
+            .collect();
+        if kept_presets.is_empty() {
+            return Err(invalid("soundfont has none of the selected presets"));
+        }
+
+        let instruments: Vec<usize> = self
+            .presets
+            .linked(&kept_presets, 24, INSTRUMENT_GENERATOR)
+            .into_iter()
+            .filter(|&index| index < self.instruments.headers.len())
+            .collect();
+        let mut sample_ids: BTreeSet<usize> = self
+            .instruments
+            .linked(&instruments, 20, SAMPLE_ID_GENERATOR)
+            .into_iter()
+            .filter(|&index| index < self.sample_headers.len())
+            .collect();
+        // Stereo samples stay paired with their other side
+        let linked: Vec<usize> = sample_ids
+            .iter()
+            .map(|&index| u16_at(&self.sample_headers[index], 42) as usize)
+            .filter(|&index| index < self.sample_headers.len())
+            .collect();
+        sample_ids.extend(linked);
+        let sample_ids: Vec<usize> = sample_ids.into_iter().collect();
+
+        let new_index =
+            |kept: &[usize], old: u16| kept.binary_search(&(old as usize)).unwrap_or(0) as u16;
+        let presets = self
+            .presets
+            .subset(&kept_presets, 24, INSTRUMENT_GENERATOR, |old| {
+                new_index(&instruments, old)
+            })?;
+        let instruments =
+            self.instruments
+                .subset(&instruments, 20, SAMPLE_ID_GENERATOR, |old| {
+                    new_index(&sample_ids, old)
+                })?;
+
+        let mut samples = Vec::new();
+        let mut sample_headers = Vec::new();
+        for &index in &sample_ids {
+            let mut header = self.sample_headers[index].clone();
+            let available = self.samples.len() / 2;
+            let start = (u32_at(&header, 20) as usize).min(available);
+            let end = (u32_at(&header, 24) as usize).clamp(start, available);
+            let new_start = samples.len() / 2;
+            samples.extend_from_slice(&self.samples[start * 2..end * 2]);
+            samples.resize(samples.len() + SAMPLE_PADDING * 2, 0);
+            let shift =
+                |offset| (u32_at(&header, offset) as usize).saturating_sub(start) + new_start;
+            let (start_loop, end_loop) = (shift(28), shift(32));
+            set_u32(&mut header, 20, new_start as u32);
+            set_u32(&mut header, 24, (new_start + end - start) as u32);
+            set_u32(&mut header, 28, start_loop as u32);
+            set_u32(&mut header, 32, end_loop as u32);
+            let link = u16_at(&header, 42);
+            set_u16(&mut header, 42, new_index(&sample_ids, link));
+            sample_headers.push(header);
+        }
+
+        Ok(Sf2File {
+            info: self.info,
+            samples: Cow::Owned(samples),
+            presets,
+            instruments,
+            sample_headers,
+        })
+    }
+
+    fn write(&self) -> Vec<u8> {
+        let mut sdta = Vec::new();
+        write_chunk(&mut sdta, b"smpl", &self.samples);
+
+        let mut pdta = Vec::new();
+        self.presets
+            .write(&mut pdta, [b"phdr", b"pbag", b"pmod", b"pgen"]);
+        self.instruments
+            .write(&mut pdta, [b"inst", b"ibag", b"imod", b"igen"]);
+        let mut terminal = vec![0; SHDR_SIZE];
+        terminal[..3].copy_from_slice(b"EOS");
+        write_records(&mut pdta, b"shdr", &self.sample_headers, &terminal);
+
+        let mut body = b"sfbk".to_vec();
+        write_list(&mut body, b"INFO", self.info);
+        write_list(&mut body, b"sdta", &sdta);
+        write_list(&mut body, b"pdta", &pdta);
+        let mut out = Vec::new();
+        write_chunk(&mut out, b"RIFF", &body);
+        out
+    }
+}
+
+#[cfg(all(test, feature = "hl4mgm"))]
+mod tests {
+    use rustysynth::SoundFont;
+
+    use super::*;
+    use crate::HL4MGM;
+
+    fn presets(soundfont: &SoundFont) -> BTreeSet<(i32, i32)> {
+        soundfont
+            .get_presets()
+            .iter()
+            .map(|preset| (preset.get_bank_number(), preset.get_patch_number()))
+            .collect()
+    }
+
+    #[test]
+    fn subset_keeps_only_chosen_presets() {
+        let full = SoundFont::new(&mut &HL4MGM[..]).unwrap();
+        let kept = SoundFontPresets::new().preset(0, 0).range(0..=0, 40..=41);
+        let sf2 = kept.subset(HL4MGM).unwrap();
+        assert!(sf2.len() < HL4MGM.len());
+        let subset = SoundFont::new(&mut sf2.as_slice()).unwrap();
+        let expected: BTreeSet<_> = presets(&full)
+            .into_iter()
+            .filter(|&(bank, preset)| kept.contains(bank as u16, preset as u16))
+            .collect();
+        assert_eq!(presets(&subset), expected);
+        assert!(!expected.is_empty());
+        assert!(subset.get_sample_headers().len() < full.get_sample_headers().len());
+        assert!(subset.get_wave_data().len() < full.get_wave_data().len());
+    }
+
+    #[test]
+    fn subset_renders_like_the_original() {
+        use rustysynth::{Synthesizer, SynthesizerSettings};
+        use std::sync::Arc;
+
+        let render = |soundfont: SoundFont| {
+            let settings = SynthesizerSettings::new(44100);
+            let mut synthesizer = Synthesizer::new(&Arc::new(soundfont), &settings).unwrap();
+            synthesizer.note_on(0, 60, 100);
+            let (mut left, mut right) = (vec![0.0; 4410], vec![0.0; 4410]);
+            synthesizer.render(&mut left, &mut right);
+            left
+        };
+        let sf2 = SoundFontPresets::new().preset(0, 0).subset(HL4MGM).unwrap();
+        let original = render(SoundFont::new(&mut &HL4MGM[..]).unwrap());
+        let subset = render(SoundFont::new(&mut sf2.as_slice()).unwrap());
+        assert_eq!(original, subset);
+        assert!(original.iter().any(|sample| sample.abs() > 0.01));
+    }
+
+    #[test]
+    fn lists_banks() {
+        let banks = soundfont_banks(HL4MGM).unwrap();
+        assert!(banks.contains(&0));
+        assert!(banks.contains(&128));
+        let sf2 = SoundFontPresets::new().bank(128).subset(HL4MGM).unwrap();
+        assert_eq!(soundfont_banks(&sf2).unwrap(), BTreeSet::from([128]));
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        let kept = SoundFontPresets::new().preset(0, 0);
+        assert!(kept.subset(b"RIFF").is_err());
+        assert!(kept.subset(&HL4MGM[..HL4MGM.len() / 2]).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bevy::prelude::*;
+
+use crate::HeadlessMidiOutput;
+
+/// Delay between every member of a sync group being ready and the group starting, giving the
+/// render tasks time to fill their buffers.
+const START_DELAY: Duration = Duration::from_millis(250);
+
+/// Frame rate of the group clock, matching the decoders' output
+const CLOCK_RATE: u64 = 44100;
+
+#[derive(Debug)]
+struct SyncState {
+    /// Index of the next output frame not yet begun by any member
+    clock: AtomicU64,
+    /// Frame on which every member starts playing, or `u64::MAX` if not scheduled yet
+    start: AtomicU64,
+    /// Number of decoders currently pulling frames
+    active: AtomicUsize,
+}
+
+impl Default for SyncState {
+    fn default() -> Self {
+        Self {
+            clock: AtomicU64::new(0),
+            start: AtomicU64::new(u64::MAX),
+            active: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Component placing a MIDI source in a sync group.
+///
+/// Every entity carrying a clone of the same group starts on the exact same output sample once
+/// all of them have begun playback, and members which fall behind skip ahead to stay locked.
+/// Members should be paused and resumed together.
+#[derive(Component, Clone, Debug, Default)]
+pub struct MidiSyncGroup(Arc<SyncState>);
+
+impl MidiSyncGroup {
+    /// Construct a new, empty sync group
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether every member of the group has started playing
+    pub fn is_started(&self) -> bool {
+        self.0.clock.load(Ordering::Acquire) > self.0.start.load(Ordering::Acquire)
+    }
+
+    /// Time elapsed since the group started, or `None` if it has not started yet
+    pub fn elapsed(&self) -> Option<Duration> {
+        let start = self.0.start.load(Ordering::Acquire);
+        let frames = self.0.clock.load(Ordering::Acquire).checked_sub(start)?;
+        Some(Duration::from_secs_f64(frames as f64 / CLOCK_RATE as f64))
+    }
+
+    fn is_scheduled(&self) -> bool {
+        self.0.start.load(Ordering::Acquire) != u64::MAX
+    }
+
+    fn schedule(&self) {
+        let delay = START_DELAY.as_secs_f64() * CLOCK_RATE as f64;
+        let start = self.0.clock.load(Ordering::Acquire) + delay as u64;
+        self.0.start.store(start, Ordering::Release);
+    }
+}
+
+/// Position of a single decoder on its group's clock
+#[derive(Debug)]
+pub(crate) struct SyncCursor {
+    group: MidiSyncGroup,
+    frame: Option<u64>,
+}
+
+impl SyncCursor {
+    pub(crate) fn new(group: MidiSyncGroup) -> Self {
+        Self { group, frame: None }
+    }
+
+    /// Begin the next output frame, returning whether the group is playing
+    pub(crate) fn advance(&mut self) -> bool {
+        let state = &self.group.0;
+        let frame = match self.frame {
+            Some(frame) => frame + 1,
+            None => {
+                let clock = state.clock.load(Ordering::Acquire);
+                // Sources joining the mixer are pulled after the ones already playing, which
+                // have begun the current frame
+                if state.active.fetch_add(1, Ordering::AcqRel) > 0 {
+                    clock.saturating_sub(1)
+                } else {
+                    clock
+                }
+            }
+        };
+        self.frame = Some(frame);
+        state.clock.fetch_max(frame + 1, Ordering::AcqRel);
+        frame >= state.start.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for SyncCursor {
+    fn drop(&mut self) {
+        if self.frame.is_some() {
+            self.group.0.active.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+type SyncMember = (
+    &'static MidiSyncGroup,
+    Has<AudioSink>,
+    Has<SpatialAudioSink>,
+    Has<HeadlessMidiOutput>,
+);
+
+/// Schedules the start of sync groups once every member has begun playback
+pub(crate) fn start_sync_groups(query: Query<SyncMember>) {
+    let mut groups: HashMap<*const SyncState, (&MidiSyncGroup, bool)> = HashMap::new();
+    for (group, sink, spatial_sink, headless) in &query {
+        if group.is_scheduled() {
+            continue;
+        }
+        let ready = &mut groups
+            .entry(Arc::as_ptr(&group.0))
+            .or_insert((group, true))
+            .1;
+        *ready &= sink || spatial_sink || headless;
+    }
+    for (group, ready) in groups.into_values() {
+        if ready {
+            group.schedule();
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Utilities for regression-testing music against stored golden fingerprints
+
+use std::{fmt::Write as _, fs, io, path::Path, sync::Arc};
+
+use rustysynth::SoundFont;
+
+use crate::{
+    analysis::spectrum, decoder::SourceProgram, MidiAudio, MidiControl, MidiError, MidiFileDecoder,
+    MidiRenderSettings,
+};
+
+/// Environment variable which, when set, makes [`assert_golden`] overwrite stored fingerprints
+pub const BLESS_VAR: &str = "BLESS_GOLDEN";
+
+/// Number of mono samples summarized by each window of a fingerprint
+const WINDOW: usize = 2048;
+
+/// Number of frequency bands per window of a fingerprint
+const BANDS: usize = 16;
+
+/// Render `audio` in full, independent of the platform's render settings.
+///
+/// The result is interleaved stereo at 44.1 kHz and identical between runs.
+pub fn render_deterministic(audio: &MidiAudio, soundfont: Arc<SoundFont>) -> Vec<f32> {
+    MidiFileDecoder::offline_program(
+        SourceProgram::Audio(audio.clone()),
+        Some(soundfont),
+        MidiControl::default(),
+        None,
+        &MidiRenderSettings::desktop(),
+    )
+    .collect()
+}
+
+/// Compact summary of rendered audio: the energy in a set of frequency bands over time
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioFingerprint {
+    /// Number of stereo frames in the audio
+    pub frames: usize,
+    /// Band energies of each window of the audio
+    pub windows: Vec<[f32; BANDS]>,
+    /// Hash of the exact samples
+    pub hash: u64,
+}
+
+impl AudioFingerprint {
+    /// Fingerprint interleaved stereo samples
+    pub fn new(samples: &[f32]) -> Self {
+        let mono: Vec<f32> = samples
+            .chunks(2)
+            .map(|frame| frame.iter().sum::<f32>() / 2.0)
+            .collect();
+        let mut magnitudes = vec![0.0; WINDOW / 2];
+        let windows = mono
+            .chunks(WINDOW)
+            .map(|chunk| {
+                let mut window = vec![0.0; WINDOW];
+                window[..chunk.len()].copy_from_slice(chunk);
+                spectrum(&window, &mut magnitudes);
+                // Bands spaced by octaves, so the lowest bins don't dominate
+                let mut bands = [0.0; BANDS];
+                for (bin, magnitude) in magnitudes.iter().enumerate().skip(1) {
+                    let band = (bin.ilog2() as usize).min(BANDS - 1);
+                    bands[band] += magnitude * magnitude;
+                }
+                bands
+            })
+            .collect();
+        // FNV-1a over the sample bits
+        let hash = samples.iter().fold(0xcbf29ce484222325_u64, |hash, sample| {
+            sample
+                .to_bits()
+                .to_le_bytes()
+                .iter()
+                .fold(hash, |hash, byte| {
+                    (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+                })
+        });
+        Self {
+            frames: samples.len() / 2,
+            windows,
+            hash,
+        }
+    }
+
+    /// Similarity to another fingerprint between 0 and 1, where 1 means identical spectra
+    pub fn similarity(&self, other: &Self) -> f32 {
+        if self.hash == other.hash && self.frames == other.frames {
+            return 1.0;
+        }
+        let len = self.windows.len().max(other.windows.len());
+        let silent = [0.0; BANDS];
+        let (mut dot, mut norm_a, mut norm_b) = (0.0_f64, 0.0_f64, 0.0_f64);
+        for index in 0..len {
+            let a = self.windows.get(index).unwrap_or(&silent);
+            let b = other.windows.get(index).unwrap_or(&silent);
+            for (a, b) in a.iter().zip(b) {
+                let (a, b) = ((*a as f64).sqrt(), (*b as f64).sqrt());
+                dot += a * b;
+                norm_a += a * a;
+                norm_b += b * b;
+            }
+        }
+        if norm_a == 0.0 && norm_b == 0.0 {
+            return 1.0;
+        }
+        (dot / (norm_a.sqrt() * norm_b.sqrt()).max(f64::EPSILON)) as f32
+    }
+
+    /// Write the fingerprint in its text format
+    pub fn to_text(&self) -> String {
+        let mut text = format!("frames {}\nhash {:016x}\n", self.frames, self.hash);
+        for window in &self.windows {
+            let line = window.iter().map(|energy| format!("{energy:e}"));
+            let _ = writeln!(text, "{}", line.collect::<Vec<_>>().join(" "));
+        }
+        text
+    }
+
+    /// Read a fingerprint written by [`AudioFingerprint::to_text`]
+    pub fn from_text(text: &str) -> Result<Self, MidiError> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut lines = text.lines();
+        let frames = lines
+            .next()
+            .and_then(|line| line.strip_prefix("frames "))
+            .and_then(|frames| frames.parse().ok())
+            .ok_or_else(|| invalid("missing frame count"))?;
+        let hash = lines
+            .next()
+            .and_then(|line| line.strip_prefix("hash "))
+            .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+            .ok_or_else(|| invalid("missing hash"))?;
+        let windows = lines
+            .map(|line| {
+                let mut bands = [0.0; BANDS];
+                let mut values = line.split_whitespace();
+                for band in &mut bands {
+                    *band = values
+                        .next()
+                        .and_then(|value| value.parse().ok())
+                        .ok_or_else(|| invalid("malformed window"))?;
+                }
+                Ok(bands)
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            frames,
+            windows,
+            hash,
+        })
+    }
+}
+
+/// Render `audio` and compare it against the fingerprint stored at `path`, panicking if its
+/// similarity is below `min_similarity`.
+///
+/// The fingerprint is written instead if the file doesn't exist or the [`BLESS_VAR`]
+/// environment variable is set.
+pub fn assert_golden(
+    audio: &MidiAudio,
+    soundfont: Arc<SoundFont>,
+    path: impl AsRef<Path>,
+    min_similarity: f32,
+) {
+    let path = path.as_ref();
+    let fingerprint = AudioFingerprint::new(&render_deterministic(audio, soundfont));
+    if std::env::var_os(BLESS_VAR).is_some() || !path.exists() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("Failed to create golden directory.");
+        }
+        fs::write(path, fingerprint.to_text()).expect("Failed to write golden fingerprint.");
+        return;
+    }
+    let text = fs::read_to_string(path).expect("Failed to read golden fingerprint.");
+    let golden = AudioFingerprint::from_text(&text).expect("Failed to parse golden fingerprint.");
+    let similarity = fingerprint.similarity(&golden);
+    assert!(
+        similarity >= min_similarity,
+        "Rendered audio differs from golden fingerprint {} (similarity {similarity}, expected at \
+         least {min_similarity}); set {BLESS_VAR} to update it.",
+        path.display(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_text_round_trips() {
+        let samples: Vec<f32> = (0..WINDOW * 5)
+            .map(|index| (index as f32 * 0.05).sin() * (index % 7) as f32 / 7.0)
+            .collect();
+        let fingerprint = AudioFingerprint::new(&samples);
+        assert_eq!(fingerprint.frames, WINDOW * 5 / 2);
+        assert_eq!(fingerprint.windows.len(), 3);
+        let parsed = AudioFingerprint::from_text(&fingerprint.to_text()).unwrap();
+        assert_eq!(parsed, fingerprint);
+        assert_eq!(parsed.similarity(&fingerprint), 1.0);
+    }
+
+    #[test]
+    fn malformed_fingerprints_are_rejected() {
+        assert!(AudioFingerprint::from_text("").is_err());
+        assert!(AudioFingerprint::from_text("frames 1\n").is_err());
+        assert!(AudioFingerprint::from_text("frames 1\nhash 0\n1 2 3\n").is_err());
+    }
+
+    #[test]
+    fn similarity_reflects_spectra() {
+        let tone = |frequency: f32| -> Vec<f32> {
+            (0..WINDOW * 8)
+                .map(|index| (index as f32 * frequency).sin())
+                .collect()
+        };
+        let low = AudioFingerprint::new(&tone(0.01));
+        let louder: Vec<f32> = tone(0.01).iter().map(|sample| sample * 0.5).collect();
+        assert!(low.similarity(&AudioFingerprint::new(&louder)) > 0.99);
+        assert!(low.similarity(&AudioFingerprint::new(&tone(0.5))) < 0.5);
+        assert_eq!(
+            AudioFingerprint::new(&[]).similarity(&AudioFingerprint::new(&[0.0; 64])),
+            1.0
+        );
+    }
+
+    #[cfg(feature = "test-sf")]
+    #[test]
+    fn test_soundfont_golden_render() {
+        use crate::{MidiNote, TEST_SOUNDFONT};
+
+        let soundfont = Arc::new(SoundFont::new(&mut &TEST_SOUNDFONT[..]).unwrap());
+        let notes = [60, 64, 67, 72]
+            .into_iter()
+            .enumerate()
+            .map(|(index, key)| MidiNote {
+                preset: index as i32 * 8,
+                key,
+                duration: std::time::Duration::from_millis(250),
+                ..Default::default()
+            })
+            .collect();
+        let audio = MidiAudio::Sequence(notes);
+        let first = render_deterministic(&audio, soundfont.clone());
+        assert_eq!(first, render_deterministic(&audio, soundfont.clone()));
+        assert!(first.iter().any(|sample| sample.abs() > 0.01));
+        assert_golden(
+            &audio,
+            soundfont,
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/golden/test_sf_arpeggio.txt"
+            ),
+            0.99,
+        );
+    }
+}
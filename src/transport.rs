@@ -0,0 +1,208 @@
+use std::time::Duration;
+
+use bevy::{
+    audio::{AudioSink, SpatialAudioSink},
+    prelude::*,
+};
+
+use crate::{
+    control::QueuedFilter,
+    midi::{GridUnit, TempoMap},
+    MidiAudio, MidiControl, MidiError,
+};
+
+/// Position on the musical grid of the [`MidiTransport`], counted from zero
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MusicalPosition {
+    /// Bar number
+    pub bar: u32,
+    /// Beat within the bar
+    pub beat: u32,
+    /// Tick within the beat
+    pub tick: u32,
+}
+
+/// Component making a MIDI source follow the play, pause and locate commands of the
+/// [`MidiTransport`].
+///
+/// Combine it with a [`MidiSyncGroup`](crate::MidiSyncGroup) to start followers on the same
+/// sample.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct FollowTransport;
+
+/// Component delaying the start of a newly triggered MIDI source until the next boundary of the
+/// [`MidiTransport`]'s musical grid
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuantizedStart {
+    /// Start on the next beat
+    Beat,
+    /// Start on the next bar line
+    #[default]
+    Bar,
+}
+
+/// Resource exposing a shared musical clock which every source carrying [`FollowTransport`]
+/// follows.
+#[derive(Resource, Debug)]
+pub struct MidiTransport {
+    tempo: TempoMap,
+    playing: bool,
+    position: Duration,
+    located: bool,
+    /// Number of times the transport was located, so clocks can follow jumps
+    pub(crate) locations: u64,
+    /// Number of times the tempo was replaced
+    pub(crate) tempo_changes: u64,
+}
+
+impl Default for MidiTransport {
+    fn default() -> Self {
+        Self {
+            tempo: TempoMap::constant(120.0, 4, 4),
+            playing: true,
+            position: Duration::ZERO,
+            located: false,
+            locations: 0,
+            tempo_changes: 0,
+        }
+    }
+}
+
+impl MidiTransport {
+    /// Start or resume playback of every follower
+    pub fn play(&mut self) {
+        self.playing = true;
+    }
+
+    /// Pause every follower at its current position
+    pub fn pause(&mut self) {
+        self.playing = false;
+    }
+
+    /// Pause every follower and return to the start
+    pub fn stop(&mut self) {
+        self.pause();
+        self.locate(Duration::ZERO);
+    }
+
+    /// Move every follower to the given time
+    pub fn locate(&mut self, position: Duration) {
+        self.position = position;
+        self.located = true;
+        self.locations += 1;
+    }
+
+    /// Move every follower to the start of the given zero-based bar
+    pub fn locate_bar(&mut self, bar: u32) {
+        let seconds = self.tempo.seconds(self.tempo.bar_start(bar));
+        self.locate(Duration::from_secs_f64(seconds));
+    }
+
+    /// Whether the transport is playing
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Current time of the transport
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+
+    /// Current bar, beat and tick of the transport
+    pub fn musical_position(&self) -> MusicalPosition {
+        let tick = self.tempo.ticks(self.position.as_secs_f64()) as u64;
+        let (bar, beat, tick) = self.tempo.bar_beat_tick(tick);
+        MusicalPosition { bar, beat, tick }
+    }
+
+    /// Time left until the next beat or bar boundary, or zero when on a boundary
+    pub fn time_to_next(&self, quantize: QuantizedStart) -> Duration {
+        let unit = match quantize {
+            QuantizedStart::Beat => GridUnit::Beat,
+            QuantizedStart::Bar => GridUnit::Bar,
+        };
+        let position = self.position.as_secs_f64();
+        let tick = self.tempo.ticks(position).ceil() as u64;
+        let boundary = self.tempo.seconds(self.tempo.next_boundary(tick, unit));
+        Duration::from_secs_f64((boundary - position).max(0.0))
+    }
+
+    /// Number of ticks in a quarter note
+    pub fn ticks_per_quarter(&self) -> u16 {
+        self.tempo.division
+    }
+
+    /// Current tempo in quarter notes per minute
+    pub fn bpm(&self) -> f64 {
+        self.tempo.bpm(self.position.as_secs_f64())
+    }
+
+    /// Use a constant tempo and time signature for the musical clock
+    pub fn set_tempo(&mut self, bpm: f64, numerator: u8, denominator: u8) {
+        self.tempo = TempoMap::constant(bpm, numerator, denominator);
+        self.tempo_changes += 1;
+    }
+
+    pub(crate) fn tempo(&self) -> &TempoMap {
+        &self.tempo
+    }
+
+    /// Use the tempo and time signature changes of a piece of MIDI audio for the musical clock
+    pub fn set_conductor(&mut self, audio: &MidiAudio) -> Result<(), MidiError> {
+        self.tempo = audio.to_song()?.tempo;
+        self.tempo_changes += 1;
+        Ok(())
+    }
+}
+
+type Follower = (&'static MidiControl, Has<AudioSink>, Has<SpatialAudioSink>);
+
+pub(crate) fn update_transport(
+    time: Res<Time<Real>>,
+    mut transport: ResMut<MidiTransport>,
+    added: Query<&MidiControl, (With<FollowTransport>, Added<MidiControl>)>,
+    followers: Query<Follower, With<FollowTransport>>,
+) {
+    if transport.located {
+        transport.located = false;
+        for (control, _, _) in &followers {
+            control.seek(transport.position);
+        }
+    } else {
+        for control in &added {
+            if !transport.position.is_zero() {
+                control.seek(transport.position);
+            }
+        }
+    }
+
+    for (control, _, _) in &followers {
+        if transport.playing {
+            control.resume();
+        } else {
+            control.pause();
+        }
+    }
+
+    if transport.playing {
+        // Follow the audio output where possible, falling back to the frame time
+        transport.position = followers
+            .iter()
+            .filter(|(control, sink, spatial_sink)| {
+                (*sink || *spatial_sink) && !control.is_stopped() && !control.is_seeking()
+            })
+            .map(|(control, _, _)| control.position())
+            .max()
+            .unwrap_or(transport.position + time.delta());
+    }
+}
+
+/// Delays queued sources carrying [`QuantizedStart`] until the next boundary of the transport
+pub(crate) fn quantize_starts(
+    transport: Res<MidiTransport>,
+    query: Query<(&MidiControl, &QuantizedStart), QueuedFilter>,
+) {
+    for (control, quantize) in &query {
+        control.set_start_delay(transport.time_to_next(*quantize));
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::MidiControl;
+
+/// Component changing how loudly a MIDI source plays notes of each velocity, to tame soundfonts
+/// which are too loud or too flat without editing the music.
+///
+/// It's applied to every note-on the source's synthesizer receives, including notes sent through
+/// its [`MidiControl`].
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+pub enum MidiVelocityCurve {
+    /// Play velocities as they are
+    #[default]
+    Linear,
+    /// Raise quiet velocities, for soundfonts whose soft notes are too quiet
+    Soft,
+    /// Lower quiet velocities, for soundfonts whose dynamics sound flat
+    Hard,
+    /// Play each velocity, from 0 to 127, with the velocity at its index
+    Table(Arc<[u8; 128]>),
+}
+
+impl MidiVelocityCurve {
+    /// Build a table from a function of each velocity
+    pub fn from_fn(mut curve: impl FnMut(u8) -> u8) -> Self {
+        Self::Table(Arc::new(std::array::from_fn(|velocity| {
+            curve(velocity as u8).min(127)
+        })))
+    }
+
+    /// Velocity to play a note of the given velocity with, keeping notes audible
+    pub fn apply(&self, velocity: u8) -> u8 {
+        let velocity = velocity.min(127);
+        if velocity == 0 {
+            return 0;
+        }
+        let exponent = match self {
+            Self::Linear => return velocity,
+            Self::Table(table) => return table[velocity as usize].max(1),
+            Self::Soft => 0.6,
+            Self::Hard => 1.6,
+        };
+        let curved = (velocity as f32 / 127.0).powf(exponent) * 127.0;
+        (curved.round() as u8).clamp(1, 127)
+    }
+}
+
+/// Compression or expansion of the dynamic range of velocities, for music whose dynamics are
+/// wildly inconsistent.
+///
+/// It can be applied when loading through [`MidiLoaderSettings`](crate::MidiLoaderSettings), to
+/// audio with [`MidiAudio::with_dynamics`](crate::MidiAudio::with_dynamics), or while playing
+/// as a [`MidiVelocityCurve`].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MidiDynamics {
+    /// Multiplier of each velocity's distance from the center, compressing below 1.0 and
+    /// expanding above it
+    pub ratio: f32,
+    /// Velocity which is left unchanged
+    pub center: u8,
+    /// Quietest velocity notes are played with
+    pub floor: u8,
+    /// Loudest velocity notes are played with
+    pub ceiling: u8,
+}
+
+impl Default for MidiDynamics {
+    fn default() -> Self {
+        Self {
+            ratio: 1.0,
+            center: 64,
+            floor: 1,
+            ceiling: 127,
+        }
+    }
+}
+
+impl MidiDynamics {
+    /// Scale the distance of velocities from the center by `ratio`
+    pub fn new(ratio: f32) -> Self {
+        Self { ratio, ..default() }
+    }
+
+    /// Keep velocities between `floor` and `ceiling`
+    pub fn with_range(mut self, floor: u8, ceiling: u8) -> Self {
+        self.floor = floor;
+        self.ceiling = ceiling;
+        self
+    }
+
+    /// Whether every velocity is left unchanged
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Velocity to play a note of the given velocity with, leaving note-offs sent as velocity 0
+    pub fn apply(&self, velocity: u8) -> u8 {
+        if velocity == 0 {
+            return 0;
+        }
+        let center = self.center.min(127) as f32;
+        let scaled = center + (velocity.min(127) as f32 - center) * self.ratio.max(0.0);
+        let (floor, ceiling) = (self.floor.clamp(1, 127), self.ceiling.min(127));
+        (scaled.round().clamp(1.0, 127.0) as u8).clamp(floor, ceiling.max(floor))
+    }
+
+    /// Curve applying these dynamics to a playing source
+    pub fn to_curve(&self) -> MidiVelocityCurve {
+        MidiVelocityCurve::from_fn(|velocity| self.apply(velocity))
+    }
+}
+
+/// Sources whose curve changed, or which can only now be controlled
+type ChangedCurveFilter = Or<(Changed<MidiVelocityCurve>, Added<MidiControl>)>;
+
+pub(crate) fn apply_velocity_curves(
+    query: Query<(&MidiControl, &MidiVelocityCurve), ChangedCurveFilter>,
+) {
+    for (control, curve) in &query {
+        control.set_velocity_curve(curve.clone());
+    }
+}